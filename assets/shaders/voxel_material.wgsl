@@ -14,7 +14,11 @@
 #import bevy_pbr::prepass_utils
 
 struct VoxelExtraData {
-    half_extents: vec3<f32>
+    half_extents: vec3<f32>,
+    // diffuse strength, glossy strength and glossy cone aperture, see `VoxelConeTracing`
+    cone_tracing: vec4<f32>,
+    // refractive index minus one of every palette index, four to a vector
+    refraction: array<vec4<f32>, 64>,
 }
 
 @group(1) @binding(0)
@@ -26,6 +30,154 @@ var palette_texture: texture_1d<f32>;
 @group(1) @binding(2)
 var<uniform> voxel_extra_data: VoxelExtraData;
 
+#ifdef VOXEL_GI_PROBES
+// L1 spherical harmonics of every probe, 4 texels per probe along x, see `vox_gi::pipeline::VoxelGIProbes`
+@group(1) @binding(3)
+var irradiance_probes: texture_2d<f32>;
+
+// keep in sync with `PROBE_GRID_SIZE` and `PROBE_SPACING` in `vox_gi::pipeline`
+const PROBE_GRID_SIZE = vec3<i32>(16, 8, 16);
+const PROBE_SPACING = 2.0;
+
+// Irradiance arriving at a surface, blended from the 8 surrounding probes of the grid around the camera.
+fn probe_irradiance(position: vec3<f32>, normal: vec3<f32>) -> vec3<f32> {
+    let origin = vec3<i32>(floor(view.world_position / PROBE_SPACING)) - PROBE_GRID_SIZE / 2;
+    let grid_position = position / PROBE_SPACING;
+    let base = vec3<i32>(floor(grid_position));
+    let f = grid_position - vec3<f32>(base);
+
+    var irradiance = vec3<f32>(0.0);
+    var weight_sum = 0.0;
+    for (var i = 0; i < 8; i = i + 1) {
+        let offset = vec3<i32>(i & 1, (i >> 1u) & 1, i >> 2u);
+        let cell = base + offset;
+        if any(cell < origin) || any(cell >= origin + PROBE_GRID_SIZE) {
+            continue;
+        }
+
+        let trilinear = mix(1.0 - f, f, vec3<f32>(offset));
+        let to_probe = normalize(vec3<f32>(cell) * PROBE_SPACING - position + normal * 0.001);
+        // probes behind the surface can't see it, fade them out smoothly
+        let backface = (dot(to_probe, normal) + 1.0) * 0.5;
+        let weight = trilinear.x * trilinear.y * trilinear.z * (backface * backface + 0.2);
+
+        let slot = ((cell % PROBE_GRID_SIZE) + PROBE_GRID_SIZE) % PROBE_GRID_SIZE;
+        let texel = vec2<i32>(slot.x * 4, slot.y + slot.z * PROBE_GRID_SIZE.y);
+        let sh0 = textureLoad(irradiance_probes, texel, 0).rgb;
+        let sh1 = textureLoad(irradiance_probes, texel + vec2<i32>(1, 0), 0).rgb;
+        let sh2 = textureLoad(irradiance_probes, texel + vec2<i32>(2, 0), 0).rgb;
+        let sh3 = textureLoad(irradiance_probes, texel + vec2<i32>(3, 0), 0).rgb;
+        let probe = 0.886227 * sh0 + 1.023328 * (sh1 * normal.y + sh2 * normal.z + sh3 * normal.x);
+
+        irradiance += max(probe, vec3<f32>(0.0)) * weight;
+        weight_sum += weight;
+    }
+
+    return irradiance / max(weight_sum, 0.0001);
+}
+#endif
+
+#ifdef VOXEL_GI_SHADOWS
+// sun visibility in r and ambient occlusion in g for every pixel of the view, see `vox_gi::pipeline::VoxelGIShadowMask`
+@group(1) @binding(4)
+var shadow_mask: texture_2d<f32>;
+
+// share of the light that still reaches fully shadowed voxels
+const SHADOW_AMBIENT = 0.4;
+#endif
+
+#ifdef VOXEL_GI_CONE_TRACING
+// premultiplied radiance and opacity around the camera, the levels are stacked along z,
+// see `vox_gi::cone::VoxelConeTracingClipmap`
+@group(1) @binding(5)
+var radiance_clipmap: texture_3d<f32>;
+@group(1) @binding(6)
+var clipmap_sampler: sampler;
+
+// keep in sync with `CLIPMAP_RESOLUTION`, `CLIPMAP_LEVELS` and `CLIPMAP_TEXEL_SIZE` in `vox_gi::cone`
+const CLIPMAP_RESOLUTION = 64.0;
+const CLIPMAP_LEVELS = 4;
+const CLIPMAP_TEXEL_SIZE = 0.5;
+// cones stop once they are this opaque
+const CONE_OPACITY = 0.95;
+const CONE_STEPS = 64;
+
+// Filtered clipmap texel at `position`, levels are centered on the camera they were built for.
+fn clipmap_sample(position: vec3<f32>, level: i32) -> vec4<f32> {
+    let texel_size = CLIPMAP_TEXEL_SIZE * exp2(f32(level));
+    let origin = floor(view.world_position / texel_size) - CLIPMAP_RESOLUTION * 0.5;
+    // half a texel away from the sides, so the filter never reads the neighbouring level
+    let local = clamp(position / texel_size - origin, vec3<f32>(0.5), vec3<f32>(CLIPMAP_RESOLUTION - 0.5));
+    let uvw = vec3<f32>(local.xy, local.z + f32(level) * CLIPMAP_RESOLUTION)
+        / vec3<f32>(CLIPMAP_RESOLUTION, CLIPMAP_RESOLUTION, CLIPMAP_RESOLUTION * f32(CLIPMAP_LEVELS));
+    return textureSampleLevel(radiance_clipmap, clipmap_sampler, uvw, 0.0);
+}
+
+// Finest level that covers `position` with a texel to spare, `CLIPMAP_LEVELS` outside of all.
+fn clipmap_level(position: vec3<f32>) -> i32 {
+    for (var level = 0; level < CLIPMAP_LEVELS; level = level + 1) {
+        let texel_size = CLIPMAP_TEXEL_SIZE * exp2(f32(level));
+        let center = floor(view.world_position / texel_size) * texel_size;
+        if all(abs(position - center) < vec3<f32>((CLIPMAP_RESOLUTION * 0.5 - 1.0) * texel_size)) {
+            return level;
+        }
+    }
+    return CLIPMAP_LEVELS;
+}
+
+// Light arriving through a cone with the given tangent of its half angle, radiance in rgb and
+// occlusion in a. Coarser levels stand in for the mips as the cone widens.
+fn cone_trace(origin: vec3<f32>, direction: vec3<f32>, aperture: f32) -> vec4<f32> {
+    var radiance = vec3<f32>(0.0);
+    var occlusion = 0.0;
+    // start a texel out, the surface itself fills the texel it lies in
+    var distance = CLIPMAP_TEXEL_SIZE;
+    for (var i = 0; i < CONE_STEPS; i = i + 1) {
+        let position = origin + direction * distance;
+        let diameter = max(CLIPMAP_TEXEL_SIZE, 2.0 * aperture * distance);
+        let lod = max(log2(diameter / CLIPMAP_TEXEL_SIZE), f32(clipmap_level(position)));
+        if lod >= f32(CLIPMAP_LEVELS) {
+            break;
+        }
+        let level = i32(lod);
+        var texel = clipmap_sample(position, level);
+        if level + 1 < CLIPMAP_LEVELS {
+            texel = mix(texel, clipmap_sample(position, level + 1), fract(lod));
+        }
+        // steps are half a texel long, so each step only gets half the opacity of a full texel
+        let alpha = 1.0 - sqrt(1.0 - min(texel.a, 0.999));
+        radiance += (1.0 - occlusion) * texel.rgb * (alpha / max(texel.a, 0.0001));
+        occlusion += (1.0 - occlusion) * alpha;
+        if occlusion >= CONE_OPACITY {
+            break;
+        }
+        distance += diameter * 0.5;
+    }
+    // whatever leaves the clipmap sees the sky, same gradient as the traced GI
+    let t = clamp(direction.y * 0.5 + 0.5, 0.0, 1.0);
+    let sky = mix(vec3<f32>(0.8, 0.85, 0.9), vec3<f32>(0.35, 0.55, 0.9), t);
+    return vec4<f32>(radiance + (1.0 - occlusion) * sky, occlusion);
+}
+
+// Irradiance from six 60 degree cones over the hemisphere around `normal`.
+fn cone_traced_diffuse(position: vec3<f32>, normal: vec3<f32>) -> vec3<f32> {
+    let up = select(vec3<f32>(0.0, 1.0, 0.0), vec3<f32>(1.0, 0.0, 0.0), abs(normal.y) > 0.9);
+    let tangent = normalize(cross(up, normal));
+    let bitangent = cross(normal, tangent);
+    let origin = position + normal * CLIPMAP_TEXEL_SIZE;
+
+    var irradiance = cone_trace(origin, normal, 0.577).rgb * 0.25;
+    for (var i = 0; i < 5; i = i + 1) {
+        let angle = f32(i) * 1.2566371;
+        let side = tangent * cos(angle) + bitangent * sin(angle);
+        // 60 degrees away from the normal, the cones touch without overlapping much
+        let direction = normal * 0.5 + side * 0.866025;
+        irradiance += cone_trace(origin, direction, 0.577).rgb * 0.15;
+    }
+    return irradiance * PI;
+}
+#endif
+
 struct FragmentInput {
     @builtin(front_facing) is_front: bool,
     @builtin(position) frag_coord: vec4<f32>,
@@ -50,12 +202,213 @@ fn intersect_aabb(
     return vec2<f32>(t_near, t_far);
 }
 
+// Where the ray enters the voxel at `map_pos`, the face normal in xyz and the distance in w,
+// all in model space. Keep in sync with `voxel_entry` in `voxel_material_prepass.wgsl`.
+fn voxel_entry(
+    origin: vec3<f32>,
+    direction: vec3<f32>,
+    map_pos: vec3<i32>,
+    bounding_box_min: vec3<f32>,
+    voxel_size: vec3<f32>
+) -> vec4<f32> {
+    let voxel_min = bounding_box_min + vec3<f32>(map_pos) * voxel_size;
+    let t1 = min((voxel_min - origin) / direction, (voxel_min + voxel_size - origin) / direction);
+    let t_near = max(max(t1.x, t1.y), t1.z);
+    let face = t1 >= max(t1.yzx, t1.zxy);
+    return vec4<f32>(-sign(direction) * vec3<f32>(face), max(t_near, 0.0));
+}
+
 const VOXEL_SCALE = 1.0;
 
 struct FragmentOutput {
     @location(0) color: vec4<f32>,
+    // the voxel surface rather than the bounding box, matching the depth of the prepass
+    @builtin(frag_depth) depth: f32,
 }
 
+// Flat shaded colour of the voxel at `map_pos` seen through the face picked by `mask`, plus the
+// indirect light and shadows of whichever GI feeds the material.
+fn shade_voxel(
+    color: vec3<f32>,
+    mask: vec3<bool>,
+    ray_dir_sign: vec3<f32>,
+    map_pos: vec3<i32>,
+    frag_coord: vec4<f32>
+) -> vec3<f32> {
+    var hit_color = color;
+    var normal = vec4<f32>(0.0, 0.0, 0.0, 0.0);
+    if mask.x {
+        hit_color = hit_color * vec3<f32>(0.5, 0.5, 0.5);
+        normal = -ray_dir_sign.x * vec4<f32>(1.0, 0.0, 0.0, 1.0);
+    }
+    if mask.y {
+        hit_color = hit_color * vec3<f32>(1.0, 1.0, 1.0);
+        normal = -ray_dir_sign.y * vec4<f32>(0.0, 1.0, 0.0, 1.0);
+    }
+    if mask.z {
+        hit_color = hit_color * vec3<f32>(0.75, 0.75, 0.75);
+        normal = -ray_dir_sign.z * vec4<f32>(0.0, 0.0, 1.0, 1.0);
+    }
+
+    let count_voxels = vec3<i32>(textureDimensions(model_texture, 0).xyz);
+    let bounding_box_min = vec3<f32>(-voxel_extra_data.half_extents / VOXEL_SCALE);
+    let bounding_box_max = vec3<f32>(voxel_extra_data.half_extents / VOXEL_SCALE);
+    let voxel_size = (bounding_box_max - bounding_box_min) / vec3<f32>(count_voxels);
+    let local_position = bounding_box_min + (vec3<f32>(map_pos) + 0.5 + normal.xyz * 0.5) * voxel_size;
+    let world_position = (mesh.model * vec4<f32>(local_position, 1.0)).xyz;
+    let world_normal = mesh_normal_local_to_world(normal.xyz);
+
+#ifdef VOXEL_GI_PROBES
+    // diffuse indirect light on top of the flat shading
+    hit_color += color * probe_irradiance(world_position, world_normal) / PI;
+#endif
+
+#ifdef VOXEL_GI_CONE_TRACING
+    let cone_tracing = voxel_extra_data.cone_tracing;
+    hit_color += color * cone_traced_diffuse(world_position, world_normal) / PI * cone_tracing.x;
+    if cone_tracing.y > 0.0 {
+        let to_eye = normalize(view.world_position - world_position);
+        let reflected = reflect(-to_eye, world_normal);
+        let glossy = cone_trace(world_position + world_normal * CLIPMAP_TEXEL_SIZE, reflected, cone_tracing.z);
+        // Schlick's Fresnel of a dielectric, grazing angles reflect the most
+        let fresnel = 0.04 + 0.96 * pow(1.0 - max(dot(world_normal, to_eye), 0.0), 5.0);
+        hit_color += glossy.rgb * fresnel * cone_tracing.y;
+    }
+#endif
+
+#ifdef VOXEL_GI_SHADOWS
+    let mask_size = vec2<i32>(textureDimensions(shadow_mask));
+    let mask_pixel = clamp(vec2<i32>(frag_coord.xy - view.viewport.xy), vec2<i32>(0), mask_size - 1);
+    let shadow = textureLoad(shadow_mask, mask_pixel, 0);
+    hit_color *= mix(SHADOW_AMBIENT, 1.0, shadow.r) * shadow.g;
+#endif
+
+    return hit_color;
+}
+
+#ifdef VOXEL_TRANSLUCENT
+// palette alphas from here on are opaque
+const OPAQUE_ALPHA = 0.999;
+// share of the light a translucent voxel absorbs that it scatters back in its own colour, this
+// stands in for the tint the blending can't give whatever is behind the model
+const SCATTERING = 0.5;
+// rays stop once this little of the background still shows through
+const MIN_THROUGHPUT = 0.01;
+
+fn refractive_index(voxel: u32) -> f32 {
+    if voxel == 0u {
+        return 1.0;
+    }
+    return 1.0 + voxel_extra_data.refraction[voxel / 4u][voxel % 4u];
+}
+
+// Walks the voxels from `origin` in voxel space, accumulating the light of translucent voxels
+// with Beer-Lambert absorption until an opaque voxel or the end of the model. Returns
+// premultiplied radiance and the share of the background it hides, alpha is negative when the
+// ray touched nothing.
+fn march_translucent(
+    origin: vec3<f32>,
+    ray_direction: vec3<f32>,
+    entry_mask: vec3<bool>,
+    frag_coord: vec4<f32>
+) -> vec4<f32> {
+    let count_voxels = vec3<i32>(textureDimensions(model_texture, 0).xyz);
+    var pnt = origin;
+    var direction = normalize(ray_direction);
+    var map_pos = vec3<i32>(pnt + 0.0001);
+    var delta_dist = abs(1.0 / direction);
+    var ray_dir_sign = sign(direction);
+    var side_dist = (ray_dir_sign * (vec3<f32>(map_pos) - pnt) + (ray_dir_sign * 0.5) + 0.5) * delta_dist;
+    var mask = entry_mask;
+    // distance from `pnt` at which the ray entered `map_pos`, in voxels
+    var t = 0.0;
+    // palette index of the medium the ray travels through, 0 for air
+    var medium = 0u;
+
+    var radiance = vec3<f32>(0.0);
+    var throughput = vec3<f32>(1.0);
+    var hit = false;
+
+    // refraction can send the ray back and forth, so allow more than a straight walk
+    let max_steps = max(max(count_voxels.x, count_voxels.y), count_voxels.z) * 4;
+    for (var i = 0; i < max_steps; i = i + 1) {
+        if any(map_pos < vec3<i32>(0)) || any(map_pos >= count_voxels) {
+            break;
+        }
+        let voxel = textureLoad(model_texture, map_pos, 0).r;
+        let palette = textureLoad(palette_texture, i32(voxel), 0);
+
+        if voxel != 0u && palette.a >= OPAQUE_ALPHA {
+            hit = true;
+            radiance += throughput * shade_voxel(palette.rgb, mask, ray_dir_sign, map_pos, frag_coord);
+            throughput = vec3<f32>(0.0);
+            break;
+        }
+
+        if voxel != medium {
+            let normal = -ray_dir_sign * vec3<f32>(mask);
+            let n1 = refractive_index(medium);
+            let n2 = refractive_index(voxel);
+            if voxel != 0u {
+                hit = true;
+                // Schlick's Fresnel, the surface shows its lit colour where it reflects
+                let f0 = max(pow((n2 - n1) / (n2 + n1), 2.0), 0.04);
+                let fresnel = f0 + (1.0 - f0) * pow(1.0 - abs(dot(direction, normal)), 5.0);
+                radiance += throughput * fresnel * shade_voxel(palette.rgb, mask, ray_dir_sign, map_pos, frag_coord);
+                throughput *= 1.0 - fresnel;
+            }
+#ifdef VOXEL_REFRACTION
+            if n1 != n2 && any(mask) {
+                var bent = refract(direction, normal, n1 / n2);
+                var next_medium = voxel;
+                if all(bent == vec3<f32>(0.0)) {
+                    // total internal reflection, the ray goes back across the face it just crossed,
+                    // which also covers the entry face of the model
+                    bent = reflect(direction, normal);
+                    map_pos -= vec3<i32>(mask) * vec3<i32>(ray_dir_sign);
+                    next_medium = medium;
+                }
+                // start a new walk from the face that was crossed
+                pnt += direction * t;
+                direction = normalize(bent);
+                delta_dist = abs(1.0 / direction);
+                ray_dir_sign = sign(direction);
+                side_dist = (ray_dir_sign * (vec3<f32>(map_pos) - pnt) + (ray_dir_sign * 0.5) + 0.5) * delta_dist;
+                t = 0.0;
+                medium = next_medium;
+                continue;
+            }
+#endif
+            medium = voxel;
+        }
+
+        let t_exit = min(min(side_dist.x, side_dist.y), side_dist.z);
+        if voxel != 0u {
+            // Beer-Lambert over the length the ray spends in the voxel, a voxel passes its own
+            // colour with weight alpha and everything with weight 1 - alpha
+            let transmittance = pow(mix(vec3<f32>(1.0), palette.rgb, palette.a), vec3<f32>(t_exit - t));
+            let absorbed = 1.0 - dot(transmittance, vec3<f32>(1.0 / 3.0));
+            radiance += throughput * palette.rgb * absorbed * SCATTERING;
+            throughput *= transmittance;
+            if max(max(throughput.x, throughput.y), throughput.z) < MIN_THROUGHPUT {
+                throughput = vec3<f32>(0.0);
+                break;
+            }
+        }
+
+        t = t_exit;
+        mask = side_dist.xyz <= min(side_dist.yzx, side_dist.zxy);
+        side_dist += vec3<f32>(mask) * delta_dist;
+        map_pos += vec3<i32>(mask) * vec3<i32>(ray_dir_sign);
+    }
+
+    if !hit {
+        return vec4<f32>(0.0, 0.0, 0.0, -1.0);
+    }
+    return vec4<f32>(radiance, 1.0 - dot(throughput, vec3<f32>(1.0 / 3.0)));
+}
+#endif
+
 @fragment
 fn fragment(in: FragmentInput) -> FragmentOutput {
     var out: FragmentOutput;
@@ -75,6 +428,21 @@ fn fragment(in: FragmentInput) -> FragmentOutput {
     var hit_point = pnt;
     pnt = (pnt - bounding_box_min) / (bounding_box_max - bounding_box_min) * vec3<f32>(count_voxels);
 
+#ifdef VOXEL_TRANSLUCENT
+    // the face of the bounding box the ray enters through, the first voxel may already be glass
+    let t_entry = min((bounding_box_min - local_orig.xyz) / direction, (bounding_box_max - local_orig.xyz) / direction);
+    let entry_mask = t_entry >= max(t_entry.yzx, t_entry.zxy);
+    let voxel_direction = direction * vec3<f32>(count_voxels) / (bounding_box_max - bounding_box_min);
+    let translucent = march_translucent(pnt, voxel_direction, entry_mask, in.frag_coord);
+    if translucent.a < 0.0 {
+        discard;
+    }
+    out.color = translucent;
+    // translucent materials skip the prepass and don't write depth, the box is close enough
+    out.depth = in.frag_coord.z;
+    return out;
+#else
+
     // epsilon
     var map_pos = vec3<i32>(pnt + 0.0001);
     let delta_dist = abs(vec3(length(direction)) / direction);
@@ -96,24 +464,7 @@ fn fragment(in: FragmentInput) -> FragmentOutput {
         if voxel != u32(0) {
             hit = true;
             let color = textureLoad(palette_texture, i32(voxel), 0).rgb;
-            var hit_color = color;
-            var normal = vec4<f32>(0.0, 0.0, 0.0, 0.0);
-            if mask.x {
-                hit_color = hit_color * vec3<f32>(0.5, 0.5, 0.5);
-                normal = -ray_dir_sign.x * vec4<f32>(1.0, 0.0, 0.0, 1.0);
-            }
-            if mask.y {
-                hit_color = hit_color * vec3<f32>(1.0, 1.0, 1.0);
-                normal = -ray_dir_sign.y * vec4<f32>(0.0, 1.0, 0.0, 1.0);
-            }
-            if mask.z {
-                hit_color = hit_color * vec3<f32>(0.75, 0.75, 0.75);
-                normal = -ray_dir_sign.z * vec4<f32>(0.0, 0.0, 1.0, 1.0);
-            }
-            
-            
-
-            final_color = vec4<f32>(hit_color, 1.0);
+            final_color = vec4<f32>(shade_voxel(color, mask, ray_dir_sign, map_pos, in.frag_coord), 1.0);
 
             // var pbr_input: PbrInput = pbr_input_new();
             // pbr_input.material.base_color = vec4<f32>(final_color.rgb, 1.0);
@@ -124,6 +475,11 @@ fn fragment(in: FragmentInput) -> FragmentOutput {
             // pbr_input.V = -direction;
 
             out.color = final_color;
+
+            let voxel_size = (bounding_box_max - bounding_box_min) / vec3<f32>(count_voxels);
+            let entry = voxel_entry(local_orig.xyz, direction, map_pos, bounding_box_min, voxel_size);
+            let clip = view.view_proj * mesh.model * vec4<f32>(local_orig.xyz + direction * entry.w, 1.0);
+            out.depth = clip.z / clip.w;
             break;
         }
         mask = side_dist.xyz <= min(side_dist.yzx, side_dist.zxy);
@@ -143,4 +499,5 @@ fn fragment(in: FragmentInput) -> FragmentOutput {
     }
 
     return out;
+#endif
 }
\ No newline at end of file
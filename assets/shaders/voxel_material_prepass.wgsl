@@ -1,12 +1,11 @@
 #import bevy_pbr::prepass_bindings
-#import bevy_pbr::pbr_bindings
-#ifdef NORMAL_PREPASS
-#import bevy_pbr::pbr_functions
-#endif // NORMAL_PREPASS
-
+#import bevy_pbr::mesh_functions
 
+// only opaque materials reach the prepass, so the GI bindings of the main shader aren't needed
 struct VoxelExtraData {
-    half_extents: vec3<f32>
+    half_extents: vec3<f32>,
+    cone_tracing: vec4<f32>,
+    refraction: array<vec4<f32>, 64>,
 }
 
 @group(1) @binding(0)
@@ -18,61 +17,13 @@ var palette_texture: texture_1d<f32>;
 @group(1) @binding(2)
 var<uniform> voxel_extra_data: VoxelExtraData;
 
-
+// the prepass vertex shader has no world position output, the ray is rebuilt from the pixel
 struct FragmentInput {
-    @builtin(front_facing) is_front: bool,
     @builtin(position) frag_coord: vec4<f32>,
-#ifdef VERTEX_UVS
-    @location(0) uv: vec2<f32>,
-#endif // VERTEX_UVS
-
-    @location(1) world_normal: vec3<f32>,
 };
 
-// Cutoff used for the premultiplied alpha modes BLEND and ADD.
-const PREMULTIPLIED_ALPHA_CUTOFF = 0.05;
-
-// We can use a simplified version of alpha_discard() here since we only need to handle the alpha_cutoff
-fn prepass_alpha_discard(in: FragmentInput) {
-
-// This is a workaround since the preprocessor does not support
-// #if defined(ALPHA_MASK) || defined(BLEND_PREMULTIPLIED_ALPHA)
-    #ifndef ALPHA_MASK
-    #ifndef BLEND_PREMULTIPLIED_ALPHA
-    #ifndef BLEND_ALPHA
-
-    #define EMPTY_PREPASS_ALPHA_DISCARD
-
-#endif // BLEND_ALPHA
-#endif // BLEND_PREMULTIPLIED_ALPHA not defined
-#endif // ALPHA_MASK not defined
-
-    #ifndef EMPTY_PREPASS_ALPHA_DISCARD
-    var output_color: vec4<f32> = material.base_color;
-
-#ifdef VERTEX_UVS
-    if (material.flags & STANDARD_MATERIAL_FLAGS_BASE_COLOR_TEXTURE_BIT) != 0u {
-        output_color = output_color * textureSample(base_color_texture, base_color_sampler, in.uv);
-    }
-#endif // VERTEX_UVS
-
-#ifdef ALPHA_MASK
-    if ((material.flags & STANDARD_MATERIAL_FLAGS_ALPHA_MODE_MASK) != 0u) && output_color.a < material.alpha_cutoff {
-        discard;
-    }
-#else // BLEND_PREMULTIPLIED_ALPHA || BLEND_ALPHA
-    let alpha_mode = material.flags & STANDARD_MATERIAL_FLAGS_ALPHA_MODE_RESERVED_BITS;
-    if (alpha_mode == STANDARD_MATERIAL_FLAGS_ALPHA_MODE_BLEND || alpha_mode == STANDARD_MATERIAL_FLAGS_ALPHA_MODE_ADD) && output_color.a < PREMULTIPLIED_ALPHA_CUTOFF {
-        discard;
-    } else if alpha_mode == STANDARD_MATERIAL_FLAGS_ALPHA_MODE_PREMULTIPLIED && all(output_color < vec4(PREMULTIPLIED_ALPHA_CUTOFF)) {
-        discard;
-    }
-#endif // !ALPHA_MASK
-
-#endif // EMPTY_PREPASS_ALPHA_DISCARD not defined
-}
-
 struct FragmentOutput {
+    @builtin(frag_depth) depth: f32,
 #ifdef NORMAL_PREPASS
     @location(0) normal: vec4<f32>,
 #endif // NORMAL_PREPASS
@@ -96,44 +47,35 @@ fn intersect_aabb(
     return vec2<f32>(t_near, t_far);
 }
 
+// Where the ray enters the voxel at `map_pos`, the face normal in xyz and the distance in w,
+// all in model space. Keep in sync with `voxel_entry` in `voxel_material.wgsl`.
+fn voxel_entry(
+    origin: vec3<f32>,
+    direction: vec3<f32>,
+    map_pos: vec3<i32>,
+    bounding_box_min: vec3<f32>,
+    voxel_size: vec3<f32>
+) -> vec4<f32> {
+    let voxel_min = bounding_box_min + vec3<f32>(map_pos) * voxel_size;
+    let t1 = min((voxel_min - origin) / direction, (voxel_min + voxel_size - origin) / direction);
+    let t_near = max(max(t1.x, t1.y), t1.z);
+    let face = t1 >= max(t1.yzx, t1.zxy);
+    return vec4<f32>(-sign(direction) * vec3<f32>(face), max(t_near, 0.0));
+}
+
 const VOXEL_SCALE = 1.0;
 
 @fragment
 fn fragment(in: FragmentInput) -> FragmentOutput {
-    prepass_alpha_discard(in);
-
     var out: FragmentOutput;
-
-    // NOTE: Unlit bit not set means == 0 is true, so the true case is if lit
-    if (material.flags & STANDARD_MATERIAL_FLAGS_UNLIT_BIT) == 0u {
-        let world_normal = prepare_world_normal(
-            in.world_normal,
-            (material.flags & STANDARD_MATERIAL_FLAGS_DOUBLE_SIDED_BIT) != 0u,
-            in.is_front,
-        );
-
-        let normal = apply_normal_mapping(
-            material.flags,
-            world_normal,
-#ifdef VERTEX_TANGENTS
-#ifdef STANDARDMATERIAL_NORMAL_MAP
-            in.world_tangent,
-#endif // STANDARDMATERIAL_NORMAL_MAP
-#endif // VERTEX_TANGENTS
-#ifdef VERTEX_UVS
-            in.uv,
-#endif // VERTEX_UVS
-        );
-
-        out.normal = vec4(normal * 0.5 + vec3(0.5), 1.0);
-    } else {
-        out.normal = vec4(in.world_normal * 0.5 + vec3(0.5), 1.0);
-    }
-
     var count_voxels = vec3<i32>(textureDimensions(model_texture, 0).xyz);
 
+    let ndc = vec2<f32>(1.0, -1.0) * ((in.frag_coord.xy - view.viewport.xy) / view.viewport.zw * 2.0 - 1.0);
+    let world = view.inverse_view_proj * vec4<f32>(ndc, in.frag_coord.z, 1.0);
+    let world_position = world.xyz / world.w;
+
     var inverse_model = transpose(mesh.inverse_transpose_model);
-    var local_dir: vec4<f32> = inverse_model * vec4(normalize(in.world_position.xyz - view.world_position.xyz), 0.0);
+    var local_dir: vec4<f32> = inverse_model * vec4(normalize(world_position - view.world_position.xyz), 0.0);
     var local_orig = inverse_model * vec4(view.world_position.xyz, 1.0);
 
     var pnt = local_orig.xyz;
@@ -143,10 +85,10 @@ fn fragment(in: FragmentInput) -> FragmentOutput {
     let bounding_box_max = vec3<f32>(voxel_extra_data.half_extents / VOXEL_SCALE);
 
     pnt = pnt + direction * max(0.0, intersect_aabb(pnt, direction, bounding_box_min, bounding_box_max).x);
-    var hit_point = pnt;
     pnt = (pnt - bounding_box_min) / (bounding_box_max - bounding_box_min) * vec3<f32>(count_voxels);
 
-    var map_pos = vec3<i32>(pnt);
+    // same walk as the opaque path of `voxel_material.wgsl`
+    var map_pos = vec3<i32>(pnt + 0.0001);
     let delta_dist = abs(vec3(length(direction)) / direction);
     let ray_dir_sign = sign(direction);
     let ray_step = vec3<i32>(ray_dir_sign);
@@ -181,5 +123,14 @@ fn fragment(in: FragmentInput) -> FragmentOutput {
         discard;
     }
 
+    let voxel_size = (bounding_box_max - bounding_box_min) / vec3<f32>(count_voxels);
+    let entry = voxel_entry(local_orig.xyz, direction, map_pos, bounding_box_min, voxel_size);
+    let clip = view.view_proj * mesh.model * vec4<f32>(local_orig.xyz + direction * entry.w, 1.0);
+    out.depth = clip.z / clip.w;
+
+#ifdef NORMAL_PREPASS
+    out.normal = vec4(normalize(mesh_normal_local_to_world(entry.xyz)) * 0.5 + vec3(0.5), 1.0);
+#endif // NORMAL_PREPASS
+
     return out;
-}
\ No newline at end of file
+}
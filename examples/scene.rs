@@ -0,0 +1,180 @@
+use std::f32::consts::PI;
+
+use bevy::{
+    diagnostic::{FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin},
+    prelude::*,
+    render::{settings::WgpuSettings, RenderPlugin},
+};
+use bevy_flycam::prelude::*;
+use bevy_inspector_egui::quick::WorldInspectorPlugin;
+use southwall::{
+    terrain::{chunk_coord, chunk_noise_map, chunk_to_model, generate_noise_map},
+    world::{
+        spawn_chunk, LoadVoxelWorld, SaveVoxelWorld, VoxelChunk, VoxelWorld, VoxelWorldPlugin,
+    },
+    Vox, VoxelBundle, VoxelMaterial, VoxelPlugin,
+};
+#[cfg(feature = "gi")]
+use southwall::{VoxelGICamera3dBundle, VoxelGIPlugin};
+
+fn main() {
+    let mut app = App::new();
+    app.add_plugins(
+        DefaultPlugins
+            .set(AssetPlugin {
+                watch_for_changes: true,
+                ..Default::default()
+            })
+            .set(WindowPlugin {
+                primary_window: Some(Window {
+                    present_mode: bevy::window::PresentMode::Mailbox,
+                    ..default()
+                }),
+                ..Default::default()
+            })
+            .set(RenderPlugin {
+                wgpu_settings: WgpuSettings {
+                    ..Default::default()
+                },
+            }),
+    )
+    .add_plugin(WorldInspectorPlugin::default())
+    .add_plugin(LogDiagnosticsPlugin::default())
+    .add_plugin(FrameTimeDiagnosticsPlugin)
+    .add_plugin(VoxelPlugin {
+        shader_root: Some("shaders".into()),
+    })
+    .add_plugin(VoxelWorldPlugin)
+    .insert_resource(VoxelWorld {
+        directory: "world".into(),
+        origin: Vec3::new(35.0, 0.0, 35.0),
+    })
+    // .add_plugin(TemporalAntiAliasPlugin)
+    .add_startup_system(setup)
+    .add_system(save_load_keys);
+
+    // with GI the scene is traced from our own camera instead of the default flycam one
+    #[cfg(feature = "gi")]
+    app.add_plugin(VoxelGIPlugin)
+        .add_plugin(NoCameraPlayerPlugin)
+        .add_startup_system(spawn_gi_camera);
+    #[cfg(not(feature = "gi"))]
+    app.add_plugin(PlayerPlugin);
+
+    app.run();
+}
+
+#[cfg(feature = "gi")]
+fn spawn_gi_camera(mut commands: Commands) {
+    commands.spawn((
+        VoxelGICamera3dBundle {
+            transform: Transform::from_xyz(-2.0, 2.5, 5.0).looking_at(Vec3::ZERO, Vec3::Y),
+            ..Default::default()
+        },
+        FlyCam,
+    ));
+}
+
+#[allow(clippy::too_many_arguments)]
+fn setup(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut vox_materials: ResMut<Assets<VoxelMaterial>>,
+    mut vox_assets: ResMut<Assets<Vox>>,
+    mut textures: ResMut<Assets<Image>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    world: Res<VoxelWorld>,
+) {
+    // commands.spawn(VoxelBundle {
+    //     material: vox_materials.add(VoxelMaterial {
+    //         vox: asset_server.load("vox/3x3x3.vox"),
+    //         ..Default::default()
+    //     }),
+    //     transform: Transform::from_xyz(0.0, 0.5, 0.0)
+    //         .with_rotation(Quat::from_rotation_y(PI / 4.0)),
+    //     ..Default::default()
+    // });
+
+    commands.spawn(VoxelBundle {
+        material: vox_materials.add(VoxelMaterial {
+            vox: asset_server.load("vox/castle.vox"),
+
+            ..Default::default()
+        }),
+        transform: Transform::from_xyz(0.0, 0.5, -10.0),
+        ..Default::default()
+    });
+
+    // commands.spawn(VoxelBundle {
+    //     material: vox_materials.add(VoxelMaterial {
+    //         vox: asset_server.load("vox/monu3.vox"),
+    //         ..Default::default()
+    //     }),
+    //     transform: Transform::from_xyz(-20.0, 0.5, -10.0),
+    //     ..Default::default()
+    // });
+    commands.spawn(MaterialMeshBundle {
+        mesh: meshes.add(Mesh::from(shape::Cube { size: 1.0 })),
+        material: materials.add(Color::rgb(1.0, 0.4, 0.4).into()),
+        transform: Transform::from_xyz(-100.0, 0.5, 0.0)
+            .with_rotation(Quat::from_rotation_y(PI / 4.0)),
+        ..Default::default()
+    });
+
+    // let plane = asset_server.load("vox/basic-tile.vox");
+
+    // for x in 0..20 {
+    //     for z in 0..20 {
+    //         commands.spawn(VoxelBundle {
+    //             material: vox_materials.add(VoxelMaterial {
+    //                 vox: plane.clone(),
+    //                 ..Default::default()
+    //             }),
+    //             transform: Transform::from_xyz(-x as f32 * 50.0, -5.0, -z as f32 * 50.0),
+    //             ..Default::default()
+    //         });
+    //     }
+    // }
+
+    let width = 512;
+    let height = 24;
+    let depth = 512;
+
+    let map = generate_noise_map(width, height, depth);
+    let chunks = chunk_noise_map(map, width, height, depth);
+
+    for (i, chunk) in chunks.iter().enumerate() {
+        let model = chunk_to_model(chunk);
+
+        let palette = vec![dot_vox::Color {
+            r: 50,
+            g: 50,
+            b: 50,
+            a: 255,
+        }];
+
+        spawn_chunk(
+            &mut commands,
+            &world,
+            VoxelChunk::from_model(chunk_coord(i, width), &model, palette),
+            &mut meshes,
+            &mut textures,
+            &mut vox_assets,
+            &mut vox_materials,
+        );
+    }
+}
+
+fn save_load_keys(
+    keys: Res<Input<KeyCode>>,
+    mut save: EventWriter<SaveVoxelWorld>,
+    mut load: EventWriter<LoadVoxelWorld>,
+) {
+    if keys.just_pressed(KeyCode::F5) {
+        save.send(SaveVoxelWorld);
+    }
+    if keys.just_pressed(KeyCode::F9) {
+        load.send(LoadVoxelWorld);
+    }
+}
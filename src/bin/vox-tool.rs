@@ -0,0 +1,314 @@
+//! Headless `.vox` processing for build pipelines, no window or GPU needed.
+//!
+//! ```text
+//! vox-tool stats <in.vox|vxr|vxc>
+//! vox-tool convert <in> <out.vox|vxr|vxc>
+//! vox-tool merge <out> <in>[@x,y,z]...
+//! vox-tool repalette <in> <palette source> <out>
+//! vox-tool bake <in> <out dir>
+//! vox-tool render <in> <out.exr|pfm|png|ppm> [samples]
+//! ```
+//!
+//! `.vxr` is the raw dense format and `.vxc` its run-length compressed variant, see [`southwall::vox_ops`].
+//! `render` path traces the models on the CPU like the GI plugin does, it needs the `gi` feature.
+
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::{anyhow, Context};
+use bevy::math::UVec3;
+use dot_vox::{Color, DotVoxData, Model};
+use southwall::vox_ops::{
+    collision_boxes, merge_models, model_stats, occupancy_mips, read_raw, repalette, write_raw,
+};
+
+const USAGE: &str = "usage: vox-tool <stats|convert|merge|repalette|bake|render> ...";
+
+fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if let Err(e) = run(&args) {
+        eprintln!("error: {e:#}");
+        std::process::exit(1);
+    }
+}
+
+fn run(args: &[String]) -> anyhow::Result<()> {
+    let Some((command, args)) = args.split_first() else {
+        return Err(anyhow!(USAGE));
+    };
+
+    match (command.as_str(), args) {
+        ("stats", [input]) => stats(input),
+        ("convert", [input, output]) => {
+            let (models, palette) = read_models(input)?;
+            write_models(output, &models, &palette)
+        }
+        ("merge", [output, inputs @ ..]) if !inputs.is_empty() => merge(output, inputs),
+        ("repalette", [input, palette_source, output]) => {
+            let (models, palette) = read_models(input)?;
+            let (_, target) = read_models(palette_source)?;
+            let models = models
+                .iter()
+                .map(|model| repalette(model, &palette, &target))
+                .collect::<Vec<_>>();
+            write_models(output, &models, &target)
+        }
+        ("bake", [input, out_dir]) => bake(input, out_dir),
+        #[cfg(feature = "gi")]
+        ("render", [input, output, rest @ ..]) if rest.len() <= 1 => {
+            let samples = match rest.first() {
+                Some(samples) => samples
+                    .parse()
+                    .with_context(|| format!("Invalid sample count {samples}"))?,
+                None => 64,
+            };
+            render(input, output, samples)
+        }
+        _ => Err(anyhow!(USAGE)),
+    }
+}
+
+fn stats(input: &str) -> anyhow::Result<()> {
+    let (models, palette) = read_models(input)?;
+    println!("{input}: {} model(s)", models.len());
+
+    for (i, model) in models.iter().enumerate() {
+        let stats = model_stats(model);
+        println!(
+            "  model {i}: {}x{}x{}, {} voxels ({:.1}% filled), {} of {} colors used",
+            stats.size.x,
+            stats.size.y,
+            stats.size.z,
+            stats.voxel_count,
+            stats.fill_ratio() * 100.0,
+            stats.used_colors(),
+            palette.len(),
+        );
+
+        let mut usage = stats
+            .palette_usage
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .collect::<Vec<_>>();
+        usage.sort_by(|a, b| b.1.cmp(a.1));
+        for (index, count) in usage.iter().take(8) {
+            let color = palette.get(*index).copied().unwrap_or(Color {
+                r: 0,
+                g: 0,
+                b: 0,
+                a: 0,
+            });
+            println!(
+                "    #{index:<3} rgba({}, {}, {}, {}): {count}",
+                color.r, color.g, color.b, color.a
+            );
+        }
+    }
+    Ok(())
+}
+
+fn merge(output: &str, inputs: &[String]) -> anyhow::Result<()> {
+    let mut loaded = vec![];
+    let mut palette = vec![];
+    for input in inputs {
+        let (path, offset) = match input.split_once('@') {
+            Some((path, offset)) => (path, parse_offset(offset)?),
+            None => (input.as_str(), UVec3::ZERO),
+        };
+        let (models, model_palette) = read_models(path)?;
+        // everything ends up in the palette of the first input
+        if palette.is_empty() {
+            palette = model_palette.clone();
+        }
+        for model in models {
+            loaded.push((repalette(&model, &model_palette, &palette), offset));
+        }
+    }
+
+    let merged = merge_models(
+        &loaded
+            .iter()
+            .map(|(model, offset)| (model, *offset))
+            .collect::<Vec<_>>(),
+    )?;
+    write_models(output, &[merged], &palette)
+}
+
+fn bake(input: &str, out_dir: &str) -> anyhow::Result<()> {
+    let (models, _) = read_models(input)?;
+    let out_dir = Path::new(out_dir);
+    std::fs::create_dir_all(out_dir)?;
+
+    for (i, model) in models.iter().enumerate() {
+        // one byte per cell, 0 or 1, x fastest
+        let mut mips = BufWriter::new(File::create(out_dir.join(format!("model{i}.occupancy")))?);
+        for (size, cells) in occupancy_mips(model) {
+            for axis in [size.x, size.y, size.z] {
+                mips.write_all(&axis.to_le_bytes())?;
+            }
+            mips.write_all(&cells.iter().map(|c| *c as u8).collect::<Vec<_>>())?;
+        }
+
+        let boxes = collision_boxes(model);
+        let mut collision = BufWriter::new(File::create(
+            out_dir.join(format!("model{i}.collision.txt")),
+        )?);
+        for (min, max) in &boxes {
+            writeln!(
+                collision,
+                "{} {} {} {} {} {}",
+                min.x, min.y, min.z, max.x, max.y, max.z
+            )?;
+        }
+
+        println!(
+            "model {i}: {} voxels -> {} collision boxes",
+            model.voxels.len(),
+            boxes.len()
+        );
+    }
+    Ok(())
+}
+
+/// Reference render of all models next to each other along x, lit by the default sun.
+#[cfg(feature = "gi")]
+fn render(input: &str, output: &str, samples: u32) -> anyhow::Result<()> {
+    use bevy::prelude::{GlobalTransform, Mat4, PerspectiveProjection, Transform, UVec2, Vec3};
+    use southwall::{
+        vox::{get_model_texture, get_palette_emission, get_palette_texture, VOXEL_SIZE},
+        vox_gi::pipeline::{VoxelGISettings, VoxelGISun},
+        VoxelGIReferenceModel, VoxelGIReferenceScene, VoxelGISky,
+    };
+
+    let (models, palette) = read_models(input)?;
+    // raw formats carry no materials, so nothing glows
+    let emission = match extension(input).as_str() {
+        "vox" => get_palette_emission(
+            &dot_vox::load(input)
+                .map_err(|e| anyhow!("Failed to load {input}: {e}"))?
+                .materials,
+        ),
+        _ => vec![],
+    };
+    let palette_texture = get_palette_texture(palette);
+    let textures = models.iter().map(get_model_texture).collect::<Vec<_>>();
+
+    let mut x = 0.0;
+    let mut placed = vec![];
+    for (model, texture) in models.iter().zip(&textures) {
+        let width = model.size.x as f32 * VOXEL_SIZE;
+        placed.push(VoxelGIReferenceModel {
+            model_texture: texture,
+            palette_texture: &palette_texture,
+            emission: &emission,
+            transform: Mat4::from_translation(Vec3::X * (x + width / 2.0)),
+        });
+        x += width + 1.0;
+    }
+    let scene = VoxelGIReferenceScene::from_models(&placed);
+    let (min, max) = scene
+        .bounds()
+        .ok_or_else(|| anyhow!("{input} has no voxels to render"))?;
+
+    // looks at the center from the front, far enough back to fit the bounding sphere
+    let center = (min + max) / 2.0;
+    let radius = (max - min).length() / 2.0;
+    let camera =
+        Transform::from_translation(center + Vec3::new(1.0, 0.8, 1.6).normalize() * radius * 2.8)
+            .looking_at(center, Vec3::Y);
+    let image = scene.render(
+        &GlobalTransform::from(camera),
+        &PerspectiveProjection::default(),
+        UVec2::new(640, 480),
+        &VoxelGISettings::HIGH,
+        VoxelGISun::default().direction,
+        &VoxelGISky::default(),
+        samples,
+    );
+    image.save(output)?;
+    println!(
+        "{output}: {}x{}, {samples} samples per pixel",
+        image.size.x, image.size.y
+    );
+    Ok(())
+}
+
+fn parse_offset(offset: &str) -> anyhow::Result<UVec3> {
+    let parts = offset
+        .split(',')
+        .map(|part| part.trim().parse::<u32>())
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Invalid offset {offset}"))?;
+    match parts[..] {
+        [x, y, z] => Ok(UVec3::new(x, y, z)),
+        _ => Err(anyhow!("Offset {offset} should be x,y,z")),
+    }
+}
+
+fn extension(path: &str) -> String {
+    Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_lowercase()
+}
+
+fn read_models(path: &str) -> anyhow::Result<(Vec<Model>, Vec<Color>)> {
+    match extension(path).as_str() {
+        "vox" => {
+            let data = dot_vox::load(path).map_err(|e| anyhow!("Failed to load {path}: {e}"))?;
+            Ok((data.models, data.palette))
+        }
+        "vxr" | "vxc" => {
+            let (model, palette) = read_raw(&mut BufReader::new(File::open(path)?))
+                .with_context(|| format!("Failed to load {path}"))?;
+            Ok((vec![model], palette))
+        }
+        ext => Err(anyhow!("Unknown input format .{ext}")),
+    }
+}
+
+fn write_models(path: &str, models: &[Model], palette: &[Color]) -> anyhow::Result<()> {
+    // check everything before the file is created, a failed command leaves no output behind
+    let raw = match (extension(path).as_str(), models) {
+        ("vox", _) => None,
+        ("vxr", [model]) => Some((model, false)),
+        ("vxc", [model]) => Some((model, true)),
+        ("vxr" | "vxc", _) => {
+            return Err(anyhow!(
+                "Raw formats hold a single model, got {}",
+                models.len()
+            ))
+        }
+        (ext, _) => return Err(anyhow!("Unknown output format .{ext}")),
+    };
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    match raw {
+        Some((model, compressed)) => write_raw(&mut writer, model, palette, compressed)?,
+        None => {
+            let data = DotVoxData {
+                version: 150,
+                // dot_vox models aren't `Clone`
+                models: models
+                    .iter()
+                    .map(|model| Model {
+                        size: model.size,
+                        voxels: model.voxels.clone(),
+                    })
+                    .collect(),
+                palette: palette.to_vec(),
+                materials: vec![],
+                scenes: vec![],
+                layers: vec![],
+            };
+            data.write_vox(&mut writer)?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
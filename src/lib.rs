@@ -0,0 +1,26 @@
+pub mod vox;
+pub mod vox_ops;
+pub mod vox_plugin;
+pub mod world;
+
+#[cfg(feature = "gi")]
+pub mod vox_gi;
+
+#[cfg(feature = "terrain")]
+pub mod terrain;
+
+pub use vox::Vox;
+pub use vox_plugin::{VoxelBundle, VoxelMaterial, VoxelPlugin};
+
+#[cfg(feature = "gi")]
+pub use vox_gi::{
+    cone::{VoxelConeTracing, VoxelConeTracingPlugin},
+    export::{VoxelGICameraKeyframe, VoxelGICameraPath, VoxelGIProgressive, VoxelGIRenderFinished},
+    pipeline::{
+        VoxelGI, VoxelGICamera3dBundle, VoxelGICameraCut, VoxelGIDebugView, VoxelGIHybridBundle,
+        VoxelGIMode, VoxelGIResolution, VoxelGISettings,
+    },
+    plugin::VoxelGIPlugin,
+    reference::{VoxelGIReferenceImage, VoxelGIReferenceModel, VoxelGIReferenceScene},
+    sky::VoxelGISky,
+};
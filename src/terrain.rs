@@ -0,0 +1,95 @@
+use bevy::prelude::IVec3;
+use dot_vox::{Model, Size, Voxel};
+use noise::{Fbm, NoiseFn, Perlin};
+
+pub const CHUNK_WIDTH: usize = 32;
+pub const CHUNK_HEIGHT: usize = 24;
+pub const CHUNK_DEPTH: usize = 32;
+
+pub fn generate_noise_map(width: usize, height: usize, depth: usize) -> Vec<f64> {
+    let mut perlin = Fbm::<Perlin>::new(234982374);
+    perlin.octaves = 2;
+    perlin.frequency = 0.5;
+
+    let mut map = Vec::with_capacity(width * height * depth);
+    for z in 0..depth {
+        for y in 0..height {
+            for x in 0..width {
+                let value = perlin.get([x as f64 / 10.0, y as f64 / 10.0, z as f64 / 10.0]);
+                map.push(value);
+            }
+        }
+    }
+    map
+}
+
+pub fn chunk_noise_map(map: Vec<f64>, width: usize, height: usize, depth: usize) -> Vec<Vec<f64>> {
+    let chunks_in_x = width / CHUNK_WIDTH;
+    let chunks_in_y = height / CHUNK_HEIGHT;
+    let chunks_in_z = depth / CHUNK_DEPTH;
+
+    let mut chunks = Vec::new();
+
+    for zc in 0..chunks_in_z {
+        for yc in 0..chunks_in_y {
+            for xc in 0..chunks_in_x {
+                let mut chunk = Vec::with_capacity(CHUNK_WIDTH * CHUNK_HEIGHT * CHUNK_DEPTH);
+
+                for z in 0..CHUNK_DEPTH {
+                    for y in 0..CHUNK_HEIGHT {
+                        for x in 0..CHUNK_WIDTH {
+                            let xi = xc * CHUNK_WIDTH + x;
+                            let yi = yc * CHUNK_HEIGHT + y;
+                            let zi = zc * CHUNK_DEPTH + z;
+
+                            let value = map[zi * width * height + yi * width + xi];
+                            chunk.push(value);
+                        }
+                    }
+                }
+
+                chunks.push(chunk);
+            }
+        }
+    }
+    chunks
+}
+
+/// Turns one chunk of the noise map into a model, every voxel uses palette index 0.
+pub fn chunk_to_model(chunk: &[f64]) -> Model {
+    let mut voxels = vec![];
+    for (index, value) in chunk.iter().enumerate() {
+        let x = (index / (CHUNK_WIDTH * CHUNK_HEIGHT)) as u8;
+        let z = ((index % (CHUNK_WIDTH * CHUNK_HEIGHT)) / CHUNK_WIDTH) as u8;
+        let y = ((index % (CHUNK_WIDTH * CHUNK_HEIGHT)) % CHUNK_WIDTH) as u8;
+
+        assert!(x < CHUNK_WIDTH as u8);
+        assert!(y < CHUNK_DEPTH as u8);
+        assert!(z < CHUNK_HEIGHT as u8);
+
+        if (CHUNK_HEIGHT as u8 - z) < ((value * 50.0) as u8) {
+            continue;
+        }
+
+        voxels.push(Voxel { x, y, z, i: 0 });
+    }
+    assert!(voxels.len() <= CHUNK_WIDTH * CHUNK_HEIGHT * CHUNK_DEPTH);
+
+    Model {
+        voxels,
+        size: Size {
+            x: CHUNK_WIDTH as u32,
+            y: CHUNK_DEPTH as u32,
+            z: CHUNK_HEIGHT as u32,
+        },
+    }
+}
+
+/// Chunk coordinate of the `i`th chunk returned by [`chunk_noise_map`] for a map `width` voxels wide.
+pub fn chunk_coord(i: usize, width: usize) -> IVec3 {
+    IVec3::new(
+        (i % (width / CHUNK_WIDTH)) as i32,
+        0,
+        (i / (width / CHUNK_WIDTH)) as i32,
+    )
+}
@@ -7,6 +7,9 @@ use bevy::{
 };
 use dot_vox::{DotVoxData, Model};
 
+/// World space size of a single voxel.
+pub const VOXEL_SIZE: f32 = 0.25;
+
 #[derive(Default)]
 pub struct VoxLoader;
 
@@ -30,6 +33,12 @@ pub struct Vox {
     pub model_texture: Handle<Image>,
     pub palette_texture: Handle<Image>,
     pub mesh: Handle<Mesh>,
+    /// Emission strength per palette index, laid out like `palette_texture`
+    pub emission: Vec<f32>,
+    /// Refractive index minus one per palette index, laid out like `palette_texture`
+    pub refraction: Vec<f32>,
+    /// Whether any voxel of the model has a palette alpha below 1 and needs blending
+    pub translucent: bool,
 }
 
 async fn load_vox<'a, 'b>(
@@ -45,18 +54,21 @@ async fn load_vox<'a, 'b>(
 
     let model = data.models.first().unwrap();
     let model_texture = get_model_texture(model);
+    let palette = get_glass_palette(data.palette, &data.materials);
+    let translucent = has_translucent_voxels(model, &palette);
     let mesh =
         load_context.set_labeled_asset("mesh", LoadedAsset::new(get_mesh_from_model(&model)));
     let model = load_context.set_labeled_asset("model", LoadedAsset::new(model_texture));
-    let palette = load_context.set_labeled_asset(
-        "palette",
-        LoadedAsset::new(get_palette_texture(data.palette)),
-    );
+    let palette =
+        load_context.set_labeled_asset("palette", LoadedAsset::new(get_palette_texture(palette)));
 
     load_context.set_default_asset(LoadedAsset::new(Vox {
         model_texture: model,
         palette_texture: palette,
         mesh,
+        emission: get_palette_emission(&data.materials),
+        refraction: get_palette_refraction(&data.materials),
+        translucent,
     }));
     Ok(())
 }
@@ -115,10 +127,100 @@ pub fn get_palette_texture(palette: Vec<dot_vox::Color>) -> Image {
     )
 }
 
+/// Emission strength of every palette entry, index 0 being empty like in `get_palette_texture`.
+/// MagicaVoxel's emissive materials store a strength in `_emit` and a power in `_flux`,
+/// which is applied as `_emit * (1 + _flux)`.
+pub fn get_palette_emission(materials: &[dot_vox::Material]) -> Vec<f32> {
+    let mut emission = vec![0.0; 257];
+    for material in materials {
+        if material.properties.get("_type").map(String::as_str) != Some("_emit") {
+            continue;
+        }
+        let property = |name: &str| {
+            material
+                .properties
+                .get(name)
+                .and_then(|value| value.parse::<f32>().ok())
+                .unwrap_or(0.0)
+        };
+        // material ids are 1-based palette indices, which is what the model texture stores
+        if let Some(entry) = emission.get_mut(material.id as usize) {
+            *entry = property("_emit") * (1.0 + property("_flux"));
+        }
+    }
+    emission
+}
+
+/// Palette with the transparency of MagicaVoxel's glass materials folded into the alpha channel,
+/// taken from `_trans` or, in older files, `_alpha`.
+pub fn get_glass_palette(
+    palette: Vec<dot_vox::Color>,
+    materials: &[dot_vox::Material],
+) -> Vec<dot_vox::Color> {
+    let mut palette = palette;
+    for material in materials {
+        if material.properties.get("_type").map(String::as_str) != Some("_glass") {
+            continue;
+        }
+        let Some(transparency) = ["_trans", "_alpha"].iter().find_map(|name| {
+            material
+                .properties
+                .get(*name)
+                .and_then(|value| value.parse::<f32>().ok())
+        }) else {
+            continue;
+        };
+        // the palette has no empty entry in front, unlike the material ids
+        if let Some(color) = (material.id as usize)
+            .checked_sub(1)
+            .and_then(|index| palette.get_mut(index))
+        {
+            let alpha = (1.0 - transparency.clamp(0.0, 1.0)) * color.a as f32;
+            color.a = alpha.round() as u8;
+        }
+    }
+    palette
+}
+
+/// Refractive index minus one of every palette entry, index 0 being empty like in
+/// `get_palette_texture`. Glass materials store it in `_ri`, older files in `_ior` already
+/// without the one, everything else doesn't bend light.
+pub fn get_palette_refraction(materials: &[dot_vox::Material]) -> Vec<f32> {
+    let mut refraction = vec![0.0; 257];
+    for material in materials {
+        if material.properties.get("_type").map(String::as_str) != Some("_glass") {
+            continue;
+        }
+        let property = |name: &str| {
+            material
+                .properties
+                .get(name)
+                .and_then(|value| value.parse::<f32>().ok())
+        };
+        let index = property("_ri")
+            .map(|ri| ri - 1.0)
+            .or_else(|| property("_ior"))
+            .unwrap_or(0.0);
+        if let Some(entry) = refraction.get_mut(material.id as usize) {
+            *entry = index.max(0.0);
+        }
+    }
+    refraction
+}
+
+/// Whether any voxel of `model` uses a palette entry that isn't fully opaque.
+pub fn has_translucent_voxels(model: &Model, palette: &[dot_vox::Color]) -> bool {
+    model.voxels.iter().any(|voxel| {
+        palette
+            .get(voxel.i as usize)
+            .is_some_and(|color| color.a < 255)
+    })
+}
+
 pub fn get_mesh_from_model(model: &Model) -> Mesh {
     Mesh::from(shape::Box::new(
-        model.size.x as f32 / 4.0,
-        model.size.z as f32 / 4.0,
-        model.size.y as f32 / 4.0,
+        model.size.x as f32 * VOXEL_SIZE,
+        model.size.z as f32 * VOXEL_SIZE,
+        model.size.y as f32 * VOXEL_SIZE,
     ))
 }
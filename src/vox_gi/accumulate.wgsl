@@ -0,0 +1,39 @@
+struct Accumulation {
+    // frames already summed up in the previous texture, 0 starts over
+    frames: u32,
+}
+
+@group(0) @binding(0)
+var<uniform> accumulation: Accumulation;
+@group(0) @binding(1)
+var radiance_texture: texture_2d<f32>;
+@group(0) @binding(2)
+var albedo_texture: texture_2d<f32>;
+@group(0) @binding(3)
+var previous_sum_texture: texture_2d<f32>;
+@group(0) @binding(4)
+var sum_output: texture_storage_2d<rgba32float, write>;
+@group(0) @binding(5)
+var average_output: texture_storage_2d<rgba16float, write>;
+
+// Adds this frame to the running sum of a progressive camera, the frame count goes in alpha. The
+// sum needs full floats, halfs stop changing after a few thousand frames.
+@compute @workgroup_size(8, 8, 1)
+fn accumulate(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let size = vec2<u32>(textureDimensions(radiance_texture));
+    if global_id.x >= size.x || global_id.y >= size.y {
+        return;
+    }
+
+    let pixel = vec2<i32>(global_id.xy);
+    // the albedo goes on before averaging, the jittered primary rays see different surfaces
+    let albedo = textureLoad(albedo_texture, pixel, 0);
+    let color = albedo.rgb * (textureLoad(radiance_texture, pixel, 0).rgb + albedo.a);
+
+    var sum = vec4<f32>(color, 1.0);
+    if accumulation.frames != 0u {
+        sum += textureLoad(previous_sum_texture, pixel, 0);
+    }
+    textureStore(sum_output, pixel, sum);
+    textureStore(average_output, pixel, vec4<f32>(sum.rgb / sum.a, 1.0));
+}
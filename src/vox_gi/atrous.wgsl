@@ -0,0 +1,75 @@
+struct AtrousStep {
+    step: u32,
+    last: u32,
+}
+
+@group(0) @binding(0)
+var input_texture: texture_2d<f32>;
+@group(0) @binding(1)
+var normal_depth_texture: texture_2d<f32>;
+@group(0) @binding(2)
+var moments_texture: texture_2d<f32>;
+@group(0) @binding(3)
+var albedo_texture: texture_2d<f32>;
+@group(0) @binding(4)
+var output_texture: texture_storage_2d<rgba16float, write>;
+@group(0) @binding(5)
+var<uniform> atrous_step: AtrousStep;
+
+fn luminance(color: vec3<f32>) -> f32 {
+    return dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+// One iteration of the edge-avoiding à-trous wavelet filter, a 5x5 B3 spline kernel with
+// `step` pixels between the taps, weighted by normal, depth and luminance similarity.
+@compute @workgroup_size(8, 8, 1)
+fn atrous(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let size = vec2<u32>(textureDimensions(input_texture));
+    if global_id.x >= size.x || global_id.y >= size.y {
+        return;
+    }
+
+    let pixel = vec2<i32>(global_id.xy);
+    let center = textureLoad(input_texture, pixel, 0);
+    let normal_depth = textureLoad(normal_depth_texture, pixel, 0);
+
+    var color = center.rgb;
+    if normal_depth.w >= 0.0 {
+        var kernel = array<f32, 3>(0.375, 0.25, 0.0625);
+        let center_luminance = luminance(center.rgb);
+        let luminance_sigma = 4.0 * sqrt(textureLoad(moments_texture, pixel, 0).z) + 0.0001;
+        let depth_sigma = 0.05 * normal_depth.w * f32(atrous_step.step) + 0.0001;
+
+        var sum = vec3<f32>(0.0);
+        var weight_sum = 0.0;
+        for (var y = -2; y <= 2; y = y + 1) {
+            for (var x = -2; x <= 2; x = x + 1) {
+                let tap = pixel + vec2<i32>(x, y) * i32(atrous_step.step);
+                if any(tap < vec2<i32>(0)) || any(tap >= vec2<i32>(size)) {
+                    continue;
+                }
+                let tap_normal_depth = textureLoad(normal_depth_texture, tap, 0);
+                if tap_normal_depth.w < 0.0 {
+                    continue;
+                }
+                let tap_color = textureLoad(input_texture, tap, 0).rgb;
+
+                let normal_weight = pow(max(dot(normal_depth.xyz, tap_normal_depth.xyz), 0.0), 64.0);
+                let depth_weight = exp(-abs(normal_depth.w - tap_normal_depth.w) / depth_sigma);
+                let luminance_weight = exp(-abs(center_luminance - luminance(tap_color)) / luminance_sigma);
+                let weight = kernel[abs(x)] * kernel[abs(y)] * normal_weight * depth_weight * luminance_weight;
+                sum += tap_color * weight;
+                weight_sum += weight;
+            }
+        }
+        color = sum / max(weight_sum, 0.0001);
+    }
+
+    // the passes filter lighting only, so the voxel colours stay sharp
+    if atrous_step.last != 0u {
+        let albedo = textureLoad(albedo_texture, pixel, 0);
+        color = albedo.rgb * (color + albedo.a);
+    }
+
+    textureStore(output_texture, pixel, vec4<f32>(color, center.a));
+}
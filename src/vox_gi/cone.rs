@@ -0,0 +1,467 @@
+use std::f32::consts::FRAC_PI_4;
+
+use bevy::{
+    asset::load_internal_asset,
+    prelude::*,
+    reflect::TypeUuid,
+    render::{
+        extract_component::{ExtractComponent, ExtractComponentPlugin},
+        extract_resource::{ExtractResource, ExtractResourcePlugin},
+        render_asset::{PrepareAssetSet, RenderAssets},
+        render_resource::*,
+        renderer::{RenderDevice, RenderQueue},
+        texture::ImageSampler,
+        view::ExtractedView,
+        Extract, ExtractSchedule, RenderApp, RenderSet,
+    },
+    utils::HashMap,
+};
+
+use crate::{
+    vox::Vox,
+    vox_plugin::{update_voxel_materials, VoxelMaterial},
+};
+
+use super::{
+    pipeline::VoxelGISun,
+    plugin::transform_aabb,
+    resources::{
+        create_clipmap_bind_group, create_clipmap_bind_group_layout, create_voxelize_bind_group,
+        create_voxelize_bind_group_layout,
+    },
+};
+
+pub const VOXEL_CONE_TRACING_SHADER: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 3717171717171762);
+
+/// Texels along every side of a clipmap level, keep in sync with the shaders.
+pub const CLIPMAP_RESOLUTION: u32 = 64;
+/// Levels of the clipmap, each one covers twice the distance of the level before.
+pub const CLIPMAP_LEVELS: u32 = 4;
+/// World space size of a texel of the finest level, two voxels.
+pub const CLIPMAP_TEXEL_SIZE: f32 = 0.5;
+/// Bytes per texel in the clipmap buffer, packed albedo and opacity plus packed emission.
+const CLIPMAP_TEXEL_BYTES: u64 = 8;
+
+/// Cheaper alternative to the path traced [`VoxelGIPlugin`](super::plugin::VoxelGIPlugin) for
+/// GPUs that can't afford tracing the BVH for every pixel. Every frame the voxel models are
+/// voxelized into a radiance clipmap around the camera with [`VoxelConeTracing`], which the voxel
+/// materials cone trace for diffuse and glossy indirect light. Works with or without the
+/// `VoxelGIPlugin`, must be added after `DefaultPlugins` and the `VoxelPlugin`.
+#[derive(Default)]
+pub struct VoxelConeTracingPlugin;
+
+impl Plugin for VoxelConeTracingPlugin {
+    fn build(&self, app: &mut App) {
+        load_internal_asset!(
+            app,
+            VOXEL_CONE_TRACING_SHADER,
+            "cone.wgsl",
+            Shader::from_wgsl
+        );
+
+        app.add_plugin(ExtractComponentPlugin::<VoxelConeTracing>::default())
+            .add_plugin(ExtractResourcePlugin::<VoxelConeTracingClipmap>::default())
+            .register_type::<VoxelConeTracing>()
+            .init_resource::<VoxelConeTracingClipmap>()
+            .add_system(assign_radiance_clipmap);
+
+        let Ok(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
+
+        render_app
+            .init_resource::<ClipmapPipeline>()
+            .init_resource::<ClipmapBuilder>()
+            .init_resource::<ClipmapScene>()
+            .add_system(extract_clipmap_scene.in_schedule(ExtractSchedule))
+            .add_system(
+                voxelize_clipmap
+                    .in_set(RenderSet::Prepare)
+                    .after(PrepareAssetSet::AssetPrepare),
+            );
+    }
+}
+
+/// Add to a regular `Camera3dBundle` to light the voxel materials with cone traced indirect
+/// light. The clipmap is centered on this camera, only one camera can use it at a time.
+#[derive(Component, ExtractComponent, Reflect, Clone, Debug)]
+#[reflect(Component)]
+pub struct VoxelConeTracing {
+    /// Multiplier on the diffuse light gathered from the clipmap
+    pub diffuse_intensity: f32,
+    /// Multiplier on the glossy reflections, 0 skips their cone entirely
+    pub glossy_intensity: f32,
+    /// Roughness of the glossy reflections from 0 to 1, rougher cones are wider and blurrier
+    pub roughness: f32,
+    /// Multiplier on the sunlight the clipmap is lit with
+    pub sun_intensity: f32,
+}
+
+impl Default for VoxelConeTracing {
+    fn default() -> Self {
+        Self {
+            diffuse_intensity: 1.0,
+            glossy_intensity: 0.5,
+            roughness: 0.3,
+            sun_intensity: 1.0,
+        }
+    }
+}
+
+impl VoxelConeTracing {
+    /// What the voxel materials read from their `VoxelExtraData::cone_tracing`.
+    fn material_data(&self) -> [f32; 4] {
+        // tangent of the half angle, a mirror still gets a cone one texel wide after a while
+        let aperture = (self.roughness.clamp(0.0, 1.0) * FRAC_PI_4).tan().max(0.02);
+        [self.diffuse_intensity, self.glossy_intensity, aperture, 0.0]
+    }
+}
+
+/// Lit clipmap the voxel materials cone trace, with premultiplied radiance in rgb and opacity in
+/// alpha. The levels are stacked along z, texel `(x, y, z)` of level `l` is at
+/// `(x, y, z + l * CLIPMAP_RESOLUTION)`. Every level is rebuilt each frame, snapped to its texel
+/// size around the camera, which the materials repeat with the position of the view they draw.
+#[derive(Resource, ExtractResource, Clone)]
+pub struct VoxelConeTracingClipmap {
+    pub radiance: Handle<Image>,
+}
+
+impl FromWorld for VoxelConeTracingClipmap {
+    fn from_world(world: &mut World) -> Self {
+        let mut image = Image::new_fill(
+            Extent3d {
+                width: CLIPMAP_RESOLUTION,
+                height: CLIPMAP_RESOLUTION,
+                depth_or_array_layers: CLIPMAP_RESOLUTION * CLIPMAP_LEVELS,
+            },
+            TextureDimension::D3,
+            &[0; 8],
+            TextureFormat::Rgba16Float,
+        );
+        image.texture_descriptor.usage |= TextureUsages::STORAGE_BINDING;
+        image.sampler_descriptor = ImageSampler::linear();
+
+        Self {
+            radiance: world.resource_mut::<Assets<Image>>().add(image),
+        }
+    }
+}
+
+/// Points every voxel material at the clipmap while a camera uses [`VoxelConeTracing`], along
+/// with the settings of that camera.
+fn assign_radiance_clipmap(
+    clipmap: Res<VoxelConeTracingClipmap>,
+    cameras: Query<&VoxelConeTracing>,
+    mut vox_materials: ResMut<Assets<VoxelMaterial>>,
+) {
+    let cone_tracing = cameras.iter().next();
+    let radiance_clipmap = cone_tracing.map(|_| clipmap.radiance.clone());
+    let data = cone_tracing.map_or([0.0; 4], VoxelConeTracing::material_data);
+
+    update_voxel_materials(
+        &mut vox_materials,
+        |material| {
+            material.radiance_clipmap != radiance_clipmap
+                || material.voxel_extra_data.cone_tracing != data
+        },
+        |material| {
+            material.radiance_clipmap = radiance_clipmap.clone();
+            material.voxel_extra_data.cone_tracing = data;
+        },
+    );
+}
+
+struct ClipmapInstance {
+    world_from_local: Mat4,
+    half_extents: Vec3,
+    model: Handle<Image>,
+    palette: Handle<Image>,
+    emission: [Vec4; 64],
+}
+
+/// Voxel models placed in the main world, extracted every frame while a camera cone traces.
+#[derive(Resource, Default)]
+pub struct ClipmapScene {
+    instances: Vec<ClipmapInstance>,
+    /// Direction towards the sun, taken from the first `DirectionalLight`
+    sun_direction: Vec3,
+}
+
+/// Emission strength of every palette entry, packed four to a vector like `cone.wgsl` reads them.
+fn palette_emission(vox: Option<&Vox>) -> [Vec4; 64] {
+    let mut emission = [Vec4::ZERO; 64];
+    let strengths = vox.map(|vox| vox.emission.as_slice()).unwrap_or_default();
+    for (i, strength) in strengths.iter().enumerate() {
+        if let Some(packed) = emission.get_mut(i / 4) {
+            packed[i % 4] = *strength;
+        }
+    }
+    emission
+}
+
+fn extract_clipmap_scene(
+    mut scene: ResMut<ClipmapScene>,
+    cameras: Extract<Query<(), With<VoxelConeTracing>>>,
+    entities: Extract<Query<(&GlobalTransform, &Handle<VoxelMaterial>)>>,
+    lights: Extract<Query<&GlobalTransform, With<DirectionalLight>>>,
+    vox_materials: Extract<Res<Assets<VoxelMaterial>>>,
+    vox_assets: Extract<Res<Assets<Vox>>>,
+) {
+    scene.instances.clear();
+    if cameras.is_empty() {
+        return;
+    }
+
+    // every model's emission is only gathered once, however often it is placed
+    let mut emissions = HashMap::new();
+    for (transform, material) in entities.iter() {
+        let Some(material) = vox_materials.get(material) else {
+            continue;
+        };
+        let (Some(model), Some(palette)) = (&material.model_texture, &material.palette_texture)
+        else {
+            continue;
+        };
+        let emission = *emissions
+            .entry(material.vox.id())
+            .or_insert_with(|| palette_emission(vox_assets.get(&material.vox)));
+        scene.instances.push(ClipmapInstance {
+            world_from_local: transform.compute_matrix(),
+            half_extents: Vec3::from(material.voxel_extra_data.half_extents),
+            model: model.clone_weak(),
+            palette: palette.clone_weak(),
+            emission,
+        });
+    }
+
+    // lights shine along their forward axis
+    scene.sun_direction = lights
+        .iter()
+        .next()
+        .map_or(VoxelGISun::default().direction, |transform| {
+            transform.back()
+        });
+}
+
+#[derive(Clone, Copy, ShaderType)]
+pub struct ClipmapParams {
+    local_from_world: Mat4,
+    /// First texel of the dispatch, in texels of the level from the world origin
+    region_min: IVec3,
+    level: u32,
+    region_size: UVec3,
+    camera: Vec3,
+    sun_direction: Vec3,
+    sun_strength: f32,
+    emission: [Vec4; 64],
+}
+
+#[derive(Resource)]
+pub struct ClipmapPipeline {
+    pub voxelize_bind_group_layout: BindGroupLayout,
+    pub clipmap_bind_group_layout: BindGroupLayout,
+    /// Albedo, opacity and emission of every texel of every level, lit into the clipmap texture
+    pub voxels: Buffer,
+    voxelize: CachedComputePipelineId,
+    downsample: CachedComputePipelineId,
+    light: CachedComputePipelineId,
+}
+
+impl FromWorld for ClipmapPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let voxelize_bind_group_layout = create_voxelize_bind_group_layout(render_device);
+        let clipmap_bind_group_layout = create_clipmap_bind_group_layout(render_device);
+        let texel_count = (CLIPMAP_RESOLUTION.pow(3) * CLIPMAP_LEVELS) as u64;
+        let voxels = render_device.create_buffer(&BufferDescriptor {
+            label: Some("voxel_cone_tracing_voxels"),
+            size: texel_count * CLIPMAP_TEXEL_BYTES,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let queue = |entry_point: &'static str, layout: &BindGroupLayout| {
+            pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+                label: Some(format!("voxel_cone_tracing_{entry_point}_pipeline").into()),
+                layout: vec![layout.clone()],
+                push_constant_ranges: vec![],
+                shader: VOXEL_CONE_TRACING_SHADER.typed(),
+                shader_defs: vec![],
+                entry_point: entry_point.into(),
+            })
+        };
+        let voxelize = queue("voxelize", &voxelize_bind_group_layout);
+        let downsample = queue("downsample", &clipmap_bind_group_layout);
+        let light = queue("light", &clipmap_bind_group_layout);
+
+        Self {
+            voxelize_bind_group_layout,
+            clipmap_bind_group_layout,
+            voxels,
+            voxelize,
+            downsample,
+            light,
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct ClipmapBuilder {
+    params: DynamicUniformBuffer<ClipmapParams>,
+}
+
+fn texel_size(level: u32) -> f32 {
+    CLIPMAP_TEXEL_SIZE * (1 << level) as f32
+}
+
+/// First texel of a level around `camera`, same as `level_origin` in `cone.wgsl`.
+fn level_origin(camera: Vec3, level: u32) -> IVec3 {
+    (camera / texel_size(level)).floor().as_ivec3() - IVec3::splat(CLIPMAP_RESOLUTION as i32 / 2)
+}
+
+/// Voxelizes, downsamples and lights the clipmap around the [`VoxelConeTracing`] camera, before
+/// the main pass samples it this frame.
+#[allow(clippy::too_many_arguments)]
+pub fn voxelize_clipmap(
+    views: Query<(&ExtractedView, &VoxelConeTracing)>,
+    scene: Res<ClipmapScene>,
+    clipmap: Res<VoxelConeTracingClipmap>,
+    images: Res<RenderAssets<Image>>,
+    mut builder: ResMut<ClipmapBuilder>,
+    pipeline: Res<ClipmapPipeline>,
+    pipeline_cache: Res<PipelineCache>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    let Some((view, cone_tracing)) = views.iter().next() else {
+        return;
+    };
+    let Some(radiance_clipmap) = images.get(&clipmap.radiance) else {
+        return;
+    };
+    let (Some(voxelize), Some(downsample), Some(light)) = (
+        pipeline_cache.get_compute_pipeline(pipeline.voxelize),
+        pipeline_cache.get_compute_pipeline(pipeline.downsample),
+        pipeline_cache.get_compute_pipeline(pipeline.light),
+    ) else {
+        return;
+    };
+
+    let camera = view.transform.translation();
+    let base = ClipmapParams {
+        local_from_world: Mat4::IDENTITY,
+        region_min: IVec3::ZERO,
+        level: 0,
+        region_size: UVec3::ZERO,
+        camera,
+        sun_direction: scene.sun_direction,
+        sun_strength: cone_tracing.sun_intensity,
+        emission: [Vec4::ZERO; 64],
+    };
+    builder.params.clear();
+
+    // instance, uniform offset and workgroups of every voxelize dispatch
+    let mut dispatches = vec![];
+    for instance in &scene.instances {
+        let (world_min, world_max) = transform_aabb(
+            instance.world_from_local,
+            -instance.half_extents,
+            instance.half_extents,
+        );
+        let local_from_world = instance.world_from_local.inverse();
+        for level in 0..CLIPMAP_LEVELS {
+            let size = texel_size(level);
+            let origin = level_origin(camera, level);
+            let region_min = (world_min / size).floor().as_ivec3().max(origin);
+            let region_max = (world_max / size)
+                .ceil()
+                .as_ivec3()
+                .min(origin + IVec3::splat(CLIPMAP_RESOLUTION as i32));
+            if region_min.cmpge(region_max).any() {
+                continue;
+            }
+            let region_size = (region_max - region_min).as_uvec3();
+            let offset = builder.params.push(ClipmapParams {
+                local_from_world,
+                region_min,
+                level,
+                region_size,
+                emission: instance.emission,
+                ..base
+            });
+            dispatches.push((instance, offset, (region_size + 3) / 4));
+        }
+    }
+    let downsample_offsets = (1..CLIPMAP_LEVELS)
+        .map(|level| builder.params.push(ClipmapParams { level, ..base }))
+        .collect::<Vec<_>>();
+    let light_offset = builder.params.push(base);
+    builder.params.write_buffer(&render_device, &render_queue);
+
+    let Some(clipmap_bind_group) = create_clipmap_bind_group(
+        &render_device,
+        &pipeline,
+        &builder.params,
+        &radiance_clipmap.texture_view,
+    ) else {
+        return;
+    };
+    // one bind group per model, shared by all its instances and levels
+    let mut bind_groups = HashMap::new();
+    for (instance, _, _) in &dispatches {
+        if bind_groups.contains_key(&instance.model.id()) {
+            continue;
+        }
+        let (Some(model), Some(palette)) =
+            (images.get(&instance.model), images.get(&instance.palette))
+        else {
+            continue;
+        };
+        if let Some(bind_group) = create_voxelize_bind_group(
+            &render_device,
+            &pipeline,
+            &builder.params,
+            &model.texture_view,
+            &palette.texture_view,
+        ) {
+            bind_groups.insert(instance.model.id(), bind_group);
+        }
+    }
+
+    let mut command_encoder = render_device.create_command_encoder(&CommandEncoderDescriptor {
+        label: Some("voxel_cone_tracing_command_encoder"),
+    });
+    command_encoder.clear_buffer(&pipeline.voxels, 0, None);
+    {
+        let mut clipmap_pass = command_encoder.begin_compute_pass(&ComputePassDescriptor {
+            label: Some("voxel_cone_tracing_clipmap_pass"),
+        });
+        clipmap_pass.set_pipeline(voxelize);
+        for (instance, offset, workgroups) in &dispatches {
+            let Some(bind_group) = bind_groups.get(&instance.model.id()) else {
+                continue;
+            };
+            clipmap_pass.set_bind_group(0, bind_group, &[*offset]);
+            clipmap_pass.dispatch_workgroups(workgroups.x, workgroups.y, workgroups.z);
+        }
+
+        // from fine to coarse, every level reads the one before it
+        clipmap_pass.set_pipeline(downsample);
+        let workgroups = (CLIPMAP_RESOLUTION / 2 + 1).div_ceil(4);
+        for offset in downsample_offsets {
+            clipmap_pass.set_bind_group(0, &clipmap_bind_group, &[offset]);
+            clipmap_pass.dispatch_workgroups(workgroups, workgroups, workgroups);
+        }
+
+        clipmap_pass.set_pipeline(light);
+        clipmap_pass.set_bind_group(0, &clipmap_bind_group, &[light_offset]);
+        clipmap_pass.dispatch_workgroups(
+            CLIPMAP_RESOLUTION / 4,
+            CLIPMAP_RESOLUTION / 4,
+            CLIPMAP_RESOLUTION * CLIPMAP_LEVELS / 4,
+        );
+    }
+    render_queue.submit([command_encoder.finish()]);
+}
@@ -0,0 +1,189 @@
+// Radiance clipmap for voxel cone tracing, after Crassin et al., "Interactive Indirect
+// Illumination Using Voxel Cone Tracing". Every level has the same number of texels around the
+// camera, each one twice as large as the level before. The models are voxelized into a buffer of
+// albedo, opacity and emission, the finer levels are downsampled into the coarser ones where they
+// overlap, then every texel is lit and stored in the clipmap texture the voxel materials sample.
+
+struct ClipmapParams {
+    local_from_world: mat4x4<f32>,
+    // first texel of the dispatch, in texels of the level from the world origin
+    region_min: vec3<i32>,
+    level: u32,
+    region_size: vec3<u32>,
+    // the clipmap is centered on this position
+    camera: vec3<f32>,
+    sun_direction: vec3<f32>,
+    sun_strength: f32,
+    // emission strength of every palette entry, four to a vector
+    emission: array<vec4<f32>, 64>,
+}
+
+@group(0) @binding(0)
+var<uniform> params: ClipmapParams;
+// albedo and opacity, then emitted light, both packed into 8 bits per channel
+@group(0) @binding(1)
+var<storage, read_write> voxels: array<vec2<u32>>;
+@group(0) @binding(2)
+var model_texture: texture_3d<u32>;
+@group(0) @binding(3)
+var palette_texture: texture_1d<f32>;
+@group(0) @binding(4)
+var radiance_clipmap: texture_storage_3d<rgba16float, write>;
+
+// keep in sync with `vox_gi::cone` and `voxel_material.wgsl`
+const CLIPMAP_RESOLUTION = 64;
+const CLIPMAP_LEVELS = 4u;
+const CLIPMAP_TEXEL_SIZE = 0.5;
+// keep in sync with `vox::VOXEL_SIZE`
+const VOXEL_SIZE = 0.25;
+// brightest emitted light a texel can hold
+const EMISSION_RANGE = 64.0;
+// texels the sun is traced through before a texel counts as lit
+const SHADOW_STEPS = 32;
+
+struct ClipmapTexel {
+    // premultiplied by the opacity
+    albedo: vec3<f32>,
+    opacity: f32,
+    emitted: vec3<f32>,
+}
+
+fn texel_size(level: u32) -> f32 {
+    return CLIPMAP_TEXEL_SIZE * f32(1u << level);
+}
+
+// First texel of a level, the camera sits in the middle of it.
+fn level_origin(level: u32) -> vec3<i32> {
+    return vec3<i32>(floor(params.camera / texel_size(level))) - CLIPMAP_RESOLUTION / 2;
+}
+
+fn texel_index(local: vec3<i32>, level: u32) -> u32 {
+    let size = CLIPMAP_RESOLUTION;
+    return u32(local.x + local.y * size + local.z * size * size) + level * u32(size * size * size);
+}
+
+fn unpack_texel(packed: vec2<u32>) -> ClipmapTexel {
+    let albedo = unpack4x8unorm(packed.x);
+    // the emission is stored with a shared multiplier in alpha
+    let emitted = unpack4x8unorm(packed.y);
+    return ClipmapTexel(albedo.rgb, albedo.a, emitted.rgb * emitted.a * EMISSION_RANGE);
+}
+
+fn pack_texel(texel: ClipmapTexel) -> vec2<u32> {
+    let albedo = clamp(vec4<f32>(texel.albedo, texel.opacity), vec4<f32>(0.0), vec4<f32>(1.0));
+    let brightest = max(max(texel.emitted.r, texel.emitted.g), texel.emitted.b);
+    let scale = ceil(clamp(brightest / EMISSION_RANGE, 0.0, 1.0) * 255.0) / 255.0;
+    let emitted = vec4<f32>(texel.emitted / max(scale * EMISSION_RANGE, 0.0001), scale);
+    return vec2<u32>(pack4x8unorm(albedo), pack4x8unorm(emitted));
+}
+
+fn palette_emission(value: u32) -> f32 {
+    return params.emission[value / 4u][value % 4u];
+}
+
+// Adds one model to the texels of `params.region_min` and `params.region_size` on one level. Each
+// texel takes 8 samples of the model, which covers every voxel on the finest level and gets
+// sparser further out.
+@compute @workgroup_size(4, 4, 4)
+fn voxelize(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    if any(global_id >= params.region_size) {
+        return;
+    }
+    let texel = params.region_min + vec3<i32>(global_id);
+    let size = texel_size(params.level);
+    let model_size = vec3<i32>(textureDimensions(model_texture));
+    // model space is centered on the origin, like the raster path
+    let half_extents = vec3<f32>(model_size) * VOXEL_SIZE * 0.5;
+
+    var albedo = vec3<f32>(0.0);
+    var emitted = vec3<f32>(0.0);
+    var filled = 0.0;
+    for (var i = 0; i < 8; i = i + 1) {
+        let offset = vec3<f32>(vec3<i32>(i & 1, (i >> 1u) & 1, i >> 2u)) * 0.5 + 0.25;
+        let world_position = (vec3<f32>(texel) + offset) * size;
+        let local_position = (params.local_from_world * vec4<f32>(world_position, 1.0)).xyz;
+        let voxel = vec3<i32>(floor((local_position + half_extents) / VOXEL_SIZE));
+        if any(voxel < vec3<i32>(0)) || any(voxel >= model_size) {
+            continue;
+        }
+        let value = textureLoad(model_texture, voxel, 0).r;
+        if value == 0u {
+            continue;
+        }
+        let color = textureLoad(palette_texture, i32(value), 0).rgb;
+        albedo += color;
+        emitted += color * palette_emission(value);
+        filled += 1.0;
+    }
+    if filled == 0.0 {
+        return;
+    }
+
+    // models overlapping the same texel add up
+    let index = texel_index(texel - level_origin(params.level), params.level);
+    var previous = unpack_texel(voxels[index]);
+    previous.albedo += albedo / 8.0;
+    previous.opacity += filled / 8.0;
+    previous.emitted += emitted / 8.0;
+    voxels[index] = pack_texel(previous);
+}
+
+// Replaces the texels of `params.level` that the level below covers completely with the average
+// of the 8 finer texels, so the coarser levels act as the mips of the finer ones.
+@compute @workgroup_size(4, 4, 4)
+fn downsample(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    // the finer level covers the middle half of this one, give or take a texel
+    let local = vec3<i32>(global_id) + CLIPMAP_RESOLUTION / 4;
+    if any(global_id > vec3<u32>(u32(CLIPMAP_RESOLUTION / 2))) {
+        return;
+    }
+    let level = params.level;
+    let finer = level - 1u;
+    let first_child = (level_origin(level) + local) * 2 - level_origin(finer);
+    if any(first_child < vec3<i32>(0)) || any(first_child + 1 >= vec3<i32>(CLIPMAP_RESOLUTION)) {
+        return;
+    }
+
+    var sum = ClipmapTexel(vec3<f32>(0.0), 0.0, vec3<f32>(0.0));
+    for (var i = 0; i < 8; i = i + 1) {
+        let child = unpack_texel(voxels[texel_index(first_child + vec3<i32>(i & 1, (i >> 1u) & 1, i >> 2u), finer)]);
+        sum.albedo += child.albedo;
+        sum.opacity += child.opacity;
+        sum.emitted += child.emitted;
+    }
+    voxels[texel_index(local, level)] = pack_texel(ClipmapTexel(sum.albedo / 8.0, sum.opacity / 8.0, sum.emitted / 8.0));
+}
+
+// Lights every texel of every level by the sun and its own emission. The levels are stacked along
+// z in the clipmap texture.
+@compute @workgroup_size(4, 4, 4)
+fn light(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let level = global_id.z / u32(CLIPMAP_RESOLUTION);
+    if level >= CLIPMAP_LEVELS {
+        return;
+    }
+    let local = vec3<i32>(vec3<u32>(global_id.xy, global_id.z % u32(CLIPMAP_RESOLUTION)));
+    let texel = unpack_texel(voxels[texel_index(local, level)]);
+    if texel.opacity == 0.0 {
+        textureStore(radiance_clipmap, vec3<i32>(global_id), vec4<f32>(0.0));
+        return;
+    }
+
+    // march towards the sun through this level, starting outside the texel itself
+    var transmittance = 1.0;
+    var position = vec3<f32>(local) + 0.5 + params.sun_direction * 1.5;
+    for (var i = 0; i < SHADOW_STEPS; i = i + 1) {
+        let cell = vec3<i32>(floor(position));
+        if any(cell < vec3<i32>(0)) || any(cell >= vec3<i32>(CLIPMAP_RESOLUTION)) {
+            break;
+        }
+        transmittance *= 1.0 - unpack_texel(voxels[texel_index(cell, level)]).opacity;
+        if transmittance < 0.01 {
+            break;
+        }
+        position += params.sun_direction;
+    }
+
+    let radiance = texel.albedo * transmittance * params.sun_strength + texel.emitted;
+    textureStore(radiance_clipmap, vec3<i32>(global_id), vec4<f32>(radiance, texel.opacity));
+}
@@ -0,0 +1,744 @@
+use std::{
+    num::NonZeroU32,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use bevy::{
+    prelude::*,
+    reflect::TypeUuid,
+    render::{
+        extract_component::ExtractComponent,
+        render_resource::*,
+        renderer::{RenderDevice, RenderQueue},
+        view::ExtractedView,
+    },
+    utils::HashMap,
+};
+use wgpu::{BufferAsyncError, Maintain};
+
+use super::{
+    pipeline::{
+        VoxelGI, VoxelGICameraCut, VoxelGIDebugView, VoxelGIMode, VoxelGIPipelineId, VoxelGIScene,
+        VoxelGISettings, VoxelGITextures,
+    },
+    reference::VoxelGIReferenceImage,
+    resources::create_accumulate_bind_group_layout,
+};
+
+pub const VOXEL_GI_ACCUMULATE_SHADER: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 3717171717171764);
+
+/// Format of the running sum, halfs stop changing after a few thousand frames.
+const ACCUMULATION_FORMAT: TextureFormat = TextureFormat::Rgba32Float;
+/// Bytes per texel of the [`ACCUMULATION_FORMAT`].
+const ACCUMULATION_TEXEL_BYTES: u32 = 16;
+/// Rows of a texture copied into a buffer have to start at multiples of this.
+const COPY_BYTES_PER_ROW_ALIGNMENT: u32 = 256;
+
+/// Turns a [`VoxelGICamera3dBundle`](super::pipeline::VoxelGICamera3dBundle) camera into a
+/// progressive reference renderer for stills. While the camera stays where it is, the frames the
+/// [`VoxelGINode`](super::node::VoxelGINode) traces are summed up instead of denoised, with the
+/// primary rays jittered inside their pixel for antialiasing. Moving the camera, a
+/// [`VoxelGICameraCut`] or a change to the GI scene starts over.
+///
+/// Once `samples` paths per pixel are in, the average is written to `output` and a
+/// [`VoxelGIRenderFinished`] event is sent. The image has the GI resolution of the camera, use
+/// [`VoxelGIResolution::Full`](super::pipeline::VoxelGIResolution::Full) for final renders.
+#[derive(Component, ExtractComponent, Clone, Debug)]
+pub struct VoxelGIProgressive {
+    /// Paths per pixel to accumulate, every frame adds
+    /// [`VoxelGISettings::samples_per_pixel`] of them
+    pub samples: u32,
+    /// Where the result is written, see [`VoxelGIReferenceImage::save`] for the formats
+    pub output: Option<PathBuf>,
+}
+
+impl Default for VoxelGIProgressive {
+    fn default() -> Self {
+        Self {
+            samples: 4096,
+            output: None,
+        }
+    }
+}
+
+/// Sent once a [`VoxelGIProgressive`] camera has all its samples, after the image was written.
+pub struct VoxelGIRenderFinished {
+    pub camera: Entity,
+    /// Linear HDR average of all samples
+    pub image: VoxelGIReferenceImage,
+    /// The `output` the camera had when it finished
+    pub output: Option<PathBuf>,
+}
+
+/// A camera pose of a [`VoxelGICameraPath`], `time` is in seconds.
+#[derive(Clone, Copy, Debug)]
+pub struct VoxelGICameraKeyframe {
+    pub time: f32,
+    pub transform: Transform,
+}
+
+/// Renders an image sequence along a scripted camera path. Add it next to a [`VoxelGIProgressive`],
+/// which decides the samples of every frame. The camera is moved to the next frame whenever the
+/// previous one has been written, and the path removes itself once the last frame is done.
+#[derive(Component, Clone, Debug)]
+pub struct VoxelGICameraPath {
+    /// Sorted by time, the positions are interpolated with a Catmull-Rom spline and the rotations
+    /// with a slerp
+    pub keyframes: Vec<VoxelGICameraKeyframe>,
+    /// Frames rendered per second of the path
+    pub frame_rate: f32,
+    /// File of every frame, with `{frame}` replaced by the zero padded frame number, like
+    /// `"render/shot_{frame}.exr"`
+    pub output: String,
+    /// Frame being rendered, `None` until the camera is placed on the first one
+    frame: Option<u32>,
+}
+
+impl VoxelGICameraPath {
+    /// Fails unless `frame_rate` is positive, frame times are frame numbers divided by it.
+    pub fn new(
+        keyframes: impl IntoIterator<Item = VoxelGICameraKeyframe>,
+        frame_rate: f32,
+        output: impl Into<String>,
+    ) -> anyhow::Result<Self> {
+        if !(frame_rate > 0.0 && frame_rate.is_finite()) {
+            return Err(anyhow::anyhow!(
+                "Invalid camera path frame rate {}",
+                frame_rate
+            ));
+        }
+        let mut keyframes = keyframes.into_iter().collect::<Vec<_>>();
+        keyframes.sort_by(|a, b| a.time.total_cmp(&b.time));
+        Ok(Self {
+            keyframes,
+            frame_rate,
+            output: output.into(),
+            frame: None,
+        })
+    }
+
+    /// Frames from the first to the last keyframe, both included.
+    pub fn frame_count(&self) -> u32 {
+        match (self.keyframes.first(), self.keyframes.last()) {
+            (Some(first), Some(last)) => {
+                ((last.time - first.time) * self.frame_rate).floor() as u32 + 1
+            }
+            _ => 0,
+        }
+    }
+
+    /// Frame the camera is currently on.
+    pub fn frame(&self) -> Option<u32> {
+        self.frame
+    }
+
+    pub fn frame_path(&self, frame: u32) -> PathBuf {
+        PathBuf::from(self.output.replace("{frame}", &format!("{frame:05}")))
+    }
+
+    /// Camera pose `frame` is rendered from.
+    pub fn frame_transform(&self, frame: u32) -> Transform {
+        let start = self.keyframes.first().map_or(0.0, |first| first.time);
+        self.transform_at(start + frame as f32 / self.frame_rate)
+    }
+
+    /// Pose at `time`, held at the first and last keyframe outside of the path.
+    pub fn transform_at(&self, time: f32) -> Transform {
+        let keyframes = &self.keyframes;
+        let (Some(first), Some(last)) = (keyframes.first(), keyframes.last()) else {
+            return Transform::default();
+        };
+        let next = keyframes.partition_point(|keyframe| keyframe.time <= time);
+        if next == 0 {
+            return first.transform;
+        }
+        if next == keyframes.len() {
+            return last.transform;
+        }
+
+        let (a, b) = (&keyframes[next - 1], &keyframes[next]);
+        let t = (time - a.time) / (b.time - a.time).max(f32::EPSILON);
+        // the neighbours repeat the ends, so the path starts and stops along its first and last leg
+        let before = keyframes[next.saturating_sub(2)].transform.translation;
+        let after = keyframes.get(next + 1).unwrap_or(b).transform.translation;
+        Transform {
+            translation: catmull_rom(
+                before,
+                a.transform.translation,
+                b.transform.translation,
+                after,
+                t,
+            ),
+            rotation: a.transform.rotation.slerp(b.transform.rotation, t),
+            scale: a.transform.scale.lerp(b.transform.scale, t),
+        }
+    }
+}
+
+/// Point between `p1` and `p2` on a uniform Catmull-Rom spline.
+fn catmull_rom(p0: Vec3, p1: Vec3, p2: Vec3, p3: Vec3, t: f32) -> Vec3 {
+    let t2 = t * t;
+    let t3 = t2 * t;
+    0.5 * (2.0 * p1
+        + (p2 - p0) * t
+        + (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3) * t2
+        + (3.0 * p1 - p0 - 3.0 * p2 + p3) * t3)
+}
+
+/// Renders the render world has read back, handed over to the main world which writes them.
+#[derive(Resource, Clone, Default)]
+pub struct VoxelGIFinishedRenders(Arc<Mutex<Vec<VoxelGIRenderFinished>>>);
+
+impl VoxelGIFinishedRenders {
+    fn push(&self, render: VoxelGIRenderFinished) {
+        self.0.lock().unwrap().push(render);
+    }
+
+    fn take(&self) -> Vec<VoxelGIRenderFinished> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+/// Writes the images of the progressive cameras that finished and tells everyone about them.
+pub fn save_finished_renders(
+    finished: Res<VoxelGIFinishedRenders>,
+    mut events: EventWriter<VoxelGIRenderFinished>,
+) {
+    for render in finished.take() {
+        if let Some(output) = &render.output {
+            match render.image.save(output) {
+                Ok(()) => info!(
+                    "Saved {}x{} voxel GI render to {}",
+                    render.image.size.x,
+                    render.image.size.y,
+                    output.display()
+                ),
+                Err(e) => error!("Failed to save {}: {e:#}", output.display()),
+            }
+        }
+        events.send(render);
+    }
+}
+
+/// Moves every [`VoxelGICameraPath`] camera to its next frame once the current one is written.
+pub fn follow_camera_paths(
+    mut commands: Commands,
+    mut finished: EventReader<VoxelGIRenderFinished>,
+    mut cameras: Query<(
+        Entity,
+        &mut VoxelGICameraPath,
+        &mut VoxelGIProgressive,
+        &mut Transform,
+    )>,
+) {
+    let finished = finished.iter().collect::<Vec<_>>();
+    for (entity, mut path, mut progressive, mut transform) in &mut cameras {
+        let next = match path.frame {
+            None => 0,
+            // a render of wherever the camera was before the path took over doesn't count
+            Some(frame)
+                if finished.iter().any(|render| {
+                    render.camera == entity
+                        && render.output.as_deref() == Some(path.frame_path(frame).as_path())
+                }) =>
+            {
+                frame + 1
+            }
+            Some(_) => continue,
+        };
+        if next >= path.frame_count() {
+            info!("Rendered {next} frames of {}", path.output);
+            commands.entity(entity).remove::<VoxelGICameraPath>();
+            continue;
+        }
+
+        path.frame = Some(next);
+        *transform = path.frame_transform(next);
+        progressive.output = Some(path.frame_path(next));
+        // the pose could be the same as the last one, which would not start over by itself
+        commands.entity(entity).insert(VoxelGICameraCut);
+    }
+}
+
+#[derive(Clone, Copy, ShaderType)]
+pub struct AccumulateParams {
+    /// Frames already summed up, 0 starts over
+    frames: u32,
+}
+
+#[derive(Resource)]
+pub struct VoxelGIAccumulatePipeline {
+    pub bind_group_layout: BindGroupLayout,
+    pub accumulate: CachedComputePipelineId,
+}
+
+impl FromWorld for VoxelGIAccumulatePipeline {
+    fn from_world(world: &mut World) -> Self {
+        let bind_group_layout =
+            create_accumulate_bind_group_layout(world.resource::<RenderDevice>());
+        let accumulate =
+            world
+                .resource::<PipelineCache>()
+                .queue_compute_pipeline(ComputePipelineDescriptor {
+                    label: Some("voxel_gi_accumulate_pipeline".into()),
+                    layout: vec![bind_group_layout.clone()],
+                    push_constant_ranges: vec![],
+                    shader: VOXEL_GI_ACCUMULATE_SHADER.typed(),
+                    shader_defs: vec![],
+                    entry_point: "accumulate".into(),
+                });
+
+        Self {
+            bind_group_layout,
+            accumulate,
+        }
+    }
+}
+
+/// Textures and readback buffer of a progressive view. They belong to the view for as long as it
+/// accumulates, the texture cache could swap them between views of the same size.
+#[derive(Clone)]
+pub struct AccumulationTextures {
+    pub size: UVec2,
+    /// Sum of all frames so far with the frame count in alpha, the pair swaps every frame
+    pub sum: [(Texture, TextureView); 2],
+    /// Average of the sum, what the camera shows
+    pub average: TextureView,
+    /// Copy of the finished sum, padded to whole rows of [`COPY_BYTES_PER_ROW_ALIGNMENT`]
+    pub readback: Buffer,
+    bytes_per_row: u32,
+}
+
+impl AccumulationTextures {
+    fn new(render_device: &RenderDevice, size: UVec2) -> Self {
+        let texture = |label: &'static str, format: TextureFormat, usage: TextureUsages| {
+            let texture = render_device.create_texture(&TextureDescriptor {
+                label: Some(label),
+                size: Extent3d {
+                    width: size.x,
+                    height: size.y,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: TextureDimension::D2,
+                format,
+                usage,
+                view_formats: &[],
+            });
+            let view = texture.create_view(&TextureViewDescriptor::default());
+            (texture, view)
+        };
+        let sum_usage = TextureUsages::STORAGE_BINDING
+            | TextureUsages::TEXTURE_BINDING
+            | TextureUsages::COPY_SRC;
+        let bytes_per_row = (size.x * ACCUMULATION_TEXEL_BYTES)
+            .div_ceil(COPY_BYTES_PER_ROW_ALIGNMENT)
+            * COPY_BYTES_PER_ROW_ALIGNMENT;
+
+        Self {
+            size,
+            sum: [
+                texture(
+                    "voxel_gi_accumulation_texture_1",
+                    ACCUMULATION_FORMAT,
+                    sum_usage,
+                ),
+                texture(
+                    "voxel_gi_accumulation_texture_2",
+                    ACCUMULATION_FORMAT,
+                    sum_usage,
+                ),
+            ],
+            average: texture(
+                "voxel_gi_accumulation_average_texture",
+                TextureFormat::Rgba16Float,
+                TextureUsages::STORAGE_BINDING | TextureUsages::TEXTURE_BINDING,
+            )
+            .1,
+            readback: render_device.create_buffer(&BufferDescriptor {
+                label: Some("voxel_gi_accumulation_readback"),
+                size: bytes_per_row as u64 * size.y as u64,
+                usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            bytes_per_row,
+        }
+    }
+
+    /// Averages the mapped readback buffer.
+    fn read_image(&self, data: &[u8]) -> VoxelGIReferenceImage {
+        let pixels = data
+            .chunks(self.bytes_per_row as usize)
+            .flat_map(|row| {
+                row[..(self.size.x * ACCUMULATION_TEXEL_BYTES) as usize]
+                    .chunks_exact(ACCUMULATION_TEXEL_BYTES as usize)
+            })
+            .map(|texel| {
+                let channel = |i: usize| {
+                    f32::from_le_bytes([texel[i], texel[i + 1], texel[i + 2], texel[i + 3]])
+                };
+                Vec3::new(channel(0), channel(4), channel(8)) / channel(12).max(1.0)
+            })
+            .collect();
+        VoxelGIReferenceImage {
+            size: self.size,
+            pixels,
+        }
+    }
+}
+
+/// What the GI passes of a [`VoxelGIProgressive`] view do this frame.
+#[derive(Component)]
+pub struct VoxelGIAccumulation {
+    pub textures: AccumulationTextures,
+    /// Offset of the view's [`AccumulateParams`]
+    pub offset: u32,
+    /// Frames summed up before this one, picks the current texture of the pair
+    pub frames: u32,
+    /// Whether this frame is added, not anymore once all samples are in
+    pub accumulate: bool,
+    /// Set on the frame that completes the sum, which gets copied into the readback buffer
+    pub read_back: bool,
+}
+
+impl VoxelGIAccumulation {
+    pub fn current(&self) -> &(Texture, TextureView) {
+        &self.textures.sum[self.frames as usize % 2]
+    }
+
+    pub fn previous(&self) -> &(Texture, TextureView) {
+        &self.textures.sum[(self.frames as usize + 1) % 2]
+    }
+
+    /// Copies the sum this frame writes into the readback buffer.
+    pub fn copy_to_readback(&self, command_encoder: &mut CommandEncoder) {
+        let size = self.textures.size;
+        command_encoder.copy_texture_to_buffer(
+            self.current().0.as_image_copy(),
+            ImageCopyBuffer {
+                buffer: &self.textures.readback,
+                layout: ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: NonZeroU32::new(self.textures.bytes_per_row),
+                    rows_per_image: None,
+                },
+            },
+            Extent3d {
+                width: size.x,
+                height: size.y,
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+}
+
+/// Result of mapping a readback buffer, filled in by the map callback once the GPU is done.
+type PendingReadback = Arc<Mutex<Option<Result<(), BufferAsyncError>>>>;
+
+struct AccumulationState {
+    textures: AccumulationTextures,
+    view_proj: Mat4,
+    /// Scene generation the sum was started with
+    generation: u32,
+    frames: u32,
+    /// Set when the sum was completed this frame
+    read_back: bool,
+    /// Output of the view when the sum was completed
+    output: Option<PathBuf>,
+    /// Set while the readback buffer is being mapped, the sum is left alone until it's read
+    pending: Option<PendingReadback>,
+}
+
+/// Running sums of all progressive views in the render world.
+#[derive(Resource, Default)]
+pub struct VoxelGIAccumulations {
+    pub params: DynamicUniformBuffer<AccumulateParams>,
+    views: HashMap<Entity, AccumulationState>,
+}
+
+type AccumulationViewQuery = (
+    Entity,
+    &'static ExtractedView,
+    &'static VoxelGI,
+    &'static VoxelGIProgressive,
+    &'static VoxelGITextures,
+    &'static VoxelGIPipelineId,
+    Option<&'static VoxelGISettings>,
+    Option<&'static VoxelGICameraCut>,
+);
+
+/// Decides which progressive views add this frame to their sum. Runs once the pipelines and the
+/// scene bind group are known, frames the GI pass can't trace yet are left out.
+#[allow(clippy::too_many_arguments)]
+pub fn queue_accumulation(
+    views: Query<AccumulationViewQuery>,
+    mut commands: Commands,
+    mut accumulations: ResMut<VoxelGIAccumulations>,
+    pipeline: Res<VoxelGIAccumulatePipeline>,
+    pipeline_cache: Res<PipelineCache>,
+    scene: Res<VoxelGIScene>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    let accumulations = &mut *accumulations;
+    accumulations.params.clear();
+    // views that are gone take their sums with them
+    let mut previous = std::mem::take(&mut accumulations.views);
+    let ready = pipeline_cache
+        .get_compute_pipeline(pipeline.accumulate)
+        .is_some()
+        && scene.bind_group.is_some();
+
+    for (entity, view, voxel_gi, progressive, textures, pipeline_id, settings, cut) in &views {
+        let settings = settings.cloned().unwrap_or_default();
+        if voxel_gi.mode != VoxelGIMode::PathTraced || settings.debug_view != VoxelGIDebugView::Off
+        {
+            continue;
+        }
+        let view_proj = view.projection * view.transform.compute_matrix().inverse();
+        let mut state = match previous.remove(&entity) {
+            Some(state) if state.textures.size == textures.size => state,
+            _ => AccumulationState {
+                textures: AccumulationTextures::new(&render_device, textures.size),
+                view_proj,
+                generation: scene.generation,
+                frames: 0,
+                read_back: false,
+                output: None,
+                pending: None,
+            },
+        };
+        if cut.is_some() || state.view_proj != view_proj || state.generation != scene.generation {
+            state.view_proj = view_proj;
+            state.generation = scene.generation;
+            state.frames = 0;
+        }
+
+        let samples = settings.samples_per_pixel.max(1);
+        let traced = ready
+            && pipeline_cache
+                .get_compute_pipeline(pipeline_id.compute)
+                .is_some();
+        // a new sum would be copied into the readback buffer while it's still mapped
+        let accumulate =
+            traced && state.pending.is_none() && state.frames * samples < progressive.samples;
+        let read_back = accumulate && (state.frames + 1) * samples >= progressive.samples;
+        let offset = accumulations.params.push(AccumulateParams {
+            frames: state.frames,
+        });
+        commands.entity(entity).insert(VoxelGIAccumulation {
+            textures: state.textures.clone(),
+            offset,
+            frames: state.frames,
+            accumulate,
+            read_back,
+        });
+
+        if accumulate {
+            state.frames += 1;
+        }
+        if read_back {
+            state.read_back = true;
+            state.output = progressive.output.clone();
+        }
+        accumulations.views.insert(entity, state);
+    }
+
+    accumulations
+        .params
+        .write_buffer(&render_device, &render_queue);
+}
+
+/// Starts mapping the readback buffers of the sums completed this frame, once the frame was
+/// submitted, and hands the averages of the ones the GPU is done with over to the main world.
+pub fn read_back_accumulation(
+    mut accumulations: ResMut<VoxelGIAccumulations>,
+    finished: Res<VoxelGIFinishedRenders>,
+    render_device: Res<RenderDevice>,
+) {
+    for state in accumulations.views.values_mut() {
+        if !std::mem::take(&mut state.read_back) {
+            continue;
+        }
+        let pending = PendingReadback::default();
+        let mapped = pending.clone();
+        render_device.map_buffer(
+            &state.textures.readback.slice(..),
+            MapMode::Read,
+            move |result| *mapped.lock().unwrap() = Some(result),
+        );
+        state.pending = Some(pending);
+    }
+
+    if accumulations
+        .views
+        .values()
+        .all(|state| state.pending.is_none())
+    {
+        return;
+    }
+    // runs the callbacks of finished maps without waiting for the rest
+    render_device.poll(Maintain::Poll);
+    for (entity, state) in &mut accumulations.views {
+        let result = state
+            .pending
+            .as_ref()
+            .and_then(|pending| pending.lock().unwrap().take());
+        let Some(result) = result else {
+            continue;
+        };
+        state.pending = None;
+        if let Err(e) = result {
+            error!("Failed to read back voxel GI render: {e}");
+            continue;
+        }
+
+        let readback = &state.textures.readback;
+        let image = state
+            .textures
+            .read_image(&readback.slice(..).get_mapped_range());
+        readback.unmap();
+
+        finished.push(VoxelGIRenderFinished {
+            camera: *entity,
+            image,
+            output: state.output.take(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyframe(time: f32, translation: Vec3, rotation: Quat) -> VoxelGICameraKeyframe {
+        VoxelGICameraKeyframe {
+            time,
+            transform: Transform {
+                translation,
+                rotation,
+                ..default()
+            },
+        }
+    }
+
+    /// Straight line along x with a quarter turn around y, given out of order.
+    fn path() -> VoxelGICameraPath {
+        VoxelGICameraPath::new(
+            [
+                keyframe(3.0, Vec3::new(3.0, 0.0, 0.0), Quat::from_rotation_y(0.75)),
+                keyframe(1.0, Vec3::new(1.0, 0.0, 0.0), Quat::from_rotation_y(0.25)),
+                keyframe(0.0, Vec3::ZERO, Quat::IDENTITY),
+                keyframe(2.0, Vec3::new(2.0, 0.0, 0.0), Quat::from_rotation_y(0.5)),
+            ],
+            24.0,
+            "render/shot_{frame}.exr",
+        )
+        .unwrap()
+    }
+
+    fn assert_near(a: Transform, b: Transform) {
+        assert!(
+            a.translation.abs_diff_eq(b.translation, 1e-5)
+                && a.rotation.abs_diff_eq(b.rotation, 1e-5)
+                && a.scale.abs_diff_eq(b.scale, 1e-5),
+            "{a:?} != {b:?}"
+        );
+    }
+
+    #[test]
+    fn frame_rate_must_be_positive() {
+        for frame_rate in [0.0, -24.0, f32::NAN, f32::INFINITY] {
+            assert!(VoxelGICameraPath::new([], frame_rate, "frame.png").is_err());
+        }
+        assert!(VoxelGICameraPath::new([], 0.5, "frame.png").is_ok());
+    }
+
+    #[test]
+    fn keyframes_are_sorted() {
+        let times = path()
+            .keyframes
+            .iter()
+            .map(|keyframe| keyframe.time)
+            .collect::<Vec<_>>();
+        assert_eq!(times, [0.0, 1.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn frame_count() {
+        // both ends are rendered
+        assert_eq!(path().frame_count(), 3 * 24 + 1);
+
+        let mut path = path();
+        path.frame_rate = 2.5;
+        assert_eq!(path.frame_count(), 8);
+        path.keyframes.truncate(1);
+        assert_eq!(path.frame_count(), 1);
+        path.keyframes.clear();
+        assert_eq!(path.frame_count(), 0);
+    }
+
+    #[test]
+    fn frame_path() {
+        let path = path();
+        assert_eq!(path.frame_path(7), PathBuf::from("render/shot_00007.exr"));
+        assert_eq!(
+            path.frame_path(123456),
+            PathBuf::from("render/shot_123456.exr")
+        );
+        let fixed = VoxelGICameraPath::new([], 24.0, "still.png").unwrap();
+        assert_eq!(fixed.frame_path(3), PathBuf::from("still.png"));
+    }
+
+    #[test]
+    fn keyframes_are_interpolated() {
+        let path = path();
+        for keyframe in &path.keyframes {
+            assert_near(path.transform_at(keyframe.time), keyframe.transform);
+        }
+        // held outside of the path
+        assert_near(path.transform_at(-1.0), path.keyframes[0].transform);
+        assert_near(path.transform_at(10.0), path.keyframes[3].transform);
+
+        // evenly spaced points on a line stay on it, and the rotation turns at a constant rate
+        let halfway = keyframe(1.5, Vec3::new(1.5, 0.0, 0.0), Quat::from_rotation_y(0.375));
+        assert_near(path.transform_at(1.5), halfway.transform);
+        let third = keyframe(
+            1.0 + 1.0 / 3.0,
+            Vec3::new(1.0 + 1.0 / 3.0, 0.0, 0.0),
+            Quat::from_rotation_y(0.25 + 0.25 / 3.0),
+        );
+        assert_near(path.transform_at(third.time), third.transform);
+
+        assert_near(path.frame_transform(36), halfway.transform);
+        assert_near(path.frame_transform(72), path.keyframes[3].transform);
+    }
+
+    #[test]
+    fn curves_through_keyframes() {
+        let path = VoxelGICameraPath::new(
+            [
+                keyframe(0.0, Vec3::ZERO, Quat::IDENTITY),
+                keyframe(1.0, Vec3::new(1.0, 1.0, 0.0), Quat::IDENTITY),
+                keyframe(2.0, Vec3::new(2.0, 0.0, 0.0), Quat::IDENTITY),
+            ],
+            24.0,
+            "frame_{frame}.png",
+        )
+        .unwrap();
+        // the spline overshoots the straight line between the keyframes towards the peak
+        let translation = path.transform_at(0.5).translation;
+        assert!(translation.y > 0.5, "{translation}");
+        assert_eq!(
+            catmull_rom(Vec3::ZERO, Vec3::X, Vec3::Y, Vec3::Z, 0.0),
+            Vec3::X
+        );
+        assert_eq!(
+            catmull_rom(Vec3::ZERO, Vec3::X, Vec3::Y, Vec3::Z, 1.0),
+            Vec3::Y
+        );
+    }
+}
@@ -0,0 +1,862 @@
+use std::sync::Arc;
+
+use bevy::{
+    prelude::*,
+    render::{
+        extract_resource::ExtractResource,
+        render_resource::*,
+        renderer::{RenderDevice, RenderQueue},
+    },
+};
+use wgpu::CommandBuffer;
+
+use super::{
+    plugin::{GpuBVH, GpuFlatNode, GpuShapes, VOXEL_GI_LBVH_SHADER},
+    resources::{create_lbvh_bind_group, create_lbvh_bind_group_layout},
+};
+
+/// Threads per workgroup of every LBVH pass, and keys per radix sort block.
+const BLOCK_SIZE: u32 = 256;
+/// Bits sorted per radix sort pass.
+const RADIX_BITS: u32 = 4;
+/// Morton codes have 30 bits. The count is even, so the sorted keys end up where they started.
+const RADIX_PASSES: u32 = 8;
+const NONE: u32 = u32::MAX;
+
+/// Spreads the lower 10 bits of `value` out to every third bit.
+fn expand_bits(value: u32) -> u32 {
+    let mut x = value & 0x3ff;
+    x = x.wrapping_mul(0x00010001) & 0xff0000ff;
+    x = x.wrapping_mul(0x00000101) & 0x0f00f00f;
+    x = x.wrapping_mul(0x00000011) & 0xc30c30c3;
+    x = x.wrapping_mul(0x00000005) & 0x49249249;
+    x
+}
+
+/// 30 bit Morton code of `point` on a 1024³ grid over the given bounds.
+pub fn morton_code(point: Vec3, bounds_min: Vec3, bounds_max: Vec3) -> u32 {
+    let extent = (bounds_max - bounds_min).max(Vec3::splat(0.000001));
+    let normalized = ((point - bounds_min) / extent).clamp(Vec3::ZERO, Vec3::ONE);
+    let cell = (normalized * 1024.0).as_uvec3().min(UVec3::splat(1023));
+    (expand_bits(cell.x) << 2) | (expand_bits(cell.y) << 1) | expand_bits(cell.z)
+}
+
+#[derive(Clone, Copy)]
+struct TreeNode {
+    left: u32,
+    right: u32,
+    parent: u32,
+}
+
+/// CPU reference of the GPU builder in `lbvh.wgsl`, pass for pass. Leaves point at the index of
+/// their box in `aabbs`, the nodes use the same flat layout as the GPU ones so both traverse to
+/// the same hits. Also builds the top level BVH, which only has one leaf per placed model.
+pub fn build_lbvh(aabbs: &[(Vec3, Vec3)]) -> Vec<GpuFlatNode> {
+    if aabbs.is_empty() {
+        return vec![];
+    }
+    let (bounds_min, bounds_max) = aabbs.iter().fold(
+        (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)),
+        |(acc_min, acc_max), (min, max)| (acc_min.min(*min), acc_max.max(*max)),
+    );
+
+    // stable like the radix sort, so equal codes stay in index order
+    let mut sorted = aabbs
+        .iter()
+        .enumerate()
+        .map(|(i, (min, max))| {
+            let centroid = (*min + *max) * 0.5;
+            (morton_code(centroid, bounds_min, bounds_max), i as u32)
+        })
+        .collect::<Vec<_>>();
+    sorted.sort_by_key(|(key, _)| *key);
+
+    let n = sorted.len() as i32;
+    // length of the common prefix of two sorted codes, duplicates are told apart by their index
+    let delta = |i: i32, j: i32| -> i32 {
+        if j < 0 || j >= n {
+            return -1;
+        }
+        let (a, b) = (sorted[i as usize].0, sorted[j as usize].0);
+        if a == b {
+            32 + (i as u32 ^ j as u32).leading_zeros() as i32
+        } else {
+            (a ^ b).leading_zeros() as i32
+        }
+    };
+
+    let node_count = 2 * sorted.len() - 1;
+    let mut tree = vec![
+        TreeNode {
+            left: NONE,
+            right: NONE,
+            parent: NONE,
+        };
+        node_count
+    ];
+    for i in 0..n - 1 {
+        // direction and far end of the range of keys this node covers
+        let d = if delta(i, i + 1) > delta(i, i - 1) {
+            1
+        } else {
+            -1
+        };
+        let delta_min = delta(i, i - d);
+        let mut l_max = 2;
+        while delta(i, i + l_max * d) > delta_min {
+            l_max *= 2;
+        }
+        let mut l = 0;
+        let mut t = l_max / 2;
+        while t >= 1 {
+            if delta(i, i + (l + t) * d) > delta_min {
+                l += t;
+            }
+            t /= 2;
+        }
+        let j = i + l * d;
+
+        // split where the highest differing bit inside the range flips
+        let delta_node = delta(i, j);
+        let mut s = 0;
+        let mut t = l;
+        loop {
+            t = (t + 1) / 2;
+            if delta(i, i + (s + t) * d) > delta_node {
+                s += t;
+            }
+            if t <= 1 {
+                break;
+            }
+        }
+        let gamma = i + s * d + d.min(0);
+
+        let leaf_offset = n - 1;
+        let left = if i.min(j) == gamma {
+            leaf_offset + gamma
+        } else {
+            gamma
+        } as u32;
+        let right = if i.max(j) == gamma + 1 {
+            leaf_offset + gamma + 1
+        } else {
+            gamma + 1
+        } as u32;
+        tree[i as usize].left = left;
+        tree[i as usize].right = right;
+        tree[left as usize].parent = i as u32;
+        tree[right as usize].parent = i as u32;
+    }
+
+    // every leaf grows itself and all its ancestors
+    let mut bounds = vec![(Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)); node_count];
+    for (leaf, (_, shape)) in sorted.iter().enumerate() {
+        let (min, max) = aabbs[*shape as usize];
+        let mut node = sorted.len() - 1 + leaf;
+        loop {
+            bounds[node].0 = bounds[node].0.min(min);
+            bounds[node].1 = bounds[node].1.max(max);
+            node = match tree[node].parent {
+                NONE => break,
+                parent => parent as usize,
+            };
+        }
+    }
+
+    (0..node_count)
+        .map(|node| {
+            // after a subtree comes the right sibling of its closest ancestor that is a left child
+            let mut exit_index = node_count as u32;
+            let mut child = node as u32;
+            let mut parent = tree[node].parent;
+            while parent != NONE {
+                if tree[parent as usize].left == child {
+                    exit_index = tree[parent as usize].right;
+                    break;
+                }
+                child = parent;
+                parent = tree[parent as usize].parent;
+            }
+
+            let (entry_index, shape_index) = match node.checked_sub(sorted.len() - 1) {
+                Some(leaf) => (NONE, sorted[leaf].1),
+                None => (tree[node].left, 0),
+            };
+            GpuFlatNode {
+                aabb_min: bounds[node].0,
+                aabb_max: bounds[node].1,
+                entry_index,
+                exit_index,
+                shape_index,
+            }
+        })
+        .collect()
+}
+
+/// Stand in nodes for a BLAS that hasn't been built yet. Every ray misses them and leaves the
+/// model right away, instead of looping over zeroed nodes.
+pub fn placeholder_nodes(node_count: u32) -> impl Iterator<Item = GpuFlatNode> {
+    (0..node_count).map(move |_| GpuFlatNode {
+        aabb_min: Vec3::splat(f32::MAX),
+        aabb_max: Vec3::splat(f32::MIN),
+        entry_index: 0,
+        exit_index: node_count,
+        shape_index: 0,
+    })
+}
+
+/// A model whose bricks are uploaded and whose BLAS still has to be built on the GPU.
+#[derive(Clone, Copy, Debug)]
+pub struct BlasBuildJob {
+    /// Where the model's nodes start in [`VoxelGIBlasBuilds::nodes`]
+    pub node_offset: u32,
+    /// Where the model's bricks start in [`VoxelGIBlasBuilds::shapes`]
+    pub shape_offset: u32,
+    pub shape_count: u32,
+    pub aabb_min: Vec3,
+    pub aabb_max: Vec3,
+}
+
+/// BLAS builds requested by the main world. Model changes upload a new node buffer, so every
+/// model is built again into it, which only takes a handful of dispatches per model.
+#[derive(Resource, ExtractResource, Clone, Default)]
+pub struct VoxelGIBlasBuilds {
+    /// Bumped with every new set of jobs, the render world builds each generation once
+    pub generation: u32,
+    pub jobs: Vec<BlasBuildJob>,
+    pub nodes: Arc<StorageBuffer<GpuBVH>>,
+    pub shapes: Arc<StorageBuffer<GpuShapes>>,
+}
+
+#[derive(Clone, Copy, ShaderType)]
+pub struct LbvhParams {
+    bounds_min: Vec3,
+    count: u32,
+    bounds_max: Vec3,
+    /// First bit of the radix sort digit of this pass
+    shift: u32,
+    node_offset: u32,
+    shape_offset: u32,
+    /// Workgroups of the radix sort passes
+    num_blocks: u32,
+}
+
+/// Entry points of `lbvh.wgsl`, in dispatch order.
+const ENTRY_POINTS: [&str; 8] = [
+    "morton",
+    "radix_count",
+    "radix_scan",
+    "radix_scatter",
+    "init_nodes",
+    "hierarchy",
+    "refit",
+    "emit",
+];
+
+#[derive(Resource)]
+pub struct LbvhPipeline {
+    pub bind_group_layout: BindGroupLayout,
+    /// One pipeline per entry point of `lbvh.wgsl`, in [`ENTRY_POINTS`] order
+    passes: [CachedComputePipelineId; 8],
+}
+
+impl FromWorld for LbvhPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let bind_group_layout = create_lbvh_bind_group_layout(world.resource::<RenderDevice>());
+
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let passes = ENTRY_POINTS.map(|entry_point| {
+            pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+                label: Some(format!("voxel_gi_lbvh_{entry_point}_pipeline").into()),
+                layout: vec![bind_group_layout.clone()],
+                push_constant_ranges: vec![],
+                shader: VOXEL_GI_LBVH_SHADER.typed(),
+                shader_defs: vec![],
+                entry_point: entry_point.into(),
+            })
+        });
+
+        Self {
+            bind_group_layout,
+            passes,
+        }
+    }
+}
+
+/// Scratch buffers of the builder, sized for the largest model seen so far.
+pub struct LbvhScratch {
+    capacity: u32,
+    /// Morton codes and brick indices, ping-ponged by the radix sort
+    pub keys: [Buffer; 2],
+    pub values: [Buffer; 2],
+    pub histograms: Buffer,
+    pub tree: Buffer,
+    pub bounds: Buffer,
+}
+
+impl LbvhScratch {
+    fn new(render_device: &RenderDevice, capacity: u32) -> Self {
+        let buffer = |label: &'static str, size: u32| {
+            render_device.create_buffer(&BufferDescriptor {
+                label: Some(label),
+                size: size as u64 * 4,
+                usage: BufferUsages::STORAGE,
+                mapped_at_creation: false,
+            })
+        };
+        let node_count = 2 * capacity - 1;
+        Self {
+            capacity,
+            keys: [
+                buffer("voxel_gi_lbvh_keys_1", capacity),
+                buffer("voxel_gi_lbvh_keys_2", capacity),
+            ],
+            values: [
+                buffer("voxel_gi_lbvh_values_1", capacity),
+                buffer("voxel_gi_lbvh_values_2", capacity),
+            ],
+            histograms: buffer(
+                "voxel_gi_lbvh_histograms",
+                (1 << RADIX_BITS) * blocks(capacity),
+            ),
+            tree: buffer("voxel_gi_lbvh_tree", node_count * 3),
+            bounds: buffer("voxel_gi_lbvh_bounds", node_count * 6),
+        }
+    }
+}
+
+fn blocks(count: u32) -> u32 {
+    count.div_ceil(BLOCK_SIZE)
+}
+
+#[derive(Resource, Default)]
+pub struct LbvhBuilder {
+    built_generation: u32,
+    scratch: Option<LbvhScratch>,
+    params: DynamicUniformBuffer<LbvhParams>,
+}
+
+/// Builds the BLASes of the extracted [`VoxelGIBlasBuilds`] on the GPU, before anything traces
+/// them this frame. Jobs stay queued until the pipelines are compiled.
+pub fn build_blases(
+    builds: Res<VoxelGIBlasBuilds>,
+    mut builder: ResMut<LbvhBuilder>,
+    pipeline: Res<LbvhPipeline>,
+    pipeline_cache: Res<PipelineCache>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    if builds.generation == builder.built_generation {
+        return;
+    }
+    let (Some(nodes), Some(shapes)) = (builds.nodes.buffer(), builds.shapes.buffer()) else {
+        return;
+    };
+    let Some(passes) = pipeline
+        .passes
+        .iter()
+        .map(|id| pipeline_cache.get_compute_pipeline(*id))
+        .collect::<Option<Vec<_>>>()
+    else {
+        return;
+    };
+    let Ok(passes) = passes.try_into() else {
+        return;
+    };
+
+    let Some(commands) = builder.encode(
+        &render_device,
+        &render_queue,
+        &pipeline.bind_group_layout,
+        passes,
+        &builds.jobs,
+        nodes,
+        shapes,
+    ) else {
+        return;
+    };
+    render_queue.submit([commands]);
+
+    builder.built_generation = builds.generation;
+}
+
+impl LbvhBuilder {
+    /// Records the dispatches building the BLAS of every job into `nodes`, with one pipeline per
+    /// entry point of `lbvh.wgsl` in dispatch order.
+    #[allow(clippy::too_many_arguments)]
+    fn encode(
+        &mut self,
+        render_device: &RenderDevice,
+        render_queue: &RenderQueue,
+        layout: &BindGroupLayout,
+        passes: [&ComputePipeline; 8],
+        jobs: &[BlasBuildJob],
+        nodes: &Buffer,
+        shapes: &Buffer,
+    ) -> Option<CommandBuffer> {
+        let [morton, histogram, scan, scatter, init, hierarchy, refit, emit] = passes;
+
+        let capacity = jobs.iter().map(|job| job.shape_count).max();
+        let capacity = capacity.unwrap_or(0).max(1).next_power_of_two();
+        if self
+            .scratch
+            .as_ref()
+            .is_none_or(|scratch| scratch.capacity < capacity)
+        {
+            self.scratch = Some(LbvhScratch::new(render_device, capacity));
+        }
+        let scratch = self.scratch.as_ref()?;
+
+        self.params.clear();
+        let offsets = jobs
+            .iter()
+            .map(|job| {
+                (0..RADIX_PASSES)
+                    .map(|pass| {
+                        self.params.push(LbvhParams {
+                            bounds_min: job.aabb_min,
+                            count: job.shape_count,
+                            bounds_max: job.aabb_max,
+                            shift: pass * RADIX_BITS,
+                            node_offset: job.node_offset,
+                            shape_offset: job.shape_offset,
+                            num_blocks: blocks(job.shape_count),
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        self.params.write_buffer(render_device, render_queue);
+        let [Some(bind_group), Some(swapped_bind_group)] = [false, true].map(|swap| {
+            create_lbvh_bind_group(
+                render_device,
+                layout,
+                &self.params,
+                shapes,
+                nodes,
+                scratch,
+                swap,
+            )
+        }) else {
+            return None;
+        };
+        let bind_groups = [bind_group, swapped_bind_group];
+
+        // pipeline, bind group, uniform offset and workgroups of every dispatch
+        let mut dispatches = vec![];
+        for (job, offsets) in jobs.iter().zip(&offsets) {
+            let count = job.shape_count;
+            if count == 0 {
+                continue;
+            }
+            let node_count = 2 * count - 1;
+            dispatches.push((morton, &bind_groups[0], offsets[0], blocks(count)));
+            for pass in 0..RADIX_PASSES as usize {
+                let bind_group = &bind_groups[pass % 2];
+                dispatches.push((histogram, bind_group, offsets[pass], blocks(count)));
+                dispatches.push((scan, bind_group, offsets[pass], 1));
+                dispatches.push((scatter, bind_group, offsets[pass], blocks(count)));
+            }
+            dispatches.push((init, &bind_groups[0], offsets[0], blocks(node_count)));
+            dispatches.push((hierarchy, &bind_groups[0], offsets[0], blocks(count - 1)));
+            dispatches.push((refit, &bind_groups[0], offsets[0], blocks(count)));
+            dispatches.push((emit, &bind_groups[0], offsets[0], blocks(node_count)));
+        }
+
+        let mut command_encoder = render_device.create_command_encoder(&CommandEncoderDescriptor {
+            label: Some("voxel_gi_lbvh_command_encoder"),
+        });
+        {
+            let mut lbvh_pass = command_encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: Some("voxel_gi_lbvh_pass"),
+            });
+            for (pipeline, bind_group, offset, workgroups) in dispatches {
+                // a single brick has no internal nodes
+                if workgroups == 0 {
+                    continue;
+                }
+                lbvh_pass.set_pipeline(pipeline);
+                lbvh_pass.set_bind_group(0, bind_group, &[offset]);
+                lbvh_pass.dispatch_workgroups(workgroups, 1, 1);
+            }
+        }
+        Some(command_encoder.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::render::render_resource::encase;
+    use dot_vox::{Model, Size, Voxel};
+    use futures_lite::future::block_on;
+    use wgpu::Maintain;
+
+    use super::*;
+    use crate::{
+        vox::get_model_texture,
+        vox_gi::plugin::{build_blas, GpuShape, BRICK_SIZE, BRICK_WORDS},
+    };
+
+    /// Same slab test as the traversal in `voxel_gi.wgsl`, for rays starting at `origin`.
+    fn ray_hits(origin: Vec3, direction: Vec3, min: Vec3, max: Vec3) -> bool {
+        let t1 = (min - origin) / direction;
+        let t2 = (max - origin) / direction;
+        t1.min(t2).max_element().max(0.0) <= t1.max(t2).min_element()
+    }
+
+    /// Shape indices of the leaves a ray hits, walking the entry and exit indices like the
+    /// shaders do. Every node is visited at most once, otherwise the layout has a cycle.
+    fn walk(nodes: &[GpuFlatNode], origin: Vec3, direction: Vec3) -> Vec<u32> {
+        let mut hits = vec![];
+        let mut node_index = 0;
+        let mut visited = 0;
+        while let Some(node) = nodes.get(node_index as usize) {
+            visited += 1;
+            assert!(visited <= nodes.len(), "traversal doesn't terminate");
+            if !ray_hits(origin, direction, node.aabb_min, node.aabb_max) {
+                node_index = node.exit_index;
+            } else if node.entry_index == NONE {
+                hits.push(node.shape_index);
+                node_index = node.exit_index;
+            } else {
+                node_index = node.entry_index;
+            }
+        }
+        assert_eq!(node_index as usize, nodes.len(), "traversal left the tree");
+        hits.sort_unstable();
+        hits
+    }
+
+    fn brute_force(aabbs: &[(Vec3, Vec3)], origin: Vec3, direction: Vec3) -> Vec<u32> {
+        (0..aabbs.len() as u32)
+            .filter(|i| {
+                let (min, max) = aabbs[*i as usize];
+                ray_hits(origin, direction, min, max)
+            })
+            .collect()
+    }
+
+    /// Deterministic numbers in `[0, 1)`.
+    fn random(state: &mut u32) -> f32 {
+        *state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+        (*state >> 8) as f32 / (1 << 24) as f32
+    }
+
+    fn random_vec3(state: &mut u32) -> Vec3 {
+        Vec3::new(random(state), random(state), random(state))
+    }
+
+    /// Rays from all around the bounds towards points inside them.
+    fn rays(aabbs: &[(Vec3, Vec3)], count: usize) -> Vec<(Vec3, Vec3)> {
+        let (min, max) = aabbs.iter().fold(
+            (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)),
+            |(acc_min, acc_max), (min, max)| (acc_min.min(*min), acc_max.max(*max)),
+        );
+        let extent = (max - min).max(Vec3::ONE);
+        let mut state = 7;
+        (0..count)
+            .map(|_| {
+                let origin = min + (random_vec3(&mut state) * 3.0 - 1.0) * extent;
+                let target = min + random_vec3(&mut state) * (max - min);
+                // a hair off, so no component of the direction is exactly zero
+                let direction = (target - origin + 0.001).normalize();
+                (origin, direction)
+            })
+            .collect()
+    }
+
+    /// Checks the flat layout of `nodes` and that tracing it finds the same leaves as testing all
+    /// boxes one by one.
+    fn check_lbvh(aabbs: &[(Vec3, Vec3)], nodes: &[GpuFlatNode]) {
+        assert_eq!(nodes.len(), 2 * aabbs.len() - 1);
+
+        let mut leaves = nodes
+            .iter()
+            .filter(|node| node.entry_index == NONE)
+            .map(|node| node.shape_index)
+            .collect::<Vec<_>>();
+        leaves.sort_unstable();
+        assert_eq!(leaves, (0..aabbs.len() as u32).collect::<Vec<_>>());
+
+        for node in nodes.iter().filter(|node| node.entry_index == NONE) {
+            let (min, max) = aabbs[node.shape_index as usize];
+            assert_eq!((node.aabb_min, node.aabb_max), (min, max));
+        }
+        // the right child is where the left subtree exits to
+        for node in nodes.iter().filter(|node| node.entry_index != NONE) {
+            let left = &nodes[node.entry_index as usize];
+            let right = &nodes[left.exit_index as usize];
+            assert_eq!(right.exit_index, node.exit_index);
+            assert_eq!(
+                (node.aabb_min, node.aabb_max),
+                (
+                    left.aabb_min.min(right.aabb_min),
+                    left.aabb_max.max(right.aabb_max)
+                )
+            );
+        }
+
+        for (origin, direction) in rays(aabbs, 500) {
+            assert_eq!(
+                walk(nodes, origin, direction),
+                brute_force(aabbs, origin, direction),
+                "ray from {origin} along {direction}"
+            );
+        }
+    }
+
+    fn unit_box(min: Vec3) -> (Vec3, Vec3) {
+        (min, min + 1.0)
+    }
+
+    #[test]
+    fn single_leaf() {
+        let aabbs = [unit_box(Vec3::new(1.0, 2.0, 3.0))];
+        let nodes = build_lbvh(&aabbs);
+        check_lbvh(&aabbs, &nodes);
+        assert_eq!(nodes[0].exit_index, 1);
+    }
+
+    #[test]
+    fn two_leaves() {
+        let aabbs = [unit_box(Vec3::ZERO), unit_box(Vec3::new(4.0, 0.0, 0.0))];
+        check_lbvh(&aabbs, &build_lbvh(&aabbs));
+    }
+
+    #[test]
+    fn duplicate_morton_codes() {
+        let mut aabbs = vec![unit_box(Vec3::splat(2.0)); 5];
+        aabbs.push(unit_box(Vec3::ZERO));
+        aabbs.extend(vec![unit_box(Vec3::new(6.0, 0.0, 2.0)); 3]);
+        aabbs.push((Vec3::splat(1.5), Vec3::splat(3.5)));
+        check_lbvh(&aabbs, &build_lbvh(&aabbs));
+
+        let identical = vec![unit_box(Vec3::ONE); 4];
+        check_lbvh(&identical, &build_lbvh(&identical));
+    }
+
+    #[test]
+    fn scattered_boxes() {
+        let mut state = 1;
+        let aabbs = (0..300)
+            .map(|_| {
+                let min = random_vec3(&mut state) * 40.0;
+                (min, min + random_vec3(&mut state) * 4.0 + 0.1)
+            })
+            .collect::<Vec<_>>();
+        check_lbvh(&aabbs, &build_lbvh(&aabbs));
+    }
+
+    /// A sphere and a floor, which leaves empty bricks out.
+    fn sphere_and_floor(size: UVec3) -> Model {
+        let voxels = (0..size.x)
+            .flat_map(|x| (0..size.y).flat_map(move |y| (0..size.z).map(move |z| (x, y, z))))
+            .filter(|(x, y, z)| {
+                let pos = Vec3::new(*x as f32, *y as f32, *z as f32) - size.as_vec3() * 0.5;
+                pos.length() < 9.0 || *z == 0
+            })
+            .map(|(x, y, z)| Voxel {
+                x: x as u8,
+                y: y as u8,
+                z: z as u8,
+                i: (x % 7) as u8,
+            })
+            .collect();
+        Model {
+            size: Size {
+                x: size.x,
+                y: size.y,
+                z: size.z,
+            },
+            voxels,
+        }
+    }
+
+    #[test]
+    fn matches_blas_bricks() {
+        let size = UVec3::new(37, 20, 29);
+        let blas = build_blas(&get_model_texture(&sphere_and_floor(size))).unwrap();
+        let aabbs = blas
+            .shapes
+            .iter()
+            .map(|shape| (shape.aabb_min, shape.aabb_max))
+            .collect::<Vec<_>>();
+        let bricks_per_axis = (size + BRICK_SIZE - 1) / BRICK_SIZE;
+        assert!(aabbs.len() < (bricks_per_axis.x * bricks_per_axis.y * bricks_per_axis.z) as usize);
+
+        let nodes = build_lbvh(&aabbs);
+        assert_eq!(nodes.len() as u32, blas.node_count);
+        assert_eq!(
+            (nodes[0].aabb_min, nodes[0].aabb_max),
+            (blas.aabb_min, blas.aabb_max)
+        );
+        check_lbvh(&aabbs, &nodes);
+    }
+
+    /// Device of the first adapter wgpu finds, `None` on machines without one.
+    fn headless_device() -> Option<(RenderDevice, RenderQueue)> {
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+        let adapter = block_on(instance.request_adapter(&wgpu::RequestAdapterOptions::default()))?;
+        let (device, queue) = block_on(adapter.request_device(
+            &wgpu::DeviceDescriptor {
+                label: None,
+                features: wgpu::Features::empty(),
+                // the builder binds more storage buffers than the defaults allow
+                limits: adapter.limits(),
+            },
+            None,
+        ))
+        .ok()?;
+        Some((RenderDevice::from(device), RenderQueue(Arc::new(queue))))
+    }
+
+    /// Runs the passes of `lbvh.wgsl` over `aabbs` and reads the nodes back.
+    fn build_lbvh_on_gpu(
+        render_device: &RenderDevice,
+        render_queue: &RenderQueue,
+        aabbs: &[(Vec3, Vec3)],
+    ) -> Vec<GpuFlatNode> {
+        let layout = create_lbvh_bind_group_layout(render_device);
+        let module = render_device.create_shader_module(ShaderModuleDescriptor {
+            label: Some("voxel_gi_lbvh_shader"),
+            source: ShaderSource::Wgsl(include_str!("lbvh.wgsl").into()),
+        });
+        let pipeline_layout = render_device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let pipelines = ENTRY_POINTS.map(|entry_point| {
+            render_device.create_compute_pipeline(&RawComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: Some(&pipeline_layout),
+                module: &module,
+                entry_point,
+            })
+        });
+
+        let (aabb_min, aabb_max) = aabbs.iter().fold(
+            (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)),
+            |(acc_min, acc_max), (min, max)| (acc_min.min(*min), acc_max.max(*max)),
+        );
+        let job = BlasBuildJob {
+            node_offset: 0,
+            shape_offset: 0,
+            shape_count: aabbs.len() as u32,
+            aabb_min,
+            aabb_max,
+        };
+        let node_count = 2 * job.shape_count - 1;
+
+        let mut nodes = StorageBuffer::from(GpuBVH {
+            length: node_count,
+            data: placeholder_nodes(node_count).collect(),
+        });
+        nodes.add_usages(BufferUsages::COPY_SRC);
+        nodes.write_buffer(render_device, render_queue);
+        let mut shapes = StorageBuffer::from(GpuShapes {
+            length: job.shape_count,
+            data: aabbs
+                .iter()
+                .map(|(aabb_min, aabb_max)| GpuShape {
+                    aabb_min: *aabb_min,
+                    aabb_max: *aabb_max,
+                    material_offset: 0,
+                    voxels: [0; BRICK_WORDS],
+                })
+                .collect(),
+        });
+        shapes.write_buffer(render_device, render_queue);
+        let (Some(nodes), Some(shapes)) = (nodes.buffer(), shapes.buffer()) else {
+            panic!("scene buffers weren't created");
+        };
+
+        let commands = LbvhBuilder::default()
+            .encode(
+                render_device,
+                render_queue,
+                &layout,
+                pipelines.each_ref(),
+                &[job],
+                nodes,
+                shapes,
+            )
+            .unwrap();
+        let readback = render_device.create_buffer(&BufferDescriptor {
+            label: Some("voxel_gi_lbvh_readback"),
+            size: nodes.size(),
+            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let mut command_encoder =
+            render_device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+        command_encoder.copy_buffer_to_buffer(nodes, 0, &readback, 0, nodes.size());
+        render_queue.submit([commands, command_encoder.finish()]);
+
+        let slice = readback.slice(..);
+        render_device.map_buffer(&slice, MapMode::Read, |result| result.unwrap());
+        render_device.poll(Maintain::Wait);
+        let bytes = slice.get_mapped_range();
+        let bvh: GpuBVH = encase::StorageBuffer::new(&bytes[..]).create().unwrap();
+        bvh.data
+    }
+
+    fn node_fields(node: &GpuFlatNode) -> (Vec3, Vec3, u32, u32, u32) {
+        (
+            node.aabb_min,
+            node.aabb_max,
+            node.entry_index,
+            node.exit_index,
+            node.shape_index,
+        )
+    }
+
+    #[test]
+    fn gpu_build_matches_cpu() {
+        let Some((render_device, render_queue)) = headless_device() else {
+            eprintln!("no GPU adapter, skipping the LBVH shader comparison");
+            return;
+        };
+
+        let blas = build_blas(&get_model_texture(&sphere_and_floor(UVec3::new(
+            37, 20, 29,
+        ))))
+        .unwrap();
+        let bricks = blas
+            .shapes
+            .iter()
+            .map(|shape| (shape.aabb_min, shape.aabb_max))
+            .collect::<Vec<_>>();
+        let mut state = 3;
+        // more boxes than one radix sort block
+        let scattered = (0..700)
+            .map(|_| {
+                let min = random_vec3(&mut state) * 40.0;
+                (min, min + random_vec3(&mut state) * 4.0 + 0.1)
+            })
+            .collect::<Vec<_>>();
+        let mut duplicates = vec![unit_box(Vec3::splat(2.0)); 5];
+        duplicates.push(unit_box(Vec3::ZERO));
+        duplicates.extend(vec![unit_box(Vec3::new(6.0, 0.0, 2.0)); 3]);
+
+        for aabbs in [
+            vec![unit_box(Vec3::new(1.0, 2.0, 3.0))],
+            vec![unit_box(Vec3::ZERO), unit_box(Vec3::new(4.0, 0.0, 0.0))],
+            duplicates,
+            bricks,
+            scattered,
+        ] {
+            let nodes = build_lbvh_on_gpu(&render_device, &render_queue, &aabbs);
+            assert_eq!(
+                nodes.iter().map(node_fields).collect::<Vec<_>>(),
+                build_lbvh(&aabbs)
+                    .iter()
+                    .map(node_fields)
+                    .collect::<Vec<_>>(),
+                "{} boxes",
+                aabbs.len()
+            );
+            check_lbvh(&aabbs, &nodes);
+        }
+    }
+}
@@ -0,0 +1,343 @@
+// Linear BVH construction over the bricks of one model, after Karras, "Maximizing Parallelism in
+// the Construction of BVHs, Octrees, and k-d Trees". Bricks are sorted along a Morton curve, the
+// hierarchy is read off the sorted codes and emitted in the flat layout `voxel_gi.wgsl` traverses.
+// `vox_gi::lbvh::build_lbvh` is the CPU reference of every pass, keep both in sync.
+
+struct WorldBvhNode {
+    aabb_min: vec3<f32>,
+    aabb_max: vec3<f32>,
+    entry_index: u32,
+    exit_index: u32,
+    shape_index: u32,
+}
+
+struct WorldBvh {
+    length: u32,
+    data: array<WorldBvhNode>,
+}
+
+struct WorldVoxel {
+    aabb_min: vec3<f32>,
+    aabb_max: vec3<f32>,
+    material_offset: u32,
+    voxels: array<u32, 128>,
+}
+struct WorldVoxels {
+    length: u32,
+    data: array<WorldVoxel>,
+}
+
+struct LbvhParams {
+    // bounds of the model, the Morton grid spans them
+    bounds_min: vec3<f32>,
+    count: u32,
+    bounds_max: vec3<f32>,
+    // first bit of the radix sort digit of this pass
+    shift: u32,
+    // where the model's nodes and bricks start in the scene buffers
+    node_offset: u32,
+    shape_offset: u32,
+    // workgroups of the radix sort passes
+    num_blocks: u32,
+}
+
+// internal nodes come first, leaf `i` is node `count - 1 + i`
+struct TreeNode {
+    left: u32,
+    right: u32,
+    parent: u32,
+}
+
+@group(0) @binding(0)
+var<uniform> params: LbvhParams;
+@group(0) @binding(1)
+var<storage, read> shapes: WorldVoxels;
+@group(0) @binding(2)
+var<storage, read_write> nodes: WorldBvh;
+// Morton codes and brick indices, sorted from `_in` into `_out`, the bind groups swap every pass
+@group(0) @binding(3)
+var<storage, read_write> keys_in: array<u32>;
+@group(0) @binding(4)
+var<storage, read_write> values_in: array<u32>;
+@group(0) @binding(5)
+var<storage, read_write> keys_out: array<u32>;
+@group(0) @binding(6)
+var<storage, read_write> values_out: array<u32>;
+// digit counts of every workgroup, digit major, scanned into scatter offsets
+@group(0) @binding(7)
+var<storage, read_write> histograms: array<u32>;
+@group(0) @binding(8)
+var<storage, read_write> tree: array<TreeNode>;
+// min xyz and max xyz of every node, as order preserving integers
+@group(0) @binding(9)
+var<storage, read_write> bounds: array<atomic<u32>>;
+
+const NONE = 0xffffffffu;
+const BLOCK_SIZE = 256u;
+const RADIX = 16u;
+
+// Spreads the lower 10 bits of `value` out to every third bit.
+fn expand_bits(value: u32) -> u32 {
+    var x = value & 0x3ffu;
+    x = (x * 0x00010001u) & 0xff0000ffu;
+    x = (x * 0x00000101u) & 0x0f00f00fu;
+    x = (x * 0x00000011u) & 0xc30c30c3u;
+    x = (x * 0x00000005u) & 0x49249249u;
+    return x;
+}
+
+// 30 bit Morton code of a point on a 1024³ grid over the model bounds.
+fn morton_code(point: vec3<f32>) -> u32 {
+    let extent = max(params.bounds_max - params.bounds_min, vec3<f32>(0.000001));
+    let normalized = clamp((point - params.bounds_min) / extent, vec3<f32>(0.0), vec3<f32>(1.0));
+    let cell = min(vec3<u32>(normalized * 1024.0), vec3<u32>(1023u));
+    return (expand_bits(cell.x) << 2u) | (expand_bits(cell.y) << 1u) | expand_bits(cell.z);
+}
+
+@compute @workgroup_size(256, 1, 1)
+fn morton(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let i = global_id.x;
+    if i >= params.count {
+        return;
+    }
+    let shape = params.shape_offset + i;
+    let centroid = (shapes.data[shape].aabb_min + shapes.data[shape].aabb_max) * 0.5;
+    keys_in[i] = morton_code(centroid);
+    values_in[i] = i;
+}
+
+var<workgroup> digit_counts: array<atomic<u32>, 16>;
+
+@compute @workgroup_size(256, 1, 1)
+fn radix_count(
+    @builtin(local_invocation_index) local_index: u32,
+    @builtin(workgroup_id) workgroup_id: vec3<u32>,
+) {
+    if local_index < RADIX {
+        atomicStore(&digit_counts[local_index], 0u);
+    }
+    workgroupBarrier();
+
+    let i = workgroup_id.x * BLOCK_SIZE + local_index;
+    if i < params.count {
+        atomicAdd(&digit_counts[(keys_in[i] >> params.shift) & (RADIX - 1u)], 1u);
+    }
+    workgroupBarrier();
+
+    if local_index < RADIX {
+        histograms[local_index * params.num_blocks + workgroup_id.x] = atomicLoad(&digit_counts[local_index]);
+    }
+}
+
+var<workgroup> chunk_sums: array<u32, 256>;
+
+// Exclusive scan of all histograms in a single workgroup, every thread takes one chunk.
+@compute @workgroup_size(256, 1, 1)
+fn radix_scan(@builtin(local_invocation_index) local_index: u32) {
+    let total = params.num_blocks * RADIX;
+    let chunk = (total + BLOCK_SIZE - 1u) / BLOCK_SIZE;
+    let start = min(local_index * chunk, total);
+    let end = min(start + chunk, total);
+
+    var sum = 0u;
+    for (var i = start; i < end; i = i + 1u) {
+        sum += histograms[i];
+    }
+    chunk_sums[local_index] = sum;
+    workgroupBarrier();
+
+    if local_index == 0u {
+        var running = 0u;
+        for (var i = 0u; i < BLOCK_SIZE; i = i + 1u) {
+            let chunk_sum = chunk_sums[i];
+            chunk_sums[i] = running;
+            running += chunk_sum;
+        }
+    }
+    workgroupBarrier();
+
+    var running = chunk_sums[local_index];
+    for (var i = start; i < end; i = i + 1u) {
+        let count = histograms[i];
+        histograms[i] = running;
+        running += count;
+    }
+}
+
+var<workgroup> block_digits: array<u32, 256>;
+
+// Stable scatter, equal digits keep their order by counting the ones before them in the block.
+@compute @workgroup_size(256, 1, 1)
+fn radix_scatter(
+    @builtin(local_invocation_index) local_index: u32,
+    @builtin(workgroup_id) workgroup_id: vec3<u32>,
+) {
+    let i = workgroup_id.x * BLOCK_SIZE + local_index;
+    var digit = RADIX;
+    if i < params.count {
+        digit = (keys_in[i] >> params.shift) & (RADIX - 1u);
+    }
+    block_digits[local_index] = digit;
+    workgroupBarrier();
+
+    if i >= params.count {
+        return;
+    }
+    var rank = 0u;
+    for (var k = 0u; k < local_index; k = k + 1u) {
+        rank += u32(block_digits[k] == digit);
+    }
+    let destination = histograms[digit * params.num_blocks + workgroup_id.x] + rank;
+    keys_out[destination] = keys_in[i];
+    values_out[destination] = values_in[i];
+}
+
+@compute @workgroup_size(256, 1, 1)
+fn init_nodes(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let node = global_id.x;
+    if node >= 2u * params.count - 1u {
+        return;
+    }
+    tree[node] = TreeNode(NONE, NONE, NONE);
+    for (var axis = 0u; axis < 3u; axis = axis + 1u) {
+        atomicStore(&bounds[node * 6u + axis], NONE);
+        atomicStore(&bounds[node * 6u + 3u + axis], 0u);
+    }
+}
+
+// Length of the common prefix of two sorted codes, duplicates are told apart by their index.
+fn delta(i: i32, j: i32) -> i32 {
+    if j < 0 || j >= i32(params.count) {
+        return -1;
+    }
+    let a = keys_in[i];
+    let b = keys_in[j];
+    if a == b {
+        return 32 + 31 - i32(firstLeadingBit(u32(i ^ j)));
+    }
+    return 31 - i32(firstLeadingBit(a ^ b));
+}
+
+@compute @workgroup_size(256, 1, 1)
+fn hierarchy(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let n = i32(params.count);
+    let i = i32(global_id.x);
+    if i >= n - 1 {
+        return;
+    }
+
+    // direction and far end of the range of keys this node covers
+    let d = select(-1, 1, delta(i, i + 1) > delta(i, i - 1));
+    let delta_min = delta(i, i - d);
+    var l_max = 2;
+    while delta(i, i + l_max * d) > delta_min {
+        l_max = l_max * 2;
+    }
+    var l = 0;
+    for (var t = l_max / 2; t >= 1; t = t / 2) {
+        if delta(i, i + (l + t) * d) > delta_min {
+            l = l + t;
+        }
+    }
+    let j = i + l * d;
+
+    // split where the highest differing bit inside the range flips
+    let delta_node = delta(i, j);
+    var s = 0;
+    var t = l;
+    loop {
+        t = (t + 1) / 2;
+        if delta(i, i + (s + t) * d) > delta_node {
+            s = s + t;
+        }
+        if t <= 1 {
+            break;
+        }
+    }
+    let gamma = i + s * d + min(d, 0);
+
+    let leaf_offset = n - 1;
+    let left = u32(select(gamma, leaf_offset + gamma, min(i, j) == gamma));
+    let right = u32(select(gamma + 1, leaf_offset + gamma + 1, max(i, j) == gamma + 1));
+    tree[i].left = left;
+    tree[i].right = right;
+    tree[left].parent = u32(i);
+    tree[right].parent = u32(i);
+}
+
+// Maps floats to integers with the same order, so the bounds can grow with atomic min and max.
+fn order_float(value: f32) -> u32 {
+    let bits = bitcast<u32>(value);
+    return select(bits | 0x80000000u, ~bits, (bits & 0x80000000u) != 0u);
+}
+
+fn unorder_float(bits: u32) -> f32 {
+    return bitcast<f32>(select(~bits, bits & 0x7fffffffu, (bits & 0x80000000u) != 0u));
+}
+
+// Every leaf grows itself and all its ancestors, which needs no ordering between workgroups.
+@compute @workgroup_size(256, 1, 1)
+fn refit(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let leaf = global_id.x;
+    if leaf >= params.count {
+        return;
+    }
+    let shape = params.shape_offset + values_in[leaf];
+    let aabb_min = shapes.data[shape].aabb_min;
+    let aabb_max = shapes.data[shape].aabb_max;
+
+    var node = params.count - 1u + leaf;
+    loop {
+        for (var axis = 0u; axis < 3u; axis = axis + 1u) {
+            atomicMin(&bounds[node * 6u + axis], order_float(aabb_min[axis]));
+            atomicMax(&bounds[node * 6u + 3u + axis], order_float(aabb_max[axis]));
+        }
+        node = tree[node].parent;
+        if node == NONE {
+            break;
+        }
+    }
+}
+
+@compute @workgroup_size(256, 1, 1)
+fn emit(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let node_count = 2u * params.count - 1u;
+    let node = global_id.x;
+    if node >= node_count {
+        return;
+    }
+
+    // after a subtree comes the right sibling of its closest ancestor that is a left child
+    var exit_index = node_count;
+    var child = node;
+    var parent = tree[node].parent;
+    while parent != NONE {
+        if tree[parent].left == child {
+            exit_index = tree[parent].right;
+            break;
+        }
+        child = parent;
+        parent = tree[parent].parent;
+    }
+
+    var flat: WorldBvhNode;
+    flat.aabb_min = vec3<f32>(
+        unorder_float(atomicLoad(&bounds[node * 6u])),
+        unorder_float(atomicLoad(&bounds[node * 6u + 1u])),
+        unorder_float(atomicLoad(&bounds[node * 6u + 2u])),
+    );
+    flat.aabb_max = vec3<f32>(
+        unorder_float(atomicLoad(&bounds[node * 6u + 3u])),
+        unorder_float(atomicLoad(&bounds[node * 6u + 4u])),
+        unorder_float(atomicLoad(&bounds[node * 6u + 5u])),
+    );
+    flat.exit_index = exit_index;
+    if node >= params.count - 1u {
+        flat.entry_index = NONE;
+        flat.shape_index = values_in[node - (params.count - 1u)];
+    } else {
+        flat.entry_index = tree[node].left;
+        flat.shape_index = 0u;
+    }
+    nodes.data[params.node_offset + node] = flat;
+}
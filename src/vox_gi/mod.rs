@@ -1,4 +1,9 @@
+pub mod cone;
+pub mod export;
+pub mod lbvh;
 pub mod node;
 pub mod pipeline;
 pub mod plugin;
+pub mod reference;
 pub mod resources;
+pub mod sky;
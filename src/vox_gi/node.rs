@@ -1,91 +1,708 @@
 use bevy::{
-    ecs::query::QueryItem,
+    core_pipeline::{blit::BlitPipeline, prepass::ViewPrepassTextures},
     prelude::*,
     render::{
-        camera::ExtractedCamera,
-        render_graph::{NodeRunError, RenderGraphContext, ViewNode},
-        render_resource::{ComputePassDescriptor, PipelineCache},
+        render_asset::RenderAssets,
+        render_graph::{Node, NodeRunError, RenderGraphContext, SlotInfo, SlotType},
+        render_resource::{
+            BindGroupDescriptor, BindGroupEntry, BindingResource, ComputePassDescriptor,
+            Operations, PipelineCache, RenderPassColorAttachment, RenderPassDescriptor,
+        },
         renderer::RenderContext,
-        view::{ViewTarget, ViewUniformOffset, ViewUniforms},
+        view::{ExtractedView, ViewTarget, ViewUniformOffset, ViewUniforms},
     },
 };
 
 use super::{
-    pipeline::{VoxelGI, VoxelGIPipeline, VoxelGIPipelineId},
-    resources::{create_scene_bind_group, create_view_bind_group},
+    export::{VoxelGIAccumulatePipeline, VoxelGIAccumulation, VoxelGIAccumulations},
+    pipeline::{
+        VoxelGI, VoxelGIDebugView, VoxelGIDenoisePipeline, VoxelGIMode, VoxelGIPipeline,
+        VoxelGIPipelineId, VoxelGIProbes, VoxelGIScene, VoxelGISettings, VoxelGIShadowMask,
+        VoxelGITextures, VoxelGIViewUniformOffset, VoxelGIViewUniforms, PROBE_GRID_SIZE,
+    },
+    resources::{
+        create_accumulate_bind_group, create_atrous_bind_group, create_guide_bind_group,
+        create_prepass_bind_group, create_probe_bind_group, create_shadow_bind_group,
+        create_temporal_bind_group, create_upsample_bind_group, create_view_bind_group,
+    },
 };
 
-pub struct VoxelGINode(
-    QueryState<(
-        &'static VoxelGI,
-        &'static VoxelGIPipelineId,
-        &'static ViewTarget,
-        &'static ViewUniformOffset,
-        &'static ExtractedCamera,
-    )>,
+fn workgroups(size: UVec2) -> (u32, u32) {
+    (size.x.div_ceil(8), size.y.div_ceil(8))
+}
+
+type VoxelGINodeQuery = (
+    &'static VoxelGI,
+    &'static VoxelGIPipelineId,
+    &'static VoxelGITextures,
+    &'static ViewUniformOffset,
+    &'static VoxelGIViewUniformOffset,
+    Option<&'static ViewPrepassTextures>,
 );
 
-impl ViewNode for VoxelGINode {
-    type ViewQuery = (
-        &'static VoxelGI,
-        &'static VoxelGIPipelineId,
-        &'static ViewTarget,
-        &'static ViewUniformOffset,
-        &'static ExtractedCamera,
-    );
+/// Traces one sample per pixel into the GI textures, and the primary hits at full resolution when
+/// the GI gets upsampled. Runs in the [`VOXEL_GI_GRAPH`](super::plugin::VOXEL_GI_GRAPH) and, for
+/// [`VoxelGIMode::Hybrid`], after the main pass of the core 3d graph.
+pub struct VoxelGINode {
+    query: QueryState<VoxelGINodeQuery, With<ExtractedView>>,
+}
+
+impl VoxelGINode {
+    pub const IN_VIEW: &'static str = "view";
+
+    pub fn new(world: &mut World) -> Self {
+        Self {
+            query: QueryState::new(world),
+        }
+    }
+}
+
+impl Node for VoxelGINode {
+    fn input(&self) -> Vec<SlotInfo> {
+        vec![SlotInfo::new(Self::IN_VIEW, SlotType::Entity)]
+    }
+
+    fn update(&mut self, world: &mut World) {
+        self.query.update_archetypes(world);
+    }
 
     fn run(
         &self,
-        _graph: &mut RenderGraphContext,
+        graph: &mut RenderGraphContext,
         render_context: &mut RenderContext,
-        (voxel_gi, pipeline_id, view_target, view_uniform_offset, camera): QueryItem<
-            Self::ViewQuery,
-        >,
         world: &World,
     ) -> Result<(), NodeRunError> {
+        let view_entity = graph.get_input_entity(Self::IN_VIEW)?;
+        let Ok((
+            voxel_gi,
+            pipeline_id,
+            textures,
+            view_uniform_offset,
+            gi_view_uniform_offset,
+            prepass_textures,
+        )) = self.query.get_manual(world, view_entity)
+        else {
+            return Ok(());
+        };
         let (
             Some(pipeline_cache),
             Some(view_uniforms),
+            Some(gi_view_uniforms),
             Some(voxel_gi_pipeline),
+            Some(scene),
         ) = (
             world.get_resource::<PipelineCache>(),
             world.get_resource::<ViewUniforms>(),
+            world.get_resource::<VoxelGIViewUniforms>(),
             world.get_resource::<VoxelGIPipeline>(),
+            world.get_resource::<VoxelGIScene>(),
+        )
+        else {
+            return Ok(());
+        };
+        let Some(pipeline) = pipeline_cache.get_compute_pipeline(pipeline_id.compute) else {
+            return Ok(());
+        };
+        let Some(scene_bind_group) = &scene.bind_group else {
+            return Ok(());
+        };
+        let Some(view_bind_group) = create_view_bind_group(
+            view_uniforms,
+            gi_view_uniforms,
+            textures,
+            gi_view_uniform_offset.frame,
+            voxel_gi_pipeline,
+            render_context.render_device(),
         ) else {
             return Ok(());
         };
-        let (Some(pipeline), Some(viewport)) = (pipeline_cache.get_compute_pipeline(pipeline_id.0), camera.physical_viewport_size) else {
+        let prepass_bind_group = match voxel_gi.mode {
+            VoxelGIMode::Hybrid => {
+                let Some(bind_group) = prepass_textures.and_then(|prepass_textures| {
+                    create_prepass_bind_group(
+                        prepass_textures,
+                        voxel_gi_pipeline,
+                        render_context.render_device(),
+                    )
+                }) else {
+                    return Ok(());
+                };
+                Some(bind_group)
+            }
+            _ => None,
+        };
+        // primary hits at full resolution, to upsample the GI with
+        let guide = pipeline_id
+            .guide
+            .and_then(|guide| pipeline_cache.get_compute_pipeline(guide))
+            .zip(textures.upsample.as_ref())
+            .and_then(|(guide, upsample)| {
+                let bind_group = create_guide_bind_group(
+                    view_uniforms,
+                    gi_view_uniforms,
+                    upsample,
+                    voxel_gi_pipeline,
+                    render_context.render_device(),
+                )?;
+                Some((guide, bind_group, upsample))
+            });
+
+        let command_encoder = render_context.command_encoder();
+        let mut gi_pass = command_encoder.begin_compute_pass(&ComputePassDescriptor {
+            label: Some("voxel_gi_pass"),
+        });
+
+        gi_pass.set_pipeline(pipeline);
+        gi_pass.set_bind_group(0, scene_bind_group, &[]);
+        if let Some(prepass_bind_group) = &prepass_bind_group {
+            gi_pass.set_bind_group(2, prepass_bind_group, &[]);
+        }
+        gi_pass.set_bind_group(
+            1,
+            &view_bind_group,
+            &[view_uniform_offset.offset, gi_view_uniform_offset.offset],
+        );
+        let (x, y) = workgroups(textures.size);
+        gi_pass.dispatch_workgroups(x, y, 1);
+
+        if let Some((guide, guide_bind_group, upsample)) = &guide {
+            gi_pass.set_pipeline(guide);
+            gi_pass.set_bind_group(
+                1,
+                guide_bind_group,
+                &[view_uniform_offset.offset, gi_view_uniform_offset.offset],
+            );
+            let (x, y) = workgroups(upsample.size);
+            gi_pass.dispatch_workgroups(x, y, 1);
+        }
+
+        Ok(())
+    }
+}
+
+type VoxelGITemporalNodeQuery = (
+    &'static VoxelGITextures,
+    &'static VoxelGIViewUniformOffset,
+    Option<&'static VoxelGISettings>,
+    Option<&'static VoxelGIAccumulation>,
+);
+
+/// Blends the new sample into the reprojected history of the previous frames, or adds it to the
+/// running sum of a [`VoxelGIProgressive`](super::export::VoxelGIProgressive) camera.
+pub struct VoxelGITemporalNode {
+    query: QueryState<VoxelGITemporalNodeQuery, With<ExtractedView>>,
+}
+
+impl VoxelGITemporalNode {
+    pub const IN_VIEW: &'static str = "view";
+
+    pub fn new(world: &mut World) -> Self {
+        Self {
+            query: QueryState::new(world),
+        }
+    }
+}
+
+impl Node for VoxelGITemporalNode {
+    fn input(&self) -> Vec<SlotInfo> {
+        vec![SlotInfo::new(Self::IN_VIEW, SlotType::Entity)]
+    }
+
+    fn update(&mut self, world: &mut World) {
+        self.query.update_archetypes(world);
+    }
+
+    fn run(
+        &self,
+        graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let view_entity = graph.get_input_entity(Self::IN_VIEW)?;
+        let Ok((textures, gi_view_uniform_offset, settings, accumulation)) =
+            self.query.get_manual(world, view_entity)
+        else {
             return Ok(());
         };
-        let Some(scene_bind_group) = create_scene_bind_group(render_context.render_device(), voxel_gi_pipeline, voxel_gi) else {
+        if settings.is_some_and(|settings| settings.debug_view != VoxelGIDebugView::Off) {
+            return Ok(());
+        }
+        if let Some(accumulation) = accumulation {
+            return run_accumulation(render_context, world, textures, accumulation);
+        }
+        let (Some(pipeline_cache), Some(gi_view_uniforms), Some(denoise_pipeline)) = (
+            world.get_resource::<PipelineCache>(),
+            world.get_resource::<VoxelGIViewUniforms>(),
+            world.get_resource::<VoxelGIDenoisePipeline>(),
+        ) else {
+            return Ok(());
+        };
+        let Some(pipeline) = pipeline_cache.get_compute_pipeline(denoise_pipeline.temporal) else {
             return Ok(());
         };
-        let Some(view_bind_group) = create_view_bind_group(view_uniforms,  view_target, voxel_gi_pipeline, render_context.render_device()) else {
+        let Some(bind_group) = create_temporal_bind_group(
+            render_context.render_device(),
+            denoise_pipeline,
+            gi_view_uniforms,
+            textures,
+            gi_view_uniform_offset.frame,
+        ) else {
             return Ok(());
         };
 
-        {
+        let command_encoder = render_context.command_encoder();
+        let mut temporal_pass = command_encoder.begin_compute_pass(&ComputePassDescriptor {
+            label: Some("voxel_gi_temporal_pass"),
+        });
+
+        temporal_pass.set_pipeline(pipeline);
+        temporal_pass.set_bind_group(0, &bind_group, &[gi_view_uniform_offset.offset]);
+        let (x, y) = workgroups(textures.size);
+        temporal_pass.dispatch_workgroups(x, y, 1);
+
+        Ok(())
+    }
+}
+
+/// Adds the frame to the sum of a progressive camera, and copies the sum out once it's complete.
+fn run_accumulation(
+    render_context: &mut RenderContext,
+    world: &World,
+    textures: &VoxelGITextures,
+    accumulation: &VoxelGIAccumulation,
+) -> Result<(), NodeRunError> {
+    if !accumulation.accumulate {
+        return Ok(());
+    }
+    let (Some(pipeline_cache), Some(accumulations), Some(accumulate_pipeline)) = (
+        world.get_resource::<PipelineCache>(),
+        world.get_resource::<VoxelGIAccumulations>(),
+        world.get_resource::<VoxelGIAccumulatePipeline>(),
+    ) else {
+        return Ok(());
+    };
+    let Some(pipeline) = pipeline_cache.get_compute_pipeline(accumulate_pipeline.accumulate) else {
+        return Ok(());
+    };
+    let Some(bind_group) = create_accumulate_bind_group(
+        render_context.render_device(),
+        accumulate_pipeline,
+        accumulations,
+        textures,
+        accumulation,
+    ) else {
+        return Ok(());
+    };
+
+    let command_encoder = render_context.command_encoder();
+    {
+        let mut accumulate_pass = command_encoder.begin_compute_pass(&ComputePassDescriptor {
+            label: Some("voxel_gi_accumulate_pass"),
+        });
+        accumulate_pass.set_pipeline(pipeline);
+        accumulate_pass.set_bind_group(0, &bind_group, &[accumulation.offset]);
+        let (x, y) = workgroups(textures.size);
+        accumulate_pass.dispatch_workgroups(x, y, 1);
+    }
+    if accumulation.read_back {
+        accumulation.copy_to_readback(command_encoder);
+    }
+
+    Ok(())
+}
+
+type VoxelGIDenoiseNodeQuery = (
+    &'static VoxelGIPipelineId,
+    &'static VoxelGITextures,
+    &'static VoxelGIViewUniformOffset,
+    &'static ViewTarget,
+    Option<&'static VoxelGISettings>,
+    Option<&'static VoxelGIAccumulation>,
+);
+
+/// Filters the accumulated GI with an edge-aware à-trous wavelet, upsamples it to the view resolution
+/// if needed and copies it into the view target. Progressive cameras show their average instead.
+pub struct VoxelGIDenoiseNode {
+    query: QueryState<VoxelGIDenoiseNodeQuery, With<ExtractedView>>,
+}
+
+impl VoxelGIDenoiseNode {
+    pub const IN_VIEW: &'static str = "view";
+
+    pub fn new(world: &mut World) -> Self {
+        Self {
+            query: QueryState::new(world),
+        }
+    }
+}
+
+impl Node for VoxelGIDenoiseNode {
+    fn input(&self) -> Vec<SlotInfo> {
+        vec![SlotInfo::new(Self::IN_VIEW, SlotType::Entity)]
+    }
+
+    fn update(&mut self, world: &mut World) {
+        self.query.update_archetypes(world);
+    }
+
+    fn run(
+        &self,
+        graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let view_entity = graph.get_input_entity(Self::IN_VIEW)?;
+        let Ok((
+            pipeline_id,
+            textures,
+            gi_view_uniform_offset,
+            view_target,
+            settings,
+            accumulation,
+        )) = self.query.get_manual(world, view_entity)
+        else {
+            return Ok(());
+        };
+        let (Some(pipeline_cache), Some(denoise_pipeline), Some(blit_pipeline)) = (
+            world.get_resource::<PipelineCache>(),
+            world.get_resource::<VoxelGIDenoisePipeline>(),
+            world.get_resource::<BlitPipeline>(),
+        ) else {
+            return Ok(());
+        };
+        let (Some(atrous), Some(blit)) = (
+            pipeline_cache.get_compute_pipeline(denoise_pipeline.atrous),
+            pipeline_cache.get_render_pipeline(pipeline_id.blit),
+        ) else {
+            return Ok(());
+        };
+
+        // debug views are written straight into the radiance texture by the GI pass
+        let debug_view =
+            settings.is_some_and(|settings| settings.debug_view != VoxelGIDebugView::Off);
+        let upsampled = textures
+            .upsample
+            .as_ref()
+            .filter(|_| !debug_view && accumulation.is_none());
+        let atrous_steps = if debug_view || accumulation.is_some() {
+            &[][..]
+        } else if upsampled.is_some() {
+            // the albedo goes back on at full resolution
+            &denoise_pipeline.atrous_lighting_step_offsets[..]
+        } else {
+            &denoise_pipeline.atrous_step_offsets[..]
+        };
+        let output = if debug_view {
+            &textures.radiance.default_view
+        } else if let Some(accumulation) = accumulation {
+            &accumulation.textures.average
+        } else if let Some(upsampled) = upsampled {
+            &upsampled.output.default_view
+        } else {
+            &textures.output().default_view
+        };
+
+        let frame = gi_view_uniform_offset.frame;
+        let mut input = &VoxelGITextures::current(&textures.history, frame).default_view;
+        for (i, offset) in atrous_steps.iter().enumerate() {
+            let output = &textures.denoise[i % 2].default_view;
+            let Some(bind_group) = create_atrous_bind_group(
+                render_context.render_device(),
+                denoise_pipeline,
+                textures,
+                frame,
+                input,
+                output,
+            ) else {
+                return Ok(());
+            };
+
             let command_encoder = render_context.command_encoder();
-            let mut solari_pass = command_encoder.begin_compute_pass(&ComputePassDescriptor {
-                label: Some("voxel_gi_pass"),
+            let mut atrous_pass = command_encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: Some("voxel_gi_atrous_pass"),
             });
+            atrous_pass.set_pipeline(atrous);
+            atrous_pass.set_bind_group(0, &bind_group, &[*offset]);
+            let (x, y) = workgroups(textures.size);
+            atrous_pass.dispatch_workgroups(x, y, 1);
+            input = output;
+        }
+
+        if let Some(upsampled) = upsampled {
+            let Some(upsample) = pipeline_cache.get_compute_pipeline(denoise_pipeline.upsample)
+            else {
+                return Ok(());
+            };
+            let Some(bind_group) = create_upsample_bind_group(
+                render_context.render_device(),
+                denoise_pipeline,
+                textures,
+                frame,
+            ) else {
+                return Ok(());
+            };
 
-            solari_pass.set_pipeline(pipeline);
-            solari_pass.set_bind_group(0, &scene_bind_group, &[]);
-            solari_pass.set_bind_group(1, &view_bind_group, &[view_uniform_offset.offset]);
-            solari_pass.dispatch_workgroups((viewport.x + 7) / 8, (viewport.y + 7) / 8, 1);
+            let command_encoder = render_context.command_encoder();
+            let mut upsample_pass = command_encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: Some("voxel_gi_upsample_pass"),
+            });
+            upsample_pass.set_pipeline(upsample);
+            upsample_pass.set_bind_group(0, &bind_group, &[]);
+            let (x, y) = workgroups(upsampled.size);
+            upsample_pass.dispatch_workgroups(x, y, 1);
         }
 
+        let blit_bind_group =
+            render_context
+                .render_device()
+                .create_bind_group(&BindGroupDescriptor {
+                    label: Some("voxel_gi_blit_bind_group"),
+                    layout: &blit_pipeline.texture_bind_group,
+                    entries: &[
+                        BindGroupEntry {
+                            binding: 0,
+                            resource: BindingResource::TextureView(output),
+                        },
+                        BindGroupEntry {
+                            binding: 1,
+                            resource: BindingResource::Sampler(&blit_pipeline.sampler),
+                        },
+                    ],
+                });
+
+        // the view target can't be bound as a storage texture, so copy the result over
+        let mut blit_pass =
+            render_context
+                .command_encoder()
+                .begin_render_pass(&RenderPassDescriptor {
+                    label: Some("voxel_gi_blit_pass"),
+                    color_attachments: &[Some(RenderPassColorAttachment {
+                        view: view_target.main_texture(),
+                        resolve_target: None,
+                        ops: Operations::default(),
+                    })],
+                    depth_stencil_attachment: None,
+                });
+        blit_pass.set_pipeline(blit);
+        blit_pass.set_bind_group(0, &blit_bind_group, &[]);
+        blit_pass.draw(0..3, 0..1);
+
         Ok(())
     }
+}
+
+/// Probes refreshed per frame is the probe count divided by this, keep in sync with `voxel_gi.wgsl`.
+const PROBE_ROTATION: u32 = 8;
+
+/// Updates a rotating subset of the irradiance probes around the camera, runs before the main pass
+/// of the core 3d graph so the voxel materials see this frame's probes.
+pub struct VoxelGIProbeNode {
+    query: QueryState<
+        (
+            &'static VoxelGI,
+            &'static VoxelGIPipelineId,
+            &'static ViewUniformOffset,
+            &'static VoxelGIViewUniformOffset,
+        ),
+        With<ExtractedView>,
+    >,
+}
+
+impl VoxelGIProbeNode {
+    pub const IN_VIEW: &'static str = "view";
+
+    pub fn new(world: &mut World) -> Self {
+        Self {
+            query: QueryState::new(world),
+        }
+    }
+}
+
+impl Node for VoxelGIProbeNode {
+    fn input(&self) -> Vec<SlotInfo> {
+        vec![SlotInfo::new(Self::IN_VIEW, SlotType::Entity)]
+    }
 
     fn update(&mut self, world: &mut World) {
-        self.0.update_archetypes(world);
+        self.query.update_archetypes(world);
+    }
+
+    fn run(
+        &self,
+        graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let view_entity = graph.get_input_entity(Self::IN_VIEW)?;
+        let Ok((voxel_gi, pipeline_id, view_uniform_offset, gi_view_uniform_offset)) =
+            self.query.get_manual(world, view_entity)
+        else {
+            return Ok(());
+        };
+        if voxel_gi.mode != VoxelGIMode::Probes {
+            return Ok(());
+        }
+        let (
+            Some(pipeline_cache),
+            Some(view_uniforms),
+            Some(gi_view_uniforms),
+            Some(voxel_gi_pipeline),
+            Some(probes),
+            Some(images),
+            Some(scene),
+        ) = (
+            world.get_resource::<PipelineCache>(),
+            world.get_resource::<ViewUniforms>(),
+            world.get_resource::<VoxelGIViewUniforms>(),
+            world.get_resource::<VoxelGIPipeline>(),
+            world.get_resource::<VoxelGIProbes>(),
+            world.get_resource::<RenderAssets<Image>>(),
+            world.get_resource::<VoxelGIScene>(),
+        )
+        else {
+            return Ok(());
+        };
+        let (Some(pipeline), Some(irradiance)) = (
+            pipeline_cache.get_compute_pipeline(pipeline_id.compute),
+            images.get(&probes.irradiance),
+        ) else {
+            return Ok(());
+        };
+        let Some(scene_bind_group) = &scene.bind_group else {
+            return Ok(());
+        };
+        let Some(probe_bind_group) = create_probe_bind_group(
+            view_uniforms,
+            gi_view_uniforms,
+            &irradiance.texture_view,
+            voxel_gi_pipeline,
+            render_context.render_device(),
+        ) else {
+            return Ok(());
+        };
+
+        let command_encoder = render_context.command_encoder();
+        let mut probe_pass = command_encoder.begin_compute_pass(&ComputePassDescriptor {
+            label: Some("voxel_gi_probe_pass"),
+        });
+
+        probe_pass.set_pipeline(pipeline);
+        probe_pass.set_bind_group(0, scene_bind_group, &[]);
+        probe_pass.set_bind_group(
+            1,
+            &probe_bind_group,
+            &[view_uniform_offset.offset, gi_view_uniform_offset.offset],
+        );
+        let probes_per_frame =
+            PROBE_GRID_SIZE.x * PROBE_GRID_SIZE.y * PROBE_GRID_SIZE.z / PROBE_ROTATION;
+        probe_pass.dispatch_workgroups(probes_per_frame.div_ceil(64), 1, 1);
+
+        Ok(())
     }
 }
 
-impl FromWorld for VoxelGINode {
-    fn from_world(world: &mut World) -> Self {
-        Self(QueryState::new(world))
+/// Traces the sun shadows and ambient occlusion of [`VoxelGIMode::Shadows`] cameras into the
+/// [`VoxelGIShadowMask`]. Runs before the main pass of the core 3d graph, which reads the mask.
+pub struct VoxelGIShadowNode {
+    query: QueryState<
+        (
+            &'static VoxelGI,
+            &'static VoxelGIPipelineId,
+            &'static ViewUniformOffset,
+            &'static VoxelGIViewUniformOffset,
+        ),
+        With<ExtractedView>,
+    >,
+}
+
+impl VoxelGIShadowNode {
+    pub const IN_VIEW: &'static str = "view";
+
+    pub fn new(world: &mut World) -> Self {
+        Self {
+            query: QueryState::new(world),
+        }
+    }
+}
+
+impl Node for VoxelGIShadowNode {
+    fn input(&self) -> Vec<SlotInfo> {
+        vec![SlotInfo::new(Self::IN_VIEW, SlotType::Entity)]
+    }
+
+    fn update(&mut self, world: &mut World) {
+        self.query.update_archetypes(world);
+    }
+
+    fn run(
+        &self,
+        graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let view_entity = graph.get_input_entity(Self::IN_VIEW)?;
+        let Ok((voxel_gi, pipeline_id, view_uniform_offset, gi_view_uniform_offset)) =
+            self.query.get_manual(world, view_entity)
+        else {
+            return Ok(());
+        };
+        if voxel_gi.mode != VoxelGIMode::Shadows {
+            return Ok(());
+        }
+        let (
+            Some(pipeline_cache),
+            Some(view_uniforms),
+            Some(gi_view_uniforms),
+            Some(voxel_gi_pipeline),
+            Some(shadow_mask),
+            Some(images),
+            Some(scene),
+        ) = (
+            world.get_resource::<PipelineCache>(),
+            world.get_resource::<ViewUniforms>(),
+            world.get_resource::<VoxelGIViewUniforms>(),
+            world.get_resource::<VoxelGIPipeline>(),
+            world.get_resource::<VoxelGIShadowMask>(),
+            world.get_resource::<RenderAssets<Image>>(),
+            world.get_resource::<VoxelGIScene>(),
+        )
+        else {
+            return Ok(());
+        };
+        let (Some(pipeline), Some(mask)) = (
+            pipeline_cache.get_compute_pipeline(pipeline_id.compute),
+            images.get(&shadow_mask.mask),
+        ) else {
+            return Ok(());
+        };
+        let Some(scene_bind_group) = &scene.bind_group else {
+            return Ok(());
+        };
+        let Some(shadow_bind_group) = create_shadow_bind_group(
+            view_uniforms,
+            gi_view_uniforms,
+            &mask.texture_view,
+            voxel_gi_pipeline,
+            render_context.render_device(),
+        ) else {
+            return Ok(());
+        };
+
+        let command_encoder = render_context.command_encoder();
+        let mut shadow_pass = command_encoder.begin_compute_pass(&ComputePassDescriptor {
+            label: Some("voxel_gi_shadow_pass"),
+        });
+
+        shadow_pass.set_pipeline(pipeline);
+        shadow_pass.set_bind_group(0, scene_bind_group, &[]);
+        shadow_pass.set_bind_group(
+            1,
+            &shadow_bind_group,
+            &[view_uniform_offset.offset, gi_view_uniform_offset.offset],
+        );
+        let (x, y) = workgroups(mask.size.as_uvec2());
+        shadow_pass.dispatch_workgroups(x, y, 1);
+
+        Ok(())
     }
 }
@@ -1,90 +1,851 @@
 use std::sync::Arc;
 
 use bevy::{
-    core_pipeline::tonemapping::{DebandDither, Tonemapping},
+    core_pipeline::{
+        blit::{BlitPipeline, BlitPipelineKey},
+        prepass::{DepthPrepass, NormalPrepass},
+        tonemapping::{DebandDither, Tonemapping},
+    },
+    math::Vec4Swizzles,
     prelude::*,
     render::{
-        camera::CameraRenderGraph,
+        camera::{CameraRenderGraph, ExtractedCamera},
         extract_component::ExtractComponent,
+        extract_resource::ExtractResource,
         primitives::Frustum,
         render_resource::*,
-        renderer::RenderDevice,
-        view::{ColorGrading, VisibleEntities},
+        renderer::{RenderDevice, RenderQueue},
+        texture::{BevyDefault, CachedTexture, TextureCache},
+        view::{ColorGrading, ExtractedView, ViewTarget, VisibleEntities},
+        Extract,
     },
+    utils::HashMap,
 };
 
 use super::{
-    plugin::{GpuBVH, GpuShapes, VOXEL_GI_GRAPH, VOXEL_GI_SHADER},
-    resources::{create_scene_bind_group_layout, create_view_bind_group_layout},
+    export::VoxelGIProgressive,
+    plugin::{
+        GpuBVH, GpuInstances, GpuLights, GpuShapes, GpuVoxelMaterials, VOXEL_GI_ATROUS_SHADER,
+        VOXEL_GI_GRAPH, VOXEL_GI_SHADER, VOXEL_GI_TEMPORAL_SHADER, VOXEL_GI_UPSAMPLE_SHADER,
+    },
+    resources::{
+        create_atrous_bind_group_layout, create_guide_bind_group_layout,
+        create_prepass_bind_group_layout, create_probe_bind_group_layout, create_scene_bind_group,
+        create_scene_bind_group_layout, create_shadow_bind_group_layout,
+        create_temporal_bind_group_layout, create_upsample_bind_group_layout,
+        create_view_bind_group_layout,
+    },
+    sky::{ExtractedVoxelGISky, PreethamSky, VoxelGISky, VoxelGISkyModel, VoxelGISkyTexture},
 };
 
+/// Format of every intermediate GI texture, radiance as well as normals, motion and moments.
+pub const VOXEL_GI_TEXTURE_FORMAT: TextureFormat = TextureFormat::Rgba16Float;
+/// Number of à-trous iterations, the filter footprint doubles with each one.
+pub const ATROUS_ITERATIONS: u32 = 4;
+/// Camera moves further than this in a single frame are treated as cuts and drop the history.
+pub const CAMERA_CUT_DISTANCE: f32 = 10.0;
+
+/// Probes in the irradiance grid around the camera, keep in sync with the shaders.
+pub const PROBE_GRID_SIZE: UVec3 = UVec3::new(16, 8, 16);
+/// World space distance between neighbouring probes.
+pub const PROBE_SPACING: f32 = 2.0;
+/// Bytes per probe in the probe buffer, 4 spherical harmonics coefficients and the grid cell.
+const PROBE_STRIDE: u64 = 80;
+
 #[derive(Resource)]
 pub struct VoxelGIPipeline {
     pub view_bind_group_layout: BindGroupLayout,
     pub scene_bind_group_layout: BindGroupLayout,
+    pub guide_bind_group_layout: BindGroupLayout,
+    pub probe_bind_group_layout: BindGroupLayout,
+    pub prepass_bind_group_layout: BindGroupLayout,
+    pub shadow_bind_group_layout: BindGroupLayout,
+    /// Accumulated spherical harmonics of every probe, copied into [`VoxelGIProbes::irradiance`]
+    pub probes: Buffer,
 }
 
 impl FromWorld for VoxelGIPipeline {
     fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let probe_count = (PROBE_GRID_SIZE.x * PROBE_GRID_SIZE.y * PROBE_GRID_SIZE.z) as u64;
         Self {
-            view_bind_group_layout: create_view_bind_group_layout(world.resource::<RenderDevice>()),
-            scene_bind_group_layout: create_scene_bind_group_layout(
-                world.resource::<RenderDevice>(),
-            ),
+            view_bind_group_layout: create_view_bind_group_layout(render_device),
+            scene_bind_group_layout: create_scene_bind_group_layout(render_device),
+            guide_bind_group_layout: create_guide_bind_group_layout(render_device),
+            probe_bind_group_layout: create_probe_bind_group_layout(render_device),
+            prepass_bind_group_layout: create_prepass_bind_group_layout(render_device),
+            shadow_bind_group_layout: create_shadow_bind_group_layout(render_device),
+            probes: render_device.create_buffer(&BufferDescriptor {
+                label: Some("voxel_gi_probes"),
+                size: probe_count * PROBE_STRIDE,
+                usage: BufferUsages::STORAGE,
+                mapped_at_creation: false,
+            }),
+        }
+    }
+}
+
+/// How a [`VoxelGI`] camera computes indirect light.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, Default)]
+pub enum VoxelGIMode {
+    /// Path trace every pixel with the [`VOXEL_GI_GRAPH`], denoised over time
+    #[default]
+    PathTraced,
+    /// Keep a grid of irradiance probes around the camera up to date and let the voxel materials
+    /// sample them. Much cheaper, use it with a regular `Camera3dBundle` plus [`VoxelGI`].
+    Probes,
+    /// Trace indirect light from the surfaces of the depth and normal prepass of the core 3d graph
+    /// and add it to the lit image after the main pass, so regular meshes and voxels both receive
+    /// it. Use it with a regular `Camera3dBundle` plus [`VoxelGIHybridBundle`] and `Msaa::Off`.
+    Hybrid,
+    /// Only trace sun shadows and short range ambient occlusion into the [`VoxelGIShadowMask`],
+    /// which the voxel materials darken themselves with. Use it with a regular `Camera3dBundle`
+    /// plus [`VoxelGI`].
+    Shadows,
+}
+
+/// Quality settings of a [`VoxelGI`] camera, cameras without one use [`VoxelGISettings::MEDIUM`].
+/// Changing `bounces`, `samples_per_pixel` or `debug_view` compiles a new pipeline, the rest can be
+/// tuned freely at runtime.
+#[derive(Component, ExtractComponent, Reflect, Clone, Debug)]
+#[reflect(Component)]
+pub struct VoxelGISettings {
+    /// Diffuse bounces after the primary hit, 0 only leaves direct light and emission
+    pub bounces: u32,
+    /// Paths traced per pixel and frame
+    pub samples_per_pixel: u32,
+    /// Rays that travel further than this, in world units, count as a miss and see the sky
+    pub max_distance: f32,
+    /// Resolution the GI is traced and denoised at, anything below full resolution is upsampled
+    /// guided by the normals and depth of the full resolution image
+    pub resolution: VoxelGIResolution,
+    /// Multiplier on the light coming from the sky
+    pub sky_intensity: f32,
+    pub debug_view: VoxelGIDebugView,
+}
+
+impl VoxelGISettings {
+    pub const LOW: Self = Self {
+        bounces: 1,
+        samples_per_pixel: 1,
+        max_distance: 64.0,
+        resolution: VoxelGIResolution::Half,
+        sky_intensity: 1.0,
+        debug_view: VoxelGIDebugView::Off,
+    };
+    pub const MEDIUM: Self = Self {
+        bounces: 2,
+        samples_per_pixel: 1,
+        max_distance: 128.0,
+        resolution: VoxelGIResolution::Full,
+        sky_intensity: 1.0,
+        debug_view: VoxelGIDebugView::Off,
+    };
+    pub const HIGH: Self = Self {
+        bounces: 4,
+        samples_per_pixel: 4,
+        max_distance: 512.0,
+        resolution: VoxelGIResolution::Full,
+        sky_intensity: 1.0,
+        debug_view: VoxelGIDebugView::Off,
+    };
+
+    /// Size of the GI textures for a view of `viewport` pixels.
+    pub fn texture_size(&self, viewport: UVec2) -> UVec2 {
+        let divisor = self.resolution.divisor();
+        (viewport + divisor - 1) / divisor
+    }
+}
+
+impl Default for VoxelGISettings {
+    fn default() -> Self {
+        Self::MEDIUM
+    }
+}
+
+#[derive(Reflect, FromReflect, Debug, PartialEq, Eq, Hash, Clone, Copy, Default)]
+pub enum VoxelGIResolution {
+    #[default]
+    Full,
+    Half,
+    Quarter,
+}
+
+impl VoxelGIResolution {
+    pub fn divisor(self) -> u32 {
+        match self {
+            VoxelGIResolution::Full => 1,
+            VoxelGIResolution::Half => 2,
+            VoxelGIResolution::Quarter => 4,
+        }
+    }
+}
+
+/// Shows one of the inputs of the denoiser or a view of the acceleration structure instead of the
+/// final image, the denoiser is skipped.
+#[derive(Reflect, FromReflect, Debug, PartialEq, Eq, Hash, Clone, Copy, Default)]
+pub enum VoxelGIDebugView {
+    #[default]
+    Off,
+    /// Palette colour of the primary hit
+    Albedo,
+    /// World space normals of the primary hit
+    Normals,
+    /// Hit distance relative to [`VoxelGISettings::max_distance`]
+    Depth,
+    /// A single frame of the path tracer, before any denoising
+    Noisy,
+    /// Edges of the BVH nodes the primary ray passes through, coloured by their depth in the tree,
+    /// the model BVHs continue below the instance leaves of the scene BVH
+    BvhNodes,
+    /// BVH nodes the primary ray visited, blue for few and red for many
+    TraversalHeatmap,
+    /// Share of filled voxels in the brick hit by the primary ray, with the brick outlines
+    BrickOccupancy,
+    /// BVH nodes and voxel steps of all rays traced for the pixel, bounces and shadow rays included
+    RayCost,
+}
+
+impl VoxelGIDebugView {
+    fn shader_def(self) -> Option<&'static str> {
+        match self {
+            VoxelGIDebugView::Off => None,
+            VoxelGIDebugView::Albedo => Some("VOXEL_GI_DEBUG_ALBEDO"),
+            VoxelGIDebugView::Normals => Some("VOXEL_GI_DEBUG_NORMALS"),
+            VoxelGIDebugView::Depth => Some("VOXEL_GI_DEBUG_DEPTH"),
+            VoxelGIDebugView::Noisy => Some("VOXEL_GI_DEBUG_NOISY"),
+            VoxelGIDebugView::BvhNodes => Some("VOXEL_GI_DEBUG_BVH"),
+            VoxelGIDebugView::TraversalHeatmap => Some("VOXEL_GI_DEBUG_HEATMAP"),
+            VoxelGIDebugView::BrickOccupancy => Some("VOXEL_GI_DEBUG_OCCUPANCY"),
+            VoxelGIDebugView::RayCost => Some("VOXEL_GI_DEBUG_RAY_COST"),
         }
     }
+
+    /// Views that look at the acceleration structure, the shader counts its work for them.
+    fn shows_structure(self) -> bool {
+        matches!(
+            self,
+            VoxelGIDebugView::BvhNodes
+                | VoxelGIDebugView::TraversalHeatmap
+                | VoxelGIDebugView::BrickOccupancy
+                | VoxelGIDebugView::RayCost
+        )
+    }
 }
 
 #[derive(PartialEq, Eq, Hash, Clone, Copy)]
-pub struct VoxelGIPipelineKey {}
+pub struct VoxelGIPipelineKey {
+    pub mode: VoxelGIMode,
+    pub bounces: u32,
+    pub samples_per_pixel: u32,
+    pub debug_view: VoxelGIDebugView,
+    /// Only trace the primary hits at full resolution, to guide the upsampling
+    pub guide: bool,
+    /// Move the primary rays around inside their pixel, for [`VoxelGIProgressive`] cameras
+    pub jitter: bool,
+    pub sky: VoxelGISkyModel,
+}
 
 impl SpecializedComputePipeline for VoxelGIPipeline {
     type Key = VoxelGIPipelineKey;
 
-    fn specialize(&self, _key: Self::Key) -> ComputePipelineDescriptor {
+    fn specialize(&self, key: Self::Key) -> ComputePipelineDescriptor {
+        let mut shader_defs = vec![
+            ShaderDefVal::UInt("VOXEL_GI_BOUNCES".into(), key.bounces),
+            ShaderDefVal::UInt("VOXEL_GI_SAMPLES".into(), key.samples_per_pixel.max(1)),
+        ];
+        if let Some(debug_view) = key.debug_view.shader_def() {
+            shader_defs.push("VOXEL_GI_DEBUG_VIEW".into());
+            shader_defs.push(debug_view.into());
+        }
+        if key.debug_view.shows_structure() {
+            shader_defs.push("VOXEL_GI_DEBUG_STRUCTURE".into());
+        }
+        if let Some(sky) = key.sky.shader_def() {
+            shader_defs.push(sky.into());
+        }
+        if key.jitter {
+            shader_defs.push("VOXEL_GI_JITTER".into());
+        }
+        let mut layout = vec![self.scene_bind_group_layout.clone()];
+        let entry_point = match key.mode {
+            VoxelGIMode::Probes => {
+                shader_defs.push("VOXEL_GI_PROBES".into());
+                layout.push(self.probe_bind_group_layout.clone());
+                "update_probes"
+            }
+            VoxelGIMode::Shadows => {
+                shader_defs.push("VOXEL_GI_SHADOWS".into());
+                layout.push(self.shadow_bind_group_layout.clone());
+                "shadows"
+            }
+            _ if key.guide => {
+                shader_defs.push("VOXEL_GI_GUIDE".into());
+                layout.push(self.guide_bind_group_layout.clone());
+                "guide"
+            }
+            _ => {
+                layout.push(self.view_bind_group_layout.clone());
+                "main"
+            }
+        };
+        if key.mode == VoxelGIMode::Hybrid {
+            shader_defs.push("VOXEL_GI_HYBRID".into());
+            layout.push(self.prepass_bind_group_layout.clone());
+        }
         ComputePipelineDescriptor {
             label: Some("voxel_gi_pipeline".into()),
-            layout: vec![
-                self.scene_bind_group_layout.clone(),
-                self.view_bind_group_layout.clone(),
-            ],
+            layout,
             push_constant_ranges: vec![],
             shader: VOXEL_GI_SHADER.typed(),
-            shader_defs: vec![],
-            entry_point: "main".into(),
+            shader_defs,
+            entry_point: entry_point.into(),
+        }
+    }
+}
+
+/// Irradiance probe atlas shared with the voxel materials. Every probe takes 4 texels along x,
+/// one per L1 spherical harmonics coefficient, probe `(x, y, z)` of the grid starts at
+/// `(x * 4, y + z * PROBE_GRID_SIZE.y)`. Probes are addressed by world cell modulo the grid size,
+/// so the grid can follow the camera without moving any data.
+#[derive(Resource, ExtractResource, Clone)]
+pub struct VoxelGIProbes {
+    pub irradiance: Handle<Image>,
+}
+
+impl FromWorld for VoxelGIProbes {
+    fn from_world(world: &mut World) -> Self {
+        let mut image = Image::new_fill(
+            Extent3d {
+                width: PROBE_GRID_SIZE.x * 4,
+                height: PROBE_GRID_SIZE.y * PROBE_GRID_SIZE.z,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            &[0; 8],
+            TextureFormat::Rgba16Float,
+        );
+        image.texture_descriptor.usage |= TextureUsages::STORAGE_BINDING;
+
+        Self {
+            irradiance: world.resource_mut::<Assets<Image>>().add(image),
         }
     }
 }
 
 #[derive(Component)]
-pub struct VoxelGIPipelineId(pub CachedComputePipelineId);
+pub struct VoxelGIPipelineId {
+    pub compute: CachedComputePipelineId,
+    /// Primary hits at full resolution, when the GI runs at a lower one
+    pub guide: Option<CachedComputePipelineId>,
+    /// Copies the GI output into the view target, or adds it on top of the lit image when hybrid
+    pub blit: CachedRenderPipelineId,
+}
 
+type PipelineViewQuery = (
+    Entity,
+    &'static ExtractedView,
+    &'static VoxelGI,
+    Option<&'static VoxelGISettings>,
+    Option<&'static VoxelGIProgressive>,
+);
+
+#[allow(clippy::too_many_arguments)]
 pub fn prepare_pipelines(
-    views: Query<Entity, With<VoxelGI>>,
+    views: Query<PipelineViewQuery>,
     mut commands: Commands,
     pipeline_cache: Res<PipelineCache>,
     mut pipelines: ResMut<SpecializedComputePipelines<VoxelGIPipeline>>,
     pipeline: Res<VoxelGIPipeline>,
+    mut blit_pipelines: ResMut<SpecializedRenderPipelines<BlitPipeline>>,
+    blit_pipeline: Res<BlitPipeline>,
+    sky_texture: Res<VoxelGISkyTexture>,
+) {
+    for (entity, view, voxel_gi, settings, progressive) in &views {
+        let settings = settings.cloned().unwrap_or_default();
+        let key = VoxelGIPipelineKey {
+            mode: voxel_gi.mode,
+            bounces: settings.bounces,
+            samples_per_pixel: settings.samples_per_pixel,
+            debug_view: settings.debug_view,
+            guide: false,
+            jitter: progressive.is_some()
+                && voxel_gi.mode == VoxelGIMode::PathTraced
+                && settings.debug_view == VoxelGIDebugView::Off,
+            sky: sky_texture.model,
+        };
+        let compute = pipelines.specialize(&pipeline_cache, &pipeline, key);
+        let guide = (matches!(voxel_gi.mode, VoxelGIMode::PathTraced | VoxelGIMode::Hybrid)
+            && settings.resolution != VoxelGIResolution::Full)
+            .then(|| {
+                pipelines.specialize(
+                    &pipeline_cache,
+                    &pipeline,
+                    VoxelGIPipelineKey {
+                        guide: true,
+                        jitter: false,
+                        ..key
+                    },
+                )
+            });
+        let blit = blit_pipelines.specialize(
+            &pipeline_cache,
+            &blit_pipeline,
+            BlitPipelineKey {
+                texture_format: if view.hdr {
+                    ViewTarget::TEXTURE_FORMAT_HDR
+                } else {
+                    TextureFormat::bevy_default()
+                },
+                // debug views replace the image so they can be seen on their own
+                blend_state: (voxel_gi.mode == VoxelGIMode::Hybrid
+                    && settings.debug_view == VoxelGIDebugView::Off)
+                    .then_some(BlendState {
+                        color: BlendComponent {
+                            src_factor: BlendFactor::One,
+                            dst_factor: BlendFactor::One,
+                            operation: BlendOperation::Add,
+                        },
+                        alpha: BlendComponent::REPLACE,
+                    }),
+                samples: 1,
+            },
+        );
+
+        commands.entity(entity).insert(VoxelGIPipelineId {
+            compute,
+            guide,
+            blit,
+        });
+    }
+}
+
+/// Temporal and à-trous passes that turn the noisy GI output into a stable image.
+#[derive(Resource)]
+pub struct VoxelGIDenoisePipeline {
+    pub temporal_bind_group_layout: BindGroupLayout,
+    pub atrous_bind_group_layout: BindGroupLayout,
+    pub upsample_bind_group_layout: BindGroupLayout,
+    pub temporal: CachedComputePipelineId,
+    pub atrous: CachedComputePipelineId,
+    pub upsample: CachedComputePipelineId,
+    /// One entry per à-trous iteration, selected with a dynamic offset
+    pub atrous_steps: DynamicUniformBuffer<AtrousStep>,
+    pub atrous_step_offsets: Vec<u32>,
+    /// Same iterations, but the last one leaves the albedo to the upsampling
+    pub atrous_lighting_step_offsets: Vec<u32>,
+}
+
+#[derive(Clone, Copy, ShaderType)]
+pub struct AtrousStep {
+    /// Distance in pixels between the filter taps
+    step: u32,
+    /// Whether this is the last iteration, which puts the albedo back
+    last: u32,
+}
+
+impl FromWorld for VoxelGIDenoisePipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let temporal_bind_group_layout = create_temporal_bind_group_layout(render_device);
+        let atrous_bind_group_layout = create_atrous_bind_group_layout(render_device);
+        let upsample_bind_group_layout = create_upsample_bind_group_layout(render_device);
+
+        let mut atrous_steps = DynamicUniformBuffer::default();
+        let mut push_steps = |modulate: bool| -> Vec<u32> {
+            (0..ATROUS_ITERATIONS)
+                .map(|i| {
+                    atrous_steps.push(AtrousStep {
+                        step: 1 << i,
+                        last: (modulate && i + 1 == ATROUS_ITERATIONS) as u32,
+                    })
+                })
+                .collect()
+        };
+        let atrous_step_offsets = push_steps(true);
+        let atrous_lighting_step_offsets = push_steps(false);
+        atrous_steps.write_buffer(render_device, world.resource::<RenderQueue>());
+
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let temporal = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+            label: Some("voxel_gi_temporal_pipeline".into()),
+            layout: vec![temporal_bind_group_layout.clone()],
+            push_constant_ranges: vec![],
+            shader: VOXEL_GI_TEMPORAL_SHADER.typed(),
+            shader_defs: vec![],
+            entry_point: "temporal".into(),
+        });
+        let atrous = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+            label: Some("voxel_gi_atrous_pipeline".into()),
+            layout: vec![atrous_bind_group_layout.clone()],
+            push_constant_ranges: vec![],
+            shader: VOXEL_GI_ATROUS_SHADER.typed(),
+            shader_defs: vec![],
+            entry_point: "atrous".into(),
+        });
+        let upsample = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+            label: Some("voxel_gi_upsample_pipeline".into()),
+            layout: vec![upsample_bind_group_layout.clone()],
+            push_constant_ranges: vec![],
+            shader: VOXEL_GI_UPSAMPLE_SHADER.typed(),
+            shader_defs: vec![],
+            entry_point: "upsample".into(),
+        });
+
+        Self {
+            temporal_bind_group_layout,
+            atrous_bind_group_layout,
+            upsample_bind_group_layout,
+            temporal,
+            atrous,
+            upsample,
+            atrous_steps,
+            atrous_step_offsets,
+            atrous_lighting_step_offsets,
+        }
+    }
+}
+
+/// Per view textures of the GI passes. The pairs swap every frame,
+/// see [`VoxelGITextures::current`] and [`VoxelGITextures::previous`].
+#[derive(Component)]
+pub struct VoxelGITextures {
+    /// Size of every texture, see [`VoxelGISettings::texture_size`]
+    pub size: UVec2,
+    /// Noisy incoming light, without the surface albedo
+    pub radiance: CachedTexture,
+    /// Albedo and emission strength of the primary hit
+    pub albedo: CachedTexture,
+    /// World normal and hit distance, -1 where the sky was hit
+    pub normal_depth: [CachedTexture; 2],
+    /// Screen space motion and the hit distance as seen from the previous camera
+    pub motion: CachedTexture,
+    /// Accumulated radiance, the alpha channel holds the history length
+    pub history: [CachedTexture; 2],
+    /// Luminance moments and variance
+    pub moments: [CachedTexture; 2],
+    /// À-trous ping-pong targets
+    pub denoise: [CachedTexture; 2],
+    /// Set when the GI runs below the view resolution
+    pub upsample: Option<VoxelGIUpsampleTextures>,
+}
+
+/// Full resolution textures of a view whose GI is upsampled.
+pub struct VoxelGIUpsampleTextures {
+    pub size: UVec2,
+    /// Albedo and emission strength of the primary hit
+    pub albedo: CachedTexture,
+    /// World normal and hit distance of the primary hit
+    pub normal_depth: CachedTexture,
+    /// Upsampled lighting multiplied by the albedo
+    pub output: CachedTexture,
+}
+
+impl VoxelGITextures {
+    pub fn current(pair: &[CachedTexture; 2], frame: u32) -> &CachedTexture {
+        &pair[frame as usize % 2]
+    }
+
+    pub fn previous(pair: &[CachedTexture; 2], frame: u32) -> &CachedTexture {
+        &pair[(frame as usize + 1) % 2]
+    }
+
+    /// Where the last à-trous iteration writes to.
+    pub fn output(&self) -> &CachedTexture {
+        &self.denoise[(ATROUS_ITERATIONS as usize + 1) % 2]
+    }
+}
+
+pub fn prepare_textures(
+    views: Query<(Entity, &ExtractedCamera, &VoxelGI, Option<&VoxelGISettings>)>,
+    mut commands: Commands,
+    mut texture_cache: ResMut<TextureCache>,
+    render_device: Res<RenderDevice>,
 ) {
-    for entity in &views {
-        let pipeline_id = pipelines.specialize(&pipeline_cache, &pipeline, VoxelGIPipelineKey {});
+    for (entity, camera, voxel_gi, settings) in &views {
+        let Some(viewport) = camera.physical_viewport_size else {
+            continue;
+        };
+        if matches!(voxel_gi.mode, VoxelGIMode::Probes | VoxelGIMode::Shadows) {
+            continue;
+        }
+        let size = settings.cloned().unwrap_or_default().texture_size(viewport);
+
+        // every texture needs its own label, or the cache could hand out the pairs swapped
+        let mut texture = |label: &'static str, size: UVec2| {
+            texture_cache.get(
+                &render_device,
+                TextureDescriptor {
+                    label: Some(label),
+                    size: Extent3d {
+                        width: size.x,
+                        height: size.y,
+                        depth_or_array_layers: 1,
+                    },
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: TextureDimension::D2,
+                    format: VOXEL_GI_TEXTURE_FORMAT,
+                    usage: TextureUsages::STORAGE_BINDING | TextureUsages::TEXTURE_BINDING,
+                    view_formats: &[],
+                },
+            )
+        };
+
+        let textures = VoxelGITextures {
+            size,
+            radiance: texture("voxel_gi_radiance_texture", size),
+            albedo: texture("voxel_gi_albedo_texture", size),
+            normal_depth: [
+                texture("voxel_gi_normal_depth_texture_1", size),
+                texture("voxel_gi_normal_depth_texture_2", size),
+            ],
+            motion: texture("voxel_gi_motion_texture", size),
+            history: [
+                texture("voxel_gi_history_texture_1", size),
+                texture("voxel_gi_history_texture_2", size),
+            ],
+            moments: [
+                texture("voxel_gi_moments_texture_1", size),
+                texture("voxel_gi_moments_texture_2", size),
+            ],
+            denoise: [
+                texture("voxel_gi_denoise_texture_1", size),
+                texture("voxel_gi_denoise_texture_2", size),
+            ],
+            upsample: (size != viewport).then(|| VoxelGIUpsampleTextures {
+                size: viewport,
+                albedo: texture("voxel_gi_guide_albedo_texture", viewport),
+                normal_depth: texture("voxel_gi_guide_normal_depth_texture", viewport),
+                output: texture("voxel_gi_upsample_texture", viewport),
+            }),
+        };
+        commands.entity(entity).insert(textures);
+    }
+}
+
+/// What the GI passes need to know about the previous frame of a view.
+#[derive(Clone, ShaderType)]
+pub struct VoxelGIViewUniform {
+    previous_view_proj: Mat4,
+    previous_world_position: Vec3,
+    /// Increases every frame, seeds the random numbers and picks the current textures of each pair
+    frame: u32,
+    /// Set when the history should be thrown away, after a camera cut or resize
+    reset: u32,
+    /// See [`VoxelGISettings`]
+    max_distance: f32,
+    sky_intensity: f32,
+    /// Direction towards the sun, see [`VoxelGISun`]
+    sun_direction: Vec3,
+    /// Perez coefficients A to E of the [`VoxelGISky::Preetham`] sky, luminance and chromaticity
+    sky_coefficients: [Vec4; 5],
+    sky_zenith: Vec4,
+}
+
+#[derive(Clone)]
+struct PreviousView {
+    view_proj: Mat4,
+    world_position: Vec3,
+    size: UVec2,
+    frame: u32,
+}
+
+#[derive(Resource, Default)]
+pub struct VoxelGIViewUniforms {
+    pub uniforms: DynamicUniformBuffer<VoxelGIViewUniform>,
+    previous: HashMap<Entity, PreviousView>,
+}
+
+#[derive(Component)]
+pub struct VoxelGIViewUniformOffset {
+    pub offset: u32,
+    pub frame: u32,
+}
+
+/// Screen space mask of the [`VoxelGIMode::Shadows`] camera, sun visibility in the red channel
+/// and ambient occlusion in the green one. It's resized to the viewport of that camera and handed
+/// to every voxel material, only one shadow camera is supported at a time.
+#[derive(Resource, ExtractResource, Clone)]
+pub struct VoxelGIShadowMask {
+    pub mask: Handle<Image>,
+}
+
+impl FromWorld for VoxelGIShadowMask {
+    fn from_world(world: &mut World) -> Self {
+        let mut image = Image::new_fill(
+            Extent3d {
+                width: 1,
+                height: 1,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            &UNSHADOWED_TEXEL,
+            VOXEL_GI_TEXTURE_FORMAT,
+        );
+        image.texture_descriptor.usage |= TextureUsages::STORAGE_BINDING;
+
+        Self {
+            mask: world.resource_mut::<Assets<Image>>().add(image),
+        }
+    }
+}
+
+/// A fully lit and unoccluded texel of the [`VoxelGIShadowMask`], four little endian 1.0 halfs
+pub(crate) const UNSHADOWED_TEXEL: [u8; 8] = [0x00, 0x3c, 0x00, 0x3c, 0x00, 0x3c, 0x00, 0x3c];
 
+/// Direction towards the sun in the render world, taken from the first `DirectionalLight`.
+#[derive(Resource)]
+pub struct VoxelGISun {
+    pub direction: Vec3,
+}
+
+impl Default for VoxelGISun {
+    fn default() -> Self {
+        Self {
+            direction: Vec3::new(0.4, 0.8, 0.3).normalize(),
+        }
+    }
+}
+
+pub fn extract_sun(
+    mut sun: ResMut<VoxelGISun>,
+    lights: Extract<Query<&GlobalTransform, With<DirectionalLight>>>,
+) {
+    *sun = match lights.iter().next() {
+        // lights shine along their forward axis
+        Some(transform) => VoxelGISun {
+            direction: transform.back(),
+        },
+        None => VoxelGISun::default(),
+    };
+}
+
+/// Insert on a [`VoxelGI`] camera to drop the accumulated history when the camera jumps
+/// somewhere else. It's removed again at the start of the next frame.
+#[derive(Component, ExtractComponent, Clone, Copy, Default)]
+pub struct VoxelGICameraCut;
+
+pub fn clear_camera_cuts(mut commands: Commands, cuts: Query<Entity, With<VoxelGICameraCut>>) {
+    for entity in &cuts {
+        commands.entity(entity).remove::<VoxelGICameraCut>();
+    }
+}
+
+type UniformViewQuery = (
+    Entity,
+    &'static ExtractedView,
+    Option<&'static VoxelGISettings>,
+    Option<&'static VoxelGICameraCut>,
+);
+
+pub fn prepare_view_uniforms(
+    views: Query<UniformViewQuery, With<VoxelGI>>,
+    mut commands: Commands,
+    mut view_uniforms: ResMut<VoxelGIViewUniforms>,
+    sun: Res<VoxelGISun>,
+    sky: Res<ExtractedVoxelGISky>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    let view_uniforms = &mut *view_uniforms;
+    view_uniforms.uniforms.clear();
+    // views that are gone take their history with them
+    let mut previous = std::mem::take(&mut view_uniforms.previous);
+    let preetham = match sky.sky {
+        VoxelGISky::Preetham { turbidity } => PreethamSky::new(sun.direction, turbidity),
+        _ => PreethamSky::default(),
+    };
+
+    for (entity, view, settings, cut) in &views {
+        let settings = settings.cloned().unwrap_or_default();
+        let current = PreviousView {
+            view_proj: view.projection * view.transform.compute_matrix().inverse(),
+            world_position: view.transform.translation(),
+            size: settings.texture_size(view.viewport.zw()),
+            frame: 0,
+        };
+        let (last, reset) = match previous.remove(&entity) {
+            Some(last) => {
+                let reset = cut.is_some()
+                    || last.size != current.size
+                    || last.world_position.distance(current.world_position) > CAMERA_CUT_DISTANCE;
+                (last, reset)
+            }
+            None => (current.clone(), true),
+        };
+
+        let frame = last.frame.wrapping_add(1);
+        let offset = view_uniforms.uniforms.push(VoxelGIViewUniform {
+            previous_view_proj: last.view_proj,
+            previous_world_position: last.world_position,
+            frame,
+            reset: reset as u32,
+            max_distance: settings.max_distance,
+            sky_intensity: settings.sky_intensity,
+            sun_direction: sun.direction,
+            sky_coefficients: preetham.coefficients.map(|c| c.extend(0.0)),
+            sky_zenith: preetham.zenith.extend(0.0),
+        });
         commands
             .entity(entity)
-            .insert(VoxelGIPipelineId(pipeline_id));
+            .insert(VoxelGIViewUniformOffset { offset, frame });
+        view_uniforms
+            .previous
+            .insert(entity, PreviousView { frame, ..current });
     }
+
+    view_uniforms
+        .uniforms
+        .write_buffer(&render_device, &render_queue);
 }
 
+/// Marks a camera as lit by the voxel GI, every camera traces the same [`VoxelGIScene`].
 #[derive(Component, ExtractComponent, Clone, Default)]
 pub struct VoxelGI {
+    pub mode: VoxelGIMode,
+}
+
+/// The GI scene in the render world, shared by all [`VoxelGI`] cameras. The buffers come from the
+/// [`VoxelGISceneBuilder`](super::plugin::VoxelGISceneBuilder) whenever it uploads new ones, the
+/// bind group is only recreated then or when the sky texture changes.
+#[derive(Resource, Default)]
+pub struct VoxelGIScene {
+    /// Top level BVH over all placed models
     pub bvh: Arc<StorageBuffer<GpuBVH>>,
+    /// Bottom level BVHs of every model, back to back
+    pub blas_nodes: Arc<StorageBuffer<GpuBVH>>,
     pub shapes: Arc<StorageBuffer<GpuShapes>>,
-    pub is_updated: bool,
+    pub instances: Arc<StorageBuffer<GpuInstances>>,
+    /// Palette colours and emission of every model, back to back
+    pub materials: Arc<StorageBuffer<GpuVoxelMaterials>>,
+    /// Emissive voxels of every placed model, sampled directly for next event estimation
+    pub lights: Arc<StorageBuffer<GpuLights>>,
+    /// Changes every time the builder hands over new buffers
+    pub generation: u32,
+    pub bind_group: Option<BindGroup>,
+    /// Scene generation and sky texture the bind group was created with
+    bind_group_key: Option<(u32, TextureViewId)>,
+}
+
+pub fn queue_scene_bind_group(
+    mut scene: ResMut<VoxelGIScene>,
+    pipeline: Res<VoxelGIPipeline>,
+    sky: Res<VoxelGISkyTexture>,
+    render_device: Res<RenderDevice>,
+) {
+    let key = (scene.generation, sky.view.id());
+    if scene.bind_group_key == Some(key) {
+        return;
+    }
+    scene.bind_group = create_scene_bind_group(&render_device, &pipeline, &scene, &sky);
+    scene.bind_group_key = scene.bind_group.is_some().then_some(key);
 }
 
 #[derive(Bundle)]
 pub struct VoxelGICamera3dBundle {
     pub path_tracer: VoxelGI,
+    pub settings: VoxelGISettings,
     pub camera: Camera,
     pub camera_render_graph: CameraRenderGraph,
     pub projection: Projection,
@@ -102,6 +863,7 @@ impl Default for VoxelGICamera3dBundle {
     fn default() -> Self {
         Self {
             path_tracer: Default::default(),
+            settings: Default::default(),
             camera_render_graph: CameraRenderGraph::new(VOXEL_GI_GRAPH),
             camera: Camera {
                 hdr: true,
@@ -119,3 +881,25 @@ impl Default for VoxelGICamera3dBundle {
         }
     }
 }
+
+/// Add to a regular `Camera3dBundle` to light it with [`VoxelGIMode::Hybrid`].
+#[derive(Bundle)]
+pub struct VoxelGIHybridBundle {
+    pub voxel_gi: VoxelGI,
+    pub settings: VoxelGISettings,
+    pub depth_prepass: DepthPrepass,
+    pub normal_prepass: NormalPrepass,
+}
+
+impl Default for VoxelGIHybridBundle {
+    fn default() -> Self {
+        Self {
+            voxel_gi: VoxelGI {
+                mode: VoxelGIMode::Hybrid,
+            },
+            settings: Default::default(),
+            depth_prepass: DepthPrepass,
+            normal_prepass: NormalPrepass,
+        }
+    }
+}
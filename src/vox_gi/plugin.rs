@@ -1,9 +1,12 @@
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use bevy::{
     asset::load_internal_asset,
     core_pipeline::{
-        core_3d::graph::node::{TONEMAPPING, UPSCALING},
+        core_3d::{
+            self,
+            graph::node::{BLOOM, TONEMAPPING, UPSCALING},
+        },
         tonemapping::TonemappingNode,
         upscaling::UpscalingNode,
     },
@@ -11,93 +14,282 @@ use bevy::{
     reflect::TypeUuid,
     render::{
         extract_component::ExtractComponentPlugin,
-        render_graph::{RenderGraphApp, ViewNodeRunner},
+        extract_resource::ExtractResourcePlugin,
+        render_asset::PrepareAssetSet,
+        render_graph::{RenderGraph, SlotInfo, SlotType},
         render_resource::*,
-        Render, RenderApp, RenderSet,
+        Extract, ExtractSchedule, RenderApp, RenderSet,
     },
-};
-use bvh::{
-    aabb::{Bounded, AABB},
-    bounding_hierarchy::{BHShape, BoundingHierarchy},
+    tasks::AsyncComputeTaskPool,
+    utils::HashMap,
 };
 
 use crate::{
+    vox::{Vox, VOXEL_SIZE},
     vox_gi::{
-        node::VoxelGINode,
-        pipeline::{prepare_pipelines, VoxelGIPipeline},
+        export::{
+            follow_camera_paths, queue_accumulation, read_back_accumulation, save_finished_renders,
+            VoxelGIAccumulatePipeline, VoxelGIAccumulations, VoxelGIFinishedRenders,
+            VoxelGIProgressive, VoxelGIRenderFinished, VOXEL_GI_ACCUMULATE_SHADER,
+        },
+        lbvh::{
+            build_blases, build_lbvh, placeholder_nodes, BlasBuildJob, LbvhBuilder, LbvhPipeline,
+            VoxelGIBlasBuilds,
+        },
+        node::{
+            VoxelGIDenoiseNode, VoxelGINode, VoxelGIProbeNode, VoxelGIShadowNode,
+            VoxelGITemporalNode,
+        },
+        pipeline::{
+            clear_camera_cuts, extract_sun, prepare_pipelines, prepare_textures,
+            prepare_view_uniforms, queue_scene_bind_group, VoxelGICameraCut, VoxelGIDebugView,
+            VoxelGIDenoisePipeline, VoxelGIMode, VoxelGIPipeline, VoxelGIProbes, VoxelGIResolution,
+            VoxelGIScene, VoxelGISettings, VoxelGIShadowMask, VoxelGISun, VoxelGIViewUniforms,
+            UNSHADOWED_TEXEL,
+        },
+        reference::VoxelGIReferenceScene,
+        sky::{
+            configure_environment_map, extract_sky, prepare_sky, ExtractedVoxelGISky, VoxelGISky,
+            VoxelGISkyTexture,
+        },
     },
-    vox_plugin::VoxelMaterial,
+    vox_plugin::{update_voxel_materials, VoxelMaterial},
 };
 
 use super::pipeline::VoxelGI;
 
 pub const VOXEL_GI_SHADER: HandleUntyped =
     HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 3717171717171755);
+pub const VOXEL_GI_TEMPORAL_SHADER: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 3717171717171758);
+pub const VOXEL_GI_ATROUS_SHADER: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 3717171717171759);
+pub const VOXEL_GI_UPSAMPLE_SHADER: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 3717171717171760);
+pub const VOXEL_GI_LBVH_SHADER: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 3717171717171761);
 pub const VOXEL_GI_GRAPH: &str = "voxel_gi_graph";
 pub const VOXEL_GI_NODE: &str = "voxel_gi_node";
+pub const VOXEL_GI_TEMPORAL_NODE: &str = "voxel_gi_temporal_node";
+pub const VOXEL_GI_DENOISE_NODE: &str = "voxel_gi_denoise_node";
+/// Added to the core 3d graph, see [`VoxelGIMode::Probes`]
+pub const VOXEL_GI_PROBE_NODE: &str = "voxel_gi_probe_node";
+/// Added to the core 3d graph, see [`VoxelGIMode::Shadows`]
+pub const VOXEL_GI_SHADOW_NODE: &str = "voxel_gi_shadow_node";
+pub const VOXEL_GI_GRAPH_INPUT_VIEW: &str = "view_entity";
 
+/// Renders cameras spawned with [`VoxelGICamera3dBundle`](super::pipeline::VoxelGICamera3dBundle)
+/// by tracing the voxel scene, and adds traced indirect light to regular 3d cameras with a
+/// [`VoxelGIHybridBundle`](super::pipeline::VoxelGIHybridBundle). Must be added after
+/// `DefaultPlugins`.
 #[derive(Default)]
 pub struct VoxelGIPlugin;
 
 impl Plugin for VoxelGIPlugin {
-    fn build(&self, _app: &mut App) {}
-    fn finish(&self, app: &mut App) {
+    fn build(&self, app: &mut App) {
         load_internal_asset!(app, VOXEL_GI_SHADER, "voxel_gi.wgsl", Shader::from_wgsl);
+        load_internal_asset!(
+            app,
+            VOXEL_GI_TEMPORAL_SHADER,
+            "temporal.wgsl",
+            Shader::from_wgsl
+        );
+        load_internal_asset!(
+            app,
+            VOXEL_GI_ATROUS_SHADER,
+            "atrous.wgsl",
+            Shader::from_wgsl
+        );
+        load_internal_asset!(
+            app,
+            VOXEL_GI_UPSAMPLE_SHADER,
+            "upsample.wgsl",
+            Shader::from_wgsl
+        );
+        load_internal_asset!(app, VOXEL_GI_LBVH_SHADER, "lbvh.wgsl", Shader::from_wgsl);
+        load_internal_asset!(
+            app,
+            VOXEL_GI_ACCUMULATE_SHADER,
+            "accumulate.wgsl",
+            Shader::from_wgsl
+        );
+
+        // progressive renders are read back in the render world and written from the main world
+        let finished_renders = VoxelGIFinishedRenders::default();
 
         app.add_plugin(ExtractComponentPlugin::<VoxelGI>::default())
-            .add_systems(Update, construct_vox_octree);
+            .add_plugin(ExtractComponentPlugin::<VoxelGISettings>::default())
+            .add_plugin(ExtractComponentPlugin::<VoxelGICameraCut>::default())
+            .add_plugin(ExtractComponentPlugin::<VoxelGIProgressive>::default())
+            .add_plugin(ExtractResourcePlugin::<VoxelGIProbes>::default())
+            .add_plugin(ExtractResourcePlugin::<VoxelGIShadowMask>::default())
+            .add_plugin(ExtractResourcePlugin::<VoxelGIBlasBuilds>::default())
+            .register_type::<VoxelGISettings>()
+            .register_type::<VoxelGIDebugView>()
+            .register_type::<VoxelGIResolution>()
+            .init_resource::<VoxelGISceneBuilder>()
+            .init_resource::<VoxelGIBlasBuilds>()
+            .init_resource::<VoxelGIProbes>()
+            .init_resource::<VoxelGIShadowMask>()
+            .init_resource::<VoxelGISky>()
+            .insert_resource(finished_renders.clone())
+            .add_event::<VoxelGIRenderFinished>()
+            .add_system(assign_irradiance_probes)
+            .add_system(assign_shadow_mask)
+            .add_system(clear_camera_cuts.in_base_set(CoreSet::First))
+            .add_system(update_voxel_blas)
+            .add_system(update_voxel_tlas.after(update_voxel_blas))
+            .add_system(configure_environment_map)
+            .add_system(save_finished_renders)
+            .add_system(follow_camera_paths.after(save_finished_renders));
 
-        let render_app = &mut app.sub_app_mut(RenderApp);
+        let Ok(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return;
+        };
 
         render_app
             .init_resource::<VoxelGIPipeline>()
             .init_resource::<SpecializedComputePipelines<VoxelGIPipeline>>()
-            .add_systems(Render, (prepare_pipelines).in_set(RenderSet::Prepare))
-            .add_render_sub_graph(VOXEL_GI_GRAPH)
-            .add_render_graph_node::<ViewNodeRunner<VoxelGINode>>(VOXEL_GI_GRAPH, VOXEL_GI_NODE)
-            .add_render_graph_node::<ViewNodeRunner<TonemappingNode>>(VOXEL_GI_GRAPH, TONEMAPPING)
-            .add_render_graph_node::<ViewNodeRunner<UpscalingNode>>(VOXEL_GI_GRAPH, UPSCALING)
-            .add_render_graph_edges(VOXEL_GI_GRAPH, &[VOXEL_GI_NODE, TONEMAPPING, UPSCALING]);
-    }
-}
+            .init_resource::<VoxelGIDenoisePipeline>()
+            .init_resource::<VoxelGIViewUniforms>()
+            .init_resource::<VoxelGIScene>()
+            .init_resource::<VoxelGISun>()
+            .init_resource::<ExtractedVoxelGISky>()
+            .init_resource::<VoxelGISkyTexture>()
+            .init_resource::<LbvhPipeline>()
+            .init_resource::<LbvhBuilder>()
+            .init_resource::<VoxelGIAccumulatePipeline>()
+            .init_resource::<VoxelGIAccumulations>()
+            .insert_resource(finished_renders)
+            .add_system(extract_sun.in_schedule(ExtractSchedule))
+            .add_system(extract_sky.in_schedule(ExtractSchedule))
+            .add_system(extract_scene.in_schedule(ExtractSchedule))
+            .add_system(build_blases.in_set(RenderSet::Prepare))
+            .add_system(
+                prepare_sky
+                    .in_set(RenderSet::Prepare)
+                    .after(PrepareAssetSet::AssetPrepare)
+                    .before(prepare_pipelines),
+            )
+            .add_system(prepare_pipelines.in_set(RenderSet::Prepare))
+            .add_system(prepare_textures.in_set(RenderSet::Prepare))
+            .add_system(prepare_view_uniforms.in_set(RenderSet::Prepare))
+            .add_system(queue_scene_bind_group.in_set(RenderSet::Queue))
+            .add_system(
+                queue_accumulation
+                    .in_set(RenderSet::Queue)
+                    .after(queue_scene_bind_group),
+            )
+            .add_system(read_back_accumulation.in_set(RenderSet::Cleanup));
 
-#[derive(Debug, Clone, Copy)]
-pub struct VoxelBox {
-    pub pos: Vec3,
-    pub scale: Vec3,
-    node_index: usize,
-    pub voxels: [u32; 512],
-}
+        let voxel_gi_node = VoxelGINode::new(&mut render_app.world);
+        let temporal = VoxelGITemporalNode::new(&mut render_app.world);
+        let denoise = VoxelGIDenoiseNode::new(&mut render_app.world);
+        let tonemapping = TonemappingNode::new(&mut render_app.world);
+        let upscaling = UpscalingNode::new(&mut render_app.world);
 
-impl Bounded for VoxelBox {
-    fn aabb(&self) -> AABB {
-        let min = self.pos + Vec3::new(-self.scale.x, -self.scale.y, -self.scale.z);
-        let max = self.pos + Vec3::new(self.scale.x, self.scale.y, self.scale.z);
-        AABB::with_bounds(min.to_array().into(), max.to_array().into())
-    }
-}
-impl BHShape for VoxelBox {
-    fn set_bh_node_index(&mut self, index: usize) {
-        self.node_index = index;
-    }
-    fn bh_node_index(&self) -> usize {
-        self.node_index
+        let mut voxel_gi_graph = RenderGraph::default();
+        voxel_gi_graph.add_node(VOXEL_GI_NODE, voxel_gi_node);
+        voxel_gi_graph.add_node(VOXEL_GI_TEMPORAL_NODE, temporal);
+        voxel_gi_graph.add_node(VOXEL_GI_DENOISE_NODE, denoise);
+        voxel_gi_graph.add_node(TONEMAPPING, tonemapping);
+        voxel_gi_graph.add_node(UPSCALING, upscaling);
+
+        let input_node_id = voxel_gi_graph.set_input(vec![SlotInfo::new(
+            VOXEL_GI_GRAPH_INPUT_VIEW,
+            SlotType::Entity,
+        )]);
+        let nodes = [
+            VOXEL_GI_NODE,
+            VOXEL_GI_TEMPORAL_NODE,
+            VOXEL_GI_DENOISE_NODE,
+            TONEMAPPING,
+            UPSCALING,
+        ];
+        for node in nodes {
+            voxel_gi_graph.add_slot_edge(input_node_id, VOXEL_GI_GRAPH_INPUT_VIEW, node, "view");
+        }
+        for pair in nodes.windows(2) {
+            voxel_gi_graph.add_node_edge(pair[0], pair[1]);
+        }
+
+        let probe_node = VoxelGIProbeNode::new(&mut render_app.world);
+        let shadow_node = VoxelGIShadowNode::new(&mut render_app.world);
+        let hybrid_voxel_gi_node = VoxelGINode::new(&mut render_app.world);
+        let hybrid_temporal = VoxelGITemporalNode::new(&mut render_app.world);
+        let hybrid_denoise = VoxelGIDenoiseNode::new(&mut render_app.world);
+        let mut render_graph = render_app.world.resource_mut::<RenderGraph>();
+        render_graph.add_sub_graph(VOXEL_GI_GRAPH, voxel_gi_graph);
+
+        let core_3d_graph = render_graph
+            .get_sub_graph_mut(core_3d::graph::NAME)
+            .unwrap();
+        core_3d_graph.add_node(VOXEL_GI_PROBE_NODE, probe_node);
+        core_3d_graph.add_slot_edge(
+            core_3d_graph.input_node().id,
+            core_3d::graph::input::VIEW_ENTITY,
+            VOXEL_GI_PROBE_NODE,
+            VoxelGIProbeNode::IN_VIEW,
+        );
+        core_3d_graph.add_node_edge(VOXEL_GI_PROBE_NODE, core_3d::graph::node::MAIN_PASS);
+        core_3d_graph.add_node(VOXEL_GI_SHADOW_NODE, shadow_node);
+        core_3d_graph.add_slot_edge(
+            core_3d_graph.input_node().id,
+            core_3d::graph::input::VIEW_ENTITY,
+            VOXEL_GI_SHADOW_NODE,
+            VoxelGIShadowNode::IN_VIEW,
+        );
+        core_3d_graph.add_node_edge(VOXEL_GI_SHADOW_NODE, core_3d::graph::node::MAIN_PASS);
+
+        // hybrid cameras add their GI to the lit image, before any post processing
+        core_3d_graph.add_node(VOXEL_GI_NODE, hybrid_voxel_gi_node);
+        core_3d_graph.add_node(VOXEL_GI_TEMPORAL_NODE, hybrid_temporal);
+        core_3d_graph.add_node(VOXEL_GI_DENOISE_NODE, hybrid_denoise);
+        let input_node_id = core_3d_graph.input_node().id;
+        let nodes = [
+            core_3d::graph::node::MAIN_PASS,
+            VOXEL_GI_NODE,
+            VOXEL_GI_TEMPORAL_NODE,
+            VOXEL_GI_DENOISE_NODE,
+            TONEMAPPING,
+        ];
+        for node in &nodes[1..4] {
+            core_3d_graph.add_slot_edge(
+                input_node_id,
+                core_3d::graph::input::VIEW_ENTITY,
+                *node,
+                "view",
+            );
+        }
+        for pair in nodes.windows(2) {
+            core_3d_graph.add_node_edge(pair[0], pair[1]);
+        }
+        // bloom is optional and registered by its own plugin
+        if core_3d_graph.get_node_state(BLOOM).is_ok() {
+            core_3d_graph.add_node_edge(VOXEL_GI_DENOISE_NODE, BLOOM);
+        }
     }
 }
 
+/// Bricks are 8x8x8 voxels, the `u8` palette values are packed four to a `u32`.
+pub const BRICK_SIZE: u32 = 8;
+pub const BRICK_WORDS: usize = (BRICK_SIZE * BRICK_SIZE * BRICK_SIZE / 4) as usize;
+
 #[derive(Copy, Clone, ShaderType, Default, Debug)]
 pub struct GpuFlatNode {
-    aabb_min: Vec3,
-    aabb_max: Vec3,
-    entry_index: u32,
-    exit_index: u32,
+    pub(super) aabb_min: Vec3,
+    pub(super) aabb_max: Vec3,
+    /// First child, `u32::MAX` for leaves
+    pub(super) entry_index: u32,
+    /// Node to continue with once this subtree is done or missed
+    pub(super) exit_index: u32,
 
     /// The index of the shape in the shapes array.
-    shape_index: u32,
+    pub(super) shape_index: u32,
 }
 #[derive(ShaderType, Default)]
 pub struct GpuBVH {
-    length: u32,
+    pub(super) length: u32,
     #[size(runtime)]
     pub data: Vec<GpuFlatNode>,
 }
@@ -105,212 +297,781 @@ pub struct GpuBVH {
 #[derive(Copy, Clone, ShaderType, Debug)]
 #[repr(C, align(16))]
 pub struct GpuShape {
-    aabb_min: Vec3,
-    aabb_max: Vec3,
-    _pad: Vec2,
-    voxels: [u32; 512],
+    pub(super) aabb_min: Vec3,
+    pub(super) aabb_max: Vec3,
+    /// Where the model's palette starts in the materials buffer
+    pub(super) material_offset: u32,
+    pub(super) voxels: [u32; BRICK_WORDS],
 }
 
 #[derive(ShaderType, Default)]
 pub struct GpuShapes {
-    length: u32,
+    pub(super) length: u32,
     #[size(runtime)]
     pub data: Vec<GpuShape>,
 }
 
-fn construct_vox_octree(
-    vox_assets: ResMut<Assets<VoxelMaterial>>,
-    assets: ResMut<Assets<Image>>,
-    entities: Query<(&Transform, &Handle<VoxelMaterial>), With<Handle<VoxelMaterial>>>,
-    render_device: Res<bevy::render::renderer::RenderDevice>,
-    render_queue: Res<bevy::render::renderer::RenderQueue>,
-    mut views: Query<&mut VoxelGI>,
-) {
-    for mut voxel_bvh in views.iter_mut() {
-        if voxel_bvh.is_updated {
-            return;
+/// Shading data of one palette entry.
+#[derive(Copy, Clone, ShaderType, Default, Debug)]
+pub struct GpuVoxelMaterial {
+    /// Linear albedo
+    pub(super) albedo: Vec3,
+    /// Emitted light as a multiple of the albedo
+    pub(super) emissive: f32,
+}
+
+#[derive(ShaderType, Default)]
+pub struct GpuVoxelMaterials {
+    length: u32,
+    #[size(runtime)]
+    pub data: Vec<GpuVoxelMaterial>,
+}
+
+/// A placed model, the top level BVH leaves point at these.
+#[derive(Copy, Clone, ShaderType, Default, Debug)]
+pub struct GpuInstance {
+    pub(super) world_from_local: Mat4,
+    pub(super) local_from_world: Mat4,
+    /// Where the model's bottom level BVH starts in the BLAS node buffer
+    pub(super) node_offset: u32,
+    pub(super) node_count: u32,
+    /// Where the model's bricks start in the shapes buffer
+    pub(super) shape_offset: u32,
+}
+
+#[derive(ShaderType, Default)]
+pub struct GpuInstances {
+    length: u32,
+    #[size(runtime)]
+    pub data: Vec<GpuInstance>,
+}
+
+/// An emissive voxel of a placed model, sampled directly by the GI shader. Every light is also a
+/// slot of an alias table over the light power, which picks lights in constant time.
+#[derive(Copy, Clone, ShaderType, Default, Debug)]
+pub struct GpuLight {
+    /// Voxel center in model space
+    pub(super) center: Vec3,
+    /// Voxel edge length in model space
+    pub(super) size: f32,
+    pub(super) instance: u32,
+    /// Index into the materials buffer
+    pub(super) material: u32,
+    /// Chance of keeping this slot instead of jumping to `alias`
+    pub(super) probability: f32,
+    pub(super) alias: u32,
+    /// Chance of picking this light, proportional to its power
+    pub(super) pdf: f32,
+}
+
+#[derive(ShaderType, Default)]
+pub struct GpuLights {
+    length: u32,
+    #[size(runtime)]
+    pub data: Vec<GpuLight>,
+}
+
+/// Bottom level acceleration structure of one model, bricks are in model space. The nodes are
+/// built on the GPU, see [`VoxelGIBlasBuilds`].
+pub(super) struct Blas {
+    pub(super) node_count: u32,
+    pub(super) shapes: Vec<GpuShape>,
+    pub(super) aabb_min: Vec3,
+    pub(super) aabb_max: Vec3,
+}
+
+/// Emissive voxel of a model that isn't buried under other voxels.
+struct VoxelEmitter {
+    /// Voxel center in model space
+    center: Vec3,
+    /// Palette index, relative to the model's materials
+    material: u32,
+}
+
+struct BlasEntry {
+    blas: Blas,
+    /// Palette texture the materials were built from
+    palette: Handle<Image>,
+    materials: Vec<GpuVoxelMaterial>,
+    emitters: Vec<VoxelEmitter>,
+    node_offset: u32,
+    shape_offset: u32,
+    material_offset: u32,
+}
+
+/// Bricks, materials and emitters of a model being packed on the [`AsyncComputeTaskPool`], the
+/// inner `None` marks models without any solid voxel.
+type PendingBlas = Arc<Mutex<Option<Option<BlasEntry>>>>;
+
+/// Model and palette texture of a material.
+type MaterialTextures = (Option<Handle<Image>>, Option<Handle<Image>>);
+
+/// Main world side of the [`VoxelGIScene`]. Model bricks are packed once per model texture and
+/// only repacked when it changes, their BVHs are then built on the GPU. The top level BVH is
+/// rebuilt on the CPU when voxel entities move, spawn or despawn.
+#[derive(Resource, Default)]
+pub struct VoxelGISceneBuilder {
+    blases: HashMap<Handle<Image>, BlasEntry>,
+    /// Models whose bricks are still being packed, with their palette texture
+    pending: HashMap<Handle<Image>, (Handle<Image>, PendingBlas)>,
+    blases_changed: bool,
+    tlas_changed: bool,
+    /// Model and palette textures of every material, the TLAS only changes when these do
+    material_textures: HashMap<Handle<VoxelMaterial>, MaterialTextures>,
+    /// Bumped whenever any buffer is replaced, tells the render world to pick them up
+    generation: u32,
+    bvh: Arc<StorageBuffer<GpuBVH>>,
+    blas_nodes: Arc<StorageBuffer<GpuBVH>>,
+    shapes: Arc<StorageBuffer<GpuShapes>>,
+    instances: Arc<StorageBuffer<GpuInstances>>,
+    materials: Arc<StorageBuffer<GpuVoxelMaterials>>,
+    lights: Arc<StorageBuffer<GpuLights>>,
+}
+
+/// GPU memory used by the GI scene buffers, in bytes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VoxelGIMemoryUsage {
+    pub models: usize,
+    pub bricks: usize,
+    pub brick_bytes: usize,
+    pub blas_node_bytes: usize,
+    pub tlas_node_bytes: usize,
+    pub instance_bytes: usize,
+    pub material_bytes: usize,
+    pub lights: usize,
+    pub light_bytes: usize,
+}
+
+impl VoxelGIMemoryUsage {
+    pub fn total_bytes(&self) -> usize {
+        self.brick_bytes
+            + self.blas_node_bytes
+            + self.tlas_node_bytes
+            + self.instance_bytes
+            + self.material_bytes
+            + self.light_bytes
+    }
+}
+
+impl VoxelGISceneBuilder {
+    pub fn memory_usage(&self) -> VoxelGIMemoryUsage {
+        let buffer_size = |buffer: Option<&Buffer>| buffer.map_or(0, |b| b.size() as usize);
+        VoxelGIMemoryUsage {
+            models: self.blases.len(),
+            bricks: self.blases.values().map(|e| e.blas.shapes.len()).sum(),
+            brick_bytes: buffer_size(self.shapes.buffer()),
+            blas_node_bytes: buffer_size(self.blas_nodes.buffer()),
+            tlas_node_bytes: buffer_size(self.bvh.buffer()),
+            instance_bytes: buffer_size(self.instances.buffer()),
+            material_bytes: buffer_size(self.materials.buffer()),
+            lights: self.lights.get().length as usize,
+            light_bytes: buffer_size(self.lights.buffer()),
+        }
+    }
+
+    /// CPU copy of the scene as it was last uploaded, for [`VoxelGIReferenceScene::render`].
+    /// The model BVHs only exist on the GPU, so they are built again with the CPU reference.
+    pub fn reference_scene(&self) -> VoxelGIReferenceScene {
+        let mut blas_nodes = self.blas_nodes.get().data.clone();
+        for entry in self.blases.values() {
+            let nodes = build_lbvh(
+                &entry
+                    .blas
+                    .shapes
+                    .iter()
+                    .map(|shape| (shape.aabb_min, shape.aabb_max))
+                    .collect::<Vec<_>>(),
+            );
+            let offset = entry.node_offset as usize;
+            if let Some(target) = blas_nodes.get_mut(offset..offset + nodes.len()) {
+                target.copy_from_slice(&nodes);
+            }
         }
 
-        let mut capacity_needed = 0;
-        for (_, mat) in entities.iter() {
-            let mat = vox_assets.get(mat).unwrap();
-            let Some(model_texture) = mat.model_texture.as_ref() else {
-                continue;
-            };
-            let texture = assets.get(model_texture).unwrap();
-            capacity_needed += texture.texture_descriptor.size.width
-                * texture.texture_descriptor.size.height
-                * texture.texture_descriptor.size.depth_or_array_layers;
+        let bvh = self.bvh.get();
+        let instances = self.instances.get();
+        VoxelGIReferenceScene {
+            tlas: bvh.data[..bvh.length as usize].to_vec(),
+            blas_nodes,
+            shapes: self.shapes.get().data.clone(),
+            instances: instances.data[..instances.length as usize].to_vec(),
+            materials: self.materials.get().data.clone(),
         }
+    }
+}
 
-        let mut shapes = Vec::with_capacity(capacity_needed as usize);
+pub(super) fn build_blas(texture: &Image) -> Option<Blas> {
+    let size = texture.texture_descriptor.size;
+    let half_extents = Vec3::new(
+        size.width as f32,
+        size.height as f32,
+        size.depth_or_array_layers as f32,
+    ) * VOXEL_SIZE
+        / 2.0;
+    let chunk_size = BRICK_SIZE as usize;
+    let mut shapes = Vec::new();
 
-        for (transform, mat) in entities.iter() {
-            let mat = vox_assets.get(mat).unwrap();
-            let Some(model_texture) = mat.model_texture.as_ref() else {
-                continue;
-            };
-            let Some(palette_texture) = mat.palette_texture.as_ref() else {
-                continue;
-            };
-            let texture = assets.get(model_texture).unwrap();
-            let palette_texture = assets.get(palette_texture).unwrap();
-            let rgba_data: Vec<[u8; 4]> = palette_texture
-                .data
-                .chunks(4)
-                .map(|c| c.try_into().unwrap())
-                .collect();
-
-            // let voxels = vec![];
-            // for x in 0..texture.texture_descriptor.size.width {
-            //     for y in 0..texture.texture_descriptor.size.height {
-            //         for z in 0..texture.texture_descriptor.size.depth_or_array_layers {
-            //             let texture_index = (x
-            //                 + y * texture.texture_descriptor.size.width
-            //                 + z * texture.texture_descriptor.size.width
-            //                     * texture.texture_descriptor.size.height)
-            //                 as usize;
-
-            //             let palette_index = texture.data[texture_index];
-            //             if palette_index == 0 {
-            //                 continue;
-            //             }
-            //             let scale = Vec3::new(
-            //                 texture.texture_descriptor.size.width as f32,
-            //                 texture.texture_descriptor.size.height as f32,
-            //                 texture.texture_descriptor.size.depth_or_array_layers as f32,
-            //             ) / 26.0;
-            //             let half_scale = scale / 2.0;
-            //             voxels.push((
-            //                 Vec3::new(x as f32, y as f32, z as f32) - transform.translation,
-            //                 palette_index,
-            //             ));
-            //         }
-            //     }
-            // }
-
-            let chunk_size = 8;
-
-            for chunk_x in (0..texture.texture_descriptor.size.width).step_by(chunk_size) {
-                for chunk_y in (0..texture.texture_descriptor.size.height).step_by(chunk_size) {
-                    for chunk_z in (0..texture.texture_descriptor.size.depth_or_array_layers)
-                        .step_by(chunk_size)
-                    {
-                        let mut voxels: [u32; 512] = [0; 512];
-                        let mut voxel_index = 0;
-
-                        for x in chunk_x
-                            ..(chunk_x + chunk_size as u32)
-                                .min(texture.texture_descriptor.size.width)
+    for chunk_x in (0..size.width).step_by(chunk_size) {
+        for chunk_y in (0..size.height).step_by(chunk_size) {
+            for chunk_z in (0..size.depth_or_array_layers).step_by(chunk_size) {
+                let mut voxels = [0u32; BRICK_WORDS];
+
+                for x in chunk_x..(chunk_x + chunk_size as u32).min(size.width) {
+                    for y in chunk_y..(chunk_y + chunk_size as u32).min(size.height) {
+                        for z in
+                            chunk_z..(chunk_z + chunk_size as u32).min(size.depth_or_array_layers)
                         {
-                            for y in chunk_y
-                                ..(chunk_y + chunk_size as u32)
-                                    .min(texture.texture_descriptor.size.height)
-                            {
-                                for z in chunk_z
-                                    ..(chunk_z + chunk_size as u32)
-                                        .min(texture.texture_descriptor.size.depth_or_array_layers)
-                                {
-                                    // Here you should extract the voxel data from your texture at position (x, y, z)
-                                    // and assign it to voxels[voxel_index]
-                                    // for example:
-                                    voxels[voxel_index] = texture.data[(x
-                                        + y * texture.texture_descriptor.size.width
-                                        + z * texture.texture_descriptor.size.width
-                                            * texture.texture_descriptor.size.height)
-                                        as usize]
-                                        as u32;
-
-                                    voxel_index += 1;
-                                }
-                            }
+                            // same `x + y * 8 + z * 64` layout the shader reads
+                            let voxel_index = ((x - chunk_x)
+                                + (y - chunk_y) * chunk_size as u32
+                                + (z - chunk_z) * (chunk_size * chunk_size) as u32)
+                                as usize;
+                            let value = texture.data
+                                [(x + y * size.width + z * size.width * size.height) as usize]
+                                as u32;
+                            voxels[voxel_index / 4] |= value << ((voxel_index % 4) * 8);
                         }
-
-                        let shape = VoxelBox {
-                            scale: Vec3::new(0.5, 0.5, 0.5),
-                            pos: transform.translation
-                                + Vec3::new(chunk_x as f32, chunk_y as f32, chunk_z as f32),
-                            voxels,
-                            node_index: 0,
-                        };
-                        shapes.push(shape);
                     }
                 }
+
+                // empty bricks can never be hit, leave them out of the BVH entirely
+                if voxels.iter().all(|word| *word == 0) {
+                    continue;
+                }
+
+                // model space matches the raster path, centered on the origin
+                let brick_min = Vec3::new(chunk_x as f32, chunk_y as f32, chunk_z as f32)
+                    * VOXEL_SIZE
+                    - half_extents;
+                shapes.push(GpuShape {
+                    aabb_min: brick_min,
+                    aabb_max: brick_min + chunk_size as f32 * VOXEL_SIZE,
+                    material_offset: 0,
+                    voxels,
+                });
             }
+        }
+    }
+
+    if shapes.is_empty() {
+        return None;
+    }
+    let aabb_min = shapes
+        .iter()
+        .fold(Vec3::splat(f32::MAX), |acc, s| acc.min(s.aabb_min));
+    let aabb_max = shapes
+        .iter()
+        .fold(Vec3::splat(f32::MIN), |acc, s| acc.max(s.aabb_max));
+
+    Some(Blas {
+        node_count: 2 * shapes.len() as u32 - 1,
+        shapes,
+        aabb_min,
+        aabb_max,
+    })
+}
 
-            //     for (i, chunk) in texture.data.chunks(512).enumerate() {
-            //         let mut voxels: [u32; 512] = [0; 512];
-
-            //         let dim = 8; // Dimension of each side of the voxel chunk
-
-            //         for x in 0..dim {
-            //             for y in 0..dim {
-            //                 for z in 0..dim {
-            //                     let linear_index = x + y * dim + z * dim * dim;
-
-            //                     if linear_index >= chunk.len() {
-            //                         break;
-            //                     }
-
-            //                     voxels[linear_index] = chunk[linear_index] as u32;
-            //                 }
-            //             }
-            //         }
-
-            //         let shape = VoxelBox {
-            //             scale: Vec3::new(0.5, 0.5, 0.5),
-            //             pos: transform.translation + (i as f32 * 8.0),
-            //             voxels,
-            //             node_index: 0,
-            //         };
-            //         shapes.push(shape);
-            //     }
+/// Points every voxel material at the probe atlas while a camera uses [`VoxelGIMode::Probes`].
+fn assign_irradiance_probes(
+    probes: Res<VoxelGIProbes>,
+    cameras: Query<&VoxelGI>,
+    mut vox_materials: ResMut<Assets<VoxelMaterial>>,
+) {
+    let irradiance_probes = cameras
+        .iter()
+        .any(|voxel_gi| voxel_gi.mode == VoxelGIMode::Probes)
+        .then(|| probes.irradiance.clone());
+
+    update_voxel_materials(
+        &mut vox_materials,
+        |material| material.irradiance_probes != irradiance_probes,
+        |material| material.irradiance_probes = irradiance_probes.clone(),
+    );
+}
+
+/// Keeps the shadow mask as large as the viewport of the [`VoxelGIMode::Shadows`] camera and
+/// points every voxel material at it while there is one.
+fn assign_shadow_mask(
+    shadow_mask: Res<VoxelGIShadowMask>,
+    cameras: Query<(&VoxelGI, &Camera)>,
+    mut images: ResMut<Assets<Image>>,
+    mut vox_materials: ResMut<Assets<VoxelMaterial>>,
+) {
+    let viewport = cameras
+        .iter()
+        .find(|(voxel_gi, _)| voxel_gi.mode == VoxelGIMode::Shadows)
+        .and_then(|(_, camera)| camera.physical_viewport_size());
+
+    let mut resized = false;
+    if let Some(viewport) = viewport {
+        let size = images
+            .get(&shadow_mask.mask)
+            .map(|image| image.size().as_uvec2());
+        if size.is_some_and(|size| size != viewport) {
+            if let Some(image) = images.get_mut(&shadow_mask.mask) {
+                image.texture_descriptor.size = Extent3d {
+                    width: viewport.x,
+                    height: viewport.y,
+                    depth_or_array_layers: 1,
+                };
+                image.data = UNSHADOWED_TEXEL.repeat((viewport.x * viewport.y) as usize);
+                resized = true;
+            }
+        }
+    }
+
+    let mask = viewport.map(|_| shadow_mask.mask.clone());
+    // a resized mask is a new texture, so the bind groups of all materials have to be rebuilt
+    update_voxel_materials(
+        &mut vox_materials,
+        |material| resized || material.shadow_mask != mask,
+        |material| material.shadow_mask = mask.clone(),
+    );
+}
+
+/// Linear palette colours of a model, with the emission strengths from its `.vox` materials.
+pub(super) fn build_materials(palette: &Image, emission: &[f32]) -> Vec<GpuVoxelMaterial> {
+    palette
+        .data
+        .chunks_exact(4)
+        .enumerate()
+        .map(|(i, c)| {
+            let [r, g, b, _] = Color::rgba_u8(c[0], c[1], c[2], c[3]).as_linear_rgba_f32();
+            GpuVoxelMaterial {
+                albedo: Vec3::new(r, g, b),
+                emissive: emission.get(i).copied().unwrap_or(0.0),
+            }
+        })
+        .collect()
+}
+
+/// Emissive voxels with at least one empty neighbour, voxels inside an emissive volume can't light
+/// anything.
+fn gather_emitters(texture: &Image, materials: &[GpuVoxelMaterial]) -> Vec<VoxelEmitter> {
+    let size = texture.texture_descriptor.size;
+    let size = UVec3::new(size.width, size.height, size.depth_or_array_layers);
+    let half_extents = size.as_vec3() * VOXEL_SIZE / 2.0;
+    let voxel = |pos: IVec3| {
+        if pos.cmplt(IVec3::ZERO).any() || pos.cmpge(size.as_ivec3()).any() {
+            return 0;
+        }
+        let pos = pos.as_uvec3();
+        texture.data[(pos.x + pos.y * size.x + pos.z * size.x * size.y) as usize]
+    };
+    let neighbours = [
+        IVec3::X,
+        IVec3::NEG_X,
+        IVec3::Y,
+        IVec3::NEG_Y,
+        IVec3::Z,
+        IVec3::NEG_Z,
+    ];
+
+    let mut emitters = vec![];
+    for z in 0..size.z as i32 {
+        for y in 0..size.y as i32 {
+            for x in 0..size.x as i32 {
+                let pos = IVec3::new(x, y, z);
+                let value = voxel(pos);
+                let emissive = materials
+                    .get(value as usize)
+                    .is_some_and(|m| m.emissive > 0.0 && m.albedo.max_element() > 0.0);
+                if value == 0 || !emissive || neighbours.iter().all(|n| voxel(pos + *n) != 0) {
+                    continue;
+                }
+                emitters.push(VoxelEmitter {
+                    center: (pos.as_vec3() + 0.5) * VOXEL_SIZE - half_extents,
+                    material: value as u32,
+                });
+            }
+        }
+    }
+    emitters
+}
+
+/// Walker's alias table over `weights`, as the keep probability and alias of every slot.
+fn build_alias_table(weights: &[f32]) -> Vec<(f32, u32)> {
+    let total = weights.iter().sum::<f32>();
+    let count = weights.len() as f32;
+    let mut scaled = weights
+        .iter()
+        .map(|w| if total > 0.0 { w * count / total } else { 1.0 })
+        .collect::<Vec<_>>();
+    let mut table = (0..weights.len() as u32)
+        .map(|i| (1.0, i))
+        .collect::<Vec<_>>();
+
+    let (mut small, mut large): (Vec<_>, Vec<_>) =
+        (0..weights.len()).partition(|i| scaled[*i] < 1.0);
+    while let (Some(s), Some(l)) = (small.pop(), large.pop()) {
+        table[s] = (scaled[s], l as u32);
+        // the large slot gives away what fills up the small one
+        scaled[l] -= 1.0 - scaled[s];
+        if scaled[l] < 1.0 {
+            small.push(l);
+        } else {
+            large.push(l);
         }
+    }
+    // leftovers only miss 1.0 through rounding
+    table
+}
+
+/// Builds BVHs for models that don't have one yet and rebuilds those whose model or palette changed.
+/// Bricks, materials and emitters are gathered on the [`AsyncComputeTaskPool`], the model and
+/// palette textures are cloned for it on the main thread.
+fn update_voxel_blas(
+    mut scene: ResMut<VoxelGISceneBuilder>,
+    mut image_events: EventReader<AssetEvent<Image>>,
+    vox_materials: Res<Assets<VoxelMaterial>>,
+    vox_assets: Res<Assets<Vox>>,
+    images: Res<Assets<Image>>,
+    entities: Query<&Handle<VoxelMaterial>>,
+) {
+    for event in image_events.iter() {
+        match event {
+            AssetEvent::Modified { handle } | AssetEvent::Removed { handle } => {
+                let count = scene.blases.len();
+                scene
+                    .blases
+                    .retain(|model, entry| model != handle && entry.palette != *handle);
+                scene.blases_changed |= scene.blases.len() != count;
+                // tasks packing the old texture are left to finish on their own
+                scene
+                    .pending
+                    .retain(|model, (palette, _)| model != handle && palette != handle);
+            }
+            AssetEvent::Created { .. } => {}
+        }
+    }
+
+    let finished: Vec<_> = scene
+        .pending
+        .iter()
+        .filter_map(|(model, (_, pending))| {
+            let entry = pending.lock().unwrap().as_mut()?.take()?;
+            Some((model.clone_weak(), entry))
+        })
+        .collect();
+    for (model, entry) in finished {
+        scene.pending.remove(&model);
+        scene.blases.insert(model, entry);
+        scene.blases_changed = true;
+    }
 
-        if shapes.is_empty() {
+    for material in entities.iter() {
+        let Some(material) = vox_materials.get(material) else {
+            continue;
+        };
+        let (Some(model_texture), Some(palette_texture)) =
+            (&material.model_texture, &material.palette_texture)
+        else {
+            continue;
+        };
+        // empty models stay pending so they aren't packed again every frame
+        if scene.blases.contains_key(model_texture) || scene.pending.contains_key(model_texture) {
             continue;
         }
-        let bvh = bvh::flat_bvh::FlatBVH::build(&mut shapes);
-        let gpu_nodes = bvh
-            .iter()
-            .map(|node| GpuFlatNode {
-                aabb_min: node.aabb.min.to_array().into(),
-                aabb_max: node.aabb.max.to_array().into(),
-                entry_index: node.entry_index,
-                exit_index: node.exit_index,
-                shape_index: node.shape_index,
+        let (Some(texture), Some(palette)) =
+            (images.get(model_texture), images.get(palette_texture))
+        else {
+            continue;
+        };
+        let (texture, palette) = (texture.clone(), palette.clone());
+        let emission = vox_assets
+            .get(&material.vox)
+            .map(|vox| vox.emission.clone())
+            .unwrap_or_default();
+        let pending = PendingBlas::default();
+        let result = pending.clone();
+        let palette_handle = palette_texture.clone_weak();
+        AsyncComputeTaskPool::get()
+            .spawn(async move {
+                let entry = build_blas(&texture).map(|blas| {
+                    let materials = build_materials(&palette, &emission);
+                    let emitters = gather_emitters(&texture, &materials);
+                    BlasEntry {
+                        blas,
+                        palette: palette_handle,
+                        materials,
+                        emitters,
+                        node_offset: 0,
+                        shape_offset: 0,
+                        material_offset: 0,
+                    }
+                });
+                *result.lock().unwrap() = Some(entry);
             })
-            .collect::<Vec<_>>();
+            .detach();
+        scene.pending.insert(
+            model_texture.clone_weak(),
+            (palette_texture.clone_weak(), pending),
+        );
+    }
+}
 
-        println!("Voxel BVH: {}", gpu_nodes.len());
-        let gpu_bvh = GpuBVH {
-            length: gpu_nodes.len() as u32,
-            data: gpu_nodes,
-        };
+/// Voxel entities that moved or got another material.
+type ChangedVoxelEntities = (
+    With<Handle<VoxelMaterial>>,
+    Or<(Changed<GlobalTransform>, Changed<Handle<VoxelMaterial>>)>,
+);
+
+/// Rebuilds the top level BVH and uploads whatever changed, only when entities or models changed.
+#[allow(clippy::too_many_arguments)]
+fn update_voxel_tlas(
+    mut scene: ResMut<VoxelGISceneBuilder>,
+    vox_materials: Res<Assets<VoxelMaterial>>,
+    entities: Query<(&GlobalTransform, &Handle<VoxelMaterial>)>,
+    changed: Query<(), ChangedVoxelEntities>,
+    mut removed: RemovedComponents<Handle<VoxelMaterial>>,
+    mut material_events: EventReader<AssetEvent<VoxelMaterial>>,
+    render_device: Res<bevy::render::renderer::RenderDevice>,
+    render_queue: Res<bevy::render::renderer::RenderQueue>,
+    mut blas_builds: ResMut<VoxelGIBlasBuilds>,
+) {
+    let scene = &mut *scene;
+    // drain the readers, unread events would show up again next frame
+    scene.tlas_changed |= removed.iter().count() > 0 || !changed.is_empty();
+    // materials only get their model texture once the `.vox` file is loaded, other
+    // material changes like emission or roughness don't affect the TLAS
+    for event in material_events.iter() {
+        match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => {
+                // weak handles, the map shouldn't keep textures alive
+                let textures = vox_materials.get(handle).map(|material| {
+                    (
+                        material.model_texture.as_ref().map(Handle::clone_weak),
+                        material.palette_texture.as_ref().map(Handle::clone_weak),
+                    )
+                });
+                let textures = textures.unwrap_or_default();
+                if scene.material_textures.get(handle) != Some(&textures) {
+                    scene
+                        .material_textures
+                        .insert(handle.clone_weak(), textures);
+                    scene.tlas_changed = true;
+                }
+            }
+            AssetEvent::Removed { handle } => {
+                scene.material_textures.remove(handle);
+                scene.tlas_changed = true;
+            }
+        }
+    }
+    scene.tlas_changed |= scene.blases_changed;
+
+    if scene.blases_changed {
+        let mut nodes = vec![];
+        let mut shapes = vec![];
+        let mut materials = vec![];
+        let mut jobs = vec![];
+        for entry in scene.blases.values_mut() {
+            entry.node_offset = nodes.len() as u32;
+            entry.shape_offset = shapes.len() as u32;
+            entry.material_offset = materials.len() as u32;
+            let material_offset = entry.material_offset;
+            jobs.push(BlasBuildJob {
+                node_offset: entry.node_offset,
+                shape_offset: entry.shape_offset,
+                shape_count: entry.blas.shapes.len() as u32,
+                aabb_min: entry.blas.aabb_min,
+                aabb_max: entry.blas.aabb_max,
+            });
+            nodes.extend(placeholder_nodes(entry.blas.node_count));
+            shapes.extend(entry.blas.shapes.iter().map(|shape| GpuShape {
+                material_offset,
+                ..*shape
+            }));
+            materials.extend_from_slice(&entry.materials);
+        }
+
+        if !nodes.is_empty() {
+            let mut blas_nodes = StorageBuffer::<GpuBVH>::default();
+            blas_nodes.set(GpuBVH {
+                length: nodes.len() as u32,
+                data: nodes,
+            });
+            blas_nodes.set_label(Some("Voxel BLAS nodes"));
+            blas_nodes.write_buffer(&render_device, &render_queue);
+
+            let mut shapes_buf = StorageBuffer::<GpuShapes>::default();
+            shapes_buf.set(GpuShapes {
+                length: shapes.len() as u32,
+                data: shapes,
+            });
+            shapes_buf.set_label(Some("Voxel BVH Shapes"));
+            shapes_buf.write_buffer(&render_device, &render_queue);
+
+            let mut materials_buf = StorageBuffer::<GpuVoxelMaterials>::default();
+            materials_buf.set(GpuVoxelMaterials {
+                length: materials.len() as u32,
+                data: materials,
+            });
+            materials_buf.set_label(Some("Voxel Materials"));
+            materials_buf.write_buffer(&render_device, &render_queue);
+
+            scene.blas_nodes = Arc::new(blas_nodes);
+            scene.shapes = Arc::new(shapes_buf);
+            scene.materials = Arc::new(materials_buf);
+
+            // the render world fills in the nodes before anything traces them
+            *blas_builds = VoxelGIBlasBuilds {
+                generation: blas_builds.generation.wrapping_add(1),
+                jobs,
+                nodes: scene.blas_nodes.clone(),
+                shapes: scene.shapes.clone(),
+            };
+        }
+        scene.blases_changed = false;
+    }
+
+    if scene.tlas_changed {
+        let mut instances = vec![];
+        let mut boxes = vec![];
+        let mut lights = vec![];
+        let mut light_power = vec![];
+        for (transform, material) in entities.iter() {
+            let Some(entry) = vox_materials
+                .get(material)
+                .and_then(|mat| mat.model_texture.as_ref())
+                .and_then(|texture| scene.blases.get(texture))
+            else {
+                continue;
+            };
+
+            let world_from_local = transform.compute_matrix();
+            let (min, max) =
+                transform_aabb(world_from_local, entry.blas.aabb_min, entry.blas.aabb_max);
+            boxes.push((min, max));
+
+            // world area of a voxel, scaled models make bigger lights
+            let scale = world_from_local.determinant().abs().cbrt();
+            let area = 6.0 * (VOXEL_SIZE * scale).powi(2);
+            for emitter in &entry.emitters {
+                let material = entry.materials[emitter.material as usize];
+                let emitted = material.albedo * material.emissive;
+                light_power.push(emitted.dot(Vec3::new(0.2126, 0.7152, 0.0722)) * area);
+                lights.push(GpuLight {
+                    center: emitter.center,
+                    size: VOXEL_SIZE,
+                    instance: instances.len() as u32,
+                    material: entry.material_offset + emitter.material,
+                    ..default()
+                });
+            }
+            instances.push(GpuInstance {
+                world_from_local,
+                local_from_world: world_from_local.inverse(),
+                node_offset: entry.node_offset,
+                node_count: entry.blas.node_count,
+                shape_offset: entry.shape_offset,
+            });
+        }
+
+        // one leaf per placed model, small enough to build right here
+        let gpu_nodes = build_lbvh(&boxes);
 
         let mut bvh = StorageBuffer::<GpuBVH>::default();
-        bvh.set(gpu_bvh);
+        bvh.set(GpuBVH {
+            length: gpu_nodes.len() as u32,
+            // runtime sized arrays can't be empty
+            data: if gpu_nodes.is_empty() {
+                vec![GpuFlatNode::default()]
+            } else {
+                gpu_nodes
+            },
+        });
         bvh.set_label(Some("Voxel BVH"));
         bvh.write_buffer(&render_device, &render_queue);
 
-        let mut shapes_buf = StorageBuffer::<GpuShapes>::default();
-        shapes_buf.set(GpuShapes {
-            length: shapes.len() as u32,
-            data: shapes
-                .iter()
-                .map(|shape| GpuShape {
-                    aabb_min: shape.aabb().min.to_array().into(),
-                    aabb_max: shape.aabb().max.to_array().into(),
-                    _pad: Vec2::default(),
-                    voxels: shape.voxels,
-                })
-                .collect::<Vec<_>>(),
+        let mut instances_buf = StorageBuffer::<GpuInstances>::default();
+        instances_buf.set(GpuInstances {
+            length: instances.len() as u32,
+            data: if instances.is_empty() {
+                vec![GpuInstance::default()]
+            } else {
+                instances
+            },
         });
-        shapes_buf.set_label(Some("Voxel BVH Shapes"));
-        shapes_buf.write_buffer(&render_device, &render_queue);
+        instances_buf.set_label(Some("Voxel BVH Instances"));
+        instances_buf.write_buffer(&render_device, &render_queue);
+
+        let total_power = light_power.iter().sum::<f32>();
+        for (light, (probability, alias)) in lights.iter_mut().zip(build_alias_table(&light_power))
+        {
+            light.probability = probability;
+            light.alias = alias;
+        }
+        // without any power the alias table picks every light alike, so must the pdf
+        let light_count = lights.len() as f32;
+        for (light, power) in lights.iter_mut().zip(&light_power) {
+            light.pdf = if total_power > 0.0 {
+                power / total_power
+            } else {
+                1.0 / light_count
+            };
+        }
+        let mut lights_buf = StorageBuffer::<GpuLights>::default();
+        lights_buf.set(GpuLights {
+            length: lights.len() as u32,
+            data: if lights.is_empty() {
+                vec![GpuLight::default()]
+            } else {
+                lights
+            },
+        });
+        lights_buf.set_label(Some("Voxel GI Lights"));
+        lights_buf.write_buffer(&render_device, &render_queue);
+
+        scene.bvh = Arc::new(bvh);
+        scene.instances = Arc::new(instances_buf);
+        scene.lights = Arc::new(lights_buf);
+
+        let usage = scene.memory_usage();
+        debug!(
+            "Voxel GI scene: {} models, {} bricks, {} lights, {} KiB total ({} KiB bricks, {} KiB BVH nodes)",
+            usage.models,
+            usage.bricks,
+            usage.lights,
+            usage.total_bytes() / 1024,
+            usage.brick_bytes / 1024,
+            (usage.blas_node_bytes + usage.tlas_node_bytes) / 1024,
+        );
+    }
+
+    if scene.tlas_changed {
+        scene.generation = scene.generation.wrapping_add(1);
+    }
+    scene.tlas_changed = false;
+}
+
+/// Hands new scene buffers over to the render world, only when the builder replaced them.
+fn extract_scene(mut scene: ResMut<VoxelGIScene>, builder: Extract<Res<VoxelGISceneBuilder>>) {
+    if scene.generation == builder.generation {
+        return;
+    }
+    scene.bvh = builder.bvh.clone();
+    scene.blas_nodes = builder.blas_nodes.clone();
+    scene.shapes = builder.shapes.clone();
+    scene.instances = builder.instances.clone();
+    scene.materials = builder.materials.clone();
+    scene.lights = builder.lights.clone();
+    scene.generation = builder.generation;
+}
 
-        voxel_bvh.bvh = Arc::new(bvh);
-        voxel_bvh.shapes = Arc::new(shapes_buf);
-        voxel_bvh.is_updated = true;
+/// World space bounds of a model space box.
+pub(super) fn transform_aabb(world_from_local: Mat4, min: Vec3, max: Vec3) -> (Vec3, Vec3) {
+    let mut world_min = Vec3::splat(f32::MAX);
+    let mut world_max = Vec3::splat(f32::MIN);
+    for corner in 0..8 {
+        let local = Vec3::new(
+            if corner & 1 == 0 { min.x } else { max.x },
+            if corner & 2 == 0 { min.y } else { max.y },
+            if corner & 4 == 0 { min.z } else { max.z },
+        );
+        let world = world_from_local.transform_point3(local);
+        world_min = world_min.min(world);
+        world_max = world_max.max(world);
     }
+    (world_min, world_max)
 }
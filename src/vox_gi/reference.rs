@@ -0,0 +1,923 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::{anyhow, Context};
+use bevy::{
+    math::{Vec3Swizzles, Vec4Swizzles},
+    prelude::*,
+};
+
+use super::{
+    lbvh::build_lbvh,
+    pipeline::VoxelGISettings,
+    plugin::{
+        build_blas, build_materials, transform_aabb, GpuFlatNode, GpuInstance, GpuShape,
+        GpuVoxelMaterial, BRICK_SIZE,
+    },
+    sky::{PreethamSky, VoxelGISky},
+};
+
+/// Offset along the normal that keeps bounces from hitting the surface they start on.
+const SURFACE_OFFSET: f32 = 0.001;
+
+/// A model placed in a [`VoxelGIReferenceScene`] built without an app.
+pub struct VoxelGIReferenceModel<'a> {
+    pub model_texture: &'a Image,
+    pub palette_texture: &'a Image,
+    /// Emission strength of every palette entry, see [`get_palette_emission`](crate::vox::get_palette_emission)
+    pub emission: &'a [f32],
+    pub transform: Mat4,
+}
+
+/// CPU copy of the GI scene in the layout the shaders read, traced the same way `voxel_gi.wgsl`
+/// traces it. Renders reference images without a GPU, to check the GI passes against. Emissive
+/// voxels are only found by bouncing into them, the shader samples them directly instead, both
+/// converge to the same image.
+pub struct VoxelGIReferenceScene {
+    pub(super) tlas: Vec<GpuFlatNode>,
+    pub(super) blas_nodes: Vec<GpuFlatNode>,
+    pub(super) shapes: Vec<GpuShape>,
+    pub(super) instances: Vec<GpuInstance>,
+    pub(super) materials: Vec<GpuVoxelMaterial>,
+}
+
+struct Hit {
+    distance: f32,
+    normal: Vec3,
+    /// Index into the materials, relative to the model while inside a BLAS
+    material: u32,
+}
+
+impl VoxelGIReferenceScene {
+    /// Packs the models the same way the GI plugin does, see
+    /// [`VoxelGISceneBuilder::reference_scene`](super::plugin::VoxelGISceneBuilder::reference_scene)
+    /// for the scene of a running app.
+    pub fn from_models(models: &[VoxelGIReferenceModel]) -> Self {
+        let mut scene = Self {
+            tlas: vec![],
+            blas_nodes: vec![],
+            shapes: vec![],
+            instances: vec![],
+            materials: vec![],
+        };
+        let mut boxes = vec![];
+        for model in models {
+            let Some(blas) = build_blas(model.model_texture) else {
+                continue;
+            };
+            let nodes = build_lbvh(
+                &blas
+                    .shapes
+                    .iter()
+                    .map(|shape| (shape.aabb_min, shape.aabb_max))
+                    .collect::<Vec<_>>(),
+            );
+            let material_offset = scene.materials.len() as u32;
+            scene.instances.push(GpuInstance {
+                world_from_local: model.transform,
+                local_from_world: model.transform.inverse(),
+                node_offset: scene.blas_nodes.len() as u32,
+                node_count: nodes.len() as u32,
+                shape_offset: scene.shapes.len() as u32,
+            });
+            boxes.push(transform_aabb(
+                model.transform,
+                blas.aabb_min,
+                blas.aabb_max,
+            ));
+
+            scene.blas_nodes.extend(nodes);
+            scene
+                .shapes
+                .extend(blas.shapes.iter().map(|shape| GpuShape {
+                    material_offset,
+                    ..*shape
+                }));
+            scene
+                .materials
+                .extend(build_materials(model.palette_texture, model.emission));
+        }
+        scene.tlas = build_lbvh(&boxes);
+        scene
+    }
+
+    /// World space bounds of everything in the scene, `None` when it's empty.
+    pub fn bounds(&self) -> Option<(Vec3, Vec3)> {
+        self.tlas.first().map(|root| (root.aabb_min, root.aabb_max))
+    }
+
+    /// Path traces the view of a camera at `transform`, averaging `samples` paths per pixel.
+    /// Uses the bounces, max distance and sky intensity of `settings`, like the GI shader does.
+    /// Environment maps aren't sampled on the CPU, the gradient sky stands in for them.
+    #[allow(clippy::too_many_arguments)]
+    pub fn render(
+        &self,
+        transform: &GlobalTransform,
+        projection: &PerspectiveProjection,
+        size: UVec2,
+        settings: &VoxelGISettings,
+        sun_direction: Vec3,
+        sky: &VoxelGISky,
+        samples: u32,
+    ) -> VoxelGIReferenceImage {
+        let projection = Mat4::perspective_infinite_reverse_rh(
+            projection.fov,
+            size.x as f32 / size.y as f32,
+            projection.near,
+        );
+        let tracer = Tracer {
+            scene: self,
+            settings,
+            sun_direction: sun_direction.normalize(),
+            preetham: match sky {
+                VoxelGISky::Preetham { turbidity } => {
+                    Some(PreethamSky::new(sun_direction.normalize(), *turbidity))
+                }
+                _ => None,
+            },
+            inverse_view_proj: transform.compute_matrix() * projection.inverse(),
+            origin: transform.translation(),
+        };
+
+        let mut pixels = vec![Vec3::ZERO; (size.x * size.y) as usize];
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let rows_per_thread = (size.y as usize).div_ceil(threads).max(1);
+        std::thread::scope(|scope| {
+            for (chunk, rows) in pixels
+                .chunks_mut(rows_per_thread * size.x as usize)
+                .enumerate()
+            {
+                let tracer = &tracer;
+                scope.spawn(move || {
+                    let first_pixel = chunk * rows_per_thread * size.x as usize;
+                    for (i, pixel) in rows.iter_mut().enumerate() {
+                        let index = (first_pixel + i) as u32;
+                        let coords = UVec2::new(index % size.x, index / size.x);
+                        *pixel = (0..samples)
+                            .map(|sample| tracer.sample(coords, size, sample))
+                            .sum::<Vec3>()
+                            / samples.max(1) as f32;
+                    }
+                });
+            }
+        });
+
+        VoxelGIReferenceImage { size, pixels }
+    }
+}
+
+struct Tracer<'a> {
+    scene: &'a VoxelGIReferenceScene,
+    settings: &'a VoxelGISettings,
+    sun_direction: Vec3,
+    preetham: Option<PreethamSky>,
+    inverse_view_proj: Mat4,
+    origin: Vec3,
+}
+
+impl<'a> Tracer<'a> {
+    /// One path through `pixel`, seeded like the GI shader seeds frame `sample`.
+    fn sample(&self, pixel: UVec2, size: UVec2, sample: u32) -> Vec3 {
+        let pixel_uv = (pixel.as_vec2() + 0.5) / size.as_vec2();
+        let pixel_ndc = pixel_uv * 2.0 - 1.0;
+        let target = self.inverse_view_proj * Vec4::new(pixel_ndc.x, -pixel_ndc.y, 1.0, 1.0);
+        let direction = (target.xyz() / target.w - self.origin).normalize();
+
+        let Some(hit) = self.trace(self.origin, direction) else {
+            return self.sky(direction);
+        };
+        let mut rng = hash((pixel.x + pixel.y * size.x) ^ hash(sample));
+        let material = self.scene.materials[hit.material as usize];
+        let hit_point = self.origin + direction * hit.distance + hit.normal * SURFACE_OFFSET;
+
+        let radiance = self.trace_indirect(hit_point, hit.normal, &mut rng)
+            + self.direct_light(hit_point, hit.normal);
+        material.albedo * (radiance + material.emissive)
+    }
+
+    /// Light arriving at a surface through the configured diffuse bounces, without direct sunlight.
+    fn trace_indirect(&self, position: Vec3, normal: Vec3, rng: &mut u32) -> Vec3 {
+        let mut radiance = Vec3::ZERO;
+        let mut throughput = Vec3::ONE;
+        let mut origin = position;
+        let mut surface_normal = normal;
+        for _ in 0..self.settings.bounces {
+            let direction = random_bounce(surface_normal, rng);
+            let Some(hit) = self.trace(origin, direction) else {
+                radiance += throughput * self.sky(direction);
+                break;
+            };
+
+            let material = self.scene.materials[hit.material as usize];
+            origin = origin + direction * hit.distance + hit.normal * SURFACE_OFFSET;
+            surface_normal = hit.normal;
+            radiance += throughput
+                * (material.albedo * material.emissive
+                    + material.albedo * self.direct_light(origin, surface_normal));
+            throughput *= material.albedo;
+        }
+        radiance
+    }
+
+    /// Sunlight reaching a surface, zero when something is in the way.
+    fn direct_light(&self, position: Vec3, normal: Vec3) -> Vec3 {
+        let n_dot_l = normal.dot(self.sun_direction);
+        if n_dot_l <= 0.0 || self.trace(position, self.sun_direction).is_some() {
+            return Vec3::ZERO;
+        }
+        Vec3::splat(n_dot_l)
+    }
+
+    fn sky(&self, direction: Vec3) -> Vec3 {
+        let radiance = match &self.preetham {
+            Some(preetham) => preetham.radiance(direction),
+            None => {
+                let t = (direction.y * 0.5 + 0.5).clamp(0.0, 1.0);
+                Vec3::new(0.8, 0.85, 0.9).lerp(Vec3::new(0.35, 0.55, 0.9), t)
+            }
+        };
+        radiance * self.settings.sky_intensity
+    }
+
+    /// Closest hit over all models, up to the max distance of the settings.
+    fn trace(&self, origin: Vec3, direction: Vec3) -> Option<Hit> {
+        let scene = self.scene;
+        let max_distance = self.settings.max_distance;
+        let mut closest: Option<Hit> = None;
+        traverse(&scene.tlas, origin, direction, |instance_index| {
+            let instance = &scene.instances[instance_index as usize];
+            // the direction is left unnormalized so distances stay in world units
+            let local_origin = instance.local_from_world.transform_point3(origin);
+            let local_direction = instance.local_from_world.transform_vector3(direction);
+            let limit = closest.as_ref().map_or(max_distance, |hit| hit.distance);
+            if let Some(hit) = self.trace_blas(local_origin, local_direction, instance, limit) {
+                let normal_from_local = instance.local_from_world.transpose();
+                closest = Some(Hit {
+                    distance: hit.distance,
+                    normal: normal_from_local.transform_vector3(hit.normal).normalize(),
+                    material: hit.material,
+                });
+            }
+        });
+        closest
+    }
+
+    /// Closest hit against the bricks of one model closer than `max_distance`, in model space.
+    fn trace_blas(
+        &self,
+        origin: Vec3,
+        direction: Vec3,
+        instance: &GpuInstance,
+        max_distance: f32,
+    ) -> Option<Hit> {
+        let start = instance.node_offset as usize;
+        let nodes = &self.scene.blas_nodes[start..start + instance.node_count as usize];
+        let mut closest: Option<Hit> = None;
+        traverse(nodes, origin, direction, |shape_index| {
+            let shape = &self.scene.shapes[(instance.shape_offset + shape_index) as usize];
+            let limit = closest.as_ref().map_or(max_distance, |hit| hit.distance);
+            let dist = intersect_aabb(origin, direction, shape.aabb_min, shape.aabb_max);
+            if dist.x < dist.y && dist.y > 0.0 && dist.x < limit {
+                if let Some(hit) = traverse_voxels(origin, direction, shape, dist) {
+                    if hit.distance < limit {
+                        closest = Some(Hit {
+                            material: shape.material_offset + hit.material,
+                            ..hit
+                        });
+                    }
+                }
+            }
+        });
+        closest
+    }
+}
+
+/// Stackless walk over flat BVH nodes, calling `leaf` with the shape index of every leaf whose
+/// parents the ray passes through.
+fn traverse(nodes: &[GpuFlatNode], origin: Vec3, direction: Vec3, mut leaf: impl FnMut(u32)) {
+    let mut node_index = 0;
+    while let Some(node) = nodes.get(node_index as usize) {
+        if node.entry_index == u32::MAX {
+            leaf(node.shape_index);
+            node_index = node.exit_index;
+        } else if intersect_aabb_bool(origin, direction, node.aabb_min, node.aabb_max) {
+            node_index = node.entry_index;
+        } else {
+            node_index = node.exit_index;
+        }
+    }
+}
+
+fn intersect_aabb(origin: Vec3, direction: Vec3, box_min: Vec3, box_max: Vec3) -> Vec2 {
+    let t_min = (box_min - origin) / direction;
+    let t_max = (box_max - origin) / direction;
+    let t1 = t_min.min(t_max);
+    let t2 = t_min.max(t_max);
+    Vec2::new(t1.max_element(), t2.min_element())
+}
+
+fn intersect_aabb_bool(origin: Vec3, direction: Vec3, box_min: Vec3, box_max: Vec3) -> bool {
+    let dist = intersect_aabb(origin, direction, box_min, box_max);
+    dist.x.max(0.0) <= dist.y
+}
+
+/// `sign` of WGSL, zero stays zero.
+fn sign(v: Vec3) -> Vec3 {
+    Vec3::select(
+        v.cmpgt(Vec3::ZERO),
+        Vec3::ONE,
+        Vec3::select(v.cmplt(Vec3::ZERO), Vec3::NEG_ONE, Vec3::ZERO),
+    )
+}
+
+fn mask_to_vec3(mask: BVec3) -> Vec3 {
+    Vec3::select(mask, Vec3::ONE, Vec3::ZERO)
+}
+
+/// Walks the 8x8x8 voxels of a brick with a DDA, starting where the ray enters the brick.
+fn traverse_voxels(origin: Vec3, direction: Vec3, shape: &GpuShape, dist: Vec2) -> Option<Hit> {
+    let count_voxels = Vec3::splat(BRICK_SIZE as f32);
+    let voxel_size = (shape.aabb_max - shape.aabb_min) / count_voxels;
+
+    let entry = dist.x.max(0.0);
+    let pnt = (origin + direction * entry - shape.aabb_min) / voxel_size;
+
+    let max_voxels = count_voxels.as_ivec3();
+    let mut map_pos = pnt.floor().as_ivec3().clamp(IVec3::ZERO, max_voxels - 1);
+    // ray parameter needed to cross one voxel on each axis, in voxels
+    let delta_dist = (1.0 / direction).abs();
+    let ray_dir_sign = sign(direction);
+    let ray_step = ray_dir_sign.as_ivec3();
+    let mut side_dist =
+        (ray_dir_sign * (map_pos.as_vec3() - pnt) + ray_dir_sign * 0.5 + 0.5) * delta_dist;
+    // the face the ray entered through, used as normal when the first voxel is already solid
+    let t_min = (shape.aabb_min - origin) / direction;
+    let t_max = (shape.aabb_max - origin) / direction;
+    let t1 = t_min.min(t_max);
+    let mut mask = t1.cmpge(t1.yzx().max(t1.zxy()));
+
+    let max_steps = max_voxels.x + max_voxels.y + max_voxels.z;
+    for _ in 0..max_steps {
+        let voxel_index = (map_pos.x
+            + map_pos.y * max_voxels.x
+            + map_pos.z * max_voxels.x * max_voxels.y) as usize;
+        let voxel = (shape.voxels[voxel_index / 4] >> ((voxel_index % 4) * 8)) & 0xff;
+
+        if voxel != 0 {
+            // ray parameter at the face we stepped through
+            let face = mask_to_vec3(mask);
+            let face_dist = face.dot(side_dist - delta_dist) * face.dot(voxel_size);
+            return Some(Hit {
+                distance: entry + face_dist.max(0.0),
+                normal: -ray_dir_sign * face,
+                material: voxel,
+            });
+        }
+        mask = side_dist.cmple(side_dist.yzx().min(side_dist.zxy()));
+        side_dist += mask_to_vec3(mask) * delta_dist;
+        map_pos += mask_to_vec3(mask).as_ivec3() * ray_step;
+
+        if map_pos.cmplt(IVec3::ZERO).any() || map_pos.cmpge(max_voxels).any() {
+            break;
+        }
+    }
+    None
+}
+
+/// Same hash as the GI shader, so both draw the same random numbers.
+fn hash(value: u32) -> u32 {
+    let state = value.wrapping_mul(747796405).wrapping_add(2891336453);
+    let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277803737);
+    (word >> 22) ^ word
+}
+
+fn random_float(state: &mut u32) -> f32 {
+    *state = hash(*state);
+    *state as f32 / 4294967295.0
+}
+
+/// Uniformly distributed direction on the unit sphere.
+fn random_direction(state: &mut u32) -> Vec3 {
+    let z = random_float(state) * 2.0 - 1.0;
+    let a = random_float(state) * std::f32::consts::TAU;
+    let r = (1.0 - z * z).sqrt();
+    Vec3::new(r * a.cos(), r * a.sin(), z)
+}
+
+/// Cosine weighted direction around `normal`.
+fn random_bounce(normal: Vec3, state: &mut u32) -> Vec3 {
+    (normal + random_direction(state)).normalize()
+}
+
+/// Linear HDR result of [`VoxelGIReferenceScene::render`] or of a
+/// [`VoxelGIProgressive`](super::export::VoxelGIProgressive) camera.
+#[derive(Clone, Debug)]
+pub struct VoxelGIReferenceImage {
+    pub size: UVec2,
+    /// Row by row, starting at the top left
+    pub pixels: Vec<Vec3>,
+}
+
+impl VoxelGIReferenceImage {
+    /// Writes the linear radiance to a `.exr` or `.pfm`, or a tonemapped `.png` or `.ppm`, picked
+    /// by extension.
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let mut writer = BufWriter::new(File::create(path)?);
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("exr") => self.write_exr(&mut writer)?,
+            Some("pfm") => self.write_pfm(&mut writer)?,
+            Some("png") => self.write_png(&mut writer)?,
+            Some("ppm") => self.write_ppm(&mut writer)?,
+            _ => return Err(anyhow!("Unknown image format {}", path.display())),
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn load_pfm(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        Self::read_pfm(&mut BufReader::new(File::open(path)?))
+            .with_context(|| format!("Failed to load {}", path.display()))
+    }
+
+    /// Little endian RGB floats, stored bottom row first as the format wants.
+    pub fn write_pfm(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        write!(writer, "PF\n{} {}\n-1.0\n", self.size.x, self.size.y)?;
+        for row in self.pixels.chunks(self.size.x as usize).rev() {
+            for pixel in row {
+                for channel in pixel.to_array() {
+                    writer.write_all(&channel.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read_pfm(reader: &mut impl BufRead) -> anyhow::Result<Self> {
+        let mut header = vec![];
+        for _ in 0..3 {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            header.push(line.trim().to_string());
+        }
+        if header[0] != "PF" {
+            return Err(anyhow!("Only RGB .pfm files are supported"));
+        }
+        let size = match header[1]
+            .split_whitespace()
+            .map(str::parse::<u32>)
+            .collect::<Result<Vec<_>, _>>()?[..]
+        {
+            [width, height] => UVec2::new(width, height),
+            _ => return Err(anyhow!("Invalid .pfm size {}", header[1])),
+        };
+        let little_endian = header[2].parse::<f32>()? < 0.0;
+
+        let mut data = vec![0; (size.x * size.y * 12) as usize];
+        reader.read_exact(&mut data)?;
+        let pixels = data
+            .chunks_exact(12)
+            .map(|pixel| {
+                let channel = |i: usize| {
+                    let bytes = [pixel[i], pixel[i + 1], pixel[i + 2], pixel[i + 3]];
+                    if little_endian {
+                        f32::from_le_bytes(bytes)
+                    } else {
+                        f32::from_be_bytes(bytes)
+                    }
+                };
+                Vec3::new(channel(0), channel(4), channel(8))
+            })
+            .collect::<Vec<_>>();
+        // bottom row first in the file
+        let pixels = pixels
+            .chunks(size.x.max(1) as usize)
+            .rev()
+            .flatten()
+            .copied()
+            .collect();
+        Ok(Self { size, pixels })
+    }
+
+    /// Uncompressed scanline OpenEXR with 32 bit float RGB channels, top row first.
+    pub fn write_exr(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        let mut header = vec![];
+        // magic number, then version 2 of a single part scanline file
+        header.extend_from_slice(&[0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0]);
+        let mut attribute = |name: &str, kind: &str, value: &[u8]| {
+            for text in [name, kind] {
+                header.extend_from_slice(text.as_bytes());
+                header.push(0);
+            }
+            header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+            header.extend_from_slice(value);
+        };
+
+        // the channels have to be listed alphabetically
+        let mut channels = vec![];
+        for name in [b'B', b'G', b'R'] {
+            channels.extend_from_slice(&[name, 0]);
+            // float pixels, not linearly perceived, reserved, no subsampling on x and y
+            channels.extend_from_slice(&2i32.to_le_bytes());
+            channels.extend_from_slice(&[0; 4]);
+            channels.extend_from_slice(&1i32.to_le_bytes());
+            channels.extend_from_slice(&1i32.to_le_bytes());
+        }
+        channels.push(0);
+        let window = [0, 0, self.size.x as i32 - 1, self.size.y as i32 - 1]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect::<Vec<_>>();
+        attribute("channels", "chlist", &channels);
+        attribute("compression", "compression", &[0]);
+        attribute("dataWindow", "box2i", &window);
+        attribute("displayWindow", "box2i", &window);
+        attribute("lineOrder", "lineOrder", &[0]);
+        attribute("pixelAspectRatio", "float", &1.0f32.to_le_bytes());
+        attribute("screenWindowCenter", "v2f", &[0; 8]);
+        attribute("screenWindowWidth", "float", &1.0f32.to_le_bytes());
+        header.push(0);
+        writer.write_all(&header)?;
+
+        // every scanline is its own block, found through a table of file offsets
+        let line_bytes = 8 + self.size.x as u64 * 12;
+        let first_line = header.len() as u64 + self.size.y as u64 * 8;
+        for y in 0..self.size.y as u64 {
+            writer.write_all(&(first_line + y * line_bytes).to_le_bytes())?;
+        }
+        for (y, row) in self.pixels.chunks(self.size.x as usize).enumerate() {
+            writer.write_all(&(y as i32).to_le_bytes())?;
+            writer.write_all(&(self.size.x * 12).to_le_bytes())?;
+            // the channels of a line follow each other, in the order of the channel list
+            for channel in [2, 1, 0] {
+                for pixel in row {
+                    writer.write_all(&pixel[channel].to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 8 bit sRGB, tonemapped with the same Reinhard on luminance the GI camera uses.
+    pub fn write_ppm(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        write!(writer, "P6\n{} {}\n255\n", self.size.x, self.size.y)?;
+        for pixel in &self.pixels {
+            writer.write_all(&tonemap(*pixel))?;
+        }
+        Ok(())
+    }
+
+    /// Tonemapped like [`write_ppm`](Self::write_ppm), stored without compression so no deflate
+    /// implementation is needed.
+    pub fn write_png(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        fn chunk(writer: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> std::io::Result<()> {
+            writer.write_all(&(data.len() as u32).to_be_bytes())?;
+            writer.write_all(kind)?;
+            writer.write_all(data)?;
+            writer.write_all(&crc32(&[&kind[..], data]).to_be_bytes())
+        }
+
+        writer.write_all(&[0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'])?;
+        let mut header = vec![];
+        header.extend_from_slice(&self.size.x.to_be_bytes());
+        header.extend_from_slice(&self.size.y.to_be_bytes());
+        // 8 bit RGB, deflate, adaptive filtering, not interlaced
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+        chunk(writer, b"IHDR", &header)?;
+
+        // every row starts with its filter type, none
+        let mut raw = Vec::with_capacity(self.pixels.len() * 3 + self.size.y as usize);
+        for row in self.pixels.chunks(self.size.x.max(1) as usize) {
+            raw.push(0);
+            for pixel in row {
+                raw.extend_from_slice(&tonemap(*pixel));
+            }
+        }
+        // zlib stream of stored deflate blocks, each at most 65535 bytes
+        let mut data = vec![0x78, 0x01];
+        let blocks = raw.chunks(0xffff);
+        let block_count = blocks.len();
+        for (i, block) in blocks.enumerate() {
+            data.push((i + 1 == block_count) as u8);
+            data.extend_from_slice(&(block.len() as u16).to_le_bytes());
+            data.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+            data.extend_from_slice(block);
+        }
+        if block_count == 0 {
+            data.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+        }
+        data.extend_from_slice(&adler32(&raw).to_be_bytes());
+        chunk(writer, b"IDAT", &data)?;
+        chunk(writer, b"IEND", &[])
+    }
+
+    /// Root mean square difference to another image of the same size, for comparing GPU output
+    /// against a reference. `None` when the sizes differ.
+    pub fn rmse(&self, other: &Self) -> Option<f32> {
+        if self.size != other.size || self.pixels.is_empty() {
+            return None;
+        }
+        let sum = self
+            .pixels
+            .iter()
+            .zip(&other.pixels)
+            .map(|(a, b)| (*a - *b).length_squared() / 3.0)
+            .sum::<f32>();
+        Some((sum / self.pixels.len() as f32).sqrt())
+    }
+}
+
+/// 8 bit sRGB of a linear radiance, after Reinhard on luminance.
+fn tonemap(pixel: Vec3) -> [u8; 3] {
+    let luminance = pixel.dot(Vec3::new(0.2126, 0.7152, 0.0722));
+    let mapped = pixel / (1.0 + luminance);
+    let [r, g, b, _] = Color::rgb_linear(mapped.x, mapped.y, mapped.z).as_rgba_f32();
+    [r, g, b].map(|channel| (channel.clamp(0.0, 1.0) * 255.0).round() as u8)
+}
+
+/// CRC-32 of the concatenated `parts`, as PNG chunks are checked with.
+fn crc32(parts: &[&[u8]]) -> u32 {
+    let mut crc = !0u32;
+    for byte in parts.iter().flat_map(|part| part.iter()) {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb88320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// Adler-32 checksum that ends a zlib stream.
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for byte in chunk {
+            a += *byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use dot_vox::{Model, Size, Voxel};
+
+    use super::*;
+    use crate::vox::{get_model_texture, get_palette_texture};
+
+    /// Stored render of [`golden_scene`], set `SOUTHWALL_BLESS=1` to write it again after an
+    /// intended change to the tracer.
+    const GOLDEN_IMAGE: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/golden/voxel_gi_reference.pfm"
+    );
+
+    /// A floor with a pillar and a glowing block on it, seen from above at an angle.
+    fn golden_scene() -> VoxelGIReferenceImage {
+        let mut voxels = vec![];
+        for x in 0..16u8 {
+            for y in 0..16u8 {
+                voxels.push(Voxel { x, y, z: 0, i: 0 });
+            }
+        }
+        for z in 1..10u8 {
+            for x in 3..6u8 {
+                for y in 3..6u8 {
+                    voxels.push(Voxel { x, y, z, i: 1 });
+                }
+            }
+        }
+        for x in 10..13u8 {
+            for y in 9..12u8 {
+                voxels.push(Voxel { x, y, z: 1, i: 2 });
+            }
+        }
+        let model = Model {
+            size: Size {
+                x: 16,
+                y: 16,
+                z: 10,
+            },
+            voxels,
+        };
+        let color = |r, g, b| dot_vox::Color { r, g, b, a: 255 };
+        let palette = vec![
+            color(200, 200, 200),
+            color(200, 60, 40),
+            color(255, 220, 150),
+        ];
+        // palette index + 1, like `get_palette_emission`
+        let emission = [0.0, 0.0, 0.0, 4.0];
+
+        let model_texture = get_model_texture(&model);
+        let palette_texture = get_palette_texture(palette);
+        let scene = VoxelGIReferenceScene::from_models(&[VoxelGIReferenceModel {
+            model_texture: &model_texture,
+            palette_texture: &palette_texture,
+            emission: &emission,
+            transform: Mat4::IDENTITY,
+        }]);
+        let camera = Transform::from_xyz(3.0, 4.0, 5.0).looking_at(Vec3::ZERO, Vec3::Y);
+        scene.render(
+            &GlobalTransform::from(camera),
+            &PerspectiveProjection::default(),
+            UVec2::new(32, 24),
+            &VoxelGISettings::MEDIUM,
+            Vec3::new(0.4, 1.0, 0.3),
+            &VoxelGISky::Gradient,
+            16,
+        )
+    }
+
+    fn gradient_image() -> VoxelGIReferenceImage {
+        let size = UVec2::new(37, 5);
+        let pixels = (0..size.x * size.y)
+            .map(|i| Vec3::new(i as f32 * 0.05, (i % 7) as f32 * 0.3, 1.0 / (i + 1) as f32))
+            .collect();
+        VoxelGIReferenceImage { size, pixels }
+    }
+
+    #[test]
+    fn render_matches_golden_image() {
+        let image = golden_scene();
+        assert_eq!(
+            image.rmse(&golden_scene()),
+            Some(0.0),
+            "render isn't deterministic"
+        );
+
+        if std::env::var_os("SOUTHWALL_BLESS").is_some() {
+            std::fs::create_dir_all(Path::new(GOLDEN_IMAGE).parent().unwrap()).unwrap();
+            image.save(GOLDEN_IMAGE).unwrap();
+        }
+        let golden = VoxelGIReferenceImage::load_pfm(GOLDEN_IMAGE).unwrap();
+        let rmse = image.rmse(&golden).unwrap();
+        assert!(
+            rmse < 1e-3,
+            "render differs from {GOLDEN_IMAGE}, rmse {rmse}"
+        );
+        // something was hit and lit, the image isn't just sky
+        assert!(image.pixels.iter().any(|p| p.max_element() > 1.0));
+    }
+
+    #[test]
+    fn rmse() {
+        let image = gradient_image();
+        assert_eq!(image.rmse(&image), Some(0.0));
+        let brighter = VoxelGIReferenceImage {
+            pixels: image.pixels.iter().map(|p| *p + 0.5).collect(),
+            ..image.clone()
+        };
+        assert!((image.rmse(&brighter).unwrap() - 0.5).abs() < 1e-5);
+        let smaller = VoxelGIReferenceImage {
+            size: UVec2::new(1, 1),
+            pixels: vec![Vec3::ZERO],
+        };
+        assert_eq!(image.rmse(&smaller), None);
+    }
+
+    #[test]
+    fn pfm_round_trip() {
+        let image = gradient_image();
+        let mut bytes = vec![];
+        image.write_pfm(&mut bytes).unwrap();
+        let read = VoxelGIReferenceImage::read_pfm(&mut bytes.as_slice()).unwrap();
+        assert_eq!(read.size, image.size);
+        assert_eq!(read.pixels, image.pixels);
+    }
+
+    #[test]
+    fn ppm_is_tonemapped() {
+        let image = gradient_image();
+        let mut bytes = vec![];
+        image.write_ppm(&mut bytes).unwrap();
+        let header = b"P6\n37 5\n255\n";
+        assert_eq!(&bytes[..header.len()], header);
+        let expected = image.pixels.iter().flat_map(|p| tonemap(*p));
+        assert!(bytes[header.len()..].iter().copied().eq(expected));
+    }
+
+    #[test]
+    fn tonemap_known_values() {
+        assert_eq!(tonemap(Vec3::ZERO), [0, 0, 0]);
+        // white at luminance 1 maps to half, which is 188 in sRGB
+        assert_eq!(tonemap(Vec3::ONE), [188, 188, 188]);
+        assert_eq!(tonemap(Vec3::splat(1e9)), [255, 255, 255]);
+    }
+
+    #[test]
+    fn png_decodes() {
+        for image in [
+            gradient_image(),
+            // more than one stored deflate block
+            VoxelGIReferenceImage {
+                size: UVec2::new(200, 120),
+                pixels: (0..200 * 120)
+                    .map(|i| Vec3::splat(i as f32 * 1e-4))
+                    .collect(),
+            },
+        ] {
+            let mut bytes = vec![];
+            image.write_png(&mut bytes).unwrap();
+            let mut reader = png::Decoder::new(bytes.as_slice()).read_info().unwrap();
+            let mut decoded = vec![0; reader.output_buffer_size()];
+            let info = reader.next_frame(&mut decoded).unwrap();
+            assert_eq!((info.width, info.height), (image.size.x, image.size.y));
+            assert_eq!(info.color_type, png::ColorType::Rgb);
+            let expected = image.pixels.iter().flat_map(|p| tonemap(*p));
+            assert!(decoded[..info.buffer_size()].iter().copied().eq(expected));
+        }
+    }
+
+    /// Reads back what [`VoxelGIReferenceImage::write_exr`] writes, nothing more.
+    fn read_exr(bytes: &[u8]) -> VoxelGIReferenceImage {
+        assert_eq!(bytes[..8], [0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0]);
+        let mut attributes = HashMap::new();
+        let mut at = 8;
+        let string = |at: &mut usize| {
+            let end = *at + bytes[*at..].iter().position(|b| *b == 0).unwrap();
+            let text = std::str::from_utf8(&bytes[*at..end]).unwrap().to_string();
+            *at = end + 1;
+            text
+        };
+        while bytes[at] != 0 {
+            let name = string(&mut at);
+            let kind = string(&mut at);
+            let len = i32::from_le_bytes(bytes[at..at + 4].try_into().unwrap()) as usize;
+            attributes.insert(name, (kind, bytes[at + 4..at + 4 + len].to_vec()));
+            at += 4 + len;
+        }
+        at += 1;
+
+        let int = |bytes: &[u8], i: usize| i32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        assert_eq!(attributes["compression"], ("compression".into(), vec![0]));
+        let (kind, window) = &attributes["dataWindow"];
+        assert_eq!(kind, "box2i");
+        let size = UVec2::new(int(window, 8) as u32 + 1, int(window, 12) as u32 + 1);
+        assert_eq!(attributes["channels"].1, {
+            let mut channels = vec![];
+            for name in [b'B', b'G', b'R'] {
+                channels
+                    .extend_from_slice(&[name, 0, 2, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0]);
+            }
+            channels.push(0);
+            channels
+        });
+
+        let mut pixels = vec![Vec3::ZERO; (size.x * size.y) as usize];
+        let mut end = 0;
+        for y in 0..size.y as usize {
+            let offset = u64::from_le_bytes(bytes[at + y * 8..at + y * 8 + 8].try_into().unwrap());
+            let line = &bytes[offset as usize..];
+            assert_eq!(int(line, 0), y as i32);
+            assert_eq!(int(line, 4) as u32, size.x * 12);
+            end = end.max(offset as usize + 8 + size.x as usize * 12);
+            for (channel, axis) in [2, 1, 0].into_iter().enumerate() {
+                for x in 0..size.x as usize {
+                    let i = 8 + (channel * size.x as usize + x) * 4;
+                    pixels[y * size.x as usize + x][axis] =
+                        f32::from_le_bytes(line[i..i + 4].try_into().unwrap());
+                }
+            }
+        }
+        // the scanlines follow the offset table and end the file
+        assert_eq!(end, bytes.len());
+        VoxelGIReferenceImage { size, pixels }
+    }
+
+    #[test]
+    fn exr_round_trip() {
+        let image = gradient_image();
+        let mut bytes = vec![];
+        image.write_exr(&mut bytes).unwrap();
+        let read = read_exr(&bytes);
+        assert_eq!(read.size, image.size);
+        assert_eq!(read.pixels, image.pixels);
+    }
+
+    #[test]
+    fn checksums() {
+        // check values of the CRC-32 and Adler-32 specifications
+        assert_eq!(crc32(&[b"123456789"]), 0xcbf43926);
+        assert_eq!(crc32(&[b"1234", b"56789"]), 0xcbf43926);
+        assert_eq!(crc32(&[]), 0);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+        assert_eq!(adler32(&[]), 1);
+        // long enough to need the periodic modulo
+        let long = vec![0xff; 100_000];
+        let (a, b) = long.iter().fold((1u64, 0u64), |(a, b), byte| {
+            let a = (a + *byte as u64) % 65521;
+            (a, (b + a) % 65521)
+        });
+        assert_eq!(adler32(&long), ((b << 16) | a) as u32);
+    }
+}
@@ -1,14 +1,80 @@
-use bevy::render::{
-    render_resource::*,
-    renderer::RenderDevice,
-    view::{ViewTarget, ViewUniform, ViewUniforms},
+use bevy::{
+    core_pipeline::prepass::ViewPrepassTextures,
+    render::{
+        render_resource::*,
+        renderer::RenderDevice,
+        view::{ViewUniform, ViewUniforms},
+    },
 };
 
 use super::{
-    pipeline::{VoxelGI, VoxelGIPipeline},
-    plugin::{GpuBVH, GpuShapes},
+    cone::{ClipmapParams, ClipmapPipeline},
+    export::{
+        AccumulateParams, VoxelGIAccumulatePipeline, VoxelGIAccumulation, VoxelGIAccumulations,
+    },
+    lbvh::{LbvhParams, LbvhScratch},
+    pipeline::{
+        AtrousStep, VoxelGIDenoisePipeline, VoxelGIPipeline, VoxelGIScene, VoxelGITextures,
+        VoxelGIUpsampleTextures, VoxelGIViewUniform, VoxelGIViewUniforms, VOXEL_GI_TEXTURE_FORMAT,
+    },
+    plugin::{GpuBVH, GpuInstances, GpuLights, GpuShapes, GpuVoxelMaterials},
+    sky::VoxelGISkyTexture,
 };
 
+/// Texture read with `textureLoad` by the GI passes.
+fn texture_entry(binding: u32) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::Texture {
+            sample_type: TextureSampleType::Float { filterable: false },
+            view_dimension: TextureViewDimension::D2,
+            multisampled: false,
+        },
+        count: None,
+    }
+}
+
+fn storage_texture_entry(binding: u32) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::StorageTexture {
+            access: StorageTextureAccess::WriteOnly,
+            format: VOXEL_GI_TEXTURE_FORMAT,
+            view_dimension: TextureViewDimension::D2,
+        },
+        count: None,
+    }
+}
+
+fn gi_view_uniform_entry(binding: u32) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::Buffer {
+            ty: BufferBindingType::Uniform,
+            has_dynamic_offset: true,
+            min_binding_size: Some(VoxelGIViewUniform::min_size()),
+        },
+        count: None,
+    }
+}
+
+/// Storage buffer of the BVH builder and the clipmap passes.
+fn storage_buffer_entry(binding: u32, read_only: bool) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::Buffer {
+            ty: BufferBindingType::Storage { read_only },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
+
 pub fn create_view_bind_group_layout(render_device: &RenderDevice) -> BindGroupLayout {
     render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
         label: Some("voxel_gi_view_bind_group_layout"),
@@ -24,45 +90,531 @@ pub fn create_view_bind_group_layout(render_device: &RenderDevice) -> BindGroupL
                 },
                 count: None,
             },
-            // Output texture
+            // radiance, albedo, normal and depth, motion
+            storage_texture_entry(1),
+            storage_texture_entry(2),
+            storage_texture_entry(3),
+            storage_texture_entry(4),
+            gi_view_uniform_entry(5),
+        ],
+    })
+}
+
+pub fn create_view_bind_group(
+    view_uniforms: &ViewUniforms,
+    gi_view_uniforms: &VoxelGIViewUniforms,
+    textures: &VoxelGITextures,
+    frame: u32,
+    pipeline: &VoxelGIPipeline,
+    render_device: &RenderDevice,
+) -> Option<BindGroup> {
+    let (Some(view_uniforms), Some(gi_view_uniforms)) = (
+        view_uniforms.uniforms.binding(),
+        gi_view_uniforms.uniforms.binding(),
+    ) else {
+        return None;
+    };
+    let normal_depth = VoxelGITextures::current(&textures.normal_depth, frame);
+    Some(render_device.create_bind_group(&BindGroupDescriptor {
+        label: Some("voxel_gi_view_bind_group"),
+        layout: &pipeline.view_bind_group_layout,
+        entries: &[
+            BindGroupEntry {
+                binding: 0,
+                resource: view_uniforms,
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: BindingResource::TextureView(&textures.radiance.default_view),
+            },
+            BindGroupEntry {
+                binding: 2,
+                resource: BindingResource::TextureView(&textures.albedo.default_view),
+            },
+            BindGroupEntry {
+                binding: 3,
+                resource: BindingResource::TextureView(&normal_depth.default_view),
+            },
+            BindGroupEntry {
+                binding: 4,
+                resource: BindingResource::TextureView(&textures.motion.default_view),
+            },
+            BindGroupEntry {
+                binding: 5,
+                resource: gi_view_uniforms,
+            },
+        ],
+    }))
+}
+
+pub fn create_guide_bind_group_layout(render_device: &RenderDevice) -> BindGroupLayout {
+    render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+        label: Some("voxel_gi_guide_bind_group_layout"),
+        entries: &[
+            // View uniforms
             BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: Some(ViewUniform::min_size()),
+                },
+                count: None,
+            },
+            // full resolution albedo, normal and depth
+            storage_texture_entry(1),
+            storage_texture_entry(2),
+            gi_view_uniform_entry(3),
+        ],
+    })
+}
+
+pub fn create_guide_bind_group(
+    view_uniforms: &ViewUniforms,
+    gi_view_uniforms: &VoxelGIViewUniforms,
+    upsample: &VoxelGIUpsampleTextures,
+    pipeline: &VoxelGIPipeline,
+    render_device: &RenderDevice,
+) -> Option<BindGroup> {
+    let (Some(view_uniforms), Some(gi_view_uniforms)) = (
+        view_uniforms.uniforms.binding(),
+        gi_view_uniforms.uniforms.binding(),
+    ) else {
+        return None;
+    };
+    Some(render_device.create_bind_group(&BindGroupDescriptor {
+        label: Some("voxel_gi_guide_bind_group"),
+        layout: &pipeline.guide_bind_group_layout,
+        entries: &[
+            BindGroupEntry {
+                binding: 0,
+                resource: view_uniforms,
+            },
+            BindGroupEntry {
                 binding: 1,
+                resource: BindingResource::TextureView(&upsample.albedo.default_view),
+            },
+            BindGroupEntry {
+                binding: 2,
+                resource: BindingResource::TextureView(&upsample.normal_depth.default_view),
+            },
+            BindGroupEntry {
+                binding: 3,
+                resource: gi_view_uniforms,
+            },
+        ],
+    }))
+}
+
+pub fn create_prepass_bind_group_layout(render_device: &RenderDevice) -> BindGroupLayout {
+    render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+        label: Some("voxel_gi_prepass_bind_group_layout"),
+        entries: &[
+            // depth
+            BindGroupLayoutEntry {
+                binding: 0,
                 visibility: ShaderStages::COMPUTE,
-                ty: BindingType::StorageTexture {
-                    access: StorageTextureAccess::ReadWrite,
-                    format: ViewTarget::TEXTURE_FORMAT_HDR,
+                ty: BindingType::Texture {
+                    sample_type: TextureSampleType::Depth,
                     view_dimension: TextureViewDimension::D2,
+                    multisampled: false,
                 },
                 count: None,
             },
+            // world normals
+            texture_entry(1),
         ],
     })
 }
 
-pub fn create_view_bind_group(
+/// Bind group of the prepass textures, `None` if the view lacks the depth or normal prepass.
+pub fn create_prepass_bind_group(
+    prepass_textures: &ViewPrepassTextures,
+    pipeline: &VoxelGIPipeline,
+    render_device: &RenderDevice,
+) -> Option<BindGroup> {
+    let (Some(depth), Some(normal)) = (&prepass_textures.depth, &prepass_textures.normal) else {
+        return None;
+    };
+    Some(render_device.create_bind_group(&BindGroupDescriptor {
+        label: Some("voxel_gi_prepass_bind_group"),
+        layout: &pipeline.prepass_bind_group_layout,
+        entries: &[
+            BindGroupEntry {
+                binding: 0,
+                resource: BindingResource::TextureView(&depth.default_view),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: BindingResource::TextureView(&normal.default_view),
+            },
+        ],
+    }))
+}
+
+pub fn create_probe_bind_group_layout(render_device: &RenderDevice) -> BindGroupLayout {
+    render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+        label: Some("voxel_gi_probe_bind_group_layout"),
+        entries: &[
+            // View uniforms, the grid is centered on the camera
+            BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: Some(ViewUniform::min_size()),
+                },
+                count: None,
+            },
+            // probes
+            BindGroupLayoutEntry {
+                binding: 1,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: false },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            // irradiance atlas
+            storage_texture_entry(2),
+            gi_view_uniform_entry(3),
+        ],
+    })
+}
+
+pub fn create_probe_bind_group(
     view_uniforms: &ViewUniforms,
-    view_target: &ViewTarget,
+    gi_view_uniforms: &VoxelGIViewUniforms,
+    irradiance: &TextureView,
     pipeline: &VoxelGIPipeline,
     render_device: &RenderDevice,
 ) -> Option<BindGroup> {
-    view_uniforms.uniforms.binding().map(|view_uniforms| {
-        render_device.create_bind_group(&BindGroupDescriptor {
-            label: Some("voxel_gi_view_bind_group"),
-            layout: &pipeline.view_bind_group_layout,
-            entries: &[
-                BindGroupEntry {
-                    binding: 0,
-                    resource: view_uniforms.clone(),
-                },
-                BindGroupEntry {
-                    binding: 1,
-                    resource: BindingResource::TextureView(view_target.main_texture()),
-                },
-            ],
-        })
+    let (Some(view_uniforms), Some(gi_view_uniforms)) = (
+        view_uniforms.uniforms.binding(),
+        gi_view_uniforms.uniforms.binding(),
+    ) else {
+        return None;
+    };
+    Some(render_device.create_bind_group(&BindGroupDescriptor {
+        label: Some("voxel_gi_probe_bind_group"),
+        layout: &pipeline.probe_bind_group_layout,
+        entries: &[
+            BindGroupEntry {
+                binding: 0,
+                resource: view_uniforms,
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: pipeline.probes.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 2,
+                resource: BindingResource::TextureView(irradiance),
+            },
+            BindGroupEntry {
+                binding: 3,
+                resource: gi_view_uniforms,
+            },
+        ],
+    }))
+}
+
+pub fn create_shadow_bind_group_layout(render_device: &RenderDevice) -> BindGroupLayout {
+    render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+        label: Some("voxel_gi_shadow_bind_group_layout"),
+        entries: &[
+            // View uniforms
+            BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: Some(ViewUniform::min_size()),
+                },
+                count: None,
+            },
+            // shadow mask
+            storage_texture_entry(1),
+            gi_view_uniform_entry(2),
+        ],
     })
 }
 
+pub fn create_shadow_bind_group(
+    view_uniforms: &ViewUniforms,
+    gi_view_uniforms: &VoxelGIViewUniforms,
+    shadow_mask: &TextureView,
+    pipeline: &VoxelGIPipeline,
+    render_device: &RenderDevice,
+) -> Option<BindGroup> {
+    let (Some(view_uniforms), Some(gi_view_uniforms)) = (
+        view_uniforms.uniforms.binding(),
+        gi_view_uniforms.uniforms.binding(),
+    ) else {
+        return None;
+    };
+    Some(render_device.create_bind_group(&BindGroupDescriptor {
+        label: Some("voxel_gi_shadow_bind_group"),
+        layout: &pipeline.shadow_bind_group_layout,
+        entries: &[
+            BindGroupEntry {
+                binding: 0,
+                resource: view_uniforms,
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: BindingResource::TextureView(shadow_mask),
+            },
+            BindGroupEntry {
+                binding: 2,
+                resource: gi_view_uniforms,
+            },
+        ],
+    }))
+}
+
+pub fn create_temporal_bind_group_layout(render_device: &RenderDevice) -> BindGroupLayout {
+    render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+        label: Some("voxel_gi_temporal_bind_group_layout"),
+        entries: &[
+            gi_view_uniform_entry(0),
+            // radiance, motion, normal and depth of this and the previous frame
+            texture_entry(1),
+            texture_entry(2),
+            texture_entry(3),
+            texture_entry(4),
+            // previous history and moments
+            texture_entry(5),
+            texture_entry(6),
+            // new history and moments
+            storage_texture_entry(7),
+            storage_texture_entry(8),
+        ],
+    })
+}
+
+pub fn create_temporal_bind_group(
+    render_device: &RenderDevice,
+    pipeline: &VoxelGIDenoisePipeline,
+    gi_view_uniforms: &VoxelGIViewUniforms,
+    textures: &VoxelGITextures,
+    frame: u32,
+) -> Option<BindGroup> {
+    let gi_view_uniforms = gi_view_uniforms.uniforms.binding()?;
+    let views = [
+        &textures.radiance,
+        &textures.motion,
+        VoxelGITextures::current(&textures.normal_depth, frame),
+        VoxelGITextures::previous(&textures.normal_depth, frame),
+        VoxelGITextures::previous(&textures.history, frame),
+        VoxelGITextures::previous(&textures.moments, frame),
+        VoxelGITextures::current(&textures.history, frame),
+        VoxelGITextures::current(&textures.moments, frame),
+    ];
+
+    let mut entries = vec![BindGroupEntry {
+        binding: 0,
+        resource: gi_view_uniforms,
+    }];
+    entries.extend(views.iter().enumerate().map(|(i, texture)| BindGroupEntry {
+        binding: i as u32 + 1,
+        resource: BindingResource::TextureView(&texture.default_view),
+    }));
+
+    Some(render_device.create_bind_group(&BindGroupDescriptor {
+        label: Some("voxel_gi_temporal_bind_group"),
+        layout: &pipeline.temporal_bind_group_layout,
+        entries: &entries,
+    }))
+}
+
+pub fn create_accumulate_bind_group_layout(render_device: &RenderDevice) -> BindGroupLayout {
+    render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+        label: Some("voxel_gi_accumulate_bind_group_layout"),
+        entries: &[
+            BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: Some(AccumulateParams::min_size()),
+                },
+                count: None,
+            },
+            // radiance and albedo of this frame, previous sum
+            texture_entry(1),
+            texture_entry(2),
+            texture_entry(3),
+            // new sum, in full floats
+            BindGroupLayoutEntry {
+                binding: 4,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::StorageTexture {
+                    access: StorageTextureAccess::WriteOnly,
+                    format: TextureFormat::Rgba32Float,
+                    view_dimension: TextureViewDimension::D2,
+                },
+                count: None,
+            },
+            // average
+            storage_texture_entry(5),
+        ],
+    })
+}
+
+pub fn create_accumulate_bind_group(
+    render_device: &RenderDevice,
+    pipeline: &VoxelGIAccumulatePipeline,
+    accumulations: &VoxelGIAccumulations,
+    textures: &VoxelGITextures,
+    accumulation: &VoxelGIAccumulation,
+) -> Option<BindGroup> {
+    let views = [
+        &textures.radiance.default_view,
+        &textures.albedo.default_view,
+        &accumulation.previous().1,
+        &accumulation.current().1,
+        &accumulation.textures.average,
+    ];
+
+    let mut entries = vec![BindGroupEntry {
+        binding: 0,
+        resource: accumulations.params.binding()?,
+    }];
+    entries.extend(views.iter().enumerate().map(|(i, view)| BindGroupEntry {
+        binding: i as u32 + 1,
+        resource: BindingResource::TextureView(view),
+    }));
+
+    Some(render_device.create_bind_group(&BindGroupDescriptor {
+        label: Some("voxel_gi_accumulate_bind_group"),
+        layout: &pipeline.bind_group_layout,
+        entries: &entries,
+    }))
+}
+
+pub fn create_atrous_bind_group_layout(render_device: &RenderDevice) -> BindGroupLayout {
+    render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+        label: Some("voxel_gi_atrous_bind_group_layout"),
+        entries: &[
+            // input, normal and depth, moments, albedo
+            texture_entry(0),
+            texture_entry(1),
+            texture_entry(2),
+            texture_entry(3),
+            storage_texture_entry(4),
+            BindGroupLayoutEntry {
+                binding: 5,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: Some(AtrousStep::min_size()),
+                },
+                count: None,
+            },
+        ],
+    })
+}
+
+/// Bind group of one à-trous iteration, reading `input` and writing `output`.
+pub fn create_atrous_bind_group(
+    render_device: &RenderDevice,
+    pipeline: &VoxelGIDenoisePipeline,
+    textures: &VoxelGITextures,
+    frame: u32,
+    input: &TextureView,
+    output: &TextureView,
+) -> Option<BindGroup> {
+    let steps = pipeline.atrous_steps.binding()?;
+    Some(render_device.create_bind_group(&BindGroupDescriptor {
+        label: Some("voxel_gi_atrous_bind_group"),
+        layout: &pipeline.atrous_bind_group_layout,
+        entries: &[
+            BindGroupEntry {
+                binding: 0,
+                resource: BindingResource::TextureView(input),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: BindingResource::TextureView(
+                    &VoxelGITextures::current(&textures.normal_depth, frame).default_view,
+                ),
+            },
+            BindGroupEntry {
+                binding: 2,
+                resource: BindingResource::TextureView(
+                    &VoxelGITextures::current(&textures.moments, frame).default_view,
+                ),
+            },
+            BindGroupEntry {
+                binding: 3,
+                resource: BindingResource::TextureView(&textures.albedo.default_view),
+            },
+            BindGroupEntry {
+                binding: 4,
+                resource: BindingResource::TextureView(output),
+            },
+            BindGroupEntry {
+                binding: 5,
+                resource: steps,
+            },
+        ],
+    }))
+}
+
+pub fn create_upsample_bind_group_layout(render_device: &RenderDevice) -> BindGroupLayout {
+    render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+        label: Some("voxel_gi_upsample_bind_group_layout"),
+        entries: &[
+            // denoised lighting, normal and depth at GI resolution
+            texture_entry(0),
+            texture_entry(1),
+            // albedo, normal and depth at full resolution
+            texture_entry(2),
+            texture_entry(3),
+            storage_texture_entry(4),
+        ],
+    })
+}
+
+/// Bind group of the upsampling pass, `None` when the view's GI runs at full resolution.
+pub fn create_upsample_bind_group(
+    render_device: &RenderDevice,
+    pipeline: &VoxelGIDenoisePipeline,
+    textures: &VoxelGITextures,
+    frame: u32,
+) -> Option<BindGroup> {
+    let upsample = textures.upsample.as_ref()?;
+    let views = [
+        textures.output(),
+        VoxelGITextures::current(&textures.normal_depth, frame),
+        &upsample.albedo,
+        &upsample.normal_depth,
+        &upsample.output,
+    ];
+    let entries = views
+        .iter()
+        .enumerate()
+        .map(|(i, texture)| BindGroupEntry {
+            binding: i as u32,
+            resource: BindingResource::TextureView(&texture.default_view),
+        })
+        .collect::<Vec<_>>();
+
+    Some(render_device.create_bind_group(&BindGroupDescriptor {
+        label: Some("voxel_gi_upsample_bind_group"),
+        layout: &pipeline.upsample_bind_group_layout,
+        entries: &entries,
+    }))
+}
+
 pub fn create_scene_bind_group_layout(render_device: &RenderDevice) -> BindGroupLayout {
     render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
         label: Some("voxel_gi_scene_bind_group_layout"),
@@ -89,6 +641,67 @@ pub fn create_scene_bind_group_layout(render_device: &RenderDevice) -> BindGroup
                 },
                 count: None,
             },
+            // instances
+            BindGroupLayoutEntry {
+                binding: 2,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: Some(GpuInstances::min_size()),
+                },
+                count: None,
+            },
+            // blas nodes
+            BindGroupLayoutEntry {
+                binding: 3,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: Some(GpuBVH::min_size()),
+                },
+                count: None,
+            },
+            // materials
+            BindGroupLayoutEntry {
+                binding: 4,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: Some(GpuVoxelMaterials::min_size()),
+                },
+                count: None,
+            },
+            // emissive voxels with their alias table
+            BindGroupLayoutEntry {
+                binding: 5,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: Some(GpuLights::min_size()),
+                },
+                count: None,
+            },
+            // environment map of the sky
+            BindGroupLayoutEntry {
+                binding: 6,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Texture {
+                    sample_type: TextureSampleType::Float { filterable: true },
+                    view_dimension: TextureViewDimension::Cube,
+                    multisampled: false,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 7,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Sampler(SamplerBindingType::Filtering),
+                count: None,
+            },
         ],
     })
 }
@@ -96,14 +709,15 @@ pub fn create_scene_bind_group_layout(render_device: &RenderDevice) -> BindGroup
 pub fn create_scene_bind_group(
     render_device: &RenderDevice,
     pipeline: &VoxelGIPipeline,
-    voxel_gi: &VoxelGI,
+    scene: &VoxelGIScene,
+    sky: &VoxelGISkyTexture,
 ) -> Option<BindGroup> {
-    let Some(bvh_buf) = voxel_gi.bvh.binding() else {
-        return None;
-    };
-    let Some(shapes_buf) = voxel_gi.shapes.binding() else {
-        return None;
-    };
+    let bvh_buf = scene.bvh.binding()?;
+    let shapes_buf = scene.shapes.binding()?;
+    let instances_buf = scene.instances.binding()?;
+    let blas_nodes_buf = scene.blas_nodes.binding()?;
+    let materials_buf = scene.materials.binding()?;
+    let lights_buf = scene.lights.binding()?;
     Some(render_device.create_bind_group(&BindGroupDescriptor {
         label: Some("voxel_gi_scene_bind_group"),
         layout: &pipeline.scene_bind_group_layout,
@@ -116,6 +730,251 @@ pub fn create_scene_bind_group(
                 binding: 1,
                 resource: shapes_buf,
             },
+            BindGroupEntry {
+                binding: 2,
+                resource: instances_buf,
+            },
+            BindGroupEntry {
+                binding: 3,
+                resource: blas_nodes_buf,
+            },
+            BindGroupEntry {
+                binding: 4,
+                resource: materials_buf,
+            },
+            BindGroupEntry {
+                binding: 5,
+                resource: lights_buf,
+            },
+            BindGroupEntry {
+                binding: 6,
+                resource: BindingResource::TextureView(&sky.view),
+            },
+            BindGroupEntry {
+                binding: 7,
+                resource: BindingResource::Sampler(&sky.sampler),
+            },
+        ],
+    }))
+}
+
+pub fn create_lbvh_bind_group_layout(render_device: &RenderDevice) -> BindGroupLayout {
+    render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+        label: Some("voxel_gi_lbvh_bind_group_layout"),
+        entries: &[
+            // per model and radix pass parameters
+            BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: Some(LbvhParams::min_size()),
+                },
+                count: None,
+            },
+            // bricks
+            storage_buffer_entry(1, true),
+            // BLAS nodes of all models
+            storage_buffer_entry(2, false),
+            // keys and values, sorted from the first pair into the second
+            storage_buffer_entry(3, false),
+            storage_buffer_entry(4, false),
+            storage_buffer_entry(5, false),
+            storage_buffer_entry(6, false),
+            // radix sort histograms
+            storage_buffer_entry(7, false),
+            // tree
+            storage_buffer_entry(8, false),
+            // node bounds
+            storage_buffer_entry(9, false),
+        ],
+    })
+}
+
+/// `swap` sorts from the second key and value buffers into the first ones.
+pub fn create_lbvh_bind_group(
+    render_device: &RenderDevice,
+    layout: &BindGroupLayout,
+    params: &DynamicUniformBuffer<LbvhParams>,
+    shapes: &Buffer,
+    nodes: &Buffer,
+    scratch: &LbvhScratch,
+    swap: bool,
+) -> Option<BindGroup> {
+    let params = params.binding()?;
+    let (input, output) = if swap { (1, 0) } else { (0, 1) };
+    Some(render_device.create_bind_group(&BindGroupDescriptor {
+        label: Some("voxel_gi_lbvh_bind_group"),
+        layout,
+        entries: &[
+            BindGroupEntry {
+                binding: 0,
+                resource: params,
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: shapes.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 2,
+                resource: nodes.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 3,
+                resource: scratch.keys[input].as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 4,
+                resource: scratch.values[input].as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 5,
+                resource: scratch.keys[output].as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 6,
+                resource: scratch.values[output].as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 7,
+                resource: scratch.histograms.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 8,
+                resource: scratch.tree.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 9,
+                resource: scratch.bounds.as_entire_binding(),
+            },
+        ],
+    }))
+}
+
+fn clipmap_params_entry(binding: u32) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::Buffer {
+            ty: BufferBindingType::Uniform,
+            has_dynamic_offset: true,
+            min_binding_size: Some(ClipmapParams::min_size()),
+        },
+        count: None,
+    }
+}
+
+/// Layout of the pass that voxelizes one model into the clipmap buffer.
+pub fn create_voxelize_bind_group_layout(render_device: &RenderDevice) -> BindGroupLayout {
+    render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+        label: Some("voxel_cone_tracing_voxelize_bind_group_layout"),
+        entries: &[
+            // per model and level parameters
+            clipmap_params_entry(0),
+            // albedo, opacity and emission of every texel
+            storage_buffer_entry(1, false),
+            // model texture
+            BindGroupLayoutEntry {
+                binding: 2,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Texture {
+                    sample_type: TextureSampleType::Uint,
+                    view_dimension: TextureViewDimension::D3,
+                    multisampled: false,
+                },
+                count: None,
+            },
+            // palette texture
+            BindGroupLayoutEntry {
+                binding: 3,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Texture {
+                    sample_type: TextureSampleType::Float { filterable: true },
+                    view_dimension: TextureViewDimension::D1,
+                    multisampled: false,
+                },
+                count: None,
+            },
+        ],
+    })
+}
+
+/// Layout of the passes that downsample and light the clipmap buffer.
+pub fn create_clipmap_bind_group_layout(render_device: &RenderDevice) -> BindGroupLayout {
+    render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+        label: Some("voxel_cone_tracing_clipmap_bind_group_layout"),
+        entries: &[
+            clipmap_params_entry(0),
+            storage_buffer_entry(1, false),
+            // lit clipmap, sampled by the voxel materials
+            BindGroupLayoutEntry {
+                binding: 4,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::StorageTexture {
+                    access: StorageTextureAccess::WriteOnly,
+                    format: TextureFormat::Rgba16Float,
+                    view_dimension: TextureViewDimension::D3,
+                },
+                count: None,
+            },
+        ],
+    })
+}
+
+pub fn create_voxelize_bind_group(
+    render_device: &RenderDevice,
+    pipeline: &ClipmapPipeline,
+    params: &DynamicUniformBuffer<ClipmapParams>,
+    model_texture: &TextureView,
+    palette_texture: &TextureView,
+) -> Option<BindGroup> {
+    Some(render_device.create_bind_group(&BindGroupDescriptor {
+        label: Some("voxel_cone_tracing_voxelize_bind_group"),
+        layout: &pipeline.voxelize_bind_group_layout,
+        entries: &[
+            BindGroupEntry {
+                binding: 0,
+                resource: params.binding()?,
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: pipeline.voxels.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 2,
+                resource: BindingResource::TextureView(model_texture),
+            },
+            BindGroupEntry {
+                binding: 3,
+                resource: BindingResource::TextureView(palette_texture),
+            },
+        ],
+    }))
+}
+
+pub fn create_clipmap_bind_group(
+    render_device: &RenderDevice,
+    pipeline: &ClipmapPipeline,
+    params: &DynamicUniformBuffer<ClipmapParams>,
+    radiance_clipmap: &TextureView,
+) -> Option<BindGroup> {
+    Some(render_device.create_bind_group(&BindGroupDescriptor {
+        label: Some("voxel_cone_tracing_clipmap_bind_group"),
+        layout: &pipeline.clipmap_bind_group_layout,
+        entries: &[
+            BindGroupEntry {
+                binding: 0,
+                resource: params.binding()?,
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: pipeline.voxels.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 4,
+                resource: BindingResource::TextureView(radiance_clipmap),
+            },
         ],
     }))
 }
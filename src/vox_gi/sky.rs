@@ -0,0 +1,396 @@
+use bevy::{
+    prelude::*,
+    render::{
+        render_asset::RenderAssets,
+        render_resource::*,
+        renderer::{RenderDevice, RenderQueue},
+        Extract,
+    },
+};
+
+/// Preetham luminance is in kcd/m², this brings a morning sky close to the brightness of the
+/// gradient.
+const PREETHAM_SCALE: f32 = 0.1;
+
+/// Light of the rays that miss every voxel, also drawn as the background of
+/// [`VoxelGICamera3dBundle`](super::pipeline::VoxelGICamera3dBundle) cameras. Scaled by
+/// [`VoxelGISettings::sky_intensity`](super::pipeline::VoxelGISettings::sky_intensity).
+#[derive(Resource, Clone, Debug, Default)]
+pub enum VoxelGISky {
+    /// Blue gradient from the horizon to the zenith
+    #[default]
+    Gradient,
+    /// Preetham et al., "A Practical Analytic Model for Daylight", lit by the
+    /// [`VoxelGISun`](super::pipeline::VoxelGISun). Turbidity goes from 2 for a clear sky to about
+    /// 10 for a hazy one.
+    Preetham { turbidity: f32 },
+    /// HDR environment cubemap. Either a cube texture or six square faces stacked vertically in
+    /// the order +x, -x, +y, -y, +z, -z, which are turned into a cube once loaded. The gradient
+    /// stands in until then.
+    Environment(Handle<Image>),
+}
+
+/// Which sky the GI shader is specialized for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum VoxelGISkyModel {
+    #[default]
+    Gradient,
+    Preetham,
+    Environment,
+}
+
+impl VoxelGISkyModel {
+    pub fn shader_def(self) -> Option<&'static str> {
+        match self {
+            Self::Gradient => None,
+            Self::Preetham => Some("VOXEL_GI_SKY_PREETHAM"),
+            Self::Environment => Some("VOXEL_GI_SKY_ENVIRONMENT"),
+        }
+    }
+}
+
+/// Perez distribution of the Preetham sky for one sun position, in the Yxy colour space.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PreethamSky {
+    /// Coefficients A to E of the luminance and both chromaticities
+    pub coefficients: [Vec3; 5],
+    /// Yxy at the zenith, divided by the Perez function there
+    pub zenith: Vec3,
+    sun_direction: Vec3,
+}
+
+impl PreethamSky {
+    pub fn new(sun_direction: Vec3, turbidity: f32) -> Self {
+        let t = turbidity;
+        let coefficients = [
+            Vec3::new(
+                0.1787 * t - 1.4630,
+                -0.0193 * t - 0.2592,
+                -0.0167 * t - 0.2608,
+            ),
+            Vec3::new(
+                -0.3554 * t + 0.4275,
+                -0.0665 * t + 0.0008,
+                -0.0950 * t + 0.0092,
+            ),
+            Vec3::new(
+                -0.0227 * t + 5.3251,
+                -0.0004 * t + 0.2125,
+                -0.0079 * t + 0.2102,
+            ),
+            Vec3::new(
+                0.1206 * t - 2.5771,
+                -0.0641 * t - 0.8989,
+                -0.0441 * t - 1.6537,
+            ),
+            Vec3::new(
+                -0.0670 * t + 0.3703,
+                -0.0033 * t + 0.0452,
+                -0.0109 * t + 0.0529,
+            ),
+        ];
+
+        // the model only holds for a sun above the horizon
+        let sun_direction = Vec3::new(sun_direction.x, sun_direction.y.max(0.0), sun_direction.z)
+            .try_normalize()
+            .unwrap_or(Vec3::Y);
+        let theta = sun_direction.y.clamp(-1.0, 1.0).acos();
+        let chi = (4.0 / 9.0 - t / 120.0) * (std::f32::consts::PI - 2.0 * theta);
+        let zenith_luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let polynomial = |m: [[f32; 4]; 3]| {
+            let thetas = Vec4::new(theta.powi(3), theta.powi(2), theta, 1.0);
+            Vec3::new(t * t, t, 1.0).dot(Vec3::new(
+                Vec4::from(m[0]).dot(thetas),
+                Vec4::from(m[1]).dot(thetas),
+                Vec4::from(m[2]).dot(thetas),
+            ))
+        };
+        let zenith_x = polynomial([
+            [0.00166, -0.00375, 0.00209, 0.0],
+            [-0.02903, 0.06377, -0.03202, 0.00394],
+            [0.11693, -0.21196, 0.06052, 0.25886],
+        ]);
+        let zenith_y = polynomial([
+            [0.00275, -0.00610, 0.00317, 0.0],
+            [-0.04214, 0.08970, -0.04153, 0.00516],
+            [0.15346, -0.26756, 0.06670, 0.26688],
+        ]);
+
+        let mut sky = Self {
+            coefficients,
+            zenith: Vec3::ONE,
+            sun_direction,
+        };
+        let zenith = Vec3::new(
+            zenith_luminance.max(0.0) * PREETHAM_SCALE,
+            zenith_x,
+            zenith_y,
+        );
+        sky.zenith = zenith / sky.perez(1.0, theta, sun_direction.y);
+        sky
+    }
+
+    fn perez(&self, cos_theta: f32, gamma: f32, cos_gamma: f32) -> Vec3 {
+        let [a, b, c, d, e] = self.coefficients;
+        (1.0 + a * (b / cos_theta).exp())
+            * (1.0 + c * (d * gamma).exp() + e * cos_gamma * cos_gamma)
+    }
+
+    /// Linear RGB radiance coming from `direction`, as the GI shader computes it.
+    pub fn radiance(&self, direction: Vec3) -> Vec3 {
+        let cos_theta = direction.y.max(0.01);
+        let cos_gamma = direction.dot(self.sun_direction).clamp(-1.0, 1.0);
+        let yxy = self.zenith * self.perez(cos_theta, cos_gamma.acos(), cos_gamma);
+
+        let xyz = Vec3::new(
+            yxy.y / yxy.z * yxy.x,
+            yxy.x,
+            (1.0 - yxy.y - yxy.z) / yxy.z * yxy.x,
+        );
+        let rgb = Mat3::from_cols(
+            Vec3::new(3.2406, -0.9689, 0.0557),
+            Vec3::new(-1.5372, 1.8758, -0.2040),
+            Vec3::new(-0.4986, 0.0415, 1.0570),
+        ) * xyz;
+        // below the horizon the ground reflects a quarter of the horizon light
+        let ground = 0.25 + 0.75 * smoothstep(-0.2, 0.0, direction.y);
+        rgb.max(Vec3::ZERO) * ground
+    }
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+/// Turns a loaded [`VoxelGISky::Environment`] image into a cube the GI shader can sample. The
+/// texels are stored as half floats, 32 bit float images can't be filtered.
+pub fn configure_environment_map(
+    sky: Res<VoxelGISky>,
+    mut image_events: EventReader<AssetEvent<Image>>,
+    mut images: ResMut<Assets<Image>>,
+) {
+    let VoxelGISky::Environment(handle) = &*sky else {
+        return;
+    };
+    let loaded = image_events.iter().any(|event| match event {
+        AssetEvent::Created { handle: created } | AssetEvent::Modified { handle: created } => {
+            created == handle
+        }
+        AssetEvent::Removed { .. } => false,
+    });
+    if !loaded && !sky.is_changed() {
+        return;
+    }
+    // touching the image sends another event, so it's left alone once it's done and when it
+    // can't be turned into a cube, which warns once per load
+    let Some(image) = images.get(handle) else {
+        return;
+    };
+    if is_environment_cube(image) {
+        return;
+    }
+    let size = image.texture_descriptor.size;
+    let stacked = size.depth_or_array_layers == 1 && size.height == size.width * 6;
+    let layered = size.depth_or_array_layers == 6 && size.height == size.width;
+    if !stacked && !layered {
+        warn!("Voxel GI environment map must be a cube or six square faces stacked vertically");
+        return;
+    }
+    let format = image.texture_descriptor.format;
+    let Some(data) = environment_to_f16(format, &image.data) else {
+        warn!(
+            "Voxel GI environment map can't be {format:?}, only RGBA float, RGBA8 and RGB9E5 \
+            images are converted"
+        );
+        return;
+    };
+
+    let Some(image) = images.get_mut(handle) else {
+        return;
+    };
+    image.data = data;
+    image.texture_descriptor.format = TextureFormat::Rgba16Float;
+    if stacked {
+        image.reinterpret_stacked_2d_as_array(6);
+    }
+    image.texture_view_descriptor = Some(TextureViewDescriptor {
+        dimension: Some(TextureViewDimension::Cube),
+        ..default()
+    });
+}
+
+/// Texels of an environment map as RGBA half floats, `None` for formats that aren't converted.
+fn environment_to_f16(format: TextureFormat, data: &[u8]) -> Option<Vec<u8>> {
+    let channels = match format {
+        TextureFormat::Rgba16Float => return Some(data.to_vec()),
+        TextureFormat::Rgba32Float => data
+            .chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            .collect::<Vec<_>>(),
+        TextureFormat::Rgba8Unorm => data.iter().map(|value| *value as f32 / 255.0).collect(),
+        TextureFormat::Rgba8UnormSrgb => data
+            .chunks_exact(4)
+            .flat_map(|texel| {
+                let [r, g, b, a] =
+                    [texel[0], texel[1], texel[2], texel[3]].map(|value| value as f32 / 255.0);
+                [srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b), a]
+            })
+            .collect(),
+        // 9 bit mantissas sharing a 5 bit exponent with a bias of 15
+        TextureFormat::Rgb9e5Ufloat => data
+            .chunks_exact(4)
+            .flat_map(|bytes| {
+                let bits = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                let scale = 2.0f32.powi((bits >> 27) as i32 - 15 - 9);
+                let mantissa = |shift: u32| ((bits >> shift) & 0x1ff) as f32 * scale;
+                [mantissa(0), mantissa(9), mantissa(18), 1.0]
+            })
+            .collect(),
+        // block compressed HDR would have to be decoded on the CPU first
+        TextureFormat::Bc6hRgbUfloat | TextureFormat::Bc6hRgbSfloat => return None,
+        _ => return None,
+    };
+    Some(
+        channels
+            .into_iter()
+            .flat_map(|value| f32_to_f16(value).to_le_bytes())
+            .collect(),
+    )
+}
+
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Whether the GI shader can sample `image` as environment map.
+fn is_environment_cube(image: &Image) -> bool {
+    let is_cube = image
+        .texture_view_descriptor
+        .as_ref()
+        .is_some_and(|view| view.dimension == Some(TextureViewDimension::Cube));
+    let size = image.texture_descriptor.size;
+    is_cube
+        && size.depth_or_array_layers == 6
+        && size.width == size.height
+        && image.texture_descriptor.format == TextureFormat::Rgba16Float
+}
+
+/// Bits of the closest half float, clamped to the largest finite one.
+fn f32_to_f16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32 - 127 + 15;
+    let mantissa = ((bits >> 13) & 0x3ff) as u16;
+    if value.is_nan() || exponent <= 0 {
+        // too dim for a normal half, nobody sees the difference to black
+        sign
+    } else if exponent >= 31 {
+        sign | 0x7bff
+    } else {
+        sign | (exponent as u16) << 10 | mantissa
+    }
+}
+
+/// [`VoxelGISky`] in the render world, the environment map is only set once it's a cube.
+#[derive(Resource, Default)]
+pub struct ExtractedVoxelGISky {
+    pub sky: VoxelGISky,
+    pub environment: Option<Handle<Image>>,
+}
+
+pub fn extract_sky(
+    mut commands: Commands,
+    sky: Extract<Res<VoxelGISky>>,
+    images: Extract<Res<Assets<Image>>>,
+) {
+    let environment = match &**sky {
+        VoxelGISky::Environment(handle) => images
+            .get(handle)
+            .filter(|image| is_environment_cube(image))
+            .map(|_| handle.clone_weak()),
+        _ => None,
+    };
+    commands.insert_resource(ExtractedVoxelGISky {
+        sky: (**sky).clone(),
+        environment,
+    });
+}
+
+/// Cube the GI shader samples, the environment map once it's on the GPU and a black placeholder
+/// before that, and the sky model the pipelines are specialized for.
+#[derive(Resource)]
+pub struct VoxelGISkyTexture {
+    pub view: TextureView,
+    pub sampler: Sampler,
+    pub model: VoxelGISkyModel,
+    placeholder: TextureView,
+}
+
+impl FromWorld for VoxelGISkyTexture {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let render_queue = world.resource::<RenderQueue>();
+        let texture = render_device.create_texture_with_data(
+            render_queue,
+            &TextureDescriptor {
+                label: Some("voxel_gi_sky_placeholder"),
+                size: Extent3d {
+                    width: 1,
+                    height: 1,
+                    depth_or_array_layers: 6,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: TextureDimension::D2,
+                format: TextureFormat::Rgba16Float,
+                usage: TextureUsages::TEXTURE_BINDING,
+                view_formats: &[],
+            },
+            &[0; 48],
+        );
+        let placeholder = texture.create_view(&TextureViewDescriptor {
+            dimension: Some(TextureViewDimension::Cube),
+            ..default()
+        });
+        let sampler = render_device.create_sampler(&SamplerDescriptor {
+            label: Some("voxel_gi_sky_sampler"),
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            ..default()
+        });
+
+        Self {
+            view: placeholder.clone(),
+            sampler,
+            model: VoxelGISkyModel::Gradient,
+            placeholder,
+        }
+    }
+}
+
+/// Must run after the images are prepared, so a freshly configured environment map is a cube.
+pub fn prepare_sky(
+    sky: Res<ExtractedVoxelGISky>,
+    images: Res<RenderAssets<Image>>,
+    mut sky_texture: ResMut<VoxelGISkyTexture>,
+) {
+    let sky_texture = &mut *sky_texture;
+    let environment = sky
+        .environment
+        .as_ref()
+        .and_then(|handle| images.get(handle));
+    sky_texture.view = match environment {
+        Some(image) => image.texture_view.clone(),
+        None => sky_texture.placeholder.clone(),
+    };
+    sky_texture.model = match (&sky.sky, environment) {
+        (VoxelGISky::Preetham { .. }, _) => VoxelGISkyModel::Preetham,
+        (_, Some(_)) => VoxelGISkyModel::Environment,
+        _ => VoxelGISkyModel::Gradient,
+    };
+}
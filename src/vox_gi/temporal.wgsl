@@ -0,0 +1,101 @@
+struct GIView {
+    previous_view_proj: mat4x4<f32>,
+    previous_world_position: vec3<f32>,
+    frame: u32,
+    reset: u32,
+    max_distance: f32,
+    sky_intensity: f32,
+    sun_direction: vec3<f32>,
+    // Perez coefficients A to E and zenith of the Preetham sky, luminance and chromaticity xy
+    sky_coefficients: array<vec4<f32>, 5>,
+    sky_zenith: vec4<f32>,
+}
+
+@group(0) @binding(0)
+var<uniform> gi_view: GIView;
+@group(0) @binding(1)
+var radiance_texture: texture_2d<f32>;
+@group(0) @binding(2)
+var motion_texture: texture_2d<f32>;
+@group(0) @binding(3)
+var normal_depth_texture: texture_2d<f32>;
+@group(0) @binding(4)
+var previous_normal_depth_texture: texture_2d<f32>;
+@group(0) @binding(5)
+var previous_history_texture: texture_2d<f32>;
+@group(0) @binding(6)
+var previous_moments_texture: texture_2d<f32>;
+@group(0) @binding(7)
+var history_output: texture_storage_2d<rgba16float, write>;
+@group(0) @binding(8)
+var moments_output: texture_storage_2d<rgba16float, write>;
+
+// the oldest samples keep 1/32 of the weight, so lighting changes still show up
+const MAX_HISTORY = 32.0;
+
+fn luminance(color: vec3<f32>) -> f32 {
+    return dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+@compute @workgroup_size(8, 8, 1)
+fn temporal(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let size = vec2<u32>(textureDimensions(radiance_texture));
+    if global_id.x >= size.x || global_id.y >= size.y {
+        return;
+    }
+
+    let pixel = vec2<i32>(global_id.xy);
+    let current = textureLoad(radiance_texture, pixel, 0).rgb;
+    let normal_depth = textureLoad(normal_depth_texture, pixel, 0);
+    let motion = textureLoad(motion_texture, pixel, 0);
+
+    // bilinear reprojection, taps that saw a different surface are left out
+    var history = vec3<f32>(0.0);
+    var moments = vec2<f32>(0.0);
+    var history_length = 0.0;
+    var weight = 0.0;
+    if gi_view.reset == 0u && normal_depth.w >= 0.0 {
+        let previous_position = vec2<f32>(global_id.xy) - motion.xy * vec2<f32>(size);
+        let base = floor(previous_position);
+        let f = previous_position - base;
+        for (var i = 0; i < 4; i = i + 1) {
+            let offset = vec2<i32>(i & 1, i >> 1u);
+            let tap = vec2<i32>(base) + offset;
+            if any(tap < vec2<i32>(0)) || any(tap >= vec2<i32>(size)) {
+                continue;
+            }
+
+            let previous_normal_depth = textureLoad(previous_normal_depth_texture, tap, 0);
+            let same_surface = previous_normal_depth.w >= 0.0
+                && dot(previous_normal_depth.xyz, normal_depth.xyz) > 0.9
+                && abs(previous_normal_depth.w - motion.z) < 0.05 * motion.z + 0.05;
+            if !same_surface {
+                continue;
+            }
+
+            let bilinear = select(1.0 - f.x, f.x, offset.x == 1) * select(1.0 - f.y, f.y, offset.y == 1);
+            let previous = textureLoad(previous_history_texture, tap, 0);
+            history += previous.rgb * bilinear;
+            history_length += previous.a * bilinear;
+            moments += textureLoad(previous_moments_texture, tap, 0).xy * bilinear;
+            weight += bilinear;
+        }
+    }
+
+    let current_luminance = luminance(current);
+    var color = current;
+    var new_moments = vec2<f32>(current_luminance, current_luminance * current_luminance);
+    var new_length = 1.0;
+    if weight > 0.001 {
+        new_length = min(history_length / weight + 1.0, MAX_HISTORY);
+        let alpha = 1.0 / new_length;
+        color = mix(history / weight, current, alpha);
+        new_moments = mix(moments / weight, new_moments, max(alpha, 0.2));
+    }
+
+    // the variance estimate is poor for the first few frames, let the filter blur more until then
+    let variance = max(new_moments.y - new_moments.x * new_moments.x, 0.0) * select(1.0, 4.0 / new_length, new_length < 4.0);
+
+    textureStore(history_output, pixel, vec4<f32>(color, new_length));
+    textureStore(moments_output, pixel, vec4<f32>(new_moments, variance, 0.0));
+}
@@ -0,0 +1,64 @@
+@group(0) @binding(0)
+var lighting_texture: texture_2d<f32>;
+@group(0) @binding(1)
+var normal_depth_texture: texture_2d<f32>;
+@group(0) @binding(2)
+var guide_albedo_texture: texture_2d<f32>;
+@group(0) @binding(3)
+var guide_normal_depth_texture: texture_2d<f32>;
+@group(0) @binding(4)
+var output_texture: texture_storage_2d<rgba16float, write>;
+
+// Joint bilateral upsampling of the denoised lighting. The 4 nearest low resolution pixels are
+// weighted by how well their normal and depth match the full resolution primary hit, then the
+// full resolution albedo is put back so the voxel colours stay sharp.
+@compute @workgroup_size(8, 8, 1)
+fn upsample(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let size = vec2<u32>(textureDimensions(guide_albedo_texture));
+    if global_id.x >= size.x || global_id.y >= size.y {
+        return;
+    }
+
+    let pixel = vec2<i32>(global_id.xy);
+    let low_size = vec2<i32>(textureDimensions(lighting_texture));
+    let albedo = textureLoad(guide_albedo_texture, pixel, 0);
+    let normal_depth = textureLoad(guide_normal_depth_texture, pixel, 0);
+    let is_sky = normal_depth.w < 0.0;
+
+    let low_position = (vec2<f32>(global_id.xy) + 0.5) * vec2<f32>(low_size) / vec2<f32>(size) - 0.5;
+    let base = vec2<i32>(floor(low_position));
+    let f = low_position - floor(low_position);
+    let depth_sigma = 0.05 * normal_depth.w + 0.001;
+
+    var lighting = vec3<f32>(0.0);
+    var weight_sum = 0.0;
+    for (var i = 0; i < 4; i = i + 1) {
+        let offset = vec2<i32>(i & 1, i >> 1u);
+        let tap = clamp(base + offset, vec2<i32>(0), low_size - 1);
+        let tap_normal_depth = textureLoad(normal_depth_texture, tap, 0);
+        // the sky only blends with the sky, surfaces only with surfaces
+        if is_sky != (tap_normal_depth.w < 0.0) {
+            continue;
+        }
+
+        let bilinear = select(1.0 - f.x, f.x, offset.x == 1) * select(1.0 - f.y, f.y, offset.y == 1);
+        var weight = bilinear + 0.0001;
+        if !is_sky {
+            let normal_weight = pow(max(dot(normal_depth.xyz, tap_normal_depth.xyz), 0.0), 8.0);
+            let depth_weight = exp(-abs(normal_depth.w - tap_normal_depth.w) / depth_sigma);
+            weight *= normal_weight * depth_weight;
+        }
+        lighting += textureLoad(lighting_texture, tap, 0).rgb * weight;
+        weight_sum += weight;
+    }
+
+    if weight_sum > 0.00001 {
+        lighting /= weight_sum;
+    } else {
+        // nothing at low resolution saw this surface, fall back to the closest pixel
+        let nearest = clamp(vec2<i32>(round(low_position)), vec2<i32>(0), low_size - 1);
+        lighting = textureLoad(lighting_texture, nearest, 0).rgb;
+    }
+
+    textureStore(output_texture, pixel, vec4<f32>(albedo.rgb * (lighting + albedo.a), 1.0));
+}
@@ -20,23 +20,162 @@ struct WorldBvh {
 struct WorldVoxel {
     aabb_min: vec3<f32>,
     aabb_max: vec3<f32>,
-    pad: vec2<f32>,
-    voxels: array<u32, 512>,
+    material_offset: u32,
+    // 8x8x8 palette values, packed four to a word
+    voxels: array<u32, 128>,
 }
 struct WorldVoxels {
     length: u32,
     data: array<WorldVoxel>,
 }
 
+struct WorldMaterial {
+    albedo: vec3<f32>,
+    emissive: f32,
+}
+struct WorldMaterials {
+    length: u32,
+    data: array<WorldMaterial>,
+}
+
+struct WorldInstance {
+    world_from_local: mat4x4<f32>,
+    local_from_world: mat4x4<f32>,
+    node_offset: u32,
+    node_count: u32,
+    shape_offset: u32,
+}
+struct WorldInstances {
+    length: u32,
+    data: array<WorldInstance>,
+}
+
+// emissive voxel, every light is also a slot of the alias table over the light power
+struct WorldLight {
+    center: vec3<f32>,
+    size: f32,
+    instance: u32,
+    material: u32,
+    probability: f32,
+    alias: u32,
+    pdf: f32,
+}
+struct WorldLights {
+    length: u32,
+    data: array<WorldLight>,
+}
+
+// top level bvh, leaves index into `world_instances`
 @group(0) @binding(0) 
 var<storage, read> world_bvh: WorldBvh;
 @group(0) @binding(1) 
 var<storage, read> world_shapes: WorldVoxels;
+@group(0) @binding(2)
+var<storage, read> world_instances: WorldInstances;
+// bottom level bvhs of all models, leaves index into `world_shapes` relative to the instance
+@group(0) @binding(3)
+var<storage, read> blas_nodes: WorldBvh;
+// palettes of all models, bricks point at the start of theirs
+@group(0) @binding(4)
+var<storage, read> world_materials: WorldMaterials;
+// emissive voxels of all placed models, in the model space of their instance
+@group(0) @binding(5)
+var<storage, read> world_lights: WorldLights;
+// environment map of `VoxelGISky::Environment`, a black placeholder for the other skies
+@group(0) @binding(6)
+var sky_texture: texture_cube<f32>;
+@group(0) @binding(7)
+var sky_sampler: sampler;
+
+struct GIView {
+    previous_view_proj: mat4x4<f32>,
+    previous_world_position: vec3<f32>,
+    frame: u32,
+    reset: u32,
+    max_distance: f32,
+    sky_intensity: f32,
+    sun_direction: vec3<f32>,
+    // Perez coefficients A to E and zenith of the Preetham sky, luminance and chromaticity xy
+    sky_coefficients: array<vec4<f32>, 5>,
+    sky_zenith: vec4<f32>,
+}
 
+#ifdef VOXEL_GI_GUIDE
+@group(1) @binding(0)
+var<uniform> view: View;
+// albedo, normal and depth of the primary hits at full resolution, to upsample the GI with
+@group(1) @binding(1)
+var guide_albedo_texture: texture_storage_2d<rgba16float, write>;
+@group(1) @binding(2)
+var guide_normal_depth_texture: texture_storage_2d<rgba16float, write>;
+@group(1) @binding(3)
+var<uniform> gi_view: GIView;
+#else
+#ifdef VOXEL_GI_SHADOWS
 @group(1) @binding(0)
 var<uniform> view: View;
+// sun visibility and ambient occlusion, see `vox_gi::pipeline::VoxelGIShadowMask`
 @group(1) @binding(1)
-var output_texture: texture_storage_2d<rgba16float, read_write>;
+var shadow_mask_texture: texture_storage_2d<rgba16float, write>;
+@group(1) @binding(2)
+var<uniform> gi_view: GIView;
+
+const AO_RAYS = 8u;
+// occluders further away than this don't darken a surface
+const AO_DISTANCE = 1.0;
+#else
+#ifndef VOXEL_GI_PROBES
+@group(1) @binding(0)
+var<uniform> view: View;
+// incoming light, multiplied with the albedo after denoising
+@group(1) @binding(1)
+var radiance_texture: texture_storage_2d<rgba16float, write>;
+// albedo and emission strength
+@group(1) @binding(2)
+var albedo_texture: texture_storage_2d<rgba16float, write>;
+// world normal and hit distance, -1 for the sky
+@group(1) @binding(3)
+var normal_depth_texture: texture_storage_2d<rgba16float, write>;
+// uv motion since the previous frame and the hit distance from the previous camera
+@group(1) @binding(4)
+var motion_texture: texture_storage_2d<rgba16float, write>;
+@group(1) @binding(5)
+var<uniform> gi_view: GIView;
+#else
+// L1 spherical harmonics of the incoming light, the cell tells which world cell a slot holds
+struct Probe {
+    sh: array<vec4<f32>, 4>,
+    cell: vec4<i32>,
+}
+
+@group(1) @binding(0)
+var<uniform> view: View;
+@group(1) @binding(1)
+var<storage, read_write> probes: array<Probe>;
+// copy of the probe coefficients sampled by the voxel materials, see `vox_gi::pipeline::VoxelGIProbes`
+@group(1) @binding(2)
+var irradiance_atlas: texture_storage_2d<rgba16float, write>;
+@group(1) @binding(3)
+var<uniform> gi_view: GIView;
+
+// keep in sync with `vox_gi::pipeline` and `vox_gi::node`
+const PROBE_GRID_SIZE = vec3<i32>(16, 8, 16);
+const PROBE_SPACING = 2.0;
+const PROBE_ROTATION = 8u;
+const PROBE_RAYS = 64u;
+// share of the previous value kept on every update
+const PROBE_HYSTERESIS = 0.9;
+#endif
+#endif
+#endif
+
+#ifdef VOXEL_GI_HYBRID
+// prepass of the core 3d graph at full resolution, the primary hits come from here instead of the BVH
+@group(2) @binding(0)
+var depth_prepass_texture: texture_depth_2d;
+@group(2) @binding(1)
+var normal_prepass_texture: texture_2d<f32>;
+#endif
 
 
 fn intersect_aabb(rayOrigin: vec3<f32>, rayDir: vec3<f32>, boxMin: vec3<f32>, boxMax: vec3<f32>) -> vec2<f32> {
@@ -65,94 +204,141 @@ struct Ray {
     direction: vec3<f32>,
 };
 
-fn traverse_voxels(ray: Ray, shape: WorldVoxel, dist: vec2<f32>) -> vec4<f32> {
+struct Hit {
+    distance: f32,
+    normal: vec3<f32>,
+    // index into `world_materials`
+    material: u32,
+};
+
+const NO_HIT = 3.402823e+38;
+// primary hit on a rasterized surface that isn't part of the voxel scene
+const NO_MATERIAL = 0xffffffffu;
+
+#ifdef VOXEL_GI_DEBUG_STRUCTURE
+// work done by this invocation so far, for the cost debug views
+var<private> debug_node_visits: u32;
+var<private> debug_voxel_steps: u32;
+// brick and instance of the closest hit of the last traversal
+var<private> debug_hit_shape: u32;
+var<private> debug_hit_instance: u32;
+#endif
+
+// Walks the 8x8x8 voxels of a brick with a DDA, starting where the ray enters the brick.
+fn traverse_voxels(ray: Ray, shape: WorldVoxel, dist: vec2<f32>) -> Hit {
     let count_voxels = vec3<f32>(8.0);
-    var pnt = ray.origin.xyz;
     let direction: vec3<f32> = ray.direction.xyz;
 
     let bounding_box_min = shape.aabb_min;
     let bounding_box_max = shape.aabb_max;
+    let voxel_size = (bounding_box_max - bounding_box_min) / count_voxels;
 
-    pnt = pnt + direction * dist.x;
-    var hit_point = pnt;
-    pnt = (pnt - bounding_box_min) / (bounding_box_max - bounding_box_min) * vec3<f32>(count_voxels);
+    let entry = max(dist.x, 0.0);
+    var pnt = ray.origin + direction * entry;
+    pnt = (pnt - bounding_box_min) / voxel_size;
 
-    var map_pos = vec3<i32>(pnt);
-    let delta_dist = abs(vec3(length(direction)) / direction);
+    var map_pos = clamp(vec3<i32>(floor(pnt)), vec3<i32>(0), vec3<i32>(7));
+    // ray parameter needed to cross one voxel on each axis, in voxels
+    let delta_dist = abs(1.0 / direction);
     let ray_dir_sign = sign(direction);
     let ray_step = vec3<i32>(ray_dir_sign);
     var side_dist = (ray_dir_sign * (vec3<f32>(map_pos) - pnt) + (ray_dir_sign * 0.5) + 0.5) * delta_dist;
     var mask = vec3(false, false, false);
+    // the face the ray entered through, used as normal when the first voxel is already solid
+    let t_min = (bounding_box_min - ray.origin) / direction;
+    let t_max = (bounding_box_max - ray.origin) / direction;
+    let t1 = min(t_min, t_max);
+    mask = t1 >= max(t1.yzx, t1.zxy);
 
-    // var final_color = vec4<f32>(0.0, 0.0, 0.0, 0.0);
-    let zero = vec3<i32>(0);
     let max_voxels = vec3<i32>(count_voxels);
-    let max_either_axis = (max(max(max_voxels.x, max_voxels.y), max_voxels.z) * 2);
-
-    var hit = false;
+    let max_steps = max_voxels.x + max_voxels.y + max_voxels.z;
 
     var local_shape = shape;
-    for (var i: i32 = 0; i < max_either_axis; i = i + 1) {
-        let voxel = local_shape.voxels[map_pos.x + map_pos.y * max_voxels.x + map_pos.z * max_voxels.x * max_voxels.y];
+    for (var i: i32 = 0; i < max_steps; i = i + 1) {
+#ifdef VOXEL_GI_DEBUG_STRUCTURE
+        debug_voxel_steps += 1u;
+#endif
+        let voxel_index = u32(map_pos.x + map_pos.y * max_voxels.x + map_pos.z * max_voxels.x * max_voxels.y);
+        let voxel = (local_shape.voxels[voxel_index / 4u] >> ((voxel_index % 4u) * 8u)) & 0xffu;
 
         if voxel != u32(0) {
-            hit = true;
-            // let color = textureLoad(palette_texture, i32(voxel), 0).rgb;
-            // var hit_color = color;
-            // var normal = vec4<f32>(0.0, 0.0, 0.0, 0.0);
-            // if mask.x {
-            //     normal = -ray_dir_sign.x * vec4<f32>(1.0, 0.0, 0.0, 1.0);
-            // }
-            // if mask.y {
-            //     normal = -ray_dir_sign.y * vec4<f32>(0.0, 1.0, 0.0, 1.0);
-            // }
-            // if mask.z {
-            //     normal = -ray_dir_sign.z * vec4<f32>(0.0, 0.0, 1.0, 1.0);
-            // }
-
-            // final_color = vec4<f32>(hit_color, 1.0);
-
-            // var pbr_input: PbrInput = pbr_input_new();
-            // pbr_input.material.base_color = vec4<f32>(final_color.rgb, 1.0);
-            // pbr_input.frag_coord = in.frag_coord;
-            // pbr_input.world_position = vec4<f32>(pnt, 1.0);
-            // pbr_input.world_normal =  mesh_normal_local_to_world(normal.rgb);
-            // pbr_input.N = mesh_normal_local_to_world(normal.rgb);
-            // pbr_input.V = -direction;
-
-            // out.color = pbr(pbr_input);
-            return vec4<f32>(1.0, 0.0, 0.0, 1.0);
+            // ray parameter at the face we stepped through
+            let face_dist = dot(vec3<f32>(mask), side_dist - delta_dist) * dot(vec3<f32>(mask), voxel_size);
+            let normal = -ray_dir_sign * vec3<f32>(mask);
+            return Hit(entry + max(face_dist, 0.0), normal, voxel);
         }
         mask = side_dist.xyz <= min(side_dist.yzx, side_dist.zxy);
         side_dist += vec3<f32>(mask) * delta_dist;
         map_pos += vec3<i32>(mask) * ray_step;
 
-        if map_pos.x < zero.x || map_pos.y < zero.y || map_pos.z < zero.z {
-            break;
-        }
-        if map_pos.x > max_voxels.x || map_pos.y > max_voxels.y || map_pos.z > max_voxels.z {
+        if any(map_pos < vec3<i32>(0)) || any(map_pos >= max_voxels) {
             break;
         }
     }
 
-    return vec4<f32>(0.0);
+    return Hit(NO_HIT, vec3<f32>(0.0), 0u);
 }
 
-fn traverse_bvh(ray: Ray) -> vec4<f32> {
+// Closest hit against the bricks of one model, `ray` is in the model's space.
+fn traverse_blas(ray: Ray, instance: WorldInstance, max_distance: f32) -> Hit {
     var node_index = u32(0);
+    var closest = Hit(max_distance, vec3<f32>(0.0), 0u);
+
+    while node_index < instance.node_count {
+        let node = blas_nodes.data[instance.node_offset + node_index];
+#ifdef VOXEL_GI_DEBUG_STRUCTURE
+        debug_node_visits += 1u;
+#endif
 
-    var closest_result = vec4<f32>(0.0);
-    var latest_dist = -1.0;
-    let color = vec4<f32>(0.0);
+        if node.entry_index == u32(-1) {
+            let shape = world_shapes.data[instance.shape_offset + node.shape_index];
+            let dist = intersect_aabb(ray.origin, ray.direction, shape.aabb_min, shape.aabb_max);
+            if dist.x < dist.y && dist.y > 0.0 && dist.x < closest.distance {
+                let hit = traverse_voxels(ray, shape, dist);
+                if hit.distance < closest.distance {
+                    closest = Hit(hit.distance, hit.normal, shape.material_offset + hit.material);
+#ifdef VOXEL_GI_DEBUG_STRUCTURE
+                    debug_hit_shape = instance.shape_offset + node.shape_index;
+#endif
+                }
+            }
+
+            node_index = node.exit_index;
+        } else if intersect_aabb_bool(ray.origin, ray.direction, node.aabb_min, node.aabb_max) {
+            node_index = node.entry_index;
+        } else {
+            node_index = node.exit_index;
+        }
+    }
+
+    return closest;
+}
+
+// Closest hit over all models whose bounds the ray passes through, up to the max distance of the view.
+fn traverse_bvh(ray: Ray) -> Hit {
+    var node_index = u32(0);
+    var closest = Hit(gi_view.max_distance, vec3<f32>(0.0), 0u);
 
     while node_index < world_bvh.length {
         let node = world_bvh.data[node_index];
+#ifdef VOXEL_GI_DEBUG_STRUCTURE
+        debug_node_visits += 1u;
+#endif
 
         if node.entry_index == u32(-1) {
-            var shape = world_shapes.data[node.shape_index];
-            let dist = intersect_aabb(ray.origin, ray.direction, shape.aabb_min, shape.aabb_max);
-            if dist.x < dist.y {
-                return traverse_voxels(ray, shape, dist);
+            let instance = world_instances.data[node.shape_index];
+            // the direction is left unnormalized so distances stay in world units
+            let local_ray = Ray(
+                (instance.local_from_world * vec4<f32>(ray.origin, 1.0)).xyz,
+                (instance.local_from_world * vec4<f32>(ray.direction, 0.0)).xyz,
+            );
+            let hit = traverse_blas(local_ray, instance, closest.distance);
+            if hit.distance < closest.distance {
+                let normal_from_local = transpose(instance.local_from_world);
+                closest = Hit(hit.distance, normalize((normal_from_local * vec4<f32>(hit.normal, 0.0)).xyz), hit.material);
+#ifdef VOXEL_GI_DEBUG_STRUCTURE
+                debug_hit_instance = node.shape_index;
+#endif
             }
 
             node_index = node.exit_index;
@@ -163,22 +349,608 @@ fn traverse_bvh(ray: Ray) -> vec4<f32> {
         }
     }
 
-    return closest_result;
+    if closest.distance >= gi_view.max_distance {
+        closest.distance = NO_HIT;
+    }
+    return closest;
 }
 
-@compute @workgroup_size(8, 8, 1)
-fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
-    let pixel_index = global_id.x + global_id.y * u32(view.viewport.z);
-    let pixel_center = vec2<f32>(global_id.xy) + 0.5;
-    let pixel_uv = pixel_center / view.viewport.zw;
+fn perez(cos_theta: f32, gamma: f32, cos_gamma: f32) -> vec3<f32> {
+    let c = gi_view.sky_coefficients;
+    return (1.0 + c[0].xyz * exp(c[1].xyz / cos_theta)) * (1.0 + c[2].xyz * exp(c[3].xyz * gamma) + c[4].xyz * cos_gamma * cos_gamma);
+}
+
+// Preetham daylight, see `vox_gi::sky::PreethamSky::radiance` for the CPU version.
+fn preetham(direction: vec3<f32>) -> vec3<f32> {
+    let cos_theta = max(direction.y, 0.01);
+    let cos_gamma = clamp(dot(direction, gi_view.sun_direction), -1.0, 1.0);
+    let yxy = gi_view.sky_zenith.xyz * perez(cos_theta, acos(cos_gamma), cos_gamma);
+
+    let xyz = vec3<f32>(yxy.y / yxy.z * yxy.x, yxy.x, (1.0 - yxy.y - yxy.z) / yxy.z * yxy.x);
+    let rgb = mat3x3<f32>(
+        vec3<f32>(3.2406, -0.9689, 0.0557),
+        vec3<f32>(-1.5372, 1.8758, -0.2040),
+        vec3<f32>(-0.4986, 0.0415, 1.0570),
+    ) * xyz;
+    // below the horizon the ground reflects a quarter of the horizon light
+    let ground = 0.25 + 0.75 * smoothstep(-0.2, 0.0, direction.y);
+    return max(rgb, vec3<f32>(0.0)) * ground;
+}
+
+fn sky(direction: vec3<f32>) -> vec3<f32> {
+#ifdef VOXEL_GI_SKY_ENVIRONMENT
+    let radiance = textureSampleLevel(sky_texture, sky_sampler, direction, 0.0).rgb;
+#else
+#ifdef VOXEL_GI_SKY_PREETHAM
+    let radiance = preetham(direction);
+#else
+    let t = clamp(direction.y * 0.5 + 0.5, 0.0, 1.0);
+    let radiance = mix(vec3<f32>(0.8, 0.85, 0.9), vec3<f32>(0.35, 0.55, 0.9), t);
+#endif
+#endif
+    return radiance * gi_view.sky_intensity;
+}
+
+fn hash(value: u32) -> u32 {
+    let state = value * 747796405u + 2891336453u;
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+fn random_float(state: ptr<function, u32>) -> f32 {
+    *state = hash(*state);
+    return f32(*state) / 4294967295.0;
+}
+
+// Uniformly distributed direction on the unit sphere.
+fn random_direction(state: ptr<function, u32>) -> vec3<f32> {
+    let z = random_float(state) * 2.0 - 1.0;
+    let a = random_float(state) * 6.2831853;
+    let r = sqrt(1.0 - z * z);
+    return vec3<f32>(r * cos(a), r * sin(a), z);
+}
+
+// Cosine weighted direction around `normal`.
+fn random_bounce(normal: vec3<f32>, state: ptr<function, u32>) -> vec3<f32> {
+    return normalize(normal + random_direction(state));
+}
+
+fn emitted(material: WorldMaterial) -> vec3<f32> {
+    return material.albedo * material.emissive;
+}
+
+// Sunlight reaching a surface, zero when something is in the way.
+fn direct_light(position: vec3<f32>, normal: vec3<f32>) -> vec3<f32> {
+    let sun = gi_view.sun_direction;
+    let n_dot_l = dot(normal, sun);
+    if n_dot_l <= 0.0 {
+        return vec3<f32>(0.0);
+    }
+    let shadow = traverse_bvh(Ray(position, sun));
+    return vec3<f32>(select(n_dot_l, 0.0, shadow.distance < NO_HIT));
+}
+
+// Camera ray through the center of `pixel` on a `size` pixels large image.
+fn primary_ray(pixel: vec2<u32>, size: vec2<u32>) -> Ray {
+    var pixel_uv = (vec2<f32>(pixel) + 0.5) / vec2<f32>(size);
+#ifdef VOXEL_GI_JITTER
+    // a different spot of the pixel every frame, which the progressive accumulation antialiases
+    var jitter_rng = hash((pixel.x + pixel.y * size.x) ^ hash(~gi_view.frame));
+    pixel_uv += (vec2<f32>(random_float(&jitter_rng), random_float(&jitter_rng)) - 0.5) / vec2<f32>(size);
+#endif
     let pixel_ndc = (pixel_uv * 2.0) - 1.0;
     let primary_ray_target = view.inverse_view_proj * vec4(pixel_ndc.x, -pixel_ndc.y, 1.0, 1.0);
-    var ray_origin = view.world_position;
-    var ray_direction = normalize((primary_ray_target.xyz / primary_ray_target.w) - ray_origin);
+    let ray_origin = view.world_position;
+    return Ray(ray_origin, normalize((primary_ray_target.xyz / primary_ray_target.w) - ray_origin));
+}
+
+// Surface seen through `pixel`, traced or read from the prepass when hybrid.
+fn primary_hit(pixel: vec2<u32>, size: vec2<u32>, ray: Ray) -> Hit {
+#ifdef VOXEL_GI_HYBRID
+    let prepass_pixel = vec2<i32>((vec2<f32>(pixel) + 0.5) * view.viewport.zw / vec2<f32>(size));
+    let depth = textureLoad(depth_prepass_texture, prepass_pixel, 0);
+    // reversed z, nothing was drawn here
+    if depth <= 0.0 {
+        return Hit(NO_HIT, vec3<f32>(0.0), NO_MATERIAL);
+    }
+    let prepass_uv = (vec2<f32>(prepass_pixel) + 0.5) / view.viewport.zw;
+    let prepass_ndc = prepass_uv * 2.0 - 1.0;
+    let world_position = view.inverse_view_proj * vec4<f32>(prepass_ndc.x, -prepass_ndc.y, depth, 1.0);
+    let distance = length(world_position.xyz / world_position.w - ray.origin);
+    let normal = normalize(textureLoad(normal_prepass_texture, prepass_pixel, 0).xyz * 2.0 - 1.0);
+
+    // the voxel scene knows the colour, as long as it saw the same surface
+    let traced = traverse_bvh(ray);
+    let material = select(NO_MATERIAL, traced.material, abs(traced.distance - distance) < 0.02 * distance + 0.1);
+    return Hit(distance, normal, material);
+#else
+    return traverse_bvh(ray);
+#endif
+}
+
+// Material of a primary hit, rasterized surfaces without voxels are assumed mid grey.
+fn primary_material(hit: Hit) -> WorldMaterial {
+    if hit.material == NO_MATERIAL {
+        return WorldMaterial(vec3<f32>(0.5), 0.0);
+    }
+    var material = world_materials.data[hit.material];
+#ifdef VOXEL_GI_HYBRID
+    // the main pass already shaded the emission
+    material.emissive = 0.0;
+#endif
+    return material;
+}
 
-    // let ray_origin = view.world_position.xyz;
-    // let ray_direction = normalize(world_space_pos.xyz - ray_origin);
-    let color = traverse_bvh(Ray(ray_origin, ray_direction));
+#ifdef VOXEL_GI_GUIDE
+@compute @workgroup_size(8, 8, 1)
+fn guide(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let size = vec2<u32>(textureDimensions(guide_albedo_texture));
+    if global_id.x >= size.x || global_id.y >= size.y {
+        return;
+    }
+
+    let pixel = vec2<i32>(global_id.xy);
+    let ray = primary_ray(global_id.xy, size);
+    let hit = primary_hit(global_id.xy, size, ray);
+    if hit.distance >= NO_HIT {
+        textureStore(guide_albedo_texture, pixel, vec4<f32>(1.0, 1.0, 1.0, 0.0));
+        textureStore(guide_normal_depth_texture, pixel, vec4<f32>(0.0, 0.0, 0.0, -1.0));
+        return;
+    }
+
+    let material = primary_material(hit);
+    textureStore(guide_albedo_texture, pixel, vec4<f32>(material.albedo, material.emissive));
+    textureStore(guide_normal_depth_texture, pixel, vec4<f32>(hit.normal, hit.distance));
+}
+#else
+#ifdef VOXEL_GI_SHADOWS
+@compute @workgroup_size(8, 8, 1)
+fn shadows(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let size = vec2<u32>(textureDimensions(shadow_mask_texture));
+    if global_id.x >= size.x || global_id.y >= size.y {
+        return;
+    }
+
+    let pixel = vec2<i32>(global_id.xy);
+    let ray = primary_ray(global_id.xy, size);
+    let hit = primary_hit(global_id.xy, size, ray);
+    if hit.distance >= NO_HIT {
+        textureStore(shadow_mask_texture, pixel, vec4<f32>(1.0));
+        return;
+    }
 
-    textureStore(output_texture, global_id.xy, color);
-}
\ No newline at end of file
+    let hit_point = ray.origin + ray.direction * hit.distance + hit.normal * 0.001;
+    // surfaces facing away from the sun are shaded by their own geometry already
+    var shadow = 1.0;
+    if dot(hit.normal, gi_view.sun_direction) > 0.0 {
+        let occluder = traverse_bvh(Ray(hit_point, gi_view.sun_direction));
+        shadow = select(1.0, 0.0, occluder.distance < NO_HIT);
+    }
+
+    var rng = hash((global_id.x + global_id.y * size.x) ^ hash(gi_view.frame));
+    var ao = 0.0;
+    for (var i = 0u; i < AO_RAYS; i = i + 1u) {
+        let occluder = traverse_bvh(Ray(hit_point, random_bounce(hit.normal, &rng)));
+        // closer occluders block more light
+        ao += clamp(occluder.distance / AO_DISTANCE, 0.0, 1.0);
+    }
+
+    textureStore(shadow_mask_texture, pixel, vec4<f32>(shadow, ao / f32(AO_RAYS), 0.0, 1.0));
+}
+#else
+#ifndef VOXEL_GI_PROBES
+fn axis_vector(axis: u32) -> vec3<f32> {
+    return vec3<f32>(vec3<u32>(0u, 1u, 2u) == vec3<u32>(axis % 3u));
+}
+
+// Light from one emissive voxel reaching a surface, picked through the alias table and divided by
+// its pdf. A random point on a random face of the voxel is tested for visibility, so the estimate
+// stays unbiased for rotated and scaled models.
+fn sample_emitters(position: vec3<f32>, normal: vec3<f32>, rng: ptr<function, u32>) -> vec3<f32> {
+    let count = world_lights.length;
+    if count == 0u {
+        return vec3<f32>(0.0);
+    }
+    var light = world_lights.data[min(u32(random_float(rng) * f32(count)), count - 1u)];
+    if random_float(rng) >= light.probability {
+        light = world_lights.data[light.alias];
+    }
+    let instance = world_instances.data[light.instance];
+
+    let face = min(u32(random_float(rng) * 6.0), 5u);
+    let face_normal = axis_vector(face) * select(-1.0, 1.0, face >= 3u);
+    let tangent = axis_vector(face + 1u) * light.size;
+    let bitangent = axis_vector(face + 2u) * light.size;
+    let local_point = light.center + face_normal * light.size * 0.5
+        + tangent * (random_float(rng) - 0.5) + bitangent * (random_float(rng) - 0.5);
+
+    let point = (instance.world_from_local * vec4<f32>(local_point, 1.0)).xyz;
+    let area = length(cross(
+        (instance.world_from_local * vec4<f32>(tangent, 0.0)).xyz,
+        (instance.world_from_local * vec4<f32>(bitangent, 0.0)).xyz,
+    ));
+    let light_normal = normalize((transpose(instance.local_from_world) * vec4<f32>(face_normal, 0.0)).xyz);
+
+    let to_light = point - position;
+    let distance_squared = dot(to_light, to_light);
+    let distance = sqrt(distance_squared);
+    let direction = to_light / distance;
+    let cos_surface = dot(normal, direction);
+    let cos_light = -dot(light_normal, direction);
+    if cos_surface <= 0.0 || cos_light <= 0.0 {
+        return vec3<f32>(0.0);
+    }
+    // the light's own face stops the ray right at the sampled point
+    let occluder = traverse_bvh(Ray(position, direction));
+    if occluder.distance < distance * 0.999 - 0.001 {
+        return vec3<f32>(0.0);
+    }
+
+    // one of six faces, uniform over the face area
+    let pdf = light.pdf / (6.0 * area);
+    let radiance = emitted(world_materials.data[light.material]);
+    return radiance * cos_surface * cos_light / (distance_squared * pdf * 3.1415927);
+}
+
+// Light arriving at a surface through `VOXEL_GI_BOUNCES` diffuse bounces, without direct sunlight.
+// Emissive voxels are sampled directly at every bounce, so hitting one adds nothing.
+fn trace_indirect(position: vec3<f32>, normal: vec3<f32>, rng: ptr<function, u32>) -> vec3<f32> {
+    var radiance = vec3<f32>(0.0);
+    var throughput = vec3<f32>(1.0);
+    var origin = position;
+    var surface_normal = normal;
+    for (var bounce = 0u; bounce < #{VOXEL_GI_BOUNCES}u; bounce = bounce + 1u) {
+        let direction = random_bounce(surface_normal, rng);
+        let hit = traverse_bvh(Ray(origin, direction));
+        if hit.distance >= NO_HIT {
+            radiance += throughput * sky(direction);
+            break;
+        }
+
+        // picks up the colour of whatever the bounce lands on
+        let material = world_materials.data[hit.material];
+        origin = origin + direction * hit.distance + hit.normal * 0.001;
+        surface_normal = hit.normal;
+        let incoming = direct_light(origin, surface_normal) + sample_emitters(origin, surface_normal, rng);
+        radiance += throughput * material.albedo * incoming;
+        throughput *= material.albedo;
+    }
+    return radiance;
+}
+
+#ifdef VOXEL_GI_DEBUG_STRUCTURE
+const DEBUG_MAX_DEPTH = 32u;
+// node visits of the primary ray that show as red in the heatmap
+const DEBUG_HEATMAP_NODES = 128.0;
+// node visits and voxel steps of all rays of a pixel that show as red
+const DEBUG_RAY_COST = 4096.0;
+
+// What the primary ray of a pixel ran into, captured before the other rays overwrite it.
+struct DebugPrimary {
+    node_visits: u32,
+    shape: u32,
+    instance: u32,
+}
+
+// Blue for 0, green for 0.5 and red for 1.
+fn heat(value: f32) -> vec3<f32> {
+    let t = clamp(value, 0.0, 1.0) * 4.0;
+    return clamp(vec3<f32>(t - 2.0, 2.0 - abs(t - 2.0), 2.0 - t), vec3<f32>(0.0), vec3<f32>(1.0));
+}
+
+// Hue that repeats every 8 tree levels.
+fn depth_color(depth: u32) -> vec3<f32> {
+    let hue = f32(depth % 8u) / 8.0;
+    let rgb = abs(fract(hue + vec3<f32>(1.0, 2.0 / 3.0, 1.0 / 3.0)) * 6.0 - 3.0) - 1.0;
+    return clamp(rgb, vec3<f32>(0.0), vec3<f32>(1.0));
+}
+
+// Distance from a point on the surface of a box to its closest edge. Edges are where two axes
+// touch a side at once, so the second smallest distance to a side decides.
+fn edge_distance(point: vec3<f32>, box_min: vec3<f32>, box_max: vec3<f32>) -> f32 {
+    let to_side = min(point - box_min, box_max - point);
+    let smallest = min(min(to_side.x, to_side.y), to_side.z);
+    let largest = max(max(to_side.x, to_side.y), to_side.z);
+    return to_side.x + to_side.y + to_side.z - smallest - largest;
+}
+
+// Keeps the closer of the edges where `ray` enters and leaves a node, `wire` is colour and distance.
+fn wire_edges(ray: Ray, box_min: vec3<f32>, box_max: vec3<f32>, depth: u32, pixel_angle: f32, wire: ptr<function, vec4<f32>>) {
+    let dist = intersect_aabb(ray.origin, ray.direction, box_min, box_max);
+    // the ray may be in a model's space, where a pixel covers a different length
+    let width = pixel_angle * length(ray.direction) * 1.5;
+    for (var i = 0; i < 2; i = i + 1) {
+        let t = dist[i];
+        if t > 0.0 && t < (*wire).w && edge_distance(ray.origin + ray.direction * t, box_min, box_max) < t * width {
+            *wire = vec4<f32>(depth_color(depth), t);
+        }
+    }
+}
+
+// Closest node edge along a ray through both BVH levels, `w` stays at `max_distance` without one.
+fn bvh_wireframe(ray: Ray, max_distance: f32, pixel_angle: f32) -> vec4<f32> {
+    var wire = vec4<f32>(0.0, 0.0, 0.0, max_distance);
+    // the exits of the nodes above tell when the walk climbs back up
+    var exits: array<u32, DEBUG_MAX_DEPTH>;
+    var depth = 0u;
+    var node_index = 0u;
+    while node_index < world_bvh.length {
+        while depth > 0u && exits[depth - 1u] == node_index {
+            depth -= 1u;
+        }
+        let node = world_bvh.data[node_index];
+        if !intersect_aabb_bool(ray.origin, ray.direction, node.aabb_min, node.aabb_max) {
+            node_index = node.exit_index;
+            continue;
+        }
+        wire_edges(ray, node.aabb_min, node.aabb_max, depth, pixel_angle, &wire);
+        if node.entry_index != u32(-1) {
+            if depth < DEBUG_MAX_DEPTH {
+                exits[depth] = node.exit_index;
+                depth += 1u;
+            }
+            node_index = node.entry_index;
+            continue;
+        }
+
+        // the model's tree continues one level below the instance
+        let instance = world_instances.data[node.shape_index];
+        let local_ray = Ray(
+            (instance.local_from_world * vec4<f32>(ray.origin, 1.0)).xyz,
+            (instance.local_from_world * vec4<f32>(ray.direction, 0.0)).xyz,
+        );
+        var blas_exits: array<u32, DEBUG_MAX_DEPTH>;
+        var blas_depth = 0u;
+        var blas_index = 0u;
+        while blas_index < instance.node_count {
+            while blas_depth > 0u && blas_exits[blas_depth - 1u] == blas_index {
+                blas_depth -= 1u;
+            }
+            let blas_node = blas_nodes.data[instance.node_offset + blas_index];
+            if !intersect_aabb_bool(local_ray.origin, local_ray.direction, blas_node.aabb_min, blas_node.aabb_max) {
+                blas_index = blas_node.exit_index;
+                continue;
+            }
+            wire_edges(local_ray, blas_node.aabb_min, blas_node.aabb_max, depth + 1u + blas_depth, pixel_angle, &wire);
+            if blas_node.entry_index == u32(-1) {
+                blas_index = blas_node.exit_index;
+            } else {
+                if blas_depth < DEBUG_MAX_DEPTH {
+                    blas_exits[blas_depth] = blas_node.exit_index;
+                    blas_depth += 1u;
+                }
+                blas_index = blas_node.entry_index;
+            }
+        }
+        node_index = node.exit_index;
+    }
+    return wire;
+}
+
+// Filled share of the 512 voxels of a brick.
+fn brick_occupancy(shape: WorldVoxel) -> f32 {
+    var local_shape = shape;
+    var filled = 0u;
+    for (var i = 0u; i < 128u; i = i + 1u) {
+        let word = local_shape.voxels[i];
+        filled += u32((word & 0xffu) != 0u) + u32((word & 0xff00u) != 0u) + u32((word & 0xff0000u) != 0u) + u32((word & 0xff000000u) != 0u);
+    }
+    return f32(filled) / 512.0;
+}
+
+// Colour of the acceleration structure debug views for the primary ray, `hit` may be a miss.
+fn debug_structure(ray: Ray, hit: Hit, primary: DebugPrimary, size: vec2<u32>) -> vec3<f32> {
+    let is_hit = hit.distance < NO_HIT;
+    let max_distance = select(gi_view.max_distance, hit.distance, is_hit);
+    // flat shading, to tell the surfaces apart behind the overlays
+    let shade = select(0.0, 0.25 + 0.5 * max(dot(hit.normal, gi_view.sun_direction), 0.0), is_hit);
+    // angle covered by one pixel, wires stay about a pixel wide at any distance
+    let pixel_angle = 2.0 / (view.projection[1][1] * f32(size.y));
+
+#ifdef VOXEL_GI_DEBUG_BVH
+    let wire = bvh_wireframe(ray, max_distance, pixel_angle);
+    return select(vec3<f32>(shade * 0.5), wire.rgb, wire.w < max_distance);
+#endif
+#ifdef VOXEL_GI_DEBUG_HEATMAP
+    return heat(f32(primary.node_visits) / DEBUG_HEATMAP_NODES) * select(0.5, shade + 0.25, is_hit);
+#endif
+#ifdef VOXEL_GI_DEBUG_RAY_COST
+    return heat(f32(debug_node_visits + debug_voxel_steps) / DEBUG_RAY_COST);
+#endif
+#ifdef VOXEL_GI_DEBUG_OCCUPANCY
+    if !is_hit || hit.material == NO_MATERIAL {
+        return vec3<f32>(0.0);
+    }
+    let shape = world_shapes.data[primary.shape];
+    let instance = world_instances.data[primary.instance];
+    let local_point = (instance.local_from_world * vec4<f32>(ray.origin + ray.direction * hit.distance, 1.0)).xyz;
+    let local_width = length((instance.local_from_world * vec4<f32>(ray.direction, 0.0)).xyz) * pixel_angle * 1.5;
+    let outline = edge_distance(local_point, shape.aabb_min, shape.aabb_max) < hit.distance * local_width;
+    return select(heat(brick_occupancy(shape)) * shade, vec3<f32>(1.0), outline);
+#endif
+}
+#endif
+
+@compute @workgroup_size(8, 8, 1)
+fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    // the GI may run at a fraction of the view resolution
+    let size = vec2<u32>(textureDimensions(radiance_texture));
+    if global_id.x >= size.x || global_id.y >= size.y {
+        return;
+    }
+
+    let pixel_uv = (vec2<f32>(global_id.xy) + 0.5) / vec2<f32>(size);
+    let ray = primary_ray(global_id.xy, size);
+    let ray_origin = ray.origin;
+    let ray_direction = ray.direction;
+
+    let pixel = vec2<i32>(global_id.xy);
+    let hit = primary_hit(global_id.xy, size, ray);
+#ifdef VOXEL_GI_DEBUG_STRUCTURE
+    let debug_primary = DebugPrimary(debug_node_visits, debug_hit_shape, debug_hit_instance);
+#endif
+    if hit.distance >= NO_HIT {
+        // the sky is infinitely far away, so only the camera rotation moves it
+        let previous_clip = gi_view.previous_view_proj * vec4<f32>(ray_direction, 0.0);
+        let previous_uv = vec2<f32>(0.5, -0.5) * previous_clip.xy / previous_clip.w + 0.5;
+        var sky_color = sky(ray_direction);
+#ifdef VOXEL_GI_HYBRID
+        // whatever the main pass drew in the background stays as it is
+        sky_color = vec3<f32>(0.0);
+#endif
+#ifdef VOXEL_GI_DEBUG_VIEW
+#ifndef VOXEL_GI_DEBUG_NOISY
+        sky_color = vec3<f32>(0.0);
+#endif
+#endif
+#ifdef VOXEL_GI_DEBUG_STRUCTURE
+        sky_color = debug_structure(ray, hit, debug_primary, size);
+#endif
+        textureStore(radiance_texture, pixel, vec4<f32>(sky_color, 1.0));
+        textureStore(albedo_texture, pixel, vec4<f32>(1.0, 1.0, 1.0, 0.0));
+        textureStore(normal_depth_texture, pixel, vec4<f32>(0.0, 0.0, 0.0, -1.0));
+        textureStore(motion_texture, pixel, vec4<f32>(pixel_uv - previous_uv, -1.0, 0.0));
+        return;
+    }
+
+    var rng = hash((global_id.x + global_id.y * size.x) ^ hash(gi_view.frame));
+    let material = primary_material(hit);
+    let world_position = ray_origin + ray_direction * hit.distance;
+    let hit_point = world_position + hit.normal * 0.001;
+
+    var indirect = vec3<f32>(0.0);
+    for (var i = 0u; i < #{VOXEL_GI_SAMPLES}u; i = i + 1u) {
+        indirect += trace_indirect(hit_point, hit.normal, &rng);
+    }
+    var radiance = indirect / f32(#{VOXEL_GI_SAMPLES}u) + sample_emitters(hit_point, hit.normal, &rng);
+#ifndef VOXEL_GI_HYBRID
+    // the hybrid renderer gets its direct light from the main pass
+    radiance += direct_light(hit_point, hit.normal);
+#endif
+
+    let previous_clip = gi_view.previous_view_proj * vec4<f32>(world_position, 1.0);
+    let previous_uv = vec2<f32>(0.5, -0.5) * previous_clip.xy / previous_clip.w + 0.5;
+    let previous_distance = distance(world_position, gi_view.previous_world_position);
+
+    // debug views skip the denoiser, so they store the final colour
+#ifdef VOXEL_GI_DEBUG_ALBEDO
+    radiance = material.albedo;
+#endif
+#ifdef VOXEL_GI_DEBUG_NORMALS
+    radiance = hit.normal * 0.5 + 0.5;
+#endif
+#ifdef VOXEL_GI_DEBUG_DEPTH
+    radiance = vec3<f32>(1.0 - hit.distance / gi_view.max_distance);
+#endif
+#ifdef VOXEL_GI_DEBUG_NOISY
+    radiance = radiance * material.albedo + emitted(material);
+#endif
+#ifdef VOXEL_GI_DEBUG_STRUCTURE
+    radiance = debug_structure(ray, hit, debug_primary, size);
+#endif
+
+    textureStore(radiance_texture, pixel, vec4<f32>(radiance, 1.0));
+    textureStore(albedo_texture, pixel, vec4<f32>(material.albedo, material.emissive));
+    textureStore(normal_depth_texture, pixel, vec4<f32>(hit.normal, hit.distance));
+    textureStore(motion_texture, pixel, vec4<f32>(pixel_uv - previous_uv, previous_distance, 0.0));
+}
+#else
+fn probe_origin() -> vec3<i32> {
+    return vec3<i32>(floor(view.world_position / PROBE_SPACING)) - PROBE_GRID_SIZE / 2;
+}
+
+fn probe_slot(cell: vec3<i32>) -> i32 {
+    let slot = ((cell % PROBE_GRID_SIZE) + PROBE_GRID_SIZE) % PROBE_GRID_SIZE;
+    return slot.x + slot.y * PROBE_GRID_SIZE.x + slot.z * PROBE_GRID_SIZE.x * PROBE_GRID_SIZE.y;
+}
+
+// Irradiance of the last update, blended from the 8 probes around `position`. Reading it back at
+// the ray hits adds one more bounce with every update.
+fn probe_irradiance(position: vec3<f32>, normal: vec3<f32>) -> vec3<f32> {
+    let grid_position = position / PROBE_SPACING;
+    let base = vec3<i32>(floor(grid_position));
+    let f = grid_position - vec3<f32>(base);
+
+    var irradiance = vec3<f32>(0.0);
+    var weight_sum = 0.0;
+    for (var i = 0; i < 8; i = i + 1) {
+        let offset = vec3<i32>(i & 1, (i >> 1u) & 1, i >> 2u);
+        let cell = base + offset;
+        let probe = probes[probe_slot(cell)];
+        // the slot still holds a cell that scrolled out of the grid
+        if any(probe.cell.xyz != cell) {
+            continue;
+        }
+
+        let trilinear = mix(1.0 - f, f, vec3<f32>(offset));
+        let to_probe = normalize(vec3<f32>(cell) * PROBE_SPACING - position + normal * 0.001);
+        let backface = (dot(to_probe, normal) + 1.0) * 0.5;
+        let weight = trilinear.x * trilinear.y * trilinear.z * (backface * backface + 0.2);
+
+        let e = 0.886227 * probe.sh[0].rgb
+            + 1.023328 * (probe.sh[1].rgb * normal.y + probe.sh[2].rgb * normal.z + probe.sh[3].rgb * normal.x);
+        irradiance += max(e, vec3<f32>(0.0)) * weight;
+        weight_sum += weight;
+    }
+
+    return irradiance / max(weight_sum, 0.0001);
+}
+
+// Light arriving at `origin` from `direction`.
+fn trace_radiance(origin: vec3<f32>, direction: vec3<f32>) -> vec3<f32> {
+    let hit = traverse_bvh(Ray(origin, direction));
+    if hit.distance >= NO_HIT {
+        return sky(direction);
+    }
+    let material = world_materials.data[hit.material];
+    let hit_point = origin + direction * hit.distance + hit.normal * 0.001;
+    let incoming = direct_light(hit_point, hit.normal) + probe_irradiance(hit_point, hit.normal) / 3.1415927;
+    return emitted(material) + material.albedo * incoming;
+}
+
+// Refreshes every `PROBE_ROTATION`th probe of the grid around the camera.
+@compute @workgroup_size(64, 1, 1)
+fn update_probes(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    let probe_count = u32(PROBE_GRID_SIZE.x * PROBE_GRID_SIZE.y * PROBE_GRID_SIZE.z);
+    let index = global_id.x * PROBE_ROTATION + gi_view.frame % PROBE_ROTATION;
+    if index >= probe_count {
+        return;
+    }
+
+    let slot = vec3<i32>(
+        i32(index) % PROBE_GRID_SIZE.x,
+        (i32(index) / PROBE_GRID_SIZE.x) % PROBE_GRID_SIZE.y,
+        i32(index) / (PROBE_GRID_SIZE.x * PROBE_GRID_SIZE.y),
+    );
+    // the world cell inside the grid that maps onto this slot
+    let origin = probe_origin();
+    let cell = origin + ((slot - origin) % PROBE_GRID_SIZE + PROBE_GRID_SIZE) % PROBE_GRID_SIZE;
+    let position = vec3<f32>(cell) * PROBE_SPACING;
+
+    var rng = hash(index ^ hash(gi_view.frame));
+    var sh = array<vec3<f32>, 4>(vec3<f32>(0.0), vec3<f32>(0.0), vec3<f32>(0.0), vec3<f32>(0.0));
+    for (var i = 0u; i < PROBE_RAYS; i = i + 1u) {
+        let direction = random_direction(&rng);
+        let radiance = trace_radiance(position, direction);
+        sh[0] += radiance * 0.282095;
+        sh[1] += radiance * 0.488603 * direction.y;
+        sh[2] += radiance * 0.488603 * direction.z;
+        sh[3] += radiance * 0.488603 * direction.x;
+    }
+
+    var probe = probes[index];
+    // a fresh cell or a camera cut starts over instead of blending with stale light
+    let keep = select(0.0, PROBE_HYSTERESIS, all(probe.cell.xyz == cell) && gi_view.reset == 0u);
+    let texel = vec2<i32>(slot.x * 4, slot.y + slot.z * PROBE_GRID_SIZE.y);
+    for (var i = 0; i < 4; i = i + 1) {
+        // monte carlo estimate over the sphere, 4 pi / rays
+        let value = mix(sh[i] * (12.566371 / f32(PROBE_RAYS)), probe.sh[i].rgb, keep);
+        probe.sh[i] = vec4<f32>(value, 1.0);
+        textureStore(irradiance_atlas, texel + vec2<i32>(i, 0), vec4<f32>(value, 1.0));
+    }
+    probe.cell = vec4<i32>(cell, 1);
+    probes[index] = probe;
+}
+#endif
+#endif
+#endif
@@ -0,0 +1,609 @@
+use std::io::{Read, Write};
+
+use bevy::math::UVec3;
+use dot_vox::{Color, Model, Size, Voxel};
+
+/// Magic bytes at the start of a raw voxel file.
+pub const RAW_MAGIC: &[u8; 4] = b"SWVX";
+pub const RAW_VERSION: u8 = 1;
+const RAW_FLAG_COMPRESSED: u8 = 1;
+/// Largest model along any axis, voxel coordinates are stored as bytes like in `.vox` files.
+pub const MAX_MODEL_SIZE: u32 = 256;
+
+pub struct ModelStats {
+    pub size: UVec3,
+    pub voxel_count: usize,
+    /// How many voxels use each palette index
+    pub palette_usage: [usize; 256],
+}
+
+impl ModelStats {
+    pub fn fill_ratio(&self) -> f32 {
+        self.voxel_count as f32 / (self.size.x * self.size.y * self.size.z).max(1) as f32
+    }
+
+    pub fn used_colors(&self) -> usize {
+        self.palette_usage
+            .iter()
+            .filter(|count| **count > 0)
+            .count()
+    }
+}
+
+pub fn model_stats(model: &Model) -> ModelStats {
+    let mut palette_usage = [0; 256];
+    for voxel in &model.voxels {
+        palette_usage[voxel.i as usize] += 1;
+    }
+
+    ModelStats {
+        size: UVec3::new(model.size.x, model.size.y, model.size.z),
+        voxel_count: model.voxels.len(),
+        palette_usage,
+    }
+}
+
+/// Dense `x + y * size.x + z * size.x * size.y` grid, 0 is empty and anything else is the palette index + 1,
+/// the same convention `get_model_texture` uses.
+pub fn dense_voxels(model: &Model) -> Vec<u8> {
+    let mut grid = vec![0; (model.size.x * model.size.y * model.size.z) as usize];
+    for voxel in &model.voxels {
+        let index = voxel.x as u32
+            + voxel.y as u32 * model.size.x
+            + voxel.z as u32 * model.size.x * model.size.y;
+        grid[index as usize] = voxel.i + 1;
+    }
+    grid
+}
+
+/// Length of the dense grid of a model of `size`, an error for sizes no `.vox` model can have.
+pub fn dense_len(size: UVec3) -> anyhow::Result<usize> {
+    if size.cmpgt(UVec3::splat(MAX_MODEL_SIZE)).any() {
+        return Err(anyhow::anyhow!(
+            "Model size {} exceeds {} voxels per axis",
+            size,
+            MAX_MODEL_SIZE
+        ));
+    }
+    size.x
+        .checked_mul(size.y)
+        .and_then(|len| len.checked_mul(size.z))
+        .map(|len| len as usize)
+        .ok_or_else(|| anyhow::anyhow!("Model size {} overflows", size))
+}
+
+pub fn model_from_dense(size: UVec3, grid: &[u8]) -> Model {
+    let mut voxels = vec![];
+    for z in 0..size.z {
+        for y in 0..size.y {
+            for x in 0..size.x {
+                let value = grid[(x + y * size.x + z * size.x * size.y) as usize];
+                if value == 0 {
+                    continue;
+                }
+                voxels.push(Voxel {
+                    x: x as u8,
+                    y: y as u8,
+                    z: z as u8,
+                    i: value - 1,
+                });
+            }
+        }
+    }
+
+    Model {
+        size: Size {
+            x: size.x,
+            y: size.y,
+            z: size.z,
+        },
+        voxels,
+    }
+}
+
+/// Run-length encodes `data` as `(run length - 1, value)` byte pairs.
+pub fn rle_encode(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut iter = data.iter().peekable();
+    while let Some(&value) = iter.next() {
+        let mut run = 0u8;
+        while run < u8::MAX && iter.peek() == Some(&&value) {
+            iter.next();
+            run += 1;
+        }
+        out.push(run);
+        out.push(value);
+    }
+    out
+}
+
+pub fn rle_decode(data: &[u8], expected_len: usize) -> anyhow::Result<Vec<u8>> {
+    if !data.len().is_multiple_of(2) {
+        return Err(anyhow::anyhow!("Truncated run-length encoded data"));
+    }
+
+    let mut out = Vec::with_capacity(expected_len);
+    for pair in data.chunks(2) {
+        out.extend(std::iter::repeat_n(pair[1], pair[0] as usize + 1));
+    }
+
+    if out.len() != expected_len {
+        return Err(anyhow::anyhow!(
+            "Expected {} voxels, decoded {}",
+            expected_len,
+            out.len()
+        ));
+    }
+    Ok(out)
+}
+
+/// Writes a model and its palette in the raw format: magic, version, flags, size, 256 palette colors
+/// and the dense voxel grid, optionally run-length encoded.
+pub fn write_raw(
+    writer: &mut impl Write,
+    model: &Model,
+    palette: &[Color],
+    compressed: bool,
+) -> anyhow::Result<()> {
+    writer.write_all(RAW_MAGIC)?;
+    writer.write_all(&[
+        RAW_VERSION,
+        if compressed { RAW_FLAG_COMPRESSED } else { 0 },
+    ])?;
+    for axis in [model.size.x, model.size.y, model.size.z] {
+        writer.write_all(&axis.to_le_bytes())?;
+    }
+    for i in 0..256 {
+        let color = palette.get(i).copied().unwrap_or(Color {
+            r: 0,
+            g: 0,
+            b: 0,
+            a: 0,
+        });
+        writer.write_all(&[color.r, color.g, color.b, color.a])?;
+    }
+
+    let grid = dense_voxels(model);
+    if compressed {
+        writer.write_all(&rle_encode(&grid))?;
+    } else {
+        writer.write_all(&grid)?;
+    }
+    Ok(())
+}
+
+pub fn read_raw(reader: &mut impl Read) -> anyhow::Result<(Model, Vec<Color>)> {
+    let mut header = [0; 18];
+    reader.read_exact(&mut header)?;
+    if &header[0..4] != RAW_MAGIC {
+        return Err(anyhow::anyhow!("Not a raw voxel file"));
+    }
+    if header[4] != RAW_VERSION {
+        return Err(anyhow::anyhow!(
+            "Unsupported raw voxel version {}",
+            header[4]
+        ));
+    }
+    let compressed = header[5] & RAW_FLAG_COMPRESSED != 0;
+    let axis = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+    let size = UVec3::new(axis(6), axis(10), axis(14));
+    let len = dense_len(size)?;
+
+    let mut palette_bytes = [0; 256 * 4];
+    reader.read_exact(&mut palette_bytes)?;
+    let palette = palette_bytes
+        .chunks(4)
+        .map(|c| Color {
+            r: c[0],
+            g: c[1],
+            b: c[2],
+            a: c[3],
+        })
+        .collect();
+
+    let mut body = vec![];
+    reader.read_to_end(&mut body)?;
+    let grid = if compressed {
+        rle_decode(&body, len)?
+    } else if body.len() == len {
+        body
+    } else {
+        return Err(anyhow::anyhow!(
+            "Expected {} voxels, found {}",
+            len,
+            body.len()
+        ));
+    };
+
+    Ok((model_from_dense(size, &grid), palette))
+}
+
+/// Combines models placed at the given offsets into one model large enough to hold all of them,
+/// up to [`MAX_MODEL_SIZE`]. Later models overwrite earlier ones where they overlap.
+pub fn merge_models(models: &[(&Model, UVec3)]) -> anyhow::Result<Model> {
+    let mut size = UVec3::ZERO;
+    for (model, offset) in models {
+        let end = [
+            offset.x.checked_add(model.size.x),
+            offset.y.checked_add(model.size.y),
+            offset.z.checked_add(model.size.z),
+        ];
+        let [Some(x), Some(y), Some(z)] = end else {
+            return Err(anyhow::anyhow!(
+                "Model at offset {} is out of range",
+                offset
+            ));
+        };
+        size = size.max(UVec3::new(x, y, z));
+    }
+    let size = size.min(UVec3::splat(MAX_MODEL_SIZE));
+
+    let mut grid = vec![0; (size.x * size.y * size.z) as usize];
+    for (model, offset) in models {
+        for voxel in &model.voxels {
+            let pos = *offset + UVec3::new(voxel.x as u32, voxel.y as u32, voxel.z as u32);
+            if pos.cmpge(size).any() {
+                continue;
+            }
+            grid[(pos.x + pos.y * size.x + pos.z * size.x * size.y) as usize] = voxel.i + 1;
+        }
+    }
+
+    Ok(model_from_dense(size, &grid))
+}
+
+/// Remaps every voxel to the closest color of `to`, ignoring fully transparent entries. Voxel
+/// indices only go up to 254, the last entry of a 256 color palette is never picked.
+pub fn repalette(model: &Model, from: &[Color], to: &[Color]) -> Model {
+    let distance = |a: &Color, b: &Color| {
+        let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2);
+        d(a.r, b.r) + d(a.g, b.g) + d(a.b, b.b) + d(a.a, b.a)
+    };
+
+    let mut remap = [0u8; 256];
+    for (i, color) in from.iter().enumerate().take(255) {
+        remap[i] = to
+            .iter()
+            .enumerate()
+            .take(255)
+            .filter(|(_, candidate)| candidate.a > 0)
+            .min_by_key(|(_, candidate)| distance(color, candidate))
+            .map(|(index, _)| index as u8)
+            .unwrap_or(i as u8);
+    }
+
+    Model {
+        size: model.size,
+        voxels: model
+            .voxels
+            .iter()
+            .map(|voxel| Voxel {
+                i: remap[voxel.i as usize],
+                ..*voxel
+            })
+            .collect(),
+    }
+}
+
+/// Occupancy pyramid, level 0 has one entry per voxel and every following level halves each axis,
+/// a cell is occupied if any of the cells below it is.
+pub fn occupancy_mips(model: &Model) -> Vec<(UVec3, Vec<bool>)> {
+    let size = UVec3::new(model.size.x, model.size.y, model.size.z);
+    let mut levels = vec![(
+        size,
+        dense_voxels(model)
+            .iter()
+            .map(|v| *v != 0)
+            .collect::<Vec<_>>(),
+    )];
+
+    loop {
+        let (size, cells) = levels.last().unwrap();
+        if size.cmple(UVec3::ONE).all() {
+            break;
+        }
+
+        let next_size = (*size + 1) / 2;
+        let mut next = vec![false; (next_size.x * next_size.y * next_size.z) as usize];
+        for z in 0..size.z {
+            for y in 0..size.y {
+                for x in 0..size.x {
+                    if !cells[(x + y * size.x + z * size.x * size.y) as usize] {
+                        continue;
+                    }
+                    let (nx, ny, nz) = (x / 2, y / 2, z / 2);
+                    next[(nx + ny * next_size.x + nz * next_size.x * next_size.y) as usize] = true;
+                }
+            }
+        }
+        levels.push((next_size, next));
+    }
+
+    levels
+}
+
+/// Greedily merges solid voxels into boxes, returned as inclusive-exclusive `(min, max)` voxel coordinates.
+pub fn collision_boxes(model: &Model) -> Vec<(UVec3, UVec3)> {
+    let size = UVec3::new(model.size.x, model.size.y, model.size.z);
+    let index = |p: UVec3| (p.x + p.y * size.x + p.z * size.x * size.y) as usize;
+    let mut solid = dense_voxels(model)
+        .iter()
+        .map(|v| *v != 0)
+        .collect::<Vec<_>>();
+
+    let mut boxes = vec![];
+    for z in 0..size.z {
+        for y in 0..size.y {
+            for x in 0..size.x {
+                let min = UVec3::new(x, y, z);
+                if !solid[index(min)] {
+                    continue;
+                }
+
+                let mut max = min + 1;
+                while max.x < size.x && solid[index(UVec3::new(max.x, y, z))] {
+                    max.x += 1;
+                }
+                let row_solid = |solid: &[bool], y: u32, z: u32| {
+                    (min.x..max.x).all(|x| solid[index(UVec3::new(x, y, z))])
+                };
+                while max.y < size.y && row_solid(&solid, max.y, z) {
+                    max.y += 1;
+                }
+                while max.z < size.z && (min.y..max.y).all(|y| row_solid(&solid, y, max.z)) {
+                    max.z += 1;
+                }
+
+                for bz in min.z..max.z {
+                    for by in min.y..max.y {
+                        for bx in min.x..max.x {
+                            solid[index(UVec3::new(bx, by, bz))] = false;
+                        }
+                    }
+                }
+                boxes.push((min, max));
+            }
+        }
+    }
+
+    boxes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn color(r: u8, g: u8, b: u8) -> Color {
+        Color { r, g, b, a: 255 }
+    }
+
+    /// Model of `size` with a voxel wherever `solid` says so, coloured by `index`.
+    fn model(size: UVec3, solid: impl Fn(UVec3) -> bool, index: impl Fn(UVec3) -> u8) -> Model {
+        let mut voxels = vec![];
+        for z in 0..size.z {
+            for y in 0..size.y {
+                for x in 0..size.x {
+                    let pos = UVec3::new(x, y, z);
+                    if solid(pos) {
+                        voxels.push(Voxel {
+                            x: x as u8,
+                            y: y as u8,
+                            z: z as u8,
+                            i: index(pos),
+                        });
+                    }
+                }
+            }
+        }
+        Model {
+            size: Size {
+                x: size.x,
+                y: size.y,
+                z: size.z,
+            },
+            voxels,
+        }
+    }
+
+    fn assert_same(a: &Model, b: &Model) {
+        assert_eq!(a.size, b.size);
+        assert_eq!(dense_voxels(a), dense_voxels(b));
+    }
+
+    #[test]
+    fn rle_round_trip() {
+        let cases: [Vec<u8>; 5] = [
+            vec![],
+            vec![7],
+            // runs longer than a byte can count
+            vec![3; 1000],
+            (0..600).map(|i| (i / 3) as u8).collect(),
+            (0..=255).collect(),
+        ];
+        for data in cases {
+            let encoded = rle_encode(&data);
+            assert_eq!(rle_decode(&encoded, data.len()).unwrap(), data);
+        }
+        assert_eq!(rle_encode(&[1, 1, 1, 2]), [2, 1, 0, 2]);
+    }
+
+    #[test]
+    fn rle_decode_rejects_bad_data() {
+        assert!(rle_decode(&[2, 1, 0], 4).is_err());
+        assert!(rle_decode(&[2, 1], 4).is_err());
+        assert!(rle_decode(&[255, 0, 255, 0], 100).is_err());
+    }
+
+    #[test]
+    fn dense_round_trip() {
+        let model = model(
+            UVec3::new(5, 3, 4),
+            |p| (p.x + p.y * 2 + p.z) % 3 == 0,
+            |p| p.x as u8 * 10,
+        );
+        let grid = dense_voxels(&model);
+        assert_eq!(grid.len(), 60);
+        assert_same(&model, &model_from_dense(UVec3::new(5, 3, 4), &grid));
+    }
+
+    #[test]
+    fn raw_round_trip() {
+        let model = model(UVec3::new(9, 4, 6), |p| p.z < 2 || p.x == 4, |p| p.y as u8);
+        let palette = (0..256)
+            .map(|i| color(i as u8, 255 - i as u8, 3))
+            .collect::<Vec<_>>();
+        for compressed in [false, true] {
+            let mut bytes = vec![];
+            write_raw(&mut bytes, &model, &palette, compressed).unwrap();
+            let (read, read_palette) = read_raw(&mut bytes.as_slice()).unwrap();
+            assert_same(&model, &read);
+            assert_eq!(read_palette, palette);
+        }
+    }
+
+    #[test]
+    fn read_raw_rejects_bad_headers() {
+        let model = model(UVec3::splat(2), |_| true, |_| 0);
+        let mut bytes = vec![];
+        write_raw(&mut bytes, &model, &[], false).unwrap();
+
+        let mut wrong_version = bytes.clone();
+        wrong_version[4] = RAW_VERSION + 1;
+        assert!(read_raw(&mut wrong_version.as_slice()).is_err());
+
+        // sizes whose voxel count doesn't fit in a u32
+        let mut huge = bytes.clone();
+        for axis in 0..3 {
+            huge[6 + axis * 4..10 + axis * 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        }
+        assert!(read_raw(&mut huge.as_slice()).is_err());
+
+        let mut too_large = bytes.clone();
+        too_large[6..10].copy_from_slice(&(MAX_MODEL_SIZE + 1).to_le_bytes());
+        assert!(read_raw(&mut too_large.as_slice()).is_err());
+
+        assert!(read_raw(&mut &bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn dense_len_limits() {
+        assert_eq!(dense_len(UVec3::new(2, 3, 4)).unwrap(), 24);
+        assert_eq!(dense_len(UVec3::splat(MAX_MODEL_SIZE)).unwrap(), 1 << 24);
+        assert!(dense_len(UVec3::new(1, MAX_MODEL_SIZE + 1, 1)).is_err());
+    }
+
+    #[test]
+    fn merge_places_models() {
+        let a = model(UVec3::splat(2), |_| true, |_| 1);
+        let b = model(UVec3::new(1, 1, 1), |_| true, |_| 5);
+        let merged = merge_models(&[(&a, UVec3::ZERO), (&b, UVec3::new(1, 1, 3))]).unwrap();
+        assert_eq!((merged.size.x, merged.size.y, merged.size.z), (2, 2, 4));
+        let grid = dense_voxels(&merged);
+        assert_eq!(grid.iter().filter(|v| **v == 2).count(), 8);
+        assert_eq!(grid[1 + 2 + 3 * 4], 6);
+
+        // later models win where they overlap
+        let merged = merge_models(&[(&a, UVec3::ZERO), (&b, UVec3::ZERO)]).unwrap();
+        assert_eq!(dense_voxels(&merged)[0], 6);
+    }
+
+    #[test]
+    fn merge_rejects_overflowing_offsets() {
+        let a = model(UVec3::splat(2), |_| true, |_| 1);
+        assert!(merge_models(&[(&a, UVec3::new(0, u32::MAX, 0))]).is_err());
+        // far away, but still addressable, models are cut off
+        let merged = merge_models(&[(&a, UVec3::new(0, 0, 1000))]).unwrap();
+        assert_eq!(merged.size.z, MAX_MODEL_SIZE);
+        assert!(merged.voxels.is_empty());
+    }
+
+    #[test]
+    fn repalette_picks_closest_opaque_color() {
+        let model = model(UVec3::new(3, 1, 1), |_| true, |p| p.x as u8);
+        let from = [color(250, 0, 0), color(0, 0, 10), color(100, 100, 100)];
+        let mut to = vec![color(0, 0, 0), color(255, 0, 0), color(90, 110, 100)];
+        // transparent entries are never picked, even when they match exactly
+        to.push(Color {
+            a: 0,
+            ..color(250, 0, 0)
+        });
+        let remapped = repalette(&model, &from, &to);
+        let indices = remapped.voxels.iter().map(|v| v.i).collect::<Vec<_>>();
+        assert_eq!(indices, [1, 0, 2]);
+    }
+
+    #[test]
+    fn repalette_never_picks_the_last_entry() {
+        let model = model(UVec3::new(2, 1, 1), |_| true, |p| p.x as u8);
+        let from = [color(10, 200, 30), color(0, 0, 0)];
+        // entry 255 is the only close match, but it can't be stored as a voxel index
+        let mut to = vec![color(0, 0, 0); 256];
+        to[255] = color(10, 200, 30);
+        to[254] = color(20, 180, 40);
+        let remapped = repalette(&model, &from, &to);
+        let indices = remapped.voxels.iter().map(|v| v.i).collect::<Vec<_>>();
+        assert_eq!(indices, [254, 0]);
+        assert_eq!(dense_voxels(&remapped), [255, 1]);
+    }
+
+    #[test]
+    fn occupancy_pyramid() {
+        let model = model(UVec3::new(4, 4, 2), |p| p == UVec3::new(3, 0, 1), |_| 0);
+        let levels = occupancy_mips(&model);
+        let sizes = levels.iter().map(|(size, _)| *size).collect::<Vec<_>>();
+        assert_eq!(
+            sizes,
+            [
+                UVec3::new(4, 4, 2),
+                UVec3::new(2, 2, 1),
+                UVec3::new(1, 1, 1)
+            ]
+        );
+        assert_eq!(levels[0].1.iter().filter(|c| **c).count(), 1);
+        assert!(levels[0].1[3 + 16]);
+        assert_eq!(levels[1].1, [false, true, false, false]);
+        assert_eq!(levels[2].1, [true]);
+
+        let odd = occupancy_mips(&model_from_dense(UVec3::new(3, 1, 1), &[0, 0, 1]));
+        assert_eq!(odd[1], (UVec3::new(2, 1, 1), vec![false, true]));
+    }
+
+    #[test]
+    fn collision_boxes_cover_solid_voxels() {
+        let cube = model(UVec3::splat(3), |_| true, |_| 0);
+        assert_eq!(collision_boxes(&cube), [(UVec3::ZERO, UVec3::splat(3))]);
+
+        // an L, the long leg is found first
+        let l_shape = model(UVec3::new(3, 2, 1), |p| p.y == 0 || p.x == 0, |_| 0);
+        assert_eq!(
+            collision_boxes(&l_shape),
+            [
+                (UVec3::ZERO, UVec3::new(3, 1, 1)),
+                (UVec3::new(0, 1, 0), UVec3::new(1, 2, 1)),
+            ]
+        );
+
+        let scattered = model(
+            UVec3::new(6, 5, 4),
+            |p| (p.x * 7 + p.y * 3 + p.z * 5) % 4 != 0,
+            |_| 0,
+        );
+        let mut covered = vec![false; 6 * 5 * 4];
+        for (min, max) in collision_boxes(&scattered) {
+            for z in min.z..max.z {
+                for y in min.y..max.y {
+                    for x in min.x..max.x {
+                        let cell = &mut covered[(x + y * 6 + z * 30) as usize];
+                        assert!(!*cell, "boxes overlap at {x}, {y}, {z}");
+                        *cell = true;
+                    }
+                }
+            }
+        }
+        let solid = dense_voxels(&scattered)
+            .iter()
+            .map(|v| *v != 0)
+            .collect::<Vec<_>>();
+        assert_eq!(covered, solid);
+    }
+}
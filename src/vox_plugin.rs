@@ -1,4 +1,5 @@
 use bevy::{
+    asset::load_internal_asset,
     core::{Pod, Zeroable},
     prelude::*,
     reflect::TypeUuid,
@@ -7,21 +8,102 @@ use bevy::{
 
 use crate::vox::{Vox, VoxLoader};
 
+pub const VOXEL_MATERIAL_SHADER: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 3717171717171756);
+pub const VOXEL_MATERIAL_PREPASS_SHADER: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 3717171717171757);
+/// Empty 3d texture bound in place of [`VoxelMaterial::radiance_clipmap`] while there is none.
+pub const FALLBACK_RADIANCE_CLIPMAP: HandleUntyped =
+    HandleUntyped::weak_from_u64(Image::TYPE_UUID, 3717171717171763);
+
 #[derive(Default)]
-pub struct VoxelPlugin;
+pub struct VoxelPlugin {
+    /// Asset folder containing `voxel_material.wgsl` and `voxel_material_prepass.wgsl`.
+    /// When set, these are loaded through the asset server instead of the embedded copies,
+    /// so the shaders can be edited with hot reloading, e.g. `Some("shaders".into())`.
+    pub shader_root: Option<String>,
+}
 
 impl Plugin for VoxelPlugin {
     fn build(&self, app: &mut App) {
+        load_internal_asset!(
+            app,
+            VOXEL_MATERIAL_SHADER,
+            "../assets/shaders/voxel_material.wgsl",
+            Shader::from_wgsl
+        );
+        load_internal_asset!(
+            app,
+            VOXEL_MATERIAL_PREPASS_SHADER,
+            "../assets/shaders/voxel_material_prepass.wgsl",
+            Shader::from_wgsl
+        );
+
+        let fallback_clipmap = Image::new_fill(
+            Extent3d {
+                width: 1,
+                height: 1,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D3,
+            &[0; 8],
+            TextureFormat::Rgba16Float,
+        );
+        app.world
+            .resource_mut::<Assets<Image>>()
+            .set_untracked(FALLBACK_RADIANCE_CLIPMAP, fallback_clipmap);
+
+        if let Some(shader_root) = &self.shader_root {
+            let asset_server = app.world.resource::<AssetServer>();
+            let overrides = VoxelShaderOverrides {
+                material: asset_server.load(format!("{shader_root}/voxel_material.wgsl")),
+                prepass: asset_server.load(format!("{shader_root}/voxel_material_prepass.wgsl")),
+            };
+            app.insert_resource(overrides)
+                .add_system(sync_shader_overrides);
+        }
+
         app.init_asset_loader::<VoxLoader>()
             .add_asset::<Vox>()
             .add_plugin(MaterialPlugin::<VoxelMaterial>::default())
-            .add_system(load_material_textures);
+            .add_system(load_material_textures)
+            .add_system(reload_modified_vox.before(load_material_textures));
     }
 }
 
 #[derive(Component)]
 struct VoxelTexturesLoaded;
 
+/// Shaders loaded from [`VoxelPlugin::shader_root`], copied over the embedded ones whenever they change.
+#[derive(Resource)]
+struct VoxelShaderOverrides {
+    material: Handle<Shader>,
+    prepass: Handle<Shader>,
+}
+
+fn sync_shader_overrides(
+    mut events: EventReader<AssetEvent<Shader>>,
+    overrides: Res<VoxelShaderOverrides>,
+    mut shaders: ResMut<Assets<Shader>>,
+) {
+    for event in events.iter() {
+        let (AssetEvent::Created { handle } | AssetEvent::Modified { handle }) = event else {
+            continue;
+        };
+        let internal = if *handle == overrides.material {
+            VOXEL_MATERIAL_SHADER
+        } else if *handle == overrides.prepass {
+            VOXEL_MATERIAL_PREPASS_SHADER
+        } else {
+            continue;
+        };
+        let Some(shader) = shaders.get(handle).cloned() else {
+            continue;
+        };
+        shaders.set_untracked(internal, shader);
+    }
+}
+
 fn load_material_textures(
     mut commands: Commands,
     mut vox_materials: ResMut<Assets<VoxelMaterial>>,
@@ -32,23 +114,25 @@ fn load_material_textures(
         (With<Handle<VoxelMaterial>>, Without<VoxelTexturesLoaded>),
     >,
 ) {
-    for (mat_id, material) in vox_materials
-        .iter_mut()
+    // collect first, `Assets::iter_mut` would mark every material as modified every frame
+    let pending = vox_materials
+        .iter()
         .filter(|(_, material)| material.model_texture.is_none())
-    {
-        let Some(vox) = vox_assets.get(&material.vox) else {
+        .map(|(mat_id, material)| (mat_id, material.vox.clone_weak()))
+        .collect::<Vec<_>>();
+
+    for (mat_id, vox) in pending {
+        let Some(vox) = vox_assets.get(&vox) else {
             continue;
         };
         let Some(mesh) = mesh_assets.get(&vox.mesh) else {
             continue;
         };
-
-        material.model_texture = Some(vox.model_texture.clone());
-        material.palette_texture = Some(vox.palette_texture.clone());
-        material.voxel_extra_data = VoxelExtraData {
-            half_extents: mesh.compute_aabb().unwrap().half_extents.to_array(),
-            _padding: 0,
+        let Some(material) = vox_materials.get_mut(&Handle::weak(mat_id)) else {
+            continue;
         };
+
+        apply_vox_to_material(material, vox, mesh);
         for (entity, entity_mat_id) in entities.iter() {
             if entity_mat_id.id() != mat_id {
                 continue;
@@ -61,6 +145,88 @@ fn load_material_textures(
     }
 }
 
+/// Refreshes every material and entity using a `.vox` file after it was changed on disk,
+/// so edits made in MagicaVoxel show up without restarting.
+fn reload_modified_vox(
+    mut commands: Commands,
+    mut events: EventReader<AssetEvent<Vox>>,
+    mut vox_materials: ResMut<Assets<VoxelMaterial>>,
+    vox_assets: Res<Assets<Vox>>,
+    mesh_assets: Res<Assets<Mesh>>,
+    entities: Query<(Entity, &Handle<VoxelMaterial>)>,
+) {
+    for event in events.iter() {
+        let AssetEvent::Modified { handle } = event else {
+            continue;
+        };
+        let Some(vox) = vox_assets.get(handle) else {
+            continue;
+        };
+        let Some(mesh) = mesh_assets.get(&vox.mesh) else {
+            continue;
+        };
+
+        // collect first, see `load_material_textures`
+        let affected = vox_materials
+            .iter()
+            .filter(|(_, material)| material.vox == *handle)
+            .map(|(mat_id, _)| mat_id)
+            .collect::<Vec<_>>();
+
+        for mat_id in affected {
+            let Some(material) = vox_materials.get_mut(&Handle::weak(mat_id)) else {
+                continue;
+            };
+            apply_vox_to_material(material, vox, mesh);
+
+            for (entity, entity_mat_id) in entities.iter() {
+                if entity_mat_id.id() != mat_id {
+                    continue;
+                }
+                commands
+                    .entity(entity)
+                    .insert(vox.mesh.clone())
+                    .insert(VoxelTexturesLoaded);
+            }
+        }
+    }
+}
+
+/// Runs `update` on every material `outdated` returns true for, leaving the rest untouched.
+#[cfg(feature = "gi")]
+pub(crate) fn update_voxel_materials(
+    vox_materials: &mut Assets<VoxelMaterial>,
+    outdated: impl Fn(&VoxelMaterial) -> bool,
+    mut update: impl FnMut(&mut VoxelMaterial),
+) {
+    // collect first, `Assets::iter_mut` would mark every material as modified every frame
+    let outdated = vox_materials
+        .iter()
+        .filter(|(_, material)| outdated(material))
+        .map(|(mat_id, _)| mat_id)
+        .collect::<Vec<_>>();
+    for mat_id in outdated {
+        if let Some(material) = vox_materials.get_mut(&Handle::weak(mat_id)) {
+            update(material);
+        }
+    }
+}
+
+fn apply_vox_to_material(material: &mut VoxelMaterial, vox: &Vox, mesh: &Mesh) {
+    material.model_texture = Some(vox.model_texture.clone());
+    material.palette_texture = Some(vox.palette_texture.clone());
+    material.translucent = vox.translucent;
+    let mut refraction = [[0.0; 4]; 64];
+    for (index, value) in vox.refraction.iter().enumerate().take(256) {
+        refraction[index / 4][index % 4] = *value;
+    }
+    material.voxel_extra_data = VoxelExtraData {
+        half_extents: mesh.compute_aabb().unwrap().half_extents.to_array(),
+        refraction,
+        ..material.voxel_extra_data
+    };
+}
+
 #[derive(Debug, Clone, TypeUuid, Default)]
 #[uuid = "8dd2b425-45a2-4a53-ac29-7ce356b2d5fe"]
 pub struct VoxelMaterial {
@@ -70,13 +236,48 @@ pub struct VoxelMaterial {
     /// Srgb texture containing the palette data
     pub palette_texture: Option<Handle<Image>>,
     pub voxel_extra_data: VoxelExtraData,
+    /// Irradiance probe atlas, sampled for diffuse indirect light when set.
+    /// Filled in by the GI plugin when a camera uses probe GI.
+    pub irradiance_probes: Option<Handle<Image>>,
+    /// Screen space sun shadows and ambient occlusion traced against the voxel scene.
+    /// Filled in by the GI plugin when a camera uses traced shadows.
+    pub shadow_mask: Option<Handle<Image>>,
+    /// Radiance clipmap around the camera, cone traced for diffuse and glossy indirect light when
+    /// set. Filled in by the cone tracing plugin, together with [`VoxelExtraData::cone_tracing`].
+    pub radiance_clipmap: Option<Handle<Image>>,
+    /// Bend rays by the refractive index of the translucent voxels they pass through, so whatever
+    /// is behind glass or water inside the model shows distorted. The rest of the scene is only
+    /// blended in behind the model and never bends.
+    pub refraction: bool,
+    /// Set from [`Vox::translucent`], the material is then drawn in the transparent phase and
+    /// accumulates colour through the translucent voxels.
+    pub translucent: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct VoxelMaterialKey {
+    irradiance_probes: bool,
+    shadow_mask: bool,
+    radiance_clipmap: bool,
+    translucent: bool,
+    refraction: bool,
 }
 
-#[derive(Debug, Clone, Default, Copy, Pod, Zeroable)]
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
 #[repr(C, align(16))]
 pub struct VoxelExtraData {
     pub half_extents: [f32; 3],
     pub _padding: u32,
+    /// Diffuse strength, glossy strength and glossy cone aperture of the cone traced light
+    pub cone_tracing: [f32; 4],
+    /// Refractive index minus one of every palette index, four to a vector
+    pub refraction: [[f32; 4]; 64],
+}
+
+impl Default for VoxelExtraData {
+    fn default() -> Self {
+        Self::zeroed()
+    }
 }
 
 #[derive(Bundle, Clone, Default)]
@@ -91,14 +292,14 @@ pub struct VoxelBundle {
 }
 
 impl AsBindGroup for VoxelMaterial {
-    type Data = ();
+    type Data = VoxelMaterialKey;
 
     fn as_bind_group(
         &self,
         layout: &bevy::render::render_resource::BindGroupLayout,
         render_device: &bevy::render::renderer::RenderDevice,
         images: &bevy::render::render_asset::RenderAssets<Image>,
-        _fallback_image: &bevy::render::texture::FallbackImage,
+        fallback_image: &bevy::render::texture::FallbackImage,
     ) -> Result<
         bevy::render::render_resource::PreparedBindGroup<Self::Data>,
         bevy::render::render_resource::AsBindGroupError,
@@ -113,6 +314,27 @@ impl AsBindGroup for VoxelMaterial {
         } else {
             return Err(AsBindGroupError::RetryNextUpdate);
         };
+        let irradiance_probes = match &self.irradiance_probes {
+            Some(probes) => match images.get(probes) {
+                Some(probes) => &probes.texture_view,
+                None => return Err(AsBindGroupError::RetryNextUpdate),
+            },
+            None => &fallback_image.texture_view,
+        };
+        let shadow_mask = match &self.shadow_mask {
+            Some(mask) => match images.get(mask) {
+                Some(mask) => &mask.texture_view,
+                None => return Err(AsBindGroupError::RetryNextUpdate),
+            },
+            None => &fallback_image.texture_view,
+        };
+        let radiance_clipmap = self
+            .radiance_clipmap
+            .clone()
+            .unwrap_or_else(|| FALLBACK_RADIANCE_CLIPMAP.typed());
+        let Some(radiance_clipmap) = images.get(&radiance_clipmap) else {
+            return Err(AsBindGroupError::RetryNextUpdate);
+        };
 
         let buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
             contents: bytemuck::cast_slice(&[self.voxel_extra_data]),
@@ -136,13 +358,35 @@ impl AsBindGroup for VoxelMaterial {
                     binding: 2,
                     resource: BindingResource::Buffer(buffer.as_entire_buffer_binding()),
                 },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: BindingResource::TextureView(irradiance_probes),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: BindingResource::TextureView(shadow_mask),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: BindingResource::TextureView(&radiance_clipmap.texture_view),
+                },
+                BindGroupEntry {
+                    binding: 6,
+                    resource: BindingResource::Sampler(&radiance_clipmap.sampler),
+                },
             ],
         });
 
         Ok(PreparedBindGroup {
             bindings: vec![],
             bind_group,
-            data: (),
+            data: VoxelMaterialKey {
+                irradiance_probes: self.irradiance_probes.is_some(),
+                shadow_mask: self.shadow_mask.is_some(),
+                radiance_clipmap: self.radiance_clipmap.is_some(),
+                translucent: self.translucent,
+                refraction: self.translucent && self.refraction,
+            },
         })
     }
 
@@ -185,26 +429,102 @@ impl AsBindGroup for VoxelMaterial {
                     },
                     count: None,
                 },
+                BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Texture {
+                        sample_type: TextureSampleType::Float { filterable: false },
+                        view_dimension: TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Texture {
+                        sample_type: TextureSampleType::Float { filterable: false },
+                        view_dimension: TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Texture {
+                        sample_type: TextureSampleType::Float { filterable: true },
+                        view_dimension: TextureViewDimension::D3,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 6,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Sampler(SamplerBindingType::Filtering),
+                    count: None,
+                },
             ],
         })
     }
 }
 
 impl Material for VoxelMaterial {
-    // fn prepass_fragment_shader() -> ShaderRef {
-    //     r#"C:\Users\dylan\dev\lastattempt\assets\shaders\voxel_material_prepass.wgsl"#.into()
-    // }
+    fn prepass_fragment_shader() -> ShaderRef {
+        VOXEL_MATERIAL_PREPASS_SHADER.typed().into()
+    }
+
     fn fragment_shader() -> ShaderRef {
-        r#"C:\Users\dylan\dev\lastattempt\assets\shaders\voxel_material.wgsl"#.into()
+        VOXEL_MATERIAL_SHADER.typed().into()
+    }
+
+    fn alpha_mode(&self) -> AlphaMode {
+        // the shader outputs radiance already weighted by how much of the background it hides
+        if self.translucent {
+            AlphaMode::Premultiplied
+        } else {
+            AlphaMode::Opaque
+        }
     }
 
     fn specialize(
         _pipeline: &bevy::pbr::MaterialPipeline<Self>,
         descriptor: &mut RenderPipelineDescriptor,
         _layout: &bevy::render::mesh::MeshVertexBufferLayout,
-        _key: bevy::pbr::MaterialPipelineKey<Self>,
+        key: bevy::pbr::MaterialPipelineKey<Self>,
     ) -> Result<(), SpecializedMeshPipelineError> {
         descriptor.primitive.cull_mode = None;
+        if descriptor.label.as_deref() == Some("prepass_pipeline") {
+            // bevy only runs a fragment shader in the prepass of opaque materials when normals are
+            // written, but the depth has to come from the voxels rather than the bounding box
+            if descriptor.fragment.is_none() {
+                descriptor.fragment = Some(FragmentState {
+                    shader: VOXEL_MATERIAL_PREPASS_SHADER.typed(),
+                    shader_defs: descriptor.vertex.shader_defs.clone(),
+                    entry_point: "fragment".into(),
+                    targets: vec![],
+                });
+            }
+            return Ok(());
+        }
+        if let Some(fragment) = descriptor.fragment.as_mut() {
+            if key.bind_group_data.irradiance_probes {
+                fragment.shader_defs.push("VOXEL_GI_PROBES".into());
+            }
+            if key.bind_group_data.shadow_mask {
+                fragment.shader_defs.push("VOXEL_GI_SHADOWS".into());
+            }
+            if key.bind_group_data.radiance_clipmap {
+                fragment.shader_defs.push("VOXEL_GI_CONE_TRACING".into());
+            }
+            if key.bind_group_data.translucent {
+                fragment.shader_defs.push("VOXEL_TRANSLUCENT".into());
+            }
+            if key.bind_group_data.refraction {
+                fragment.shader_defs.push("VOXEL_REFRACTION".into());
+            }
+        }
 
         Ok(())
     }
@@ -0,0 +1,866 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use bevy::prelude::*;
+use dot_vox::Color;
+
+use crate::{
+    vox::{
+        get_mesh_from_model, get_model_texture, get_palette_texture, has_translucent_voxels, Vox,
+        VOXEL_SIZE,
+    },
+    vox_ops::{dense_len, model_from_dense, rle_decode, rle_encode},
+    vox_plugin::{VoxelBundle, VoxelMaterial},
+};
+
+pub const CHUNK_MAGIC: &[u8; 4] = b"SWCK";
+pub const CHUNK_VERSION: u16 = 1;
+pub const REGION_MAGIC: &[u8; 4] = b"SWRG";
+pub const REGION_VERSION: u16 = 1;
+/// Chunks per axis stored in a single region file
+pub const REGION_SIZE: i32 = 16;
+
+const STORAGE_RLE: u8 = 0;
+const STORAGE_PALETTE: u8 = 1;
+
+#[derive(Default)]
+pub struct VoxelWorldPlugin;
+
+impl Plugin for VoxelWorldPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<VoxelWorld>()
+            .add_event::<SaveVoxelWorld>()
+            .add_event::<LoadVoxelWorld>()
+            .add_system(save_voxel_world)
+            .add_system(load_voxel_world)
+            .add_system(update_edited_chunks);
+    }
+}
+
+/// Where the world is saved and how chunk coordinates map to world space.
+#[derive(Resource, Clone)]
+pub struct VoxelWorld {
+    /// Directory the region files are written to
+    pub directory: PathBuf,
+    /// World position of chunk `(0, 0, 0)`
+    pub origin: Vec3,
+}
+
+impl Default for VoxelWorld {
+    fn default() -> Self {
+        Self {
+            directory: "world".into(),
+            origin: Vec3::ZERO,
+        }
+    }
+}
+
+impl VoxelWorld {
+    pub fn chunk_translation(&self, chunk: &VoxelChunk) -> Vec3 {
+        let world_size = Vec3::new(
+            chunk.size.x as f32,
+            chunk.size.z as f32,
+            chunk.size.y as f32,
+        ) * VOXEL_SIZE;
+        self.origin + chunk.coord.as_vec3() * world_size
+    }
+}
+
+/// Write every [`VoxelChunk`] to [`VoxelWorld::directory`].
+pub struct SaveVoxelWorld;
+
+/// Replace all [`VoxelChunk`] entities with the ones stored in [`VoxelWorld::directory`].
+pub struct LoadVoxelWorld;
+
+/// Editable voxel data of a chunk, saved with the world. Changing it updates the rendered textures.
+#[derive(Component, Clone, Debug)]
+pub struct VoxelChunk {
+    pub coord: IVec3,
+    /// Size in `.vox` axes, z is up
+    pub size: UVec3,
+    /// Dense `x + y * size.x + z * size.x * size.y` grid, 0 is empty and anything else is the palette index + 1
+    pub voxels: Vec<u8>,
+    pub palette: Vec<Color>,
+}
+
+impl VoxelChunk {
+    pub fn from_model(coord: IVec3, model: &dot_vox::Model, palette: Vec<Color>) -> Self {
+        Self {
+            coord,
+            size: UVec3::new(model.size.x, model.size.y, model.size.z),
+            voxels: crate::vox_ops::dense_voxels(model),
+            palette,
+        }
+    }
+
+    pub fn to_model(&self) -> dot_vox::Model {
+        model_from_dense(self.size, &self.voxels)
+    }
+
+    /// Header, palette and voxels, stored either run-length encoded or as bit-packed indices
+    /// into the values used by this chunk, whichever is smaller. Fails for chunks
+    /// [`VoxelChunk::decode`] couldn't read back.
+    pub fn encode(&self) -> anyhow::Result<Vec<u8>> {
+        if dense_len(self.size)? != self.voxels.len() {
+            return Err(anyhow::anyhow!(
+                "Chunk {} of size {} has {} voxels",
+                self.coord,
+                self.size,
+                self.voxels.len()
+            ));
+        }
+        if self.palette.len() > u16::MAX as usize {
+            return Err(anyhow::anyhow!(
+                "Chunk {} has {} palette colors",
+                self.coord,
+                self.palette.len()
+            ));
+        }
+
+        let mut out = vec![];
+        out.extend_from_slice(CHUNK_MAGIC);
+        out.extend_from_slice(&CHUNK_VERSION.to_le_bytes());
+        for axis in [self.size.x, self.size.y, self.size.z] {
+            out.extend_from_slice(&(axis as u16).to_le_bytes());
+        }
+        out.extend_from_slice(&(self.palette.len() as u16).to_le_bytes());
+        for color in &self.palette {
+            out.extend_from_slice(&[color.r, color.g, color.b, color.a]);
+        }
+
+        let rle = rle_encode(&self.voxels);
+        let packed = pack_palette(&self.voxels);
+        let (storage, body) = if packed.len() < rle.len() {
+            (STORAGE_PALETTE, packed)
+        } else {
+            (STORAGE_RLE, rle)
+        };
+        out.push(storage);
+        out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        out.extend_from_slice(&body);
+        Ok(out)
+    }
+
+    pub fn decode(coord: IVec3, bytes: &[u8]) -> anyhow::Result<Self> {
+        let mut reader = ByteReader(bytes);
+        if reader.take(4)? != CHUNK_MAGIC {
+            return Err(anyhow::anyhow!("Not a voxel chunk"));
+        }
+        let version = reader.u16()?;
+        if version != CHUNK_VERSION {
+            return Err(anyhow::anyhow!("Unsupported chunk version {}", version));
+        }
+        let size = UVec3::new(
+            reader.u16()? as u32,
+            reader.u16()? as u32,
+            reader.u16()? as u32,
+        );
+        let palette_len = reader.u16()? as usize;
+        let palette = reader
+            .take(palette_len * 4)?
+            .chunks(4)
+            .map(|c| Color {
+                r: c[0],
+                g: c[1],
+                b: c[2],
+                a: c[3],
+            })
+            .collect();
+
+        // the size comes from the file, chunks larger than any model are corrupt
+        let len = dense_len(size)?;
+        let storage = reader.take(1)?[0];
+        let body_len = reader.u32()? as usize;
+        let body = reader.take(body_len)?;
+        let voxels = match storage {
+            STORAGE_RLE => rle_decode(body, len)?,
+            STORAGE_PALETTE => unpack_palette(body, len)?,
+            _ => return Err(anyhow::anyhow!("Unknown chunk storage {}", storage)),
+        };
+
+        Ok(Self {
+            coord,
+            size,
+            voxels,
+            palette,
+        })
+    }
+}
+
+/// Local palette of the distinct values followed by `ceil(log2(n))` bit indices into it.
+fn pack_palette(voxels: &[u8]) -> Vec<u8> {
+    let mut used = [false; 256];
+    for v in voxels {
+        used[*v as usize] = true;
+    }
+    let values = (0..=255u8)
+        .filter(|v| used[*v as usize])
+        .collect::<Vec<_>>();
+    let bits = bits_for(values.len());
+
+    let mut out = vec![values.len() as u8];
+    out.extend_from_slice(&values);
+    if bits == 0 {
+        return out;
+    }
+
+    let mut lookup = [0u8; 256];
+    for (i, v) in values.iter().enumerate() {
+        lookup[*v as usize] = i as u8;
+    }
+    let mut acc = 0u32;
+    let mut acc_bits = 0;
+    for v in voxels {
+        acc |= (lookup[*v as usize] as u32) << acc_bits;
+        acc_bits += bits;
+        while acc_bits >= 8 {
+            out.push(acc as u8);
+            acc >>= 8;
+            acc_bits -= 8;
+        }
+    }
+    if acc_bits > 0 {
+        out.push(acc as u8);
+    }
+    out
+}
+
+fn unpack_palette(data: &[u8], len: usize) -> anyhow::Result<Vec<u8>> {
+    let mut reader = ByteReader(data);
+    // an empty chunk has no values at all, otherwise 256 distinct values wrap around to 0
+    let count = match reader.take(1)?[0] {
+        0 if len == 0 => return Ok(vec![]),
+        0 => 256,
+        n => n as usize,
+    };
+    let values = reader.take(count)?;
+    let bits = bits_for(count);
+    if bits == 0 {
+        return Ok(vec![values[0]; len]);
+    }
+
+    let packed = reader.0;
+    if packed.len() * 8 < len * bits as usize {
+        return Err(anyhow::anyhow!("Truncated palette compressed chunk"));
+    }
+    let mask = (1u32 << bits) - 1;
+    let mut out = Vec::with_capacity(len);
+    for i in 0..len {
+        let bit = i * bits as usize;
+        let byte = bit / 8;
+        let mut word = packed[byte] as u32;
+        if byte + 1 < packed.len() {
+            word |= (packed[byte + 1] as u32) << 8;
+        }
+        let index = ((word >> (bit % 8)) & mask) as usize;
+        out.push(
+            *values
+                .get(index)
+                .ok_or_else(|| anyhow::anyhow!("Invalid palette index"))?,
+        );
+    }
+    Ok(out)
+}
+
+fn bits_for(count: usize) -> u32 {
+    usize::BITS - count.saturating_sub(1).leading_zeros()
+}
+
+struct ByteReader<'a>(&'a [u8]);
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(anyhow::anyhow!("Unexpected end of data"));
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u16(&mut self) -> anyhow::Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+}
+
+/// Container for the chunks of one `REGION_SIZE³` area: a header, an index of
+/// `(local coord, offset, length)` entries and the encoded chunks.
+pub struct RegionFile;
+
+impl RegionFile {
+    pub fn region_of(coord: IVec3) -> IVec3 {
+        IVec3::new(
+            coord.x.div_euclid(REGION_SIZE),
+            coord.y.div_euclid(REGION_SIZE),
+            coord.z.div_euclid(REGION_SIZE),
+        )
+    }
+
+    pub fn file_name(region: IVec3) -> String {
+        format!("r.{}.{}.{}.swr", region.x, region.y, region.z)
+    }
+
+    /// Fails unless all chunks lie in the same region.
+    pub fn write(writer: &mut impl Write, chunks: &[&VoxelChunk]) -> anyhow::Result<()> {
+        if let Some(first) = chunks.first() {
+            let region = Self::region_of(first.coord);
+            if let Some(chunk) = chunks.iter().find(|c| Self::region_of(c.coord) != region) {
+                return Err(anyhow::anyhow!(
+                    "Chunk {} lies outside region {}",
+                    chunk.coord,
+                    region
+                ));
+            }
+        }
+        let encoded = chunks
+            .iter()
+            .map(|c| c.encode())
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        writer.write_all(REGION_MAGIC)?;
+        writer.write_all(&REGION_VERSION.to_le_bytes())?;
+        writer.write_all(&(chunks.len() as u32).to_le_bytes())?;
+
+        let header_len = 4 + 2 + 4 + chunks.len() * (3 + 4 + 4);
+        let mut offset = header_len as u32;
+        for (chunk, bytes) in chunks.iter().zip(&encoded) {
+            let local = chunk.coord - Self::region_of(chunk.coord) * REGION_SIZE;
+            writer.write_all(&[local.x as u8, local.y as u8, local.z as u8])?;
+            writer.write_all(&offset.to_le_bytes())?;
+            writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+            offset += bytes.len() as u32;
+        }
+        for bytes in &encoded {
+            writer.write_all(bytes)?;
+        }
+        Ok(())
+    }
+
+    pub fn read(reader: &mut impl Read, region: IVec3) -> anyhow::Result<Vec<VoxelChunk>> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes)?;
+
+        let mut header = ByteReader(&bytes);
+        if header.take(4)? != REGION_MAGIC {
+            return Err(anyhow::anyhow!("Not a region file"));
+        }
+        let version = header.u16()?;
+        if version != REGION_VERSION {
+            return Err(anyhow::anyhow!("Unsupported region version {}", version));
+        }
+
+        let count = header.u32()?;
+        if count > REGION_SIZE.pow(3) as u32 {
+            return Err(anyhow::anyhow!(
+                "Region file lists {} chunks, more than a region holds",
+                count
+            ));
+        }
+        let header_len = 4 + 2 + 4 + count as usize * (3 + 4 + 4);
+        let mut chunks = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let local = header.take(3)?;
+            let coord = region * REGION_SIZE
+                + IVec3::new(local[0] as i32, local[1] as i32, local[2] as i32);
+            let offset = header.u32()? as usize;
+            let len = header.u32()? as usize;
+            // chunks can only follow the index, and have to end before the file does
+            let data = offset
+                .checked_add(len)
+                .filter(|end| offset >= header_len && *end <= bytes.len())
+                .map(|end| &bytes[offset..end])
+                .ok_or_else(|| anyhow::anyhow!("Chunk {} lies outside the region file", coord))?;
+            chunks.push(VoxelChunk::decode(coord, data)?);
+        }
+        Ok(chunks)
+    }
+}
+
+pub fn spawn_chunk(
+    commands: &mut Commands,
+    world: &VoxelWorld,
+    chunk: VoxelChunk,
+    meshes: &mut Assets<Mesh>,
+    textures: &mut Assets<Image>,
+    vox_assets: &mut Assets<Vox>,
+    vox_materials: &mut Assets<VoxelMaterial>,
+) -> Entity {
+    let model = chunk.to_model();
+    let translation = world.chunk_translation(&chunk);
+
+    commands
+        .spawn(VoxelBundle {
+            material: vox_materials.add(VoxelMaterial {
+                vox: vox_assets.add(Vox {
+                    model_texture: textures.add(get_model_texture(&model)),
+                    palette_texture: textures.add(get_palette_texture(chunk.palette.clone())),
+                    mesh: meshes.add(get_mesh_from_model(&model)),
+                    emission: vec![],
+                    refraction: vec![],
+                    translucent: has_translucent_voxels(&model, &chunk.palette),
+                }),
+                ..Default::default()
+            }),
+            transform: Transform::from_translation(translation),
+            ..Default::default()
+        })
+        .insert(chunk)
+        .id()
+}
+
+fn save_voxel_world(
+    mut events: EventReader<SaveVoxelWorld>,
+    world: Res<VoxelWorld>,
+    chunks: Query<&VoxelChunk>,
+) {
+    if events.iter().count() == 0 {
+        return;
+    }
+
+    match save_regions(&world.directory, &chunks.iter().collect::<Vec<_>>()) {
+        Ok(regions) => info!(
+            "Saved {} chunks in {} regions to {}",
+            chunks.iter().len(),
+            regions,
+            world.directory.display()
+        ),
+        Err(e) => error!("Failed to save voxel world: {e:#}"),
+    }
+}
+
+/// Writes a region file for every region with chunks in it and removes the files of regions
+/// without any, returns the number of regions written.
+fn save_regions(directory: &Path, chunks: &[&VoxelChunk]) -> anyhow::Result<usize> {
+    let mut regions: HashMap<IVec3, Vec<&VoxelChunk>> = HashMap::new();
+    for chunk in chunks {
+        regions
+            .entry(RegionFile::region_of(chunk.coord))
+            .or_default()
+            .push(chunk);
+    }
+
+    std::fs::create_dir_all(directory)?;
+    for (region, chunks) in &regions {
+        // write next to the region and swap it in, a failed save leaves the old file intact
+        let path = directory.join(RegionFile::file_name(*region));
+        let temp_path = path.with_extension("swr.tmp");
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        RegionFile::write(&mut writer, chunks)?;
+        writer.flush()?;
+        drop(writer);
+        std::fs::rename(&temp_path, &path)?;
+    }
+
+    // chunks deleted since the last save would come back on load otherwise
+    for entry in std::fs::read_dir(directory)? {
+        let path = entry?.path();
+        let region = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(parse_region_name);
+        if region.is_some_and(|region| !regions.contains_key(&region)) {
+            std::fs::remove_file(&path)?;
+        }
+    }
+    Ok(regions.len())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn load_voxel_world(
+    mut commands: Commands,
+    mut events: EventReader<LoadVoxelWorld>,
+    world: Res<VoxelWorld>,
+    existing: Query<Entity, With<VoxelChunk>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut textures: ResMut<Assets<Image>>,
+    mut vox_assets: ResMut<Assets<Vox>>,
+    mut vox_materials: ResMut<Assets<VoxelMaterial>>,
+) {
+    if events.iter().count() == 0 {
+        return;
+    }
+
+    let read_regions = || -> anyhow::Result<Vec<VoxelChunk>> {
+        let mut chunks = vec![];
+        for entry in std::fs::read_dir(&world.directory)? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            let Some(region) = parse_region_name(name) else {
+                continue;
+            };
+            chunks.extend(RegionFile::read(
+                &mut BufReader::new(File::open(&path)?),
+                region,
+            )?);
+        }
+        Ok(chunks)
+    };
+
+    let chunks = match read_regions() {
+        Ok(chunks) => chunks,
+        Err(e) => {
+            error!("Failed to load voxel world: {e:#}");
+            return;
+        }
+    };
+
+    for entity in existing.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+
+    info!(
+        "Loaded {} chunks from {}",
+        chunks.len(),
+        world.directory.display()
+    );
+    for chunk in chunks {
+        spawn_chunk(
+            &mut commands,
+            &world,
+            chunk,
+            &mut meshes,
+            &mut textures,
+            &mut vox_assets,
+            &mut vox_materials,
+        );
+    }
+}
+
+fn parse_region_name(name: &str) -> Option<IVec3> {
+    let parts = name
+        .strip_prefix("r.")?
+        .strip_suffix(".swr")?
+        .split('.')
+        .map(|p| p.parse::<i32>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    match parts[..] {
+        [x, y, z] => Some(IVec3::new(x, y, z)),
+        _ => None,
+    }
+}
+
+/// Re-uploads the model texture of chunks whose voxels were edited.
+fn update_edited_chunks(
+    chunks: Query<(Ref<VoxelChunk>, &Handle<VoxelMaterial>), Changed<VoxelChunk>>,
+    mut vox_materials: ResMut<Assets<VoxelMaterial>>,
+    vox_assets: Res<Assets<Vox>>,
+    mut textures: ResMut<Assets<Image>>,
+) {
+    for (chunk, material) in chunks.iter() {
+        if chunk.is_added() {
+            continue;
+        }
+        let Some(vox) = vox_materials
+            .get(material)
+            .and_then(|m| vox_assets.get(&m.vox))
+        else {
+            continue;
+        };
+
+        let model = chunk.to_model();
+        textures.set_untracked(&vox.model_texture, get_model_texture(&model));
+        textures.set_untracked(
+            &vox.palette_texture,
+            get_palette_texture(chunk.palette.clone()),
+        );
+        // the bind group holds on to the old texture view until the material changes
+        vox_materials.get_mut(material);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vox_ops::MAX_MODEL_SIZE;
+
+    fn chunk(coord: IVec3, size: UVec3, voxel: impl Fn(UVec3) -> u8) -> VoxelChunk {
+        let mut voxels = Vec::with_capacity((size.x * size.y * size.z) as usize);
+        for z in 0..size.z {
+            for y in 0..size.y {
+                for x in 0..size.x {
+                    voxels.push(voxel(UVec3::new(x, y, z)));
+                }
+            }
+        }
+        VoxelChunk {
+            coord,
+            size,
+            voxels,
+            palette: vec![
+                Color {
+                    r: 255,
+                    g: 0,
+                    b: 0,
+                    a: 255,
+                },
+                Color {
+                    r: 0,
+                    g: 128,
+                    b: 255,
+                    a: 100,
+                },
+            ],
+        }
+    }
+
+    fn assert_same(a: &VoxelChunk, b: &VoxelChunk) {
+        assert_eq!(a.coord, b.coord);
+        assert_eq!(a.size, b.size);
+        assert_eq!(a.voxels, b.voxels);
+        assert_eq!(a.palette, b.palette);
+    }
+
+    /// Storage byte after the magic, version, size, palette length and the two palette colors.
+    fn storage(encoded: &[u8]) -> u8 {
+        encoded[4 + 2 + 6 + 2 + 4 * 2]
+    }
+
+    fn round_trip(chunk: &VoxelChunk) -> u8 {
+        let encoded = chunk.encode().unwrap();
+        assert_same(chunk, &VoxelChunk::decode(chunk.coord, &encoded).unwrap());
+        storage(&encoded)
+    }
+
+    #[test]
+    fn rle_chunk_round_trip() {
+        // long runs compress better than bit-packed indices
+        let layers = chunk(IVec3::new(1, -2, 3), UVec3::new(16, 16, 16), |p| {
+            (p.z < 4) as u8 + (p.z < 2) as u8
+        });
+        assert_eq!(round_trip(&layers), STORAGE_RLE);
+    }
+
+    #[test]
+    fn palette_chunk_round_trip() {
+        // noise defeats run-length encoding
+        let noise = chunk(IVec3::ZERO, UVec3::new(9, 7, 5), |p| {
+            ((p.x * 7 + p.y * 13 + p.z * 5) % 3) as u8
+        });
+        assert_eq!(round_trip(&noise), STORAGE_PALETTE);
+    }
+
+    #[test]
+    fn empty_chunk_round_trip() {
+        round_trip(&chunk(IVec3::ZERO, UVec3::ZERO, |_| 1));
+        round_trip(&chunk(IVec3::ZERO, UVec3::new(4, 0, 4), |_| 1));
+        round_trip(&chunk(IVec3::ZERO, UVec3::new(4, 4, 4), |_| 0));
+    }
+
+    #[test]
+    fn single_material_chunk_round_trip() {
+        round_trip(&chunk(IVec3::ZERO, UVec3::new(1, 1, 1), |_| 2));
+        round_trip(&chunk(IVec3::ZERO, UVec3::new(32, 32, 32), |_| 1));
+        // both storages have to handle a single value
+        let solid = chunk(IVec3::ZERO, UVec3::new(5, 3, 2), |_| 1);
+        assert_eq!(pack_palette(&solid.voxels), [1, 1]);
+        assert_eq!(
+            unpack_palette(&[1, 1], solid.voxels.len()).unwrap(),
+            solid.voxels
+        );
+    }
+
+    #[test]
+    fn pack_palette_round_trip() {
+        for count in [1, 2, 3, 4, 5, 16, 17, 200, 256] {
+            let voxels = (0..1000)
+                .map(|i| ((i * 37) % count) as u8)
+                .collect::<Vec<_>>();
+            let packed = pack_palette(&voxels);
+            assert_eq!(unpack_palette(&packed, voxels.len()).unwrap(), voxels);
+        }
+        assert!(unpack_palette(&pack_palette(&[]), 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn corrupt_chunks_fail() {
+        let noise = chunk(IVec3::ZERO, UVec3::new(9, 7, 5), |p| {
+            ((p.x + p.y * p.z) % 3) as u8
+        });
+        let encoded = noise.encode().unwrap();
+        for len in [0, 3, 10, encoded.len() / 2, encoded.len() - 1] {
+            assert!(VoxelChunk::decode(IVec3::ZERO, &encoded[..len]).is_err());
+        }
+
+        let mut magic = encoded.clone();
+        magic[0] = b'X';
+        assert!(VoxelChunk::decode(IVec3::ZERO, &magic).is_err());
+
+        let mut unknown = encoded.clone();
+        unknown[4 + 2 + 6 + 2 + 4 * 2] = 7;
+        assert!(VoxelChunk::decode(IVec3::ZERO, &unknown).is_err());
+
+        // sizes beyond what a model can hold are rejected before allocating anything
+        let mut huge = encoded;
+        huge[6..12].copy_from_slice(&[0xff; 6]);
+        assert!(VoxelChunk::decode(IVec3::ZERO, &huge).is_err());
+    }
+
+    #[test]
+    fn unreadable_chunks_are_not_encoded() {
+        let huge = chunk(IVec3::ZERO, UVec3::new(MAX_MODEL_SIZE + 1, 1, 1), |_| 1);
+        assert!(huge.encode().is_err());
+
+        let mut short = chunk(IVec3::ZERO, UVec3::new(2, 2, 2), |_| 1);
+        short.voxels.pop();
+        assert!(short.encode().is_err());
+
+        let mut colorful = chunk(IVec3::ZERO, UVec3::new(2, 2, 2), |_| 1);
+        colorful.palette = vec![colorful.palette[0]; u16::MAX as usize + 1];
+        assert!(colorful.encode().is_err());
+
+        let largest = chunk(IVec3::ZERO, UVec3::new(MAX_MODEL_SIZE, 1, 2), |p| p.x as u8);
+        round_trip(&largest);
+    }
+
+    #[test]
+    fn chunk_version_mismatch_fails() {
+        let mut encoded = chunk(IVec3::ZERO, UVec3::new(2, 2, 2), |_| 1)
+            .encode()
+            .unwrap();
+        encoded[4..6].copy_from_slice(&(CHUNK_VERSION + 1).to_le_bytes());
+        let error = VoxelChunk::decode(IVec3::ZERO, &encoded).unwrap_err();
+        assert!(error.to_string().contains("version"));
+    }
+
+    fn region_chunks() -> Vec<VoxelChunk> {
+        vec![
+            chunk(IVec3::new(-16, 0, 15), UVec3::new(8, 8, 8), |p| {
+                (p.z < 3) as u8
+            }),
+            chunk(IVec3::new(-1, 15, 0), UVec3::new(3, 5, 7), |p| {
+                ((p.x + p.y) % 3) as u8
+            }),
+            chunk(IVec3::new(-9, 4, 4), UVec3::ZERO, |_| 0),
+        ]
+    }
+
+    fn write_region(chunks: &[VoxelChunk]) -> Vec<u8> {
+        let mut bytes = vec![];
+        RegionFile::write(&mut bytes, &chunks.iter().collect::<Vec<_>>()).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn region_file_round_trip() {
+        let chunks = region_chunks();
+        let region = RegionFile::region_of(chunks[0].coord);
+        assert!(chunks
+            .iter()
+            .all(|chunk| RegionFile::region_of(chunk.coord) == region));
+
+        let bytes = write_region(&chunks);
+        let read = RegionFile::read(&mut bytes.as_slice(), region).unwrap();
+        assert_eq!(read.len(), chunks.len());
+        for (a, b) in chunks.iter().zip(&read) {
+            assert_same(a, b);
+        }
+
+        let empty = write_region(&[]);
+        assert!(RegionFile::read(&mut empty.as_slice(), region)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn regions_hold_one_region() {
+        let chunks = [
+            chunk(IVec3::new(15, 0, 0), UVec3::ONE, |_| 1),
+            chunk(IVec3::new(16, 0, 0), UVec3::ONE, |_| 1),
+        ];
+        let mut bytes = vec![];
+        assert!(RegionFile::write(&mut bytes, &chunks.iter().collect::<Vec<_>>()).is_err());
+    }
+
+    #[test]
+    fn saving_replaces_all_regions() {
+        let directory = std::env::temp_dir().join(format!("southwall-save-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let region_files = || {
+            let mut names = std::fs::read_dir(&directory)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+
+        let chunks = region_chunks();
+        let far = chunk(IVec3::new(40, 0, 0), UVec3::ONE, |_| 1);
+        let all = chunks.iter().chain([&far]).collect::<Vec<_>>();
+        assert_eq!(save_regions(&directory, &all).unwrap(), 2);
+        assert_eq!(region_files(), ["r.-1.0.0.swr", "r.2.0.0.swr"]);
+
+        // the region of the deleted chunk goes away, unrelated files stay
+        std::fs::write(directory.join("notes.txt"), "keep").unwrap();
+        assert_eq!(
+            save_regions(&directory, &chunks.iter().collect::<Vec<_>>()).unwrap(),
+            1
+        );
+        assert_eq!(region_files(), ["notes.txt", "r.-1.0.0.swr"]);
+        let bytes = std::fs::read(directory.join("r.-1.0.0.swr")).unwrap();
+        let read = RegionFile::read(&mut bytes.as_slice(), IVec3::new(-1, 0, 0)).unwrap();
+        assert_eq!(read.len(), chunks.len());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn region_version_mismatch_fails() {
+        let mut bytes = write_region(&region_chunks());
+        bytes[4..6].copy_from_slice(&(REGION_VERSION + 1).to_le_bytes());
+        let error = RegionFile::read(&mut bytes.as_slice(), IVec3::new(-1, 0, 0)).unwrap_err();
+        assert!(error.to_string().contains("version"));
+    }
+
+    #[test]
+    fn corrupt_regions_fail() {
+        let region = IVec3::new(-1, 0, 0);
+        let bytes = write_region(&region_chunks());
+        let read = |bytes: &[u8]| RegionFile::read(&mut &bytes[..], region);
+        assert!(read(&bytes[..bytes.len() - 1]).is_err());
+
+        // more chunks than fit in a region
+        let mut count = bytes.clone();
+        count[6..10].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(read(&count).is_err());
+
+        // the first index entry starts after the header and the local coordinate
+        let entry = 4 + 2 + 4 + 3;
+        for (offset, len) in [
+            (u32::MAX, 8),
+            (8, u32::MAX),
+            (0, 4),
+            (bytes.len() as u32, 1),
+        ] {
+            let mut index = bytes.clone();
+            index[entry..entry + 4].copy_from_slice(&offset.to_le_bytes());
+            index[entry + 4..entry + 8].copy_from_slice(&len.to_le_bytes());
+            assert!(read(&index).is_err());
+        }
+    }
+
+    #[test]
+    fn region_names() {
+        assert_eq!(
+            RegionFile::region_of(IVec3::new(-1, 15, 16)),
+            IVec3::new(-1, 0, 1)
+        );
+        let region = IVec3::new(-3, 0, 12);
+        assert_eq!(
+            parse_region_name(&RegionFile::file_name(region)),
+            Some(region)
+        );
+        assert_eq!(parse_region_name("r.1.2.swr"), None);
+        assert_eq!(parse_region_name("chunk.vox"), None);
+    }
+}